@@ -45,4 +45,5 @@ class TestObject(ObjectGeneratorBase):
         # float32に変換
         img = img.astype(np.float32) / 255.0        
 
-        return GeneratorWgslReturn(self.shader, img.tobytes(), img.shape[1], img.shape[0])
\ No newline at end of file
+        # OpenCVのフレームはガンマ補正済みの値なので、シェーダーの出力はsRGBとして扱う
+        return GeneratorWgslReturn(self.shader, img.tobytes(), img.shape[1], img.shape[0], color_space="srgb")
\ No newline at end of file
@@ -1,10 +1,27 @@
+import gpu_util
+import logging
+import numpy as np
 from .plugin_base import MainPluginBase, SubPluginBase
 from .plugin_base.generator_base import FilterGeneratorBase, ObjectGeneratorBase
 from .types.frame_structure import LayerStructure as LayerStructure
 from _typeshed import Incomplete
+from collections.abc import Buffer
 from typing import Callable
 
 executor: Incomplete
+logger: Incomplete
+
+class TracingHandler(logging.Handler):
+    """
+    loggingのレコードを、gpu_utilを通してネイティブ側のtracingに転送するハンドラ。
+    """
+    def emit(self, record: logging.LogRecord) -> None: ...
+
+def install_log_bridge() -> None:
+    """
+    ルートロガーにTracingHandlerを追加し、すべてのロガーの出力をネイティブ側のtracingに転送する。
+    ルートロガーのレベルはtracingで出力される最も詳細なレベルに合わせる。何度呼び出してもハンドラは1つだけ追加される。
+    """
 
 class PluginManager:
     """
@@ -16,8 +33,9 @@ class PluginManager:
     data_dir: Incomplete
     plugin_dir_name: Incomplete
     generator: Incomplete
+    last_report: gpu_util.PyExecutionReport | None
     compose_wgsl: Incomplete
-    def __init__(self, data_dir: str, plugin_dir_name: str = 'plugins') -> None:
+    def __init__(self, data_dir: str, plugin_dir_name: str = 'plugins', generator_options: gpu_util.PyImageGeneratorOptions | None = None) -> None:
         '''
         フレーム生成マネージャーの初期化をする。data_dirはデータディレクトリのパス(通常はget_data_dirによるもの)、plugin_dir_nameはプラグインディレクトリの名前を指定する。
         プラグインディレクトリの構造は以下のようになることを想定している。
@@ -35,6 +53,7 @@ class PluginManager:
         Args:
             data_dir (str): データディレクトリのパス
             plugin_dir_name (str): プラグインディレクトリの名前 (デフォルト: "plugins")
+            generator_options (gpu_util.PyImageGeneratorOptions | None): 使用するアダプタなどのGPU設定 (デフォルト: 自動選択、パイプラインキャッシュはdata_dir/cacheに保存)
         '''
     @classmethod
     def plugin(cls, func: type[MainPluginBase]) -> Callable:
@@ -75,7 +94,7 @@ class PluginManager:
         Returns:
             bool: プラグインが正常に追加または更新された場合はTrue、それ以外の場合はFalse
         """
-    def make_frame(self, frame_number: int, frame_structure: list[LayerStructure], width: int, height: int, buffer_ptr: int) -> None:
+    def make_frame(self, frame_number: int, frame_structure: list[LayerStructure], width: int, height: int, buffer: Buffer, output_format: str = "rgba8", output_color_space: str = "srgb", output_alpha_mode: str = "straight") -> None:
         """
         指定されたフレーム構造に基づいてフレームを生成するメソッド。
 
@@ -84,18 +103,41 @@ class PluginManager:
             frame_structure (list[LayerStructure]): フレーム構造のリスト
             width (int): フレームの幅
             height (int): フレームの高さ
-            buffer_ptr (int): 書き込み先バッファのポインタ
+            buffer (Buffer): 書き込み先の書き込み可能なバッファ (bytearray, memoryview, uint8のnumpy配列など)。gpu_util.PyImageGenerator.output_sizeのサイズ以上確保すること
+            output_format (str): 出力フォーマット (rgba8, bgra8, rgba16, rgba32f, i420, nv12)
+            output_color_space (str): 出力の色空間 (srgb, linear_srgb, rec709, display_p3, linear_display_p3, rec2020_pq, rec2020_hlg, linear_rec2020)
+            output_alpha_mode (str): 出力のアルファの形式 (straight, premultiplied)
         """
-    def make_frames(self, start_frame_number: int, amount: int, *args, **kwargs):
+    async def make_frame_async(self, frame_number: int, frame_structure: list[LayerStructure], width: int, height: int, buffer: Buffer, output_format: str = "rgba8", output_color_space: str = "srgb", output_alpha_mode: str = "straight") -> None:
         """
-        指定された数だけフレームをmultithreadingで生成するメソッド。make_frameと同じ引数を受け取り、amountで指定された数だけフレームを生成してリストで返す。
+        make_frameの非同期版。GPUの処理を待つ間にイベントループへ制御を返すため、
+        asyncio.gatherなどで複数のフレームの生成を重ねることができる。
+
+        Args:
+            frame_number (int): 生成するフレームの番号
+            frame_structure (list[LayerStructure]): フレーム構造のリスト
+            width (int): フレームの幅
+            height (int): フレームの高さ
+            buffer (Buffer): 書き込み先の書き込み可能なバッファ (bytearray, memoryview, uint8のnumpy配列など)。gpu_util.PyImageGenerator.output_sizeのサイズ以上確保すること
+            output_format (str): 出力フォーマット (rgba8, bgra8, rgba16, rgba32f, i420, nv12)
+            output_color_space (str): 出力の色空間 (srgb, linear_srgb, rec709, display_p3, linear_display_p3, rec2020_pq, rec2020_hlg, linear_rec2020)
+            output_alpha_mode (str): 出力のアルファの形式 (straight, premultiplied)
+        """
+    def make_frames(self, start_frame_number: int, amount: int, frame_structure: list[LayerStructure], width: int, height: int, output_format: str = "rgba8", output_color_space: str = "srgb", output_alpha_mode: str = "straight") -> list[np.ndarray]:
+        """
+        指定された数だけフレームをmultithreadingで生成するメソッド。
+        フレームごとに出力の配列を確保するため、各フレームは互いに独立して並列に生成される。
 
         Args:
             start_frame_number (int): 生成を開始するフレームの番号
             amount (int): 生成するフレームの数
-            *args: make_frameに渡す引数
-            **kwargs: make_frameに渡すキーワード引数
+            frame_structure (list[LayerStructure]): フレーム構造のリスト
+            width (int): フレームの幅
+            height (int): フレームの高さ
+            output_format (str): 出力フォーマット (rgba8, bgra8, rgba16, rgba32f, i420, nv12)
+            output_color_space (str): 出力の色空間 (srgb, linear_srgb, rec709, display_p3, linear_display_p3, rec2020_pq, rec2020_hlg, linear_rec2020)
+            output_alpha_mode (str): 出力のアルファの形式 (straight, premultiplied)
 
         Returns:
-            list[np.ndarray]: 生成されたフレームのリスト
+            list[np.ndarray]: フレーム番号順に並べた生成されたフレームのリスト。配列の形状はgpu_util.PyImageGenerator.generate_arrayと同じ
         """
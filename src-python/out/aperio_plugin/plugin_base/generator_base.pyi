@@ -5,9 +5,11 @@ from gpu_util import PyCompiledFunc as PyCompiledFunc, PyCompiledWgsl as PyCompi
 @dataclass
 class GeneratorWgslReturn:
     compiled: PyCompiledWgsl
-    params: bytes
+    params: bytes | dict | list | None
     output_width: int
     output_height: int
+    color_space: str | None = None
+    alpha_mode: str = "premultiplied"
 
 @dataclass
 class GeneratorFuncReturn:
@@ -2,29 +2,456 @@
 # ruff: noqa: E501, F401
 
 import builtins
+import collections.abc
+import numpy.typing
+import os
+import pathlib
 import typing
 
+class BindingError(GpuUtilError):
+    r"""
+    ステップの入力、バッファ、サンプラーなどがシェーダーの宣言と一致しない場合の例外。
+    """
+    ...
+
+class CpuFuncError(GpuUtilError):
+    r"""
+    CPU関数が失敗した場合の例外。
+    
+    Pythonの関数が例外を送出した場合は、その例外が__cause__に設定されます。
+    """
+    ...
+
+class DeviceError(GpuUtilError):
+    r"""
+    アダプタやデバイスの取得に失敗した場合や、アダプタが必要な機能に対応していない場合の例外。
+    """
+    ...
+
+class GpuUtilError(builtins.Exception):
+    r"""
+    gpu_utilの処理で発生する例外の基底クラス。
+    
+    属性としてstep_index (エラーが発生したステップの位置) とshader_id (シェーダーのID) を持ち、分からない場合はNoneになります。
+    """
+    ...
+
+@typing.final
+class PyAdapterInfo:
+    @property
+    def name(self) -> builtins.str: ...
+    @property
+    def backend(self) -> builtins.str: ...
+    @property
+    def device_type(self) -> builtins.str: ...
+    @property
+    def driver(self) -> builtins.str: ...
+    @property
+    def driver_info(self) -> builtins.str: ...
+    @property
+    def features(self) -> builtins.list[builtins.str]: ...
+    def __repr__(self) -> builtins.str: ...
+
 @typing.final
 class PyCompiledFunc:
-    def __new__(cls, id: builtins.str, func: typing.Any) -> PyCompiledFunc: ...
+    def __new__(cls, id: builtins.str, func: typing.Any, color_space: builtins.str = 'srgb', alpha_mode: builtins.str = 'straight') -> PyCompiledFunc:
+        r"""
+        CPUで実行する関数を登録します。
+        color_spaceは関数が受け取る入力と返す出力の色空間です (デフォルトはsrgb)。
+        alpha_modeは入出力のアルファの形式で、straightかpremultipliedのいずれかです (デフォルトはstraight)。
+        """
 
 @typing.final
 class PyCompiledWgsl:
-    def __new__(cls, id: builtins.str, wgsl_code: builtins.str, generator: PyImageGenerator, sampler_options: typing.Optional[PySamplerOptions]) -> PyCompiledWgsl: ...
+    @property
+    def entry_points(self) -> builtins.list[builtins.str]:
+        r"""
+        コンピュートシェーダーのエントリーポイント名 (宣言順)
+        """
+    def __new__(cls, id: builtins.str, wgsl_code: builtins.str, generator: PyImageGenerator, sampler_options: typing.Optional[PySamplerOptions] = None, samplers: typing.Optional[typing.Mapping[builtins.str, PySamplerOptions]] = None) -> PyCompiledWgsl:
+        r"""
+        WGSLをコンパイルします。
+        sampler_optionsはすべてのsamplerに、samplersは名前が一致するsamplerにのみ使われます。
+        """
+    def param_layout(self, buffer: typing.Optional[builtins.str] = None, entry_point: typing.Optional[builtins.str] = None) -> PyParamLayout:
+        r"""
+        バッファに渡すパラメータのレイアウトを取得します。
+        bufferを省略した場合は、エントリーポイントが使用する唯一のバッファのレイアウトを返します。
+        entry_pointを省略した場合は`main`、エントリーポイントが1つだけならそれを使います。
+        """
+
+@typing.final
+class PyExecutablePlan:
+    def step_names(self) -> builtins.list[builtins.str]:
+        r"""
+        paramsやbuffersを書き換えられる、名前を持つステップの一覧を返します。
+        """
+    def execute(self, buffer: collections.abc.Buffer, params: dict[str, bytes | dict | list] | None = None, buffers: dict[str, dict[str, bytes | dict | list]] | None = None, format: builtins.str = 'rgba8', color_space: builtins.str = 'srgb', alpha_mode: builtins.str = 'straight') -> None:
+        r"""
+        ステップのバッファを書き換えてから計画を実行し、結果をbufferに書き込みます。
+        paramsはステップの名前とparamsを渡したバッファの新しい値、buffersはステップの名前と
+        変数名ごとのバッファの新しい値です。値の形式はadd_wgslと同じで、準備したときと同じバイト数である必要があります。
+        書き換えた内容は以降の実行でも保持されます。buffer, format, color_space, alpha_modeはgenerateと同じです。
+        """
+
+@typing.final
+class PyExecutionReport:
+    r"""
+    プロファイリングを有効にした生成の実行レポート
+    """
+    @property
+    def steps(self) -> builtins.list[PyStepReport]:
+        r"""
+        処理を始めた順に並べた項目
+        """
+    @property
+    def total_ms(self) -> builtins.float:
+        r"""
+        生成全体にかかった時間 (ミリ秒)
+        """
+    @property
+    def gpu_ms(self) -> typing.Optional[builtins.float]:
+        r"""
+        すべての項目のGPUでの実行時間の合計 (ミリ秒)。タイムスタンプが使えない場合はNone
+        """
+    @property
+    def gpu_timestamps(self) -> builtins.bool:
+        r"""
+        GPUのタイムスタンプを計測できたかどうか
+        """
+    def to_json(self) -> builtins.str:
+        r"""
+        レポートをJSONの文字列に変換します。
+        """
+    def to_chrome_trace(self) -> builtins.str:
+        r"""
+        Chromeのトレースイベント形式 (chrome://tracingやPerfettoで開けるJSON) の文字列に変換します。
+        """
 
 @typing.final
 class PyImageGenerateBuilder:
     def __new__(cls) -> PyImageGenerateBuilder: ...
-    def add_wgsl(self, wgsl: PyCompiledWgsl, params: typing.Optional[bytes], output_width: builtins.int, output_height: builtins.int) -> PyImageGenerateBuilder: ...
+    def add_wgsl(self, wgsl: PyCompiledWgsl, params: bytes | dict | list | None, output_width: builtins.int, output_height: builtins.int, buffers: dict[str, bytes | dict | list] | None = None, dispatch: typing.Optional[tuple[builtins.int, builtins.int, builtins.int]] = None, workgroups: typing.Optional[tuple[builtins.int, builtins.int, builtins.int]] = None, entry_point: typing.Optional[builtins.str] = None, color_space: typing.Optional[builtins.str] = None, alpha_mode: builtins.str = 'premultiplied', name: typing.Optional[builtins.str] = None, cache: builtins.bool = False) -> PyImageGenerateBuilder:
+        r"""
+        WGSL処理ステップを追加します。
+        paramsはbuffersで指定されなかった唯一のバッファに、buffersは変数名が一致するバッファに渡されます。
+        値にはbytesのほか、シェーダーで宣言された型に従って自動で詰められるdictやlistを渡せます。
+        dispatchにはシェーダーの呼び出し回数 (x, y, z) を、workgroupsにはワークグループ数 (x, y, z) を指定できます。
+        どちらも指定しない場合は出力の1ピクセルにつき1回呼び出されます。
+        entry_pointを省略した場合は`main`、エントリーポイントが1つだけならそれを使います。
+        color_spaceはシェーダーが書き込む値の色空間で、省略した場合は作業用の色空間として扱われます。
+        alpha_modeはシェーダーが入出力に使うアルファの形式で、straightかpremultipliedのいずれかです (デフォルトはpremultiplied)。
+        nameは`ExecutablePlan.execute`でパラメータを書き換えるときにステップを指定する名前です。
+        cacheをTrueにすると、入力とパラメータが以前の実行と同じ場合に結果キャッシュの結果を再利用します。
+        結果のテクスチャはキャッシュに保持されるため、毎フレーム内容が変わるステップには指定しないでください。
+        """
     def add_parallel_wgsl(self, pipelines: typing.Sequence[PyImageGenerateBuilder]) -> PyImageGenerateBuilder: ...
     def add_func(self, func: PyCompiledFunc, params: typing.Optional[typing.Any], output_width: builtins.int, output_height: builtins.int) -> PyImageGenerateBuilder: ...
+    def add_graph(self, graph: PyImageGraphBuilder) -> PyImageGenerateBuilder:
+        r"""
+        処理グラフをステップとして追加します。
+        グラフには直前のステップの出力が渡され、グラフの出力が次のステップの入力になります。
+        ノード名の重複や存在しないノードの参照、循環する依存関係がある場合はBindingErrorを送出します。
+        どの出力にも使われないノードは実行されません。
+        """
+    def add_cached(self, key: builtins.str, pipeline: PyImageGenerateBuilder) -> PyImageGenerateBuilder:
+        r"""
+        結果をキャッシュするサブパイプラインをステップとして追加します。
+        keyと入力の内容が以前の生成と同じ場合は、サブパイプラインを実行せずに前回の結果を使います。
+        CPU関数のように入力以外から結果が決まる処理は、その内容をkeyに含めてください。
+        """
 
 @typing.final
 class PyImageGenerator:
-    def __new__(cls) -> PyImageGenerator: ...
-    def generate(self, builder: PyImageGenerateBuilder, buffer_ptr: builtins.int) -> None: ...
+    @property
+    def profiling(self) -> builtins.bool:
+        r"""
+        プロファイリングが有効かどうか。有効にすると、generateとgenerate_asyncが各ステップの処理時間を計測した
+        PyExecutionReportを返します。計測のために生成ごとに読み戻しが1回増えるため、必要なときだけ有効にしてください。
+        """
+    @profiling.setter
+    def profiling(self, value: builtins.bool) -> None: ...
+    @property
+    def max_result_cache_bytes(self) -> builtins.int:
+        r"""
+        結果キャッシュの容量 (バイト)。0にすると結果をキャッシュしません。
+        """
+    @max_result_cache_bytes.setter
+    def max_result_cache_bytes(self, value: builtins.int) -> None: ...
+    @property
+    def working_space(self) -> builtins.str:
+        r"""
+        WGSLのステップが入出力に使う作業用の色空間の名前を返します。
+        """
+    def __new__(cls, options: typing.Optional[PyImageGeneratorOptions] = None) -> PyImageGenerator: ...
+    @staticmethod
+    def enumerate_adapters(backend: typing.Optional[builtins.str] = None) -> builtins.list[PyAdapterInfo]:
+        r"""
+        利用可能なアダプタの一覧を取得します。
+        """
+    def adapter_info(self) -> PyAdapterInfo:
+        r"""
+        使用中のアダプタの情報を取得します。
+        """
+    def supports_binding_array(self) -> builtins.bool:
+        r"""
+        binding_arrayで入力を受け取るシェーダーが使えるかどうかを返します。
+        Falseの場合は`texture_2d_array`または個別の`texture_2d`で入力を受け取る規約を使用してください。
+        """
+    def supports_gpu_timestamps(self) -> builtins.bool:
+        r"""
+        GPUのタイムスタンプでコンピュートパスの実行時間を計測できるかどうかを返します。
+        Falseの場合、実行レポートにはCPUでの処理時間のみが記録されます。
+        """
+    def result_cache_stats(self) -> PyResultCacheStats:
+        r"""
+        結果キャッシュの統計を返します。
+        """
+    def clear_result_cache(self) -> None:
+        r"""
+        結果キャッシュのすべての結果を破棄します。
+        """
+    @staticmethod
+    def output_size(width: builtins.int, height: builtins.int, format: builtins.str = 'rgba8') -> builtins.int:
+        r"""
+        指定したフォーマットで出力した場合のバイト数を返します。
+        generateに渡すバッファはこのサイズ以上確保してください。
+        """
+    def generate(self, builder: PyImageGenerateBuilder, buffer: collections.abc.Buffer, format: builtins.str = 'rgba8', color_space: builtins.str = 'srgb', alpha_mode: builtins.str = 'straight') -> typing.Optional[PyExecutionReport]:
+        r"""
+        パイプラインを実行し、結果をbufferに書き込みます。
+        bufferはbytearrayやmemoryview、uint8のnumpy配列など、書き込み可能で連続したバッファプロトコルのオブジェクトです。
+        formatはrgba8, bgra8, rgba16, rgba32f, i420, nv12のいずれかです。
+        color_spaceは出力の色空間で、作業用の色空間から自動で変換されます。
+        alpha_modeは出力のアルファの形式で、straightかpremultipliedのいずれかです。
+        実行中はGILを解放するため、複数のスレッドから同時に呼び出すと並列に処理されます。
+        profilingが有効な場合は実行レポートを、それ以外の場合はNoneを返します。
+        """
+    async def generate_async(self, builder: PyImageGenerateBuilder, buffer: collections.abc.Buffer, format: builtins.str = 'rgba8', color_space: builtins.str = 'srgb', alpha_mode: builtins.str = 'straight') -> typing.Optional[PyExecutionReport]:
+        r"""
+        generateの非同期版です。awaitしている間、GPUの処理はバックグラウンドのスレッドで実行されるため、
+        asyncioのイベントループをブロックせずに複数のフレームの処理を重ねることができます。
+        bufferは処理が完了するまで変更しないでください。
+        """
+    def generate_batch(self, builders: typing.Sequence[PyImageGenerateBuilder], buffers: collections.abc.Sequence[collections.abc.Buffer], callback: typing.Callable[[int], None] | None = None, format: builtins.str = 'rgba8', color_space: builtins.str = 'srgb', alpha_mode: builtins.str = 'straight') -> None:
+        r"""
+        複数のフレームのパイプラインをまとめてサブミットし、結果をbuffersの対応するバッファに書き込みます。
+        読み戻しが完了したフレームから順に書き込み、callbackが指定されていればそのフレームの番号を渡して呼び出します。
+        buffersはbuildersと同じ数だけ必要です。format, color_space, alpha_modeはgenerateと同じです。
+        callbackが例外を送出した場合は残りのフレームを破棄して中断します。
+        """
+    def prepare(self, builder: PyImageGenerateBuilder) -> PyExecutablePlan:
+        r"""
+        パイプラインを検証し、WGSLステップのテクスチャ、バインドグループ、バッファを作成した実行計画を返します。
+        同じパイプラインをパラメータだけ変えて繰り返し実行する場合は、generateより1回あたりの処理が少なくなります。
+        """
+    def generate_array(self, builder: PyImageGenerateBuilder, format: builtins.str = 'rgba8', color_space: builtins.str = 'srgb', alpha_mode: builtins.str = 'straight') -> numpy.typing.NDArray[typing.Any]:
+        r"""
+        パイプラインを実行し、結果をnumpy配列として返します。
+        rgba8とbgra8は(H, W, 4)のuint8、rgba16は(H, W, 4)のuint16、rgba32fは(H, W, 4)のfloat32、
+        i420とnv12は1次元のuint8の配列になります。
+        """
+
+@typing.final
+class PyImageGeneratorOptions:
+    def __new__(cls, backend: typing.Optional[builtins.str] = None, power_preference: typing.Optional[builtins.str] = None, force_fallback_adapter: builtins.bool = False, adapter_name: typing.Optional[builtins.str] = None, adapter_index: typing.Optional[builtins.int] = None, cache_dir: typing.Optional[builtins.str | os.PathLike | pathlib.Path] = None, working_space: builtins.str = 'linear_srgb') -> PyImageGeneratorOptions:
+        r"""
+        working_spaceはWGSLのステップが入出力に使う色空間です (デフォルトはlinear_srgb)。
+        """
+
+@typing.final
+class PyImageGraphBuilder:
+    def __new__(cls) -> PyImageGraphBuilder: ...
+    def add_wgsl(self, name: builtins.str, wgsl: PyCompiledWgsl, params: bytes | dict | list | None, output_width: builtins.int, output_height: builtins.int, inputs: list[str | int] | None = None, buffers: dict[str, bytes | dict | list] | None = None, dispatch: typing.Optional[tuple[builtins.int, builtins.int, builtins.int]] = None, workgroups: typing.Optional[tuple[builtins.int, builtins.int, builtins.int]] = None, entry_point: typing.Optional[builtins.str] = None, color_space: typing.Optional[builtins.str] = None, alpha_mode: builtins.str = 'premultiplied', cache: builtins.bool = False) -> PyImageGraphBuilder:
+        r"""
+        WGSL処理のノードを追加します。
+        inputsには入力にするノードの名前か、グラフに渡された画像の位置を、シェーダーに渡す順に指定します。
+        ノードの名前を指定した場合は、そのノードのすべての出力が入力になります。
+        その他の引数はPyImageGenerateBuilder.add_wgslと同じです。
+        """
+    def add_func(self, name: builtins.str, func: PyCompiledFunc, params: typing.Optional[typing.Any], output_width: builtins.int, output_height: builtins.int, inputs: list[str | int] | None = None) -> PyImageGraphBuilder:
+        r"""
+        CPU関数処理のノードを追加します。inputsはadd_wgslと同じです。
+        """
+    def add_pipeline(self, name: builtins.str, pipeline: PyImageGenerateBuilder, inputs: list[str | int] | None = None) -> PyImageGraphBuilder:
+        r"""
+        直列のパイプラインをノードとして追加します。入力はパイプラインの最初のステップに渡されます。
+        """
+    def output(self, name: builtins.str) -> PyImageGraphBuilder:
+        r"""
+        グラフの出力にするノードを追加します。複数指定した場合は、指定した順に出力が並びます。
+        """
+
+@typing.final
+class PyParamField:
+    r"""
+    シェーダーのパラメータ構造体のメンバー
+    """
+    @property
+    def name(self) -> builtins.str: ...
+    @property
+    def offset(self) -> builtins.int: ...
+    @property
+    def size(self) -> builtins.int: ...
+    @property
+    def wgsl_type(self) -> builtins.str: ...
+    def __repr__(self) -> builtins.str: ...
+
+@typing.final
+class PyParamLayout:
+    r"""
+    シェーダーのバッファに渡すパラメータのレイアウト。
+    配列の場合、fieldsとstrideは要素のものになります。
+    """
+    @property
+    def buffer(self) -> builtins.str: ...
+    @property
+    def wgsl_type(self) -> builtins.str: ...
+    @property
+    def size(self) -> builtins.int: ...
+    @property
+    def align(self) -> builtins.int: ...
+    @property
+    def runtime_sized(self) -> builtins.bool:
+        r"""
+        実行時に長さが決まる配列かどうか
+        """
+    @property
+    def stride(self) -> builtins.int: ...
+    @property
+    def fields(self) -> builtins.list[PyParamField]: ...
+    def __repr__(self) -> builtins.str: ...
+
+@typing.final
+class PyResultCacheStats:
+    r"""
+    結果キャッシュの統計
+    """
+    @property
+    def hits(self) -> builtins.int:
+        r"""
+        キャッシュから結果を返したステップの数
+        """
+    @property
+    def misses(self) -> builtins.int:
+        r"""
+        キャッシュになく、実行したステップの数
+        """
+    @property
+    def evictions(self) -> builtins.int:
+        r"""
+        容量を超えたために破棄した結果の数
+        """
+    @property
+    def entries(self) -> builtins.int:
+        r"""
+        保持している結果の数
+        """
+    @property
+    def bytes(self) -> builtins.int:
+        r"""
+        保持している結果の合計のバイト数
+        """
 
 @typing.final
 class PySamplerOptions:
     def __new__(cls, address_mode: builtins.str, filter: builtins.str) -> PySamplerOptions: ...
 
+@typing.final
+class PyStepReport:
+    r"""
+    実行レポートの1つの項目
+    """
+    @property
+    def id(self) -> builtins.str:
+        r"""
+        WGSLステップとパイプラインの作成ではシェーダーのID、それ以外では種類の名前
+        """
+    @property
+    def kind(self) -> builtins.str:
+        r"""
+        wgsl, cpu_func, upload, download, pipeline_compile, outputのいずれか
+        """
+    @property
+    def step_index(self) -> typing.Optional[builtins.int]:
+        r"""
+        パイプラインの中でのステップの位置
+        """
+    @property
+    def resolution(self) -> typing.Optional[tuple[builtins.int, builtins.int]]:
+        r"""
+        出力の解像度 (幅, 高さ)
+        """
+    @property
+    def cpu_start_ms(self) -> builtins.float:
+        r"""
+        生成の開始からCPUで処理を始めるまでの時間 (ミリ秒)
+        """
+    @property
+    def cpu_ms(self) -> builtins.float:
+        r"""
+        CPUでの処理時間 (ミリ秒)
+        """
+    @property
+    def gpu_start_ms(self) -> typing.Optional[builtins.float]:
+        r"""
+        最初のコンピュートパスの開始からGPUで処理を始めるまでの時間 (ミリ秒)
+        """
+    @property
+    def gpu_ms(self) -> typing.Optional[builtins.float]:
+        r"""
+        GPUでのコンピュートパスの実行時間の合計 (ミリ秒)
+        """
+    @property
+    def cache(self) -> typing.Optional[builtins.str]:
+        r"""
+        キャッシュを引いた結果 (hitまたはmiss)
+        """
+    def __repr__(self) -> builtins.str: ...
+
+class ReadbackError(GpuUtilError):
+    r"""
+    GPUの処理の完了待ちや、結果の読み戻しに失敗した場合の例外。
+    """
+    ...
+
+class ShaderCompileError(ShaderError):
+    r"""
+    WGSLの解析・検証に失敗した場合の例外。
+    
+    属性としてfile_id, line, column, message, snippetを持ちます。
+    """
+    ...
+
+class ShaderError(GpuUtilError):
+    r"""
+    シェーダーのコンパイルに失敗した場合の例外。
+    """
+    ...
+
+class SizeError(GpuUtilError):
+    r"""
+    データの大きさや解像度、ディスパッチの数が期待と一致しない、または上限を超えた場合の例外。
+    """
+    ...
+
+class ValidationError(GpuUtilError):
+    r"""
+    wgpuやバックエンドがリソースの作成やコマンドを拒否した場合の例外。
+    
+    属性としてbinding (原因のバインディング。特定できない場合はNone) も持ちます。
+    """
+    ...
+
+def emit_log(level: builtins.int, logger: builtins.str, message: builtins.str) -> None:
+    r"""
+    Pythonの`logging`のレコードを、tracingのイベントとして出力します。
+    levelはPythonのログレベル (`logging.DEBUG`など)、loggerはロガーの名前です。
+    """
+
+def max_log_level() -> builtins.int:
+    r"""
+    tracingで出力される最も詳細なレベルを、Pythonのログレベルで返します。
+    Pythonのロガーのレベルに設定すると、出力されないレコードを作らずに済みます。
+    """
+
@@ -1,10 +1,11 @@
 import glob
 import hashlib
+import logging
 import math
 import os.path
 import shutil
+from collections.abc import Buffer
 from concurrent.futures.thread import ThreadPoolExecutor
-import struct
 import time
 from typing import Callable
 import gpu_util
@@ -35,6 +36,30 @@ from .plugin_base.generator_base import FilterGeneratorBase, GeneratorFuncReturn
 from .types.frame_structure import LayerStructure
 
 executor = ThreadPoolExecutor()
+logger = logging.getLogger(__name__)
+
+
+class TracingHandler(logging.Handler):
+    """
+    loggingのレコードを、gpu_utilを通してネイティブ側のtracingに転送するハンドラ。
+    """
+
+    def emit(self, record: logging.LogRecord) -> None:
+        try:
+            gpu_util.emit_log(record.levelno, record.name, self.format(record))
+        except Exception:
+            self.handleError(record)
+
+
+def install_log_bridge() -> None:
+    """
+    ルートロガーにTracingHandlerを追加し、すべてのロガーの出力をネイティブ側のtracingに転送する。
+    ルートロガーのレベルはtracingで出力される最も詳細なレベルに合わせる。何度呼び出してもハンドラは1つだけ追加される。
+    """
+    root = logging.getLogger()
+    if not any(isinstance(h, TracingHandler) for h in root.handlers):
+        root.addHandler(TracingHandler())
+    root.setLevel(gpu_util.max_log_level())
 
 
 class PluginManager:
@@ -47,7 +72,8 @@ class PluginManager:
     object_plugins: dict[str, ObjectGeneratorBase] = {}
     filter_plugins: dict[str, FilterGeneratorBase] = {}
 
-    def __init__(self, data_dir: str, plugin_dir_name="plugins"):
+    def __init__(self, data_dir: str, plugin_dir_name="plugins",
+                 generator_options: gpu_util.PyImageGeneratorOptions | None = None):
         """
         フレーム生成マネージャーの初期化をする。data_dirはデータディレクトリのパス(通常はget_data_dirによるもの)、plugin_dir_nameはプラグインディレクトリの名前を指定する。
         プラグインディレクトリの構造は以下のようになることを想定している。
@@ -65,20 +91,28 @@ class PluginManager:
         Args:
             data_dir (str): データディレクトリのパス
             plugin_dir_name (str): プラグインディレクトリの名前 (デフォルト: "plugins")
+            generator_options (gpu_util.PyImageGeneratorOptions | None): 使用するアダプタなどのGPU設定 (デフォルト: 自動選択、パイプラインキャッシュはdata_dir/cacheに保存)
         """
 
         # openCLが使えるか確認して、有効化
         if cv2.ocl.haveOpenCL():
             cv2.ocl.setUseOpenCL(True)
-            print(f"OpenCV: OpenCL is available. OpenCL is set to {cv2.ocl.useOpenCL()}")
+            logger.info("OpenCV: OpenCL is available. OpenCL is set to %s", cv2.ocl.useOpenCL())
         else:
-            print("OpenCV: OpenCL is not available.")
+            logger.info("OpenCV: OpenCL is not available.")
 
         self.data_dir = data_dir
         self.plugin_dir_name = plugin_dir_name
-        self.generator = gpu_util.PyImageGenerator()
-
-        with open(os.path.join(os.path.dirname(__file__), "shaders", "compose.wgsl"), "r") as f:
+        if generator_options is None:
+            generator_options = gpu_util.PyImageGeneratorOptions(cache_dir=os.path.join(data_dir, "cache"))
+        self.generator = gpu_util.PyImageGenerator(generator_options)
+        # generator.profilingが有効な場合、最後に生成したフレームの実行レポート
+        self.last_report: gpu_util.PyExecutionReport | None = None
+        logger.info("gpu_util: Using adapter %s", self.generator.adapter_info())
+
+        # binding_arrayに対応していないアダプタでは、配列テクスチャを使う版の合成シェーダーを使う
+        compose_shader = "compose.wgsl" if self.generator.supports_binding_array() else "compose_texture_array.wgsl"
+        with open(os.path.join(os.path.dirname(__file__), "shaders", compose_shader), "r") as f:
             sampler = gpu_util.PySamplerOptions("clamp_to_edge", "linear")
             self.compose_wgsl = gpu_util.PyCompiledWgsl("compose_layer", f.read(), self.generator, sampler)
 
@@ -89,7 +123,7 @@ class PluginManager:
         for d in dirs:
             plugin_name = d.split("/")[-1]
             if not os.path.exists(f"{d}/__init__.py"):
-                print(f"Plugin {plugin_name} does not have an __init__.py file. Skipping.")
+                logger.warning("Plugin %s does not have an __init__.py file. Skipping.", plugin_name)
                 continue
             __import__(f"{self.plugin_dir_name}.{plugin_name}")
 
@@ -103,21 +137,19 @@ class PluginManager:
 
         for name, plugin_cls in self.__plugins.items():
             if name in self.plugins:
-                print(f"INFO: Plugin {name} is already registered. Skipping.")
+                logger.info("Plugin %s is already registered. Skipping.", name)
                 continue  # 既に登録されている場合はスキップ
 
             try:
                 plugin_instance = plugin_cls(self, self.generator)  # PluginManagerのインスタンスを渡す
                 self.plugins[name] = plugin_instance
-                print(f"Registered plugin: {plugin_instance.name}")
-            except Exception as e:
-                print(f"Failed to load plugin {name}: {e}")
+                logger.info("Registered plugin: %s", plugin_instance.name)
+            except Exception:
+                logger.exception("Failed to load plugin %s", name)
 
-            print("Loaded Plugins ---")
-            print("\n".join(
-                list(map(lambda n: f"{n[0]}(Object)- {n[1].get_display_info()}", self.object_plugins.items()))))
-            print("\n".join(
-                list(map(lambda n: f"{n[0]}(Filter)- {n[1].get_display_info()}", self.filter_plugins.items()))))
+            logger.debug("Loaded Plugins ---\n%s\n%s",
+                         "\n".join(f"{n}(Object)- {p.get_display_info()}" for n, p in self.object_plugins.items()),
+                         "\n".join(f"{n}(Filter)- {p.get_display_info()}" for n, p in self.filter_plugins.items()))
 
     @classmethod
     def plugin(cls, func: type[MainPluginBase]) -> Callable:
@@ -182,16 +214,16 @@ class PluginManager:
         # TODO: URLからのダウンロードや、zipファイルの解凍などもここで行う
 
         if not os.path.exists(plugin_dir) or not os.path.isdir(plugin_dir):
-            print(f"Plugin directory {plugin_dir} does not exist.")
+            logger.warning("Plugin directory %s does not exist.", plugin_dir)
             return False
 
         plugin_name = plugin_dir.split("/")[-1]
         if plugin_name in self.plugins:
             # 既に登録されている場合は__init__.pyのハッシュ値を比較して、異なる場合のみ更新する
             # TODO: バージョン確認で新しければアップデート、古ければ確認みたいにしたい
-            print(f"Plugin {plugin_name} is already registered. Trying to update to specified version.")
+            logger.info("Plugin %s is already registered. Trying to update to specified version.", plugin_name)
             if not os.path.exists(f"{plugin_dir}/__init__.py"):
-                print(f"Plugin {plugin_name} does not have an __init__.py file. Skipping.")
+                logger.warning("Plugin %s does not have an __init__.py file. Skipping.", plugin_name)
                 return False
 
             with open(f"{plugin_dir}/__init__.py", "rb") as f:
@@ -199,133 +231,188 @@ class PluginManager:
                 with open(f"{self.data_dir}/{self.plugin_dir_name}/{plugin_name}/__init__.py", "rb") as ef:
                     existing_hash = hashlib.sha256(ef.read()).hexdigest()
                     if new_hash == existing_hash:
-                        print(f"Plugin {plugin_name} is completely same. Skipping.")
+                        logger.info("Plugin %s is completely same. Skipping.", plugin_name)
                         return True
 
         shutil.copytree(plugin_dir, f"{self.data_dir}/{self.plugin_dir_name}/{plugin_name}", dirs_exist_ok=True)
 
         # プラグインを再読み込みして登録する
         if not os.path.exists(f"{self.data_dir}/{self.plugin_dir_name}/{plugin_name}/__init__.py"):
-            print(f"Plugin {plugin_name} does not have an __init__.py file after copying. Skipping.")
+            logger.warning("Plugin %s does not have an __init__.py file after copying. Skipping.", plugin_name)
             return False
         __import__(f"{self.plugin_dir_name}.{plugin_name}")
-        print(f"Plugin {plugin_name} has been added/updated.")
+        logger.info("Plugin %s has been added/updated.", plugin_name)
 
         self.__load_plugins()
         return True
 
-    def make_frame(self, frame_number: int, frame_structure: list[LayerStructure], 
-                             width: int, height: int, buffer_ptr: int) -> None:
+    def _build_frame(self, frame_number: int, frame_structure: list[LayerStructure],
+                     width: int, height: int) -> gpu_util.PyImageGenerateBuilder:
         """
-        指定されたフレーム構造に基づいてフレームを生成するメソッド。
+        指定されたフレーム構造から、フレームを生成するパイプラインを構築するメソッド。
 
         Args:
             frame_number (int): 生成するフレームの番号
             frame_structure (list[LayerStructure]): フレーム構造のリスト
             width (int): フレームの幅
             height (int): フレームの高さ
-            buffer_ptr (int): 書き込み先バッファのポインタ
+
+        Returns:
+            gpu_util.PyImageGenerateBuilder: フレームを生成するパイプライン
         """
-        try:
-            if not isinstance(frame_structure, list):
-                raise TypeError("frame_structure must be a list of LayerStructure")
-            if not all(isinstance(layer, dict) for layer in frame_structure):
-                raise TypeError("Each layer in frame_structure must be a LayerStructure")
-            if not isinstance(width, int) or not isinstance(height, int):
-                raise TypeError("width and height must be integers")
-            if width <= 0 or height <= 0:
-                raise ValueError("width and height must be positive integers")
-            if len(frame_structure) == 0:
-                raise ValueError("frame_structure must contain at least one layer")
-
-            # レイヤーごとにフレームを生成して合成する
-            layer_builders = []
-            params = []
-            for layer in frame_structure:
-                layer_builder = gpu_util.PyImageGenerateBuilder()
-                obj_name = layer["obj"]["name"]
-
-                if obj_name not in self.object_plugins:
-                    raise ValueError(f"Object plugin {obj_name} is not registered")
-
-                obj_plugin = self.object_plugins[obj_name]
-                layer_frame = obj_plugin.generate(frame_number, layer["obj"]["parameters"], width, height)
+        if not isinstance(frame_structure, list):
+            raise TypeError("frame_structure must be a list of LayerStructure")
+        if not all(isinstance(layer, dict) for layer in frame_structure):
+            raise TypeError("Each layer in frame_structure must be a LayerStructure")
+        if not isinstance(width, int) or not isinstance(height, int):
+            raise TypeError("width and height must be integers")
+        if width <= 0 or height <= 0:
+            raise ValueError("width and height must be positive integers")
+        if len(frame_structure) == 0:
+            raise ValueError("frame_structure must contain at least one layer")
+
+        # レイヤーごとにフレームを生成して合成する
+        layer_builders = []
+        params = []
+        for layer in frame_structure:
+            layer_builder = gpu_util.PyImageGenerateBuilder()
+            obj_name = layer["obj"]["name"]
+
+            if obj_name not in self.object_plugins:
+                raise ValueError(f"Object plugin {obj_name} is not registered")
+
+            obj_plugin = self.object_plugins[obj_name]
+            layer_frame = obj_plugin.generate(frame_number, layer["obj"]["parameters"], width, height)
+            if isinstance(layer_frame, GeneratorWgslReturn):
+                layer_builder = layer_builder.add_wgsl(layer_frame.compiled, layer_frame.params, 
+                                 layer_frame.output_width, layer_frame.output_height,
+                                 color_space=layer_frame.color_space, alpha_mode=layer_frame.alpha_mode)
+            elif isinstance(layer_frame, GeneratorFuncReturn):
+                layer_builder = layer_builder.add_func(layer_frame.compiled, layer_frame.params,
+                                  layer_frame.output_width, layer_frame.output_height)
+
+            # エフェクト適用
+            for effect in layer["effects"]:
+                if effect["name"] not in self.filter_plugins:
+                    raise ValueError(f"Filter plugin {effect['name']} is not registered")
+
+                filter_plugin = self.filter_plugins[effect["name"]]
+                layer_frame = filter_plugin.generate(frame_number, effect["parameters"], width, height)
                 if isinstance(layer_frame, GeneratorWgslReturn):
                     layer_builder = layer_builder.add_wgsl(layer_frame.compiled, layer_frame.params, 
-                                     layer_frame.output_width, layer_frame.output_height)
+                                     layer_frame.output_width, layer_frame.output_height,
+                                     color_space=layer_frame.color_space, alpha_mode=layer_frame.alpha_mode)
                 elif isinstance(layer_frame, GeneratorFuncReturn):
                     layer_builder = layer_builder.add_func(layer_frame.compiled, layer_frame.params,
                                       layer_frame.output_width, layer_frame.output_height)
 
-                # エフェクト適用
-                for effect in layer["effects"]:
-                    if effect["name"] not in self.filter_plugins:
-                        raise ValueError(f"Filter plugin {effect['name']} is not registered")
-
-                    filter_plugin = self.filter_plugins[effect["name"]]
-                    layer_frame = filter_plugin.generate(frame_number, effect["parameters"], width, height)
-                    if isinstance(layer_frame, GeneratorWgslReturn):
-                        layer_builder = layer_builder.add_wgsl(layer_frame.compiled, layer_frame.params, 
-                                         layer_frame.output_width, layer_frame.output_height)
-                    elif isinstance(layer_frame, GeneratorFuncReturn):
-                        layer_builder = layer_builder.add_func(layer_frame.compiled, layer_frame.params,
-                                          layer_frame.output_width, layer_frame.output_height)
-
-                layer_builders.append(layer_builder)
-
-                # params準備
-                # 回転をラジアンに変換してから回転行列を計算
-                rotation_rad = math.radians(layer["rotation"])
-                cos_theta = math.cos(rotation_rad)
-                sin_theta = math.sin(rotation_rad)
-                alpha = layer["alpha"]
-                rotation_matrix = [cos_theta, sin_theta, -sin_theta, cos_theta]
-
-                fmt = "<iiff"  # x, y, scale, alpha
-                fmt += "4f"  # rotation_matrix (2x2 floats)
-                params_bytes = struct.pack(fmt, layer["x"], layer["y"], layer["scale"], alpha, *rotation_matrix)
-                params.append(params_bytes)
-
-            # GPU処理実行
-            builder = gpu_util.PyImageGenerateBuilder() \
-                .add_parallel_wgsl(layer_builders) \
-                .add_wgsl(self.compose_wgsl, b"".join(params), width, height)
+            layer_builders.append(layer_builder)
+
+            # params準備 (シェーダーのLayerParamsのレイアウトに合わせてRust側で詰められる)
+            # 回転をラジアンに変換してから回転行列を計算
+            rotation_rad = math.radians(layer["rotation"])
+            cos_theta = math.cos(rotation_rad)
+            sin_theta = math.sin(rotation_rad)
+            params.append({
+                "x": layer["x"],
+                "y": layer["y"],
+                "scale": layer["scale"],
+                "alpha": layer["alpha"],
+                "rotation_matrix": [[cos_theta, sin_theta], [-sin_theta, cos_theta]],  # 列優先
+            })
+
+        return gpu_util.PyImageGenerateBuilder() \
+            .add_parallel_wgsl(layer_builders) \
+            .add_wgsl(self.compose_wgsl, params, width, height)
+
+    def make_frame(self, frame_number: int, frame_structure: list[LayerStructure], 
+                             width: int, height: int, buffer: Buffer, output_format: str = "rgba8",
+                             output_color_space: str = "srgb", output_alpha_mode: str = "straight") -> None:
+        """
+        指定されたフレーム構造に基づいてフレームを生成するメソッド。
+
+        Args:
+            frame_number (int): 生成するフレームの番号
+            frame_structure (list[LayerStructure]): フレーム構造のリスト
+            width (int): フレームの幅
+            height (int): フレームの高さ
+            buffer (Buffer): 書き込み先の書き込み可能なバッファ (bytearray, memoryview, uint8のnumpy配列など)。gpu_util.PyImageGenerator.output_sizeのサイズ以上確保すること
+            output_format (str): 出力フォーマット (rgba8, bgra8, rgba16, rgba32f, i420, nv12)
+            output_color_space (str): 出力の色空間 (srgb, linear_srgb, rec709, display_p3, linear_display_p3, rec2020_pq, rec2020_hlg, linear_rec2020)
+            output_alpha_mode (str): 出力のアルファの形式 (straight, premultiplied)
+        """
+        try:
+            builder = self._build_frame(frame_number, frame_structure, width, height)
 
             # 直接バッファに書き込み
-            self.generator.generate(builder, buffer_ptr)
+            self.last_report = self.generator.generate(builder, buffer, output_format, output_color_space,
+                                                       output_alpha_mode)
+
+        except Exception:
+            # ShaderErrorなどの例外をそのまま呼び出し元に伝え、種類ごとに捕捉できるようにする
+            logger.exception("Failed to make frame %d", frame_number)
+            raise
+
+    async def make_frame_async(self, frame_number: int, frame_structure: list[LayerStructure],
+                               width: int, height: int, buffer: Buffer, output_format: str = "rgba8",
+                               output_color_space: str = "srgb", output_alpha_mode: str = "straight") -> None:
+        """
+        make_frameの非同期版。GPUの処理を待つ間にイベントループへ制御を返すため、
+        asyncio.gatherなどで複数のフレームの生成を重ねることができる。
+
+        Args:
+            frame_number (int): 生成するフレームの番号
+            frame_structure (list[LayerStructure]): フレーム構造のリスト
+            width (int): フレームの幅
+            height (int): フレームの高さ
+            buffer (Buffer): 書き込み先の書き込み可能なバッファ (bytearray, memoryview, uint8のnumpy配列など)。gpu_util.PyImageGenerator.output_sizeのサイズ以上確保すること
+            output_format (str): 出力フォーマット (rgba8, bgra8, rgba16, rgba32f, i420, nv12)
+            output_color_space (str): 出力の色空間 (srgb, linear_srgb, rec709, display_p3, linear_display_p3, rec2020_pq, rec2020_hlg, linear_rec2020)
+            output_alpha_mode (str): 出力のアルファの形式 (straight, premultiplied)
+        """
+        try:
+            builder = self._build_frame(frame_number, frame_structure, width, height)
 
-        except Exception as e:
-            import traceback
-            traceback.print_exc()
-            raise RuntimeError(f"Failed to make frame: {e}")
+            # GPUの処理はバックグラウンドで行われ、完了後にバッファに書き込まれる
+            self.last_report = await self.generator.generate_async(builder, buffer, output_format,
+                                                                   output_color_space, output_alpha_mode)
 
+        except Exception:
+            logger.exception("Failed to make frame %d", frame_number)
+            raise
 
-    def make_frames(self, start_frame_number: int, amount: int, *args, **kwargs):
+
+    def make_frames(self, start_frame_number: int, amount: int, frame_structure: list[LayerStructure],
+                    width: int, height: int, output_format: str = "rgba8", output_color_space: str = "srgb",
+                    output_alpha_mode: str = "straight") -> list[np.ndarray]:
         """
-        指定された数だけフレームをmultithreadingで生成するメソッド。make_frameと同じ引数を受け取り、amountで指定された数だけフレームを生成してリストで返す。
+        指定された数だけフレームをmultithreadingで生成するメソッド。
+        フレームごとに出力の配列を確保するため、各フレームは互いに独立して並列に生成される。
 
         Args:
             start_frame_number (int): 生成を開始するフレームの番号
             amount (int): 生成するフレームの数
-            *args: make_frameに渡す引数
-            **kwargs: make_frameに渡すキーワード引数
+            frame_structure (list[LayerStructure]): フレーム構造のリスト
+            width (int): フレームの幅
+            height (int): フレームの高さ
+            output_format (str): 出力フォーマット (rgba8, bgra8, rgba16, rgba32f, i420, nv12)
+            output_color_space (str): 出力の色空間 (srgb, linear_srgb, rec709, display_p3, linear_display_p3, rec2020_pq, rec2020_hlg, linear_rec2020)
+            output_alpha_mode (str): 出力のアルファの形式 (straight, premultiplied)
 
         Returns:
-            list[np.ndarray]: 生成されたフレームのリスト
+            list[np.ndarray]: フレーム番号順に並べた生成されたフレームのリスト。配列の形状はgpu_util.PyImageGenerator.generate_arrayと同じ
         """
         try:
             if not isinstance(amount, int) or amount <= 0:
                 raise ValueError("amount must be a positive integer")
 
-            frames = []
-            futures = [executor.submit(self.make_frame, start_frame_number + i, *args, **kwargs)
-                       for i in range(amount)]
-            for future in futures:
-                frames.append(future.result())
-
+            def make_frame_array(frame_number: int) -> np.ndarray:
+                builder = self._build_frame(frame_number, frame_structure, width, height)
+                return self.generator.generate_array(builder, output_format, output_color_space, output_alpha_mode)
 
-            return frames
-        except Exception as e:
-            import traceback
-            traceback.print_exc()
-            raise RuntimeError(f"Failed to make frames: {e}")
+            # 生成中はGILが解放されるため、各フレームの処理はスレッドごとに並列に進む
+            futures = [executor.submit(make_frame_array, start_frame_number + i) for i in range(amount)]
+            return [future.result() for future in futures]
+        except Exception:
+            logger.exception("Failed to make frames from %d", start_frame_number)
+            raise
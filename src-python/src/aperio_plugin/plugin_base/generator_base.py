@@ -8,9 +8,11 @@ from . import SubPluginBase
 @dataclass
 class GeneratorWgslReturn:
     compiled: PyCompiledWgsl
-    params: bytes
+    params: bytes | dict | list | None  # dictやlistはシェーダーの型に従って自動で詰められる
     output_width: int
     output_height: int
+    color_space: str | None = None  # Noneの場合は作業用の色空間 (linear_srgb) として扱われる
+    alpha_mode: str = "premultiplied"  # シェーダーが入出力に使うアルファの形式 (straight, premultiplied)
 
 @dataclass
 class GeneratorFuncReturn:
@@ -1,3 +1,6 @@
+// 入力と出力はプリマルチプライドアルファ (RGBにアルファを乗算済み) として扱う。
+// 乗算済みの値を線形補間するため、回転や拡大縮小したレイヤーの縁が暗くならない。
+
 // 各レイヤーのメタ情報を格納する構造体
 struct LayerParams {
   x: i32,     // レイヤーの左上のx座標
@@ -60,17 +63,13 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
 
       // textureSampleを使うために座標を正規化
       let src_coord_normalized = src_coord_pixel / layer_dims_f;
-      let src_color = textureSampleLevel(inputTex[i], linear_sampler, src_coord_normalized, 0.0);
+      let sampled = textureSampleLevel(inputTex[i], linear_sampler, src_coord_normalized, 0.0);
+      // レイヤーの透明度を適用 (プリマルチプライド済みなのでRGBにも掛ける)
+      let src_color = sampled * params.alpha;
 
-      // --- アルファブレンディング (Over演算) ---
+      // --- アルファブレンディング (プリマルチプライドアルファのOver演算) ---
       // 現在の色 (destination color) の上に新しいレイヤーの色を重ねる
-      let dst_color = final_color;
-      let alpha = src_color.a * params.alpha;
-
-      let blended_rgb = src_color.rgb * alpha + dst_color.rgb * (1.0 - alpha);
-      let blended_a = src_color.a + dst_color.a * (1.0 - src_color.a);
-
-      final_color = vec4<f32>(blended_rgb, blended_a);
+      final_color = src_color + final_color * (1.0 - src_color.a);
     }
   }
 
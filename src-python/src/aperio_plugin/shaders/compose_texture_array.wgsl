@@ -0,0 +1,84 @@
+// compose.wgsl の binding array を使わない版。
+// TEXTURE_BINDING_ARRAY に対応していないアダプタ (GLやソフトウェアレンダラなど) で使用する。
+// 入力レイヤーは最大の解像度に揃えた配列テクスチャの各レイヤーに詰められて渡される。
+
+// 入力と出力はプリマルチプライドアルファ (RGBにアルファを乗算済み) として扱う。
+// 乗算済みの値を線形補間するため、回転や拡大縮小したレイヤーの縁が暗くならない。
+
+// 各レイヤーのメタ情報を格納する構造体
+struct LayerParams {
+  x: i32,     // レイヤーの左上のx座標
+  y: i32,     // レイヤーの左上のy座標
+  scale: f32,  // レイヤーの拡大・縮小率
+  alpha: f32,  // レイヤーの透明度 (0.0〜1.0)
+  rotation_matrix: mat2x2<f32>, // レイヤーの回転行列
+};
+
+// --- リソースのバインディング定義 ---
+
+// グループ0: テクスチャ関連
+@group(0) @binding(0) var inputTex: texture_2d_array<f32>;
+@group(0) @binding(1) var outputTex: texture_storage_2d<rgba32float, write>;
+@group(0) @binding(2) var linear_sampler: sampler;
+@group(0) @binding(3) var<storage, read> input_sizes: array<vec2<u32>>; // 各レイヤーの実際の解像度
+
+// グループ1: メタデータ
+@group(1) @binding(0) var<storage, read> layer_params_array: array<LayerParams>;
+
+
+// --- コンピュートシェーダー本体 ---
+
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+  let output_coord = vec2<i32>(global_id.xy);
+  let output_dims = textureDimensions(outputTex);
+
+  // 処理対象が出力テクスチャの範囲外であれば、何もしない
+  if (output_coord.x >= i32(output_dims.x) || output_coord.y >= i32(output_dims.y)) {
+    return;
+  }
+
+  // このピクセルの最終的な色。初期値は透明な黒 (背景)
+  var final_color = vec4<f32>(0.0, 0.0, 0.0, 0.0);
+
+  let num_layers = arrayLength(&layer_params_array);
+  // 配列テクスチャ全体の解像度 (正規化座標の計算に使う)
+  let array_dims_f = vec2<f32>(textureDimensions(inputTex));
+
+  // 全てのレイヤーを順番に重ね合わせる
+  for (var i: u32 = 0u; i < num_layers; i = i + 1u) {
+    let params = layer_params_array[i];
+    let layer_dims_f = vec2<f32>(input_sizes[i]);
+    if (params.scale <= 0.0) {
+      continue;
+    }
+
+    // 出力ピクセル座標から、レイヤーテクスチャ上の対応する座標を計算
+    let output_center = vec2<f32>(f32(params.x), f32(params.y));
+    let relative_coord = vec2<f32>(output_coord) - output_center;
+
+    // 事前に計算された回転行列を適用
+    let rotated_coord = params.rotation_matrix * relative_coord;
+
+    // スケールを適用
+    let src_coord_pixel = rotated_coord / params.scale;
+
+    if (src_coord_pixel.x >= 0.0 && src_coord_pixel.x < layer_dims_f.x &&
+        src_coord_pixel.y >= 0.0 && src_coord_pixel.y < layer_dims_f.y) {
+
+      // レイヤーの領域外 (配列テクスチャの余白) をサンプリングしないよう、テクセル中心の範囲に収める
+      let clamped_pixel = clamp(src_coord_pixel, vec2<f32>(0.5), layer_dims_f - vec2<f32>(0.5));
+      let src_coord_normalized = clamped_pixel / array_dims_f;
+      let sampled = textureSampleLevel(inputTex, linear_sampler, src_coord_normalized, i, 0.0);
+      // レイヤーの透明度を適用 (プリマルチプライド済みなのでRGBにも掛ける)
+      let src_color = sampled * params.alpha;
+
+      // --- アルファブレンディング (プリマルチプライドアルファのOver演算) ---
+      // 現在の色 (destination color) の上に新しいレイヤーの色を重ねる
+      final_color = src_color + final_color * (1.0 - src_color.a);
+    }
+  }
+
+  // 計算した最終的な色を出力テクスチャに書き込む
+  textureStore(outputTex, output_coord, final_color);
+}
@@ -0,0 +1,413 @@
+// color_space.rs
+
+use rayon::{iter::ParallelIterator, slice::ParallelSliceMut};
+
+/// 色域 (原色の色度)。白色点はすべてD65です。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Primaries {
+    /// BT.709 / sRGB
+    Bt709,
+    /// Display P3
+    DisplayP3,
+    /// BT.2020
+    Bt2020,
+}
+
+/// 伝達関数。線形の値をどのように符号化しているかを表します。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TransferFunction {
+    /// 線形
+    Linear,
+    /// IEC 61966-2-1 (sRGB, Display P3)
+    Srgb,
+    /// BT.709のOETF
+    Rec709,
+    /// SMPTE ST 2084 (PQ)。線形の1.0を203cd/m² (BT.2408の基準白) として扱います。
+    Pq,
+    /// ARIB STD-B67 (HLG) のOETF。線形の1.0を基準白 (信号値0.75) として扱い、OOTFは適用しません。
+    Hlg,
+}
+
+/// アルファの扱い。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AlphaMode {
+    /// RGBにアルファを乗算していない値。一般的な画像ファイルやCPU上の処理で使われます。
+    Straight,
+    /// RGBにアルファを乗算済みの値。線形補間や合成が正しく行えるため、WGSLのステップはデフォルトでこちらを使います。
+    #[default]
+    Premultiplied,
+}
+
+/// テクスチャやCPU上の画像が保持している値の色空間。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ColorSpace {
+    pub primaries: Primaries,
+    pub transfer: TransferFunction,
+}
+
+impl ColorSpace {
+    pub const SRGB: Self = Self::new(Primaries::Bt709, TransferFunction::Srgb);
+    pub const LINEAR_SRGB: Self = Self::new(Primaries::Bt709, TransferFunction::Linear);
+    pub const REC709: Self = Self::new(Primaries::Bt709, TransferFunction::Rec709);
+    pub const DISPLAY_P3: Self = Self::new(Primaries::DisplayP3, TransferFunction::Srgb);
+    pub const LINEAR_DISPLAY_P3: Self = Self::new(Primaries::DisplayP3, TransferFunction::Linear);
+    pub const REC2020_PQ: Self = Self::new(Primaries::Bt2020, TransferFunction::Pq);
+    pub const REC2020_HLG: Self = Self::new(Primaries::Bt2020, TransferFunction::Hlg);
+    pub const LINEAR_REC2020: Self = Self::new(Primaries::Bt2020, TransferFunction::Linear);
+
+    pub const fn new(primaries: Primaries, transfer: TransferFunction) -> Self {
+        Self {
+            primaries,
+            transfer,
+        }
+    }
+}
+
+/// 色空間とアルファの扱いの変換。`color.wgsl`の`ColorConversion`と同じ手順で、
+/// アルファの除算 → 伝達関数のデコード → 原色の変換行列 → 伝達関数のエンコード → アルファの乗算を行います。
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ColorConversion {
+    matrix: [[f32; 3]; 3],
+    src_transfer: TransferFunction,
+    dst_transfer: TransferFunction,
+    src_premultiplied: bool,
+    dst_premultiplied: bool,
+}
+
+impl ColorConversion {
+    /// `from`から`to`への変換。色空間とアルファの扱いが同じ場合は値を変えない恒等変換になります。
+    pub(crate) fn new(
+        from: ColorSpace,
+        from_alpha: AlphaMode,
+        to: ColorSpace,
+        to_alpha: AlphaMode,
+    ) -> Self {
+        let identity = Self {
+            matrix: IDENTITY,
+            src_transfer: TransferFunction::Linear,
+            dst_transfer: TransferFunction::Linear,
+            src_premultiplied: false,
+            dst_premultiplied: false,
+        };
+        if from == to && from_alpha == to_alpha {
+            return identity;
+        }
+        // 伝達関数や行列は乗算前の値に適用する必要があるため、一度アルファを除算してから変換する
+        let alpha = Self {
+            src_premultiplied: from_alpha == AlphaMode::Premultiplied,
+            dst_premultiplied: to_alpha == AlphaMode::Premultiplied,
+            ..identity
+        };
+        if from == to {
+            return alpha;
+        }
+        let matrix = if from.primaries == to.primaries {
+            IDENTITY
+        } else {
+            let m = mul(
+                &invert(&rgb_to_xyz(to.primaries)),
+                &rgb_to_xyz(from.primaries),
+            );
+            m.map(|row| row.map(|v| v as f32))
+        };
+        Self {
+            matrix,
+            src_transfer: from.transfer,
+            dst_transfer: to.transfer,
+            ..alpha
+        }
+    }
+
+    /// 値を変えない変換かどうか
+    pub(crate) fn is_identity(&self) -> bool {
+        self.matrix == IDENTITY
+            && self.src_transfer == TransferFunction::Linear
+            && self.dst_transfer == TransferFunction::Linear
+            && self.src_premultiplied == self.dst_premultiplied
+    }
+
+    /// CPU上のRGBA f32の画像を変換します。
+    pub(crate) fn convert_cpu(&self, data: &mut [f32]) {
+        if self.is_identity() {
+            return;
+        }
+        data.par_chunks_exact_mut(4)
+            .for_each(|pixel| self.apply(pixel));
+    }
+
+    fn apply(&self, pixel: &mut [f32]) {
+        let a = pixel[3];
+        let mut rgb = [pixel[0], pixel[1], pixel[2]];
+        // アルファが0の画素は色を持たないため、除算せずにそのまま扱う
+        if self.src_premultiplied && a > 0.0 {
+            rgb = rgb.map(|v| v / a);
+        }
+        let linear = rgb.map(|v| decode(self.src_transfer, v));
+        let m = &self.matrix;
+        let converted: [f32; 3] = std::array::from_fn(|r| {
+            m[r][0] * linear[0] + m[r][1] * linear[1] + m[r][2] * linear[2]
+        });
+        rgb = converted.map(|v| encode(self.dst_transfer, v));
+        if self.dst_premultiplied {
+            rgb = rgb.map(|v| v * a);
+        }
+        pixel[..3].copy_from_slice(&rgb);
+    }
+
+    /// `color.wgsl`の`ColorConversion`のメモリレイアウト (uniform) に詰めたバイト列
+    pub(crate) fn to_uniform_bytes(self) -> [u8; 64] {
+        let mut words = [0u32; 16];
+        // mat3x3<f32>は列優先で、各列が16バイトに揃えられる
+        for column in 0..3 {
+            for row in 0..3 {
+                words[column * 4 + row] = self.matrix[row][column].to_bits();
+            }
+        }
+        words[12] = transfer_id(self.src_transfer);
+        words[13] = transfer_id(self.dst_transfer);
+        words[14] = self.src_premultiplied as u32;
+        words[15] = self.dst_premultiplied as u32;
+        bytemuck::cast(words)
+    }
+}
+
+/// `color.wgsl`の`TRANSFER_*`と対応
+fn transfer_id(transfer: TransferFunction) -> u32 {
+    match transfer {
+        TransferFunction::Linear => 0,
+        TransferFunction::Srgb => 1,
+        TransferFunction::Rec709 => 2,
+        TransferFunction::Pq => 3,
+        TransferFunction::Hlg => 4,
+    }
+}
+
+const IDENTITY: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+// PQの定数 (SMPTE ST 2084)
+const PQ_M1: f32 = 2610.0 / 16384.0;
+const PQ_M2: f32 = 2523.0 / 4096.0 * 128.0;
+const PQ_C1: f32 = 3424.0 / 4096.0;
+const PQ_C2: f32 = 2413.0 / 4096.0 * 32.0;
+const PQ_C3: f32 = 2392.0 / 4096.0 * 32.0;
+// 線形の1.0に対応する輝度 (cd/m²) と、PQの最大輝度
+const PQ_REFERENCE_WHITE: f32 = 203.0;
+const PQ_MAX_LUMINANCE: f32 = 10000.0;
+
+// HLGの定数 (ARIB STD-B67)
+const HLG_A: f32 = 0.178_832_77;
+const HLG_B: f32 = 0.284_668_92;
+const HLG_C: f32 = 0.559_910_7;
+// 信号値0.75 (基準白) に対応するシーン光
+const HLG_REFERENCE_WHITE: f32 = 0.264_962_56;
+
+/// 伝達関数で符号化された値を線形に戻します。
+fn decode(transfer: TransferFunction, v: f32) -> f32 {
+    match transfer {
+        TransferFunction::Linear => v,
+        TransferFunction::Srgb => {
+            v.signum() * {
+                let v = v.abs();
+                if v <= 0.04045 {
+                    v / 12.92
+                } else {
+                    ((v + 0.055) / 1.055).powf(2.4)
+                }
+            }
+        }
+        TransferFunction::Rec709 => {
+            v.signum() * {
+                let v = v.abs();
+                if v < 0.081 {
+                    v / 4.5
+                } else {
+                    ((v + 0.099) / 1.099).powf(1.0 / 0.45)
+                }
+            }
+        }
+        TransferFunction::Pq => {
+            let p = v.max(0.0).powf(1.0 / PQ_M2);
+            let y = ((p - PQ_C1).max(0.0) / (PQ_C2 - PQ_C3 * p)).powf(1.0 / PQ_M1);
+            y * PQ_MAX_LUMINANCE / PQ_REFERENCE_WHITE
+        }
+        TransferFunction::Hlg => {
+            let v = v.max(0.0);
+            let e = if v <= 0.5 {
+                v * v / 3.0
+            } else {
+                (((v - HLG_C) / HLG_A).exp() + HLG_B) / 12.0
+            };
+            e / HLG_REFERENCE_WHITE
+        }
+    }
+}
+
+/// 線形の値を伝達関数で符号化します。
+fn encode(transfer: TransferFunction, v: f32) -> f32 {
+    match transfer {
+        TransferFunction::Linear => v,
+        TransferFunction::Srgb => {
+            v.signum() * {
+                let v = v.abs();
+                if v <= 0.0031308 {
+                    v * 12.92
+                } else {
+                    1.055 * v.powf(1.0 / 2.4) - 0.055
+                }
+            }
+        }
+        TransferFunction::Rec709 => {
+            v.signum() * {
+                let v = v.abs();
+                if v < 0.018 {
+                    v * 4.5
+                } else {
+                    1.099 * v.powf(0.45) - 0.099
+                }
+            }
+        }
+        TransferFunction::Pq => {
+            let y = (v.max(0.0) * PQ_REFERENCE_WHITE / PQ_MAX_LUMINANCE).powf(PQ_M1);
+            ((PQ_C1 + PQ_C2 * y) / (1.0 + PQ_C3 * y)).powf(PQ_M2)
+        }
+        TransferFunction::Hlg => {
+            let e = v.max(0.0) * HLG_REFERENCE_WHITE;
+            if e <= 1.0 / 12.0 {
+                (3.0 * e).sqrt()
+            } else {
+                HLG_A * (12.0 * e - HLG_B).ln() + HLG_C
+            }
+        }
+    }
+}
+
+type Mat3 = [[f64; 3]; 3];
+
+/// 原色の色度 (x, y) から、線形RGB -> XYZの行列を計算します。
+fn rgb_to_xyz(primaries: Primaries) -> Mat3 {
+    let [r, g, b] = match primaries {
+        Primaries::Bt709 => [[0.640, 0.330], [0.300, 0.600], [0.150, 0.060]],
+        Primaries::DisplayP3 => [[0.680, 0.320], [0.265, 0.690], [0.150, 0.060]],
+        Primaries::Bt2020 => [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046]],
+    };
+    let white = [0.3127, 0.3290];
+    let xyz = |[x, y]: [f64; 2]| [x / y, 1.0, (1.0 - x - y) / y];
+
+    let (r, g, b) = (xyz(r), xyz(g), xyz(b));
+    let primaries: Mat3 = std::array::from_fn(|i| [r[i], g[i], b[i]]);
+    // 白色点がRGB=(1, 1, 1)になるように各原色の強さを決める
+    let w = xyz(white);
+    let inv = invert(&primaries);
+    let s: [f64; 3] =
+        std::array::from_fn(|i| inv[i][0] * w[0] + inv[i][1] * w[1] + inv[i][2] * w[2]);
+    std::array::from_fn(|i| std::array::from_fn(|j| primaries[i][j] * s[j]))
+}
+
+fn mul(a: &Mat3, b: &Mat3) -> Mat3 {
+    std::array::from_fn(|i| std::array::from_fn(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+fn invert(m: &Mat3) -> Mat3 {
+    let cofactor = |r: usize, c: usize| {
+        let (r1, r2) = ((r + 1) % 3, (r + 2) % 3);
+        let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+        m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+    };
+    let det: f64 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+    std::array::from_fn(|i| std::array::from_fn(|j| cofactor(j, i) / det))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSFERS: [TransferFunction; 5] = [
+        TransferFunction::Linear,
+        TransferFunction::Srgb,
+        TransferFunction::Rec709,
+        TransferFunction::Pq,
+        TransferFunction::Hlg,
+    ];
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn encode_and_decode_round_trip() {
+        for transfer in TRANSFERS {
+            for i in 0..=20 {
+                let v = i as f32 / 10.0;
+                assert_close(decode(transfer, encode(transfer, v)), v, 1e-4 * v.max(1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn transfer_functions_match_reference_values() {
+        // sRGBの0.5は線形で約0.214
+        assert_close(decode(TransferFunction::Srgb, 0.5), 0.214_041, 1e-5);
+        // 線形区間の境界
+        assert_close(encode(TransferFunction::Srgb, 0.0031308), 0.040_45, 1e-5);
+        assert_close(encode(TransferFunction::Rec709, 0.01), 0.045, 1e-6);
+        // 負の値は符号を保ったまま変換する
+        assert_close(encode(TransferFunction::Srgb, -0.214_041), -0.5, 1e-5);
+        // PQは線形の1.0を203cd/m²として符号化する
+        assert_close(encode(TransferFunction::Pq, 1.0), 0.580_688, 1e-4);
+        assert_close(encode(TransferFunction::Pq, 10000.0 / 203.0), 1.0, 1e-5);
+        // HLGは線形の1.0を基準白の信号値0.75として符号化する
+        assert_close(encode(TransferFunction::Hlg, 1.0), 0.75, 1e-5);
+        assert_close(
+            encode(TransferFunction::Hlg, 1.0 / (12.0 * HLG_REFERENCE_WHITE)),
+            0.5,
+            1e-5,
+        );
+    }
+
+    #[test]
+    fn rgb_to_xyz_maps_white_to_d65() {
+        let d65 = [0.950_456, 1.0, 1.089_058];
+        for primaries in [Primaries::Bt709, Primaries::DisplayP3, Primaries::Bt2020] {
+            let m = rgb_to_xyz(primaries);
+            for (row, expected) in m.iter().zip(d65) {
+                assert!((row.iter().sum::<f64>() - expected).abs() < 1e-4);
+            }
+        }
+        // BT.709の輝度の係数
+        let y = rgb_to_xyz(Primaries::Bt709)[1];
+        for (actual, expected) in y.iter().zip([0.2126, 0.7152, 0.0722]) {
+            assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+        }
+    }
+
+    #[test]
+    fn conversion_round_trips_between_color_spaces() {
+        let pixel = [0.8f32, 0.4, 0.1, 0.5];
+        for (from, to) in [
+            (ColorSpace::SRGB, ColorSpace::REC2020_PQ),
+            (ColorSpace::REC709, ColorSpace::REC2020_HLG),
+            (ColorSpace::DISPLAY_P3, ColorSpace::LINEAR_SRGB),
+        ] {
+            let forward =
+                ColorConversion::new(from, AlphaMode::Straight, to, AlphaMode::Premultiplied);
+            let back =
+                ColorConversion::new(to, AlphaMode::Premultiplied, from, AlphaMode::Straight);
+            let mut data = pixel.to_vec();
+            forward.convert_cpu(&mut data);
+            back.convert_cpu(&mut data);
+            for (actual, expected) in data.iter().zip(pixel) {
+                assert_close(*actual, expected, 1e-4);
+            }
+        }
+        let same = ColorConversion::new(
+            ColorSpace::SRGB,
+            AlphaMode::Straight,
+            ColorSpace::SRGB,
+            AlphaMode::Straight,
+        );
+        assert!(same.is_identity());
+    }
+}
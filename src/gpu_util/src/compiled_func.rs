@@ -1,5 +1,6 @@
 // compiled_func.rs
 
+use crate::color_space::{AlphaMode, ColorSpace};
 use anyhow::Result;
 use std::sync::Arc;
 
@@ -24,6 +25,10 @@ pub type CpuFunction = dyn Fn(&[CpuInputImage], Option<&[u8]>) -> Result<CpuOutp
 #[derive(Clone)]
 pub struct CompiledFunc {
     pub func: Arc<CpuFunction>,
+    /// 関数が受け取る入力と返す出力の色空間。入力はこの色空間に変換されてから渡されます。
+    pub color_space: ColorSpace,
+    /// 関数が扱うアルファの形式。入力はこの形式に変換されてから渡されます。
+    pub alpha_mode: AlphaMode,
 }
 
 impl CompiledFunc {
@@ -39,6 +44,20 @@ impl CompiledFunc {
     pub fn new(func: Box<CpuFunction>) -> Self {
         Self {
             func: Arc::from(func),
+            color_space: ColorSpace::SRGB,
+            alpha_mode: AlphaMode::Straight,
         }
     }
+
+    /// 関数が扱う色空間を設定します。デフォルトは一般的な画像と同じsRGBです。
+    pub fn with_color_space(mut self, color_space: ColorSpace) -> Self {
+        self.color_space = color_space;
+        self
+    }
+
+    /// 関数が扱うアルファの形式を設定します。デフォルトは一般的な画像と同じストレートアルファです。
+    pub fn with_alpha_mode(mut self, alpha_mode: AlphaMode) -> Self {
+        self.alpha_mode = alpha_mode;
+        self
+    }
 }
@@ -1,7 +1,16 @@
 // compiled_wgsl.rs
 
-use anyhow::Result;
-use std::sync::Arc;
+use crate::{
+    error::GpuUtilError,
+    image_generator::result_cache::content_key,
+    param_layout::{ParamLayout, ParamValue},
+    pipeline_disk_cache::stable_hash,
+    shader_reflection::{
+        compute_entry_points, BindingRole, InputLayout, ReflectedBinding, ShaderReflection,
+    },
+};
+use anyhow::{bail, Context, Result};
+use std::{collections::HashMap, fmt, sync::Arc};
 use wgpu::Device;
 
 pub struct SamplerOptions {
@@ -9,39 +18,457 @@ pub struct SamplerOptions {
     pub filter: wgpu::FilterMode,
 }
 
+/// WGSLの解析・検証に失敗した場合のエラー。
+/// 行と列は1始まりで、列はバイトではなく文字単位です。位置が特定できないエラーの場合は0になります。
+#[derive(Clone, Debug)]
+pub struct ShaderCompileError {
+    /// エラーが発生したシェーダーのID
+    pub file_id: String,
+    pub line: u32,
+    pub column: u32,
+    pub message: String,
+    /// エラー箇所の行と、該当範囲を示す`^`の行
+    pub snippet: String,
+}
+
+impl ShaderCompileError {
+    pub(crate) fn new(
+        file_id: &str,
+        source: &str,
+        message: String,
+        location: Option<naga::SourceLocation>,
+    ) -> Self {
+        let Some(location) = location else {
+            return Self {
+                file_id: file_id.to_string(),
+                line: 0,
+                column: 0,
+                message,
+                snippet: String::new(),
+            };
+        };
+
+        let line_text = source
+            .lines()
+            .nth(location.line_number.saturating_sub(1) as usize)
+            .unwrap_or_default();
+        // nagaの位置はバイト単位なので、^を揃えるために文字数に直す。
+        // 範囲が複数行にまたがる場合は、この行の終わりまでに収める
+        let start_byte = location.line_position.saturating_sub(1) as usize;
+        let end_byte = start_byte + location.length as usize;
+        let chars_before = |byte: usize| {
+            line_text
+                .char_indices()
+                .take_while(|&(i, _)| i < byte)
+                .count()
+        };
+        let start = chars_before(start_byte);
+        let length = (chars_before(end_byte) - start).max(1);
+        let snippet = format!(
+            "{:>4} | {}\n     | {}{}",
+            location.line_number,
+            line_text,
+            " ".repeat(start),
+            "^".repeat(length)
+        );
+
+        Self {
+            file_id: file_id.to_string(),
+            line: location.line_number,
+            column: start as u32 + 1,
+            message,
+            snippet,
+        }
+    }
+}
+
+impl fmt::Display for ShaderCompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file_id, self.line, self.column, self.message
+        )?;
+        if !self.snippet.is_empty() {
+            write!(f, "\n{}", self.snippet)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ShaderCompileError {}
+
+/// デバイスで有効な機能から、naga の検証で許可する機能を決める
+fn validator_capabilities(features: wgpu::Features) -> naga::valid::Capabilities {
+    use naga::valid::Capabilities;
+
+    let mut capabilities = Capabilities::default();
+    if features
+        .contains(wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING)
+    {
+        capabilities |= Capabilities::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING;
+    }
+    capabilities
+}
+
+/// サンプラーを作成します。アダプタが対応していない設定の場合はエラーになります。
+fn create_sampler(
+    id: &str,
+    device: &Device,
+    options: &SamplerOptions,
+) -> Result<Arc<wgpu::Sampler>> {
+    if options.address_mode == wgpu::AddressMode::ClampToBorder
+        && !device
+            .features()
+            .contains(wgpu::Features::ADDRESS_MODE_CLAMP_TO_BORDER)
+    {
+        bail!(GpuUtilError::device(
+            Some(id),
+            "clamp_to_border is not supported by the current adapter".to_string(),
+        ));
+    }
+
+    // Rgba32Floatをフィルタリングできないアダプタではnearestに落とす
+    let filter = if device
+        .features()
+        .contains(wgpu::Features::FLOAT32_FILTERABLE)
+    {
+        options.filter
+    } else {
+        wgpu::FilterMode::Nearest
+    };
+    Ok(Arc::new(device.create_sampler(&wgpu::SamplerDescriptor {
+        address_mode_u: options.address_mode,
+        address_mode_v: options.address_mode,
+        address_mode_w: options.address_mode,
+        mag_filter: filter,
+        min_filter: filter,
+        mipmap_filter: filter,
+        ..Default::default()
+    })))
+}
+
 #[derive(Clone)]
 pub struct CompiledWgsl {
     pub(crate) id: String,
     pub(crate) module: Arc<wgpu::ShaderModule>,
+    // 名前を指定されていないすべてのsamplerに使うサンプラー
     pub(crate) sampler: Option<Arc<wgpu::Sampler>>,
+    // 名前ごとのサンプラー
+    pub(crate) samplers: HashMap<String, Arc<wgpu::Sampler>>,
+    // サンプラーの設定のハッシュ。結果キャッシュのキーに使う
+    pub(crate) sampler_hash: u64,
+    // コンピュートシェーダーのエントリーポイントごとのリフレクション (宣言順)
+    pub(crate) reflections: Arc<Vec<ShaderReflection>>,
+    // WGSLソースのハッシュ。パイプラインキャッシュのキーに使う
+    pub(crate) source_hash: u64,
     pub(crate) _source: Arc<str>,
 }
 
 impl CompiledWgsl {
-    pub fn new(id: &str, wgsl_code: &str, device: &Device, sampler_options: Option<&SamplerOptions>) -> Result<Self> {
+    pub fn new(
+        id: &str,
+        wgsl_code: &str,
+        device: &Device,
+        sampler_options: Option<&SamplerOptions>,
+    ) -> Result<Self> {
         let shader_module_descriptor = wgpu::ShaderModuleDescriptor {
             label: Some(id), // labelにもIDを使用
             source: wgpu::ShaderSource::Wgsl(wgsl_code.into()),
         };
 
-        let module = device.create_shader_module(shader_module_descriptor);
-        let sampler = sampler_options.map(|options| {
-            Arc::new(device.create_sampler(&wgpu::SamplerDescriptor {
-                address_mode_u: options.address_mode,
-                address_mode_v: options.address_mode,
-                address_mode_w: options.address_mode,
-                mag_filter: options.filter,
-                min_filter: options.filter,
-                mipmap_filter: options.filter,
-                ..Default::default()
-            }))
+        // デバイスに渡す前にWGSLを解析・検証し、エラーの位置を特定できるようにする
+        let naga_module = naga::front::wgsl::parse_str(wgsl_code).map_err(|e| {
+            GpuUtilError::from(ShaderCompileError::new(
+                id,
+                wgsl_code,
+                e.message().to_string(),
+                e.location(wgsl_code),
+            ))
+        })?;
+
+        // binding arrayを宣言したシェーダーは、非対応のアダプタではモジュールの作成自体が失敗する
+        let uses_binding_array = naga_module.global_variables.iter().any(|(_, var)| {
+            matches!(
+                naga_module.types[var.ty].inner,
+                naga::TypeInner::BindingArray { .. }
+            )
         });
+        if uses_binding_array
+            && !device
+                .features()
+                .contains(wgpu::Features::TEXTURE_BINDING_ARRAY)
+        {
+            bail!(GpuUtilError::device(
+                Some(id),
+                "binding_array is not supported by the current adapter. \
+                 Use the texture_2d_array or individual texture convention instead"
+                    .to_string(),
+            ));
+        }
+
+        let module_info = naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            validator_capabilities(device.features()),
+        )
+        .validate(&naga_module)
+        .map_err(|e| {
+            // 原因のエラーも含めて表示する
+            let mut message = e.as_inner().to_string();
+            let mut source = std::error::Error::source(e.as_inner());
+            while let Some(inner) = source {
+                message.push_str(&format!(": {}", inner));
+                source = inner.source();
+            }
+            GpuUtilError::from(ShaderCompileError::new(
+                id,
+                wgsl_code,
+                message,
+                e.location(wgsl_code),
+            ))
+        })?;
+        let reflections = compute_entry_points(&naga_module)
+            .map(|entry_point| {
+                ShaderReflection::new(id, wgsl_code, &naga_module, &module_info, entry_point)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(GpuUtilError::from)?;
+        if reflections.is_empty() {
+            bail!(GpuUtilError::from(ShaderCompileError::new(
+                id,
+                wgsl_code,
+                "No compute entry point was found".to_string(),
+                None,
+            )));
+        }
+
+        let sampler = sampler_options
+            .map(|options| create_sampler(id, device, options))
+            .transpose()?;
+        let module = device.create_shader_module(shader_module_descriptor);
 
         Ok(Self {
             id: id.to_string(),
             module: Arc::new(module),
             sampler,
+            samplers: HashMap::new(),
+            sampler_hash: content_key(
+                sampler_options.map(|options| (options.address_mode, options.filter)),
+            ),
+            reflections: Arc::new(reflections),
+            source_hash: stable_hash(wgsl_code.as_bytes()),
             _source: Arc::from(wgsl_code),
         })
     }
-}
\ No newline at end of file
+
+    /// 指定した名前の`sampler`にだけ使うサンプラーを設定します。
+    /// 設定されていない`sampler`には`new`で指定したサンプラーが使われます。
+    pub fn with_sampler(
+        mut self,
+        device: &Device,
+        name: &str,
+        options: &SamplerOptions,
+    ) -> Result<Self> {
+        if !self.reflections.iter().any(|r| r.has_sampler(name)) {
+            bail!(GpuUtilError::binding(
+                Some(&self.id),
+                format!("there is no sampler binding named `{}`", name),
+            ));
+        }
+        let sampler = create_sampler(&self.id, device, options)?;
+        self.samplers.insert(name.to_string(), sampler);
+        self.sampler_hash = content_key((
+            self.sampler_hash,
+            name,
+            options.address_mode,
+            options.filter,
+        ));
+        Ok(self)
+    }
+
+    /// コンピュートシェーダーのエントリーポイント名 (宣言順)
+    pub fn entry_points(&self) -> impl Iterator<Item = &str> {
+        self.reflections.iter().map(|r| r.entry_point.as_str())
+    }
+
+    /// 入力の規約
+    pub fn input_layout(&self, entry_point: Option<&str>) -> Result<InputLayout> {
+        Ok(self.reflection(entry_point)?.input_layout)
+    }
+
+    /// エントリーポイントのバインディング情報を取得します。
+    /// `entry_point`が`None`の場合は`main`、エントリーポイントが1つだけならそれを使います。
+    pub fn reflection(&self, entry_point: Option<&str>) -> Result<&ShaderReflection> {
+        let found = match entry_point {
+            Some(name) => self.reflections.iter().find(|r| r.entry_point == name),
+            None => self
+                .reflections
+                .iter()
+                .find(|r| r.entry_point == "main")
+                .or(match self.reflections.as_slice() {
+                    [only] => Some(only),
+                    _ => None,
+                }),
+        };
+        found.with_context(|| {
+            GpuUtilError::binding(
+                Some(&self.id),
+                format!(
+                    "{}. Available entry points: {}",
+                    match entry_point {
+                        Some(name) => format!("compute entry point `{}` was not found", name),
+                        None => "specify the entry point to use".to_string(),
+                    },
+                    self.entry_points().collect::<Vec<_>>().join(", ")
+                ),
+            )
+        })
+    }
+
+    /// バッファに渡すパラメータのレイアウトを取得します。
+    /// `buffer`が`None`の場合は、エントリーポイントが使用する唯一のバッファのレイアウトを返します。
+    pub fn param_layout(
+        &self,
+        entry_point: Option<&str>,
+        buffer: Option<&str>,
+    ) -> Result<&ParamLayout> {
+        Ok(self.param_layout_for(entry_point, buffer, |_| false)?.1)
+    }
+
+    /// 値をバッファのレイアウトに従ってバイト列に詰めます。
+    /// `buffer`が`None`の場合は、エントリーポイントが使用する唯一のバッファに詰めます。
+    pub fn pack_params(
+        &self,
+        entry_point: Option<&str>,
+        buffer: Option<&str>,
+        value: &ParamValue,
+    ) -> Result<Vec<u8>> {
+        self.pack_params_for(entry_point, buffer, value, |_| false)
+    }
+
+    /// `pack_params`と同様ですが、`buffer`が`None`の場合は`is_named`で名前を指定されたバッファを除いて探します。
+    pub(crate) fn pack_params_for(
+        &self,
+        entry_point: Option<&str>,
+        buffer: Option<&str>,
+        value: &ParamValue,
+        is_named: impl Fn(&str) -> bool,
+    ) -> Result<Vec<u8>> {
+        let (binding, layout) = self.param_layout_for(entry_point, buffer, is_named)?;
+        layout
+            .pack(value, &binding.name)
+            .map_err(|e| match e.downcast::<GpuUtilError>() {
+                Ok(error) => error.in_shader(&self.id).into(),
+                Err(e) => e,
+            })
+    }
+
+    pub(crate) fn param_layout_for(
+        &self,
+        entry_point: Option<&str>,
+        buffer: Option<&str>,
+        is_named: impl Fn(&str) -> bool,
+    ) -> Result<(&ReflectedBinding, &ParamLayout)> {
+        let reflection = self.reflection(entry_point)?;
+        let binding = match buffer {
+            Some(name) => reflection.buffer_binding(&self.id, name)?,
+            None => reflection.params_binding(&self.id, is_named)?,
+        };
+        match &binding.role {
+            BindingRole::Buffer {
+                layout: Some(layout),
+                ..
+            } => Ok((binding, layout)),
+            _ => bail!(GpuUtilError::binding(
+                Some(&self.id),
+                format!(
+                    "buffer {} contains types that cannot be packed automatically. Pass bytes instead",
+                    binding.describe()
+                ),
+            )),
+        }
+    }
+
+    /// 指定した名前の`sampler`に渡すサンプラー
+    pub(crate) fn sampler_for(&self, name: &str) -> Option<&wgpu::Sampler> {
+        self.samplers
+            .get(name)
+            .or(self.sampler.as_ref())
+            .map(|s| s.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // snippetの^の行から、^の開始位置 (文字単位) と個数を返す
+    fn caret(error: &ShaderCompileError) -> (usize, usize) {
+        let marker = error.snippet.lines().nth(1).unwrap();
+        let marker = marker.strip_prefix("     | ").unwrap();
+        let start = marker.chars().take_while(|&c| c == ' ').count();
+        (start, marker.chars().count() - start)
+    }
+
+    #[test]
+    fn caret_counts_characters_before_multibyte_text() {
+        let source = "fn main() {\n    /* 色の計算 */ let x = vec4<f32>(1.0);\n}";
+        let line = source.lines().nth(1).unwrap();
+        let byte = line.find("vec4<f32>").unwrap();
+        let location = naga::SourceLocation {
+            line_number: 2,
+            line_position: byte as u32 + 1,
+            offset: (source.find("vec4<f32>").unwrap()) as u32,
+            length: "vec4<f32>".len() as u32,
+        };
+        let error = ShaderCompileError::new("test", source, "error".to_string(), Some(location));
+
+        let chars = line[..byte].chars().count();
+        assert_eq!(caret(&error), (chars, "vec4<f32>".len()));
+        assert_eq!(error.column as usize, chars + 1);
+        assert!(error.snippet.starts_with(&format!("   2 | {}\n", line)));
+    }
+
+    #[test]
+    fn caret_for_multibyte_span_and_parse_error() {
+        // エラーの範囲自体に複数バイトの文字を含む場合も、文字数分の^になる
+        let source = "let 値 = 1;";
+        let byte = source.find('値').unwrap();
+        let location = naga::SourceLocation {
+            line_number: 1,
+            line_position: byte as u32 + 1,
+            offset: byte as u32,
+            length: '値'.len_utf8() as u32,
+        };
+        let error = ShaderCompileError::new("test", source, "error".to_string(), Some(location));
+        assert_eq!(caret(&error), (4, 1));
+
+        // nagaの解析エラーの位置からも同じ変換が行われる
+        let source = "/* 日本語のコメント */ fn main( {}";
+        let parse_error = naga::front::wgsl::parse_str(source).unwrap_err();
+        let error = ShaderCompileError::new(
+            "test",
+            source,
+            parse_error.message().to_string(),
+            parse_error.location(source),
+        );
+        let expected = source[..source.find(" {").unwrap() + 1].chars().count();
+        assert_eq!(caret(&error), (expected, 1));
+        assert_eq!(error.line, 1);
+        assert_eq!(error.column as usize, expected + 1);
+    }
+
+    #[test]
+    fn caret_stops_at_end_of_line() {
+        let source = "fn f() {\n  let a = 日本;\n}";
+        let location = naga::SourceLocation {
+            line_number: 2,
+            line_position: 11,
+            offset: 19,
+            length: 100,
+        };
+        let error = ShaderCompileError::new("test", source, "error".to_string(), Some(location));
+        // "  let a = "の後ろの"日本;"の3文字まで
+        assert_eq!(caret(&error), (10, 3));
+    }
+}
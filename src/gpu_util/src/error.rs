@@ -0,0 +1,217 @@
+// error.rs
+
+use crate::compiled_wgsl::ShaderCompileError;
+use thiserror::Error;
+
+/// gpu_utilの処理で発生するエラー。
+///
+/// 関数は`anyhow::Error`としてエラーを返すため、`downcast_ref::<GpuUtilError>()`で取り出せます。
+/// 各エラーは、分かる場合は発生したパイプラインのステップの位置とシェーダーのIDを持ちます。
+#[derive(Debug, Error)]
+pub enum GpuUtilError {
+    /// アダプタやデバイスの取得に失敗した場合や、アダプタが必要な機能に対応していない場合
+    #[error("{}{message}", location(*.step_index, .shader_id.as_deref()))]
+    Device {
+        step_index: Option<usize>,
+        shader_id: Option<String>,
+        message: String,
+    },
+    /// WGSLの解析・検証に失敗した場合。シェーダーのIDは`error.file_id`です。
+    #[error("{}{error}", location(*.step_index, None))]
+    ShaderCompile {
+        step_index: Option<usize>,
+        error: ShaderCompileError,
+    },
+    /// ステップの入力、バッファ、サンプラーなどがシェーダーの宣言と一致しない場合
+    #[error("{}{message}", location(*.step_index, .shader_id.as_deref()))]
+    Binding {
+        step_index: Option<usize>,
+        shader_id: Option<String>,
+        message: String,
+    },
+    /// データの大きさや解像度、ディスパッチの数が期待と一致しない、または上限を超えた場合
+    #[error("{}{message}", location(*.step_index, .shader_id.as_deref()))]
+    Size {
+        step_index: Option<usize>,
+        shader_id: Option<String>,
+        message: String,
+    },
+    /// CPU関数が失敗した場合。errorは関数が返したエラーで、Pythonの関数の場合は送出された例外 (`PyErr`) です。
+    #[error("{}CPU function failed: {error:#}", location(*.step_index, None))]
+    CpuFunc {
+        step_index: Option<usize>,
+        error: anyhow::Error,
+    },
+    /// wgpuやバックエンドがリソースの作成やコマンドを拒否した場合。
+    /// bindingは特定できた場合の原因のバインディングです。
+    #[error("{}{}{message}", location(*.step_index, .shader_id.as_deref()), binding_location(.binding.as_deref()))]
+    Validation {
+        step_index: Option<usize>,
+        shader_id: Option<String>,
+        binding: Option<String>,
+        message: String,
+    },
+    /// GPUの処理の完了待ちや、テクスチャ・バッファの読み戻しに失敗した場合
+    #[error("{}{message}", location(*.step_index, .shader_id.as_deref()))]
+    Readback {
+        step_index: Option<usize>,
+        shader_id: Option<String>,
+        message: String,
+    },
+}
+
+impl GpuUtilError {
+    pub(crate) fn device(shader_id: Option<&str>, message: String) -> Self {
+        Self::Device {
+            step_index: None,
+            shader_id: shader_id.map(str::to_string),
+            message,
+        }
+    }
+
+    pub(crate) fn binding(shader_id: Option<&str>, message: String) -> Self {
+        Self::Binding {
+            step_index: None,
+            shader_id: shader_id.map(str::to_string),
+            message,
+        }
+    }
+
+    pub(crate) fn size(shader_id: Option<&str>, message: String) -> Self {
+        Self::Size {
+            step_index: None,
+            shader_id: shader_id.map(str::to_string),
+            message,
+        }
+    }
+
+    pub(crate) fn validation(
+        shader_id: Option<&str>,
+        binding: Option<String>,
+        message: String,
+    ) -> Self {
+        Self::Validation {
+            step_index: None,
+            shader_id: shader_id.map(str::to_string),
+            binding,
+            message,
+        }
+    }
+
+    pub(crate) fn readback(message: String) -> Self {
+        Self::Readback {
+            step_index: None,
+            shader_id: None,
+            message,
+        }
+    }
+
+    /// エラーが発生したステップの位置を設定します。
+    pub(crate) fn at_step(mut self, index: usize) -> Self {
+        *self.step_index_mut() = Some(index);
+        self
+    }
+
+    /// エラーが発生したシェーダーのIDを設定します。既に設定されている場合や、IDを持たない種類では何もしません。
+    pub(crate) fn in_shader(mut self, id: &str) -> Self {
+        match &mut self {
+            Self::Device { shader_id, .. }
+            | Self::Binding { shader_id, .. }
+            | Self::Size { shader_id, .. }
+            | Self::Validation { shader_id, .. }
+            | Self::Readback { shader_id, .. } => {
+                shader_id.get_or_insert_with(|| id.to_string());
+            }
+            Self::ShaderCompile { .. } | Self::CpuFunc { .. } => {}
+        }
+        self
+    }
+
+    /// エラーが発生したステップの位置
+    pub fn step_index(&self) -> Option<usize> {
+        match self {
+            Self::Device { step_index, .. }
+            | Self::ShaderCompile { step_index, .. }
+            | Self::Binding { step_index, .. }
+            | Self::Size { step_index, .. }
+            | Self::CpuFunc { step_index, .. }
+            | Self::Validation { step_index, .. }
+            | Self::Readback { step_index, .. } => *step_index,
+        }
+    }
+
+    /// エラーが発生したシェーダーのID
+    pub fn shader_id(&self) -> Option<&str> {
+        match self {
+            Self::Device { shader_id, .. }
+            | Self::Binding { shader_id, .. }
+            | Self::Size { shader_id, .. }
+            | Self::Validation { shader_id, .. }
+            | Self::Readback { shader_id, .. } => shader_id.as_deref(),
+            Self::ShaderCompile { error, .. } => Some(&error.file_id),
+            Self::CpuFunc { .. } => None,
+        }
+    }
+
+    /// 検証エラーの原因のバインディング
+    pub fn binding_name(&self) -> Option<&str> {
+        match self {
+            Self::Validation { binding, .. } => binding.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn step_index_mut(&mut self) -> &mut Option<usize> {
+        match self {
+            Self::Device { step_index, .. }
+            | Self::ShaderCompile { step_index, .. }
+            | Self::Binding { step_index, .. }
+            | Self::Size { step_index, .. }
+            | Self::CpuFunc { step_index, .. }
+            | Self::Validation { step_index, .. }
+            | Self::Readback { step_index, .. } => step_index,
+        }
+    }
+}
+
+impl From<ShaderCompileError> for GpuUtilError {
+    fn from(error: ShaderCompileError) -> Self {
+        Self::ShaderCompile {
+            step_index: None,
+            error,
+        }
+    }
+}
+
+/// `Result`のエラーが`GpuUtilError`で、ステップの位置を持たない場合に位置を設定します。
+pub(crate) trait WithStepIndex<T> {
+    fn with_step_index(self, step_index: usize) -> anyhow::Result<T>;
+}
+
+impl<T> WithStepIndex<T> for anyhow::Result<T> {
+    fn with_step_index(self, step_index: usize) -> anyhow::Result<T> {
+        self.map_err(|e| match e.downcast::<GpuUtilError>() {
+            Ok(mut error) => {
+                error.step_index_mut().get_or_insert(step_index);
+                error.into()
+            }
+            Err(e) => e,
+        })
+    }
+}
+
+// メッセージの先頭に付ける、エラーが発生した位置
+fn location(step_index: Option<usize>, shader_id: Option<&str>) -> String {
+    match (step_index, shader_id) {
+        (Some(step_index), Some(shader_id)) => {
+            format!("Step {}: shader {}: ", step_index, shader_id)
+        }
+        (Some(step_index), None) => format!("Step {}: ", step_index),
+        (None, Some(shader_id)) => format!("Shader {}: ", shader_id),
+        (None, None) => String::new(),
+    }
+}
+
+fn binding_location(binding: Option<&str>) -> String {
+    binding.map_or_else(String::new, |binding| format!("binding {}: ", binding))
+}
@@ -0,0 +1,460 @@
+// executable_plan.rs
+
+use crate::{
+    color_space::{AlphaMode, ColorSpace},
+    error::GpuUtilError,
+    execution_report::StepKind,
+    image_generate_builder::{ImageGenerateBuilder, PipelineStep},
+    image_generator::{
+        final_process::PreparedOutput,
+        wgsl_process::{prepare_wgsl_step, PreparedWgslStep},
+        ImageGenerator, ProcessingState, StepOutput,
+    },
+    output_format::OutputOptions,
+    pipeline_graph::{GraphNode, PipelineGraph, ResolvedInput},
+};
+use anyhow::{bail, Context, Result};
+use futures::lock::Mutex;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+};
+use tracing::instrument;
+
+/// 実行計画のステップのバッファの書き換え。
+#[derive(Clone, Debug)]
+pub struct ParamUpdate {
+    /// 書き換えるステップの名前。同じ名前のステップが複数ある場合はすべて書き換えられます。
+    pub step: String,
+    /// 書き換えるバッファの変数名。`None`の場合はparamsを渡したバッファです。
+    pub buffer: Option<String>,
+    /// 新しい内容。準備したときに渡したデータと同じバイト数である必要があります。
+    pub data: Vec<u8>,
+}
+
+impl ParamUpdate {
+    /// ステップのparamsを書き換えます。
+    pub fn params(step: &str, data: Vec<u8>) -> Self {
+        Self {
+            step: step.to_string(),
+            buffer: None,
+            data,
+        }
+    }
+
+    /// ステップの名前を指定したバッファを書き換えます。
+    pub fn buffer(step: &str, buffer: &str, data: Vec<u8>) -> Self {
+        Self {
+            step: step.to_string(),
+            buffer: Some(buffer.to_string()),
+            data,
+        }
+    }
+}
+
+/// `ImageGenerator::prepare`で検証とリソースの作成を済ませたパイプライン。
+///
+/// WGSLステップの出力テクスチャ、パイプライン、バインドグループ、バッファは準備したときに作成され、
+/// 実行のたびにはバッファの書き換えとコマンドの記録だけを行います。
+/// 最終処理のバインドグループとバッファも出力の設定ごとに一度だけ作成され、
+/// すべてのステップと最終処理を1つのコマンドバッファに記録してサブミットします。
+/// CPU関数やCPUのデータを入力に持つステップを含む計画は、実行のたびにCPUのデータを受け渡す必要があるため、
+/// バッファを書き換えた後は通常の生成と同じ手順で実行されます。
+/// 計画が作成したテクスチャは計画が破棄されるまで他の生成に貸し出されず、同じ計画の実行は一度に1つずつ行われます。
+pub struct ExecutablePlan {
+    // 計画が作成したリソースを貸し出し続けるインスタンス
+    generator: ImageGenerator,
+    // WGSLステップを準備済みのステップに置き換えたパイプライン
+    builder: ImageGenerateBuilder,
+    // 名前を持つ準備済みのステップ
+    steps: HashMap<String, Vec<Arc<PreparedWgslStep>>>,
+    // すべてのステップをGPUだけで実行できる場合の、実行順のステップと最終的な出力のテクスチャ
+    direct: Option<DirectPlan>,
+    // 出力の設定ごとに準備した最終処理。
+    // 計画のテクスチャとバッファは実行ごとに書き換えるため、このロックで実行を直列にする
+    outputs: Mutex<HashMap<OutputOptions, PreparedOutput>>,
+}
+
+impl ExecutablePlan {
+    #[instrument(level = "debug", name = "prepare_plan", skip_all, fields(steps = builder.steps.len()))]
+    pub(crate) fn new(generator: &ImageGenerator, builder: &ImageGenerateBuilder) -> Result<Self> {
+        let generator = generator.with_lease();
+        let mut planner = Planner {
+            generator: &generator,
+            steps: HashMap::new(),
+            order: Vec::new(),
+            direct: true,
+        };
+        let prepared = planner.prepare_steps(&builder.steps, ProcessingState::new());
+        generator.flush_disk_cache();
+        let (steps, final_state) = prepared?;
+        if final_state.len() != 1 {
+            bail!(GpuUtilError::binding(
+                None,
+                format!(
+                    "Final processing state should have exactly one element, but has {}",
+                    final_state.len()
+                ),
+            ));
+        }
+
+        let direct = match &final_state[0] {
+            StepOutput::Gpu {
+                texture,
+                width,
+                height,
+                color_space,
+                alpha_mode,
+            } if planner.direct => Some(DirectPlan {
+                steps: planner.order,
+                texture: texture.clone(),
+                size: (*width, *height),
+                color: (*color_space, *alpha_mode),
+            }),
+            _ => None,
+        };
+        let steps_by_name = planner.steps;
+        Ok(Self {
+            generator,
+            builder: ImageGenerateBuilder {
+                steps: Arc::new(steps),
+            },
+            steps: steps_by_name,
+            direct,
+            outputs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// パラメータを書き換えられる、名前を持つステップの一覧を返します。
+    pub fn step_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.steps.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// 計画が最終的に出力する画像の解像度を返します。
+    pub fn output_size(&self) -> Option<(u32, u32)> {
+        self.builder.output_size()
+    }
+
+    /// バッファを書き換えてから計画を実行し、sRGBのRGBA8の画像を生成します。
+    pub async fn execute(&self, updates: &[ParamUpdate]) -> Result<Vec<u8>> {
+        self.execute_with_output(updates, &OutputOptions::default())
+            .await
+    }
+
+    /// バッファを書き換えてから計画を実行し、指定したフォーマットと色空間の画像を生成します。
+    /// 書き換えた内容は以降の実行でも保持されます。
+    #[instrument(level = "debug", name = "execute_plan", skip_all, fields(updates = updates.len()))]
+    pub async fn execute_with_output(
+        &self,
+        updates: &[ParamUpdate],
+        output: &OutputOptions,
+    ) -> Result<Vec<u8>> {
+        let mut outputs = self.outputs.lock().await;
+
+        // 一部だけ書き換えられた状態にならないよう、すべての書き換えを検証してから書き込む
+        let mut writes = Vec::new();
+        for update in updates {
+            let steps = self
+                .steps
+                .get(&update.step)
+                .with_context(|| format!("The plan has no step named `{}`", update.step))?;
+            for step in steps {
+                for buffer in step.update_targets(update.buffer.as_deref(), update.data.len())? {
+                    writes.push((buffer, &update.data));
+                }
+            }
+        }
+        let write_all = || {
+            for (buffer, data) in &writes {
+                // 書き込むサイズは4バイトの倍数である必要があるため、バッファの大きさまで0で埋める
+                if data.len() as u64 == buffer.size() {
+                    self.generator.queue.write_buffer(buffer, 0, data);
+                } else {
+                    let mut padded = data.to_vec();
+                    padded.resize(buffer.size() as usize, 0);
+                    self.generator.queue.write_buffer(buffer, 0, &padded);
+                }
+            }
+        };
+
+        let Some(direct) = &self.direct else {
+            // CPUのデータを受け渡すステップがある場合は、書き換えた後に通常の生成と同じ手順で実行する
+            self.generator.with_error_scope(None, None, || {
+                write_all();
+                Ok(())
+            })?;
+            return self
+                .generator
+                .generate_with_output(self.builder.clone(), output)
+                .await;
+        };
+
+        // 書き換え、すべてのステップと最終処理の記録を1つのエラースコープで行う
+        let (commands, prepared) = self.generator.with_error_scope(None, None, || {
+            write_all();
+            let prepared = match outputs.entry(*output) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(PreparedOutput::new(
+                    &self.generator,
+                    &direct.texture,
+                    direct.size,
+                    direct.color,
+                    output,
+                )),
+            };
+
+            let mut encoder =
+                self.generator
+                    .device
+                    .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                        label: Some("Executable Plan Encoder"),
+                    });
+            for step in &direct.steps {
+                let span = self.generator.profile_span(
+                    StepKind::Wgsl,
+                    &step.wgsl.id,
+                    Some(step.step_index),
+                    step.output_size(),
+                );
+                step.record_direct(&self.generator, &mut encoder, &span)?;
+                span.finish(None);
+            }
+            let span = self.generator.profile_span(
+                StepKind::Output,
+                StepKind::Output.as_str(),
+                None,
+                Some(direct.size),
+            );
+            prepared.record(&self.generator, &mut encoder, &span);
+            span.finish(None);
+            Ok((encoder.finish(), &*prepared))
+        })?;
+
+        self.generator
+            .submit_and_read(
+                None,
+                Some(StepKind::Output.as_str()),
+                std::iter::once(commands),
+                std::slice::from_ref(&prepared.readback),
+            )
+            .await?
+            .pop()
+            .context("Failed to read back the output")
+    }
+
+    /// 名前を指定したステップのうち、最初に準備したものを返します。
+    pub(crate) fn step(&self, name: &str) -> Option<&PreparedWgslStep> {
+        self.steps.get(name)?.first().map(Arc::as_ref)
+    }
+}
+
+// CPUのデータを受け渡さずに、準備済みのステップを順に記録するだけで実行できる計画
+struct DirectPlan {
+    // 実行順の準備済みのステップ
+    steps: Vec<Arc<PreparedWgslStep>>,
+    // 最終的な出力のテクスチャとその解像度、色空間とアルファの形式
+    texture: Arc<wgpu::Texture>,
+    size: (u32, u32),
+    color: (ColorSpace, AlphaMode),
+}
+
+// パイプラインを先頭から辿り、WGSLステップを準備済みのステップに置き換える
+struct Planner<'a> {
+    generator: &'a ImageGenerator,
+    steps: HashMap<String, Vec<Arc<PreparedWgslStep>>>,
+    // 実行順に並べた準備済みのステップ
+    order: Vec<Arc<PreparedWgslStep>>,
+    // CPUのデータを受け渡すステップがなく、orderの順に記録するだけで実行できるか
+    direct: bool,
+}
+
+impl Planner<'_> {
+    /// ステップの列を準備します。状態のCPUのデータは解像度と色空間だけを持つ空のデータです。
+    fn prepare_steps(
+        &mut self,
+        steps: &[PipelineStep],
+        mut state: ProcessingState,
+    ) -> Result<(Vec<PipelineStep>, ProcessingState)> {
+        let mut prepared = Vec::with_capacity(steps.len());
+        for (i, step) in steps.iter().enumerate() {
+            let (step, new_state) = self.prepare_step(step, &state, i, None)?;
+            prepared.push(step);
+            state = new_state;
+        }
+        Ok((prepared, state))
+    }
+
+    fn prepare_step(
+        &mut self,
+        step: &PipelineStep,
+        state: &ProcessingState,
+        step_index: usize,
+        node_name: Option<&str>,
+    ) -> Result<(PipelineStep, ProcessingState)> {
+        match step {
+            PipelineStep::Wgsl {
+                wgsl,
+                params,
+                options,
+                output_width,
+                output_height,
+            } => {
+                let mut prepared =
+                    self.generator
+                        .with_error_scope(Some(step_index), Some(&wgsl.id), || {
+                            prepare_wgsl_step(
+                                self.generator,
+                                state,
+                                wgsl,
+                                params.as_deref(),
+                                options,
+                                step_index,
+                                *output_width,
+                                *output_height,
+                            )
+                        })?;
+                // グラフのノードは名前がなければノードの名前で書き換えられるようにする
+                if prepared.name.is_none() {
+                    prepared.name = node_name.map(str::to_string);
+                }
+                let output = vec![prepared.output.clone()];
+                self.direct &= prepared.has_gpu_inputs_only();
+                let prepared = Arc::new(prepared);
+                self.order.push(prepared.clone());
+                if let Some(name) = &prepared.name {
+                    self.steps
+                        .entry(name.clone())
+                        .or_default()
+                        .push(prepared.clone());
+                }
+                Ok((PipelineStep::Prepared { step: prepared }, output))
+            }
+            PipelineStep::Parallel { pipelines } => {
+                let mut branches = Vec::with_capacity(pipelines.len());
+                let mut output = ProcessingState::new();
+                for pipeline in pipelines {
+                    let (steps, mut branch_output) =
+                        self.prepare_steps(&pipeline.steps, state.clone())?;
+                    branches.push(ImageGenerateBuilder {
+                        steps: Arc::new(steps),
+                    });
+                    output.append(&mut branch_output);
+                }
+                Ok((
+                    PipelineStep::Parallel {
+                        pipelines: branches,
+                    },
+                    output,
+                ))
+            }
+            PipelineStep::CpuFunc {
+                func,
+                output_width,
+                output_height,
+                ..
+            } => {
+                // CPU関数の結果は実行するまで分からないため、解像度と色空間だけを後続のステップに渡す
+                self.direct = false;
+                let output = vec![StepOutput::Cpu {
+                    data: Arc::new(Vec::new()),
+                    width: *output_width,
+                    height: *output_height,
+                    color_space: func.color_space,
+                    alpha_mode: func.alpha_mode,
+                }];
+                Ok((step.clone(), output))
+            }
+            PipelineStep::Graph { graph } => {
+                let (graph, output) = self.prepare_graph(graph, state, step_index)?;
+                Ok((
+                    PipelineStep::Graph {
+                        graph: Arc::new(graph),
+                    },
+                    output,
+                ))
+            }
+            PipelineStep::Cached { pipeline, .. } => {
+                // 計画のテクスチャは実行ごとに書き換えられるため、結果はキャッシュせずに毎回実行する
+                let (steps, output) = self.prepare_steps(&pipeline.steps, state.clone())?;
+                Ok((
+                    PipelineStep::Parallel {
+                        pipelines: vec![ImageGenerateBuilder {
+                            steps: Arc::new(steps),
+                        }],
+                    },
+                    output,
+                ))
+            }
+            PipelineStep::Prepared { .. } => {
+                bail!(GpuUtilError::binding(
+                    None,
+                    "the step is already part of an executable plan".to_string(),
+                )
+                .at_step(step_index))
+            }
+        }
+    }
+
+    fn prepare_graph(
+        &mut self,
+        graph: &PipelineGraph,
+        previous: &ProcessingState,
+        step_index: usize,
+    ) -> Result<(PipelineGraph, ProcessingState)> {
+        let mut nodes = Vec::with_capacity(graph.nodes.len());
+        let mut outputs: Vec<ProcessingState> = Vec::with_capacity(graph.nodes.len());
+
+        for node in &graph.nodes {
+            let mut inputs = ProcessingState::new();
+            for input in &node.inputs {
+                match *input {
+                    // ノードは実行順に並んでいるため、入力のノードは準備済み
+                    ResolvedInput::Node(producer) => {
+                        inputs.extend(outputs[producer].iter().cloned());
+                    }
+                    ResolvedInput::Previous(index) => {
+                        let Some(image) = previous.get(index) else {
+                            bail!(GpuUtilError::binding(
+                                None,
+                                format!(
+                                    "graph node `{}` takes input {}, but the graph received only {} images",
+                                    node.name,
+                                    index,
+                                    previous.len()
+                                ),
+                            )
+                            .at_step(step_index));
+                        };
+                        inputs.push(image.clone());
+                    }
+                }
+            }
+
+            let (step, output) = self
+                .prepare_step(&node.step, &inputs, step_index, Some(&node.name))
+                .with_context(|| format!("Graph node `{}` failed", node.name))?;
+            nodes.push(GraphNode {
+                name: node.name.clone(),
+                step,
+                inputs: node.inputs.clone(),
+                last_use: node.last_use,
+            });
+            outputs.push(output);
+        }
+
+        let output = graph
+            .outputs
+            .iter()
+            .flat_map(|&i| outputs[i].iter().cloned())
+            .collect();
+        Ok((
+            PipelineGraph {
+                nodes,
+                outputs: graph.outputs.clone(),
+            },
+            output,
+        ))
+    }
+}
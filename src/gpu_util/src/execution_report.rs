@@ -0,0 +1,147 @@
+// execution_report.rs
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+
+/// 実行レポートの項目の種類。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepKind {
+    /// WGSLシェーダーのステップ。入力の色空間の変換を含みます。
+    Wgsl,
+    /// CPU関数のステップ。
+    CpuFunc,
+    /// CPUのデータのGPUへのアップロード。
+    Upload,
+    /// GPUのテクスチャの読み戻し。読み戻す前にサブミットした処理の完了を待つ時間を含みます。
+    Download,
+    /// パイプラインキャッシュにないシェーダーのパイプラインの作成。
+    PipelineCompile,
+    /// 最終的な出力フォーマットへの変換。
+    Output,
+}
+
+impl StepKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StepKind::Wgsl => "wgsl",
+            StepKind::CpuFunc => "cpu_func",
+            StepKind::Upload => "upload",
+            StepKind::Download => "download",
+            StepKind::PipelineCompile => "pipeline_compile",
+            StepKind::Output => "output",
+        }
+    }
+}
+
+/// 結果キャッシュやパイプラインキャッシュを引いた結果。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Miss => "miss",
+        }
+    }
+}
+
+/// 実行レポートの1つの項目。
+#[derive(Clone, Debug, Serialize)]
+pub struct StepReport {
+    /// WGSLステップとパイプラインの作成ではシェーダーのID、それ以外では種類の名前です。
+    pub id: String,
+    pub kind: StepKind,
+    /// パイプラインの中でのステップの位置。パイプラインの作成のようにステップに属さない項目では`None`です。
+    pub step_index: Option<usize>,
+    /// 出力の解像度 (幅, 高さ)
+    pub resolution: Option<(u32, u32)>,
+    /// 生成の開始からCPUで処理を始めるまでの時間 (ミリ秒)
+    pub cpu_start_ms: f64,
+    /// CPUでの処理時間 (ミリ秒)。入れ子になった項目 (WGSLステップの中のアップロードなど) の時間を含みます。
+    pub cpu_ms: f64,
+    /// 最初のコンピュートパスの開始からGPUで処理を始めるまでの時間 (ミリ秒)
+    pub gpu_start_ms: Option<f64>,
+    /// GPUでのコンピュートパスの実行時間の合計 (ミリ秒)。タイムスタンプが使えない場合やパスがない場合は`None`です。
+    pub gpu_ms: Option<f64>,
+    /// WGSLステップでは結果キャッシュ、パイプラインの作成ではパイプラインキャッシュを引いた結果です。
+    pub cache: Option<CacheStatus>,
+}
+
+/// プロファイリングを有効にした生成の実行レポート。
+#[derive(Clone, Debug, Serialize)]
+pub struct ExecutionReport {
+    /// 処理を始めた順に並べた項目
+    pub steps: Vec<StepReport>,
+    /// 生成全体にかかった時間 (ミリ秒)
+    pub total_ms: f64,
+    /// GPUのタイムスタンプを計測できたかどうか。`false`の場合はすべての`gpu_ms`が`None`になります。
+    pub gpu_timestamps: bool,
+}
+
+impl ExecutionReport {
+    /// すべての項目のGPUでの実行時間の合計 (ミリ秒)
+    pub fn gpu_ms(&self) -> Option<f64> {
+        self.gpu_timestamps
+            .then(|| self.steps.iter().filter_map(|s| s.gpu_ms).sum())
+    }
+
+    /// レポートをJSONの文字列に変換します。
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Chromeのトレースイベント形式 (chrome://tracingやPerfettoで開けるJSON) に変換します。
+    /// CPUとGPUの処理は別のトラックに並び、GPUのトラックは最初のコンピュートパスの開始を0とします。
+    pub fn to_chrome_trace(&self) -> Result<String> {
+        const CPU_TRACK: u32 = 1;
+        const GPU_TRACK: u32 = 2;
+
+        let mut events = vec![
+            json!({"name": "thread_name", "ph": "M", "pid": 1, "tid": CPU_TRACK, "args": {"name": "CPU"}}),
+            json!({"name": "thread_name", "ph": "M", "pid": 1, "tid": GPU_TRACK, "args": {"name": "GPU"}}),
+        ];
+        for step in &self.steps {
+            let args = json!({
+                "kind": step.kind,
+                "step_index": step.step_index,
+                "resolution": step.resolution,
+                "cache": step.cache,
+            });
+            // トレースの時刻はマイクロ秒
+            events.push(json!({
+                "name": step.id,
+                "cat": step.kind.as_str(),
+                "ph": "X",
+                "pid": 1,
+                "tid": CPU_TRACK,
+                "ts": step.cpu_start_ms * 1000.0,
+                "dur": step.cpu_ms * 1000.0,
+                "args": args,
+            }));
+            if let (Some(start), Some(duration)) = (step.gpu_start_ms, step.gpu_ms) {
+                events.push(json!({
+                    "name": step.id,
+                    "cat": step.kind.as_str(),
+                    "ph": "X",
+                    "pid": 1,
+                    "tid": GPU_TRACK,
+                    "ts": start * 1000.0,
+                    "dur": duration * 1000.0,
+                    "args": args,
+                }));
+            }
+        }
+
+        Ok(serde_json::to_string(&json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ms",
+        }))?)
+    }
+}
@@ -0,0 +1,136 @@
+// generator_options.rs
+
+use crate::color_space::ColorSpace;
+use crate::error::GpuUtilError;
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+
+/// 使用するアダプタを明示的に指定する方法。
+#[derive(Clone, Debug)]
+pub enum AdapterSelector {
+    /// アダプタ名で選択します（大文字小文字を区別しない部分一致）。
+    Name(String),
+    /// `enumerate_adapters`が返すリスト上のインデックスで選択します。
+    Index(usize),
+}
+
+/// ImageGeneratorの初期化オプション。
+#[derive(Clone, Debug)]
+pub struct ImageGeneratorOptions {
+    /// アダプタを探すバックエンド (Vulkan, GLなど)。
+    pub backends: wgpu::Backends,
+    /// アダプタ選択時の電力設定。
+    pub power_preference: wgpu::PowerPreference,
+    /// ソフトウェアアダプタ (llvmpipe, lavapipeなど) を強制するかどうか。
+    pub force_fallback_adapter: bool,
+    /// 特定のアダプタを使用する場合の指定。`None`の場合はwgpuに選択を任せます。
+    pub adapter: Option<AdapterSelector>,
+    /// パイプラインキャッシュを永続化するディレクトリ。`None`の場合はメモリ上にのみキャッシュします。
+    pub cache_dir: Option<PathBuf>,
+    /// WGSLのステップが入出力に使う作業用の色空間。合成やリサンプリングが正しくなるよう、線形の色空間を推奨します。
+    pub working_space: ColorSpace,
+}
+
+impl Default for ImageGeneratorOptions {
+    fn default() -> Self {
+        Self {
+            backends: wgpu::Backends::all(),
+            power_preference: wgpu::PowerPreference::default(),
+            force_fallback_adapter: false,
+            adapter: None,
+            cache_dir: None,
+            working_space: ColorSpace::LINEAR_SRGB,
+        }
+    }
+}
+
+/// アダプタの情報と対応機能をまとめた構造体。
+#[derive(Clone, Debug)]
+pub struct AdapterDescription {
+    pub name: String,
+    pub backend: wgpu::Backend,
+    pub device_type: wgpu::DeviceType,
+    pub driver: String,
+    pub driver_info: String,
+    pub features: wgpu::Features,
+}
+
+impl From<&wgpu::Adapter> for AdapterDescription {
+    fn from(adapter: &wgpu::Adapter) -> Self {
+        let info = adapter.get_info();
+        Self {
+            name: info.name,
+            backend: info.backend,
+            device_type: info.device_type,
+            driver: info.driver,
+            driver_info: info.driver_info,
+            features: adapter.features(),
+        }
+    }
+}
+
+/// 指定されたバックエンドで利用可能なすべてのアダプタの情報を返します。
+/// 返却順は`AdapterSelector::Index`で使用されるインデックスと一致します。
+pub fn enumerate_adapters(backends: wgpu::Backends) -> Vec<AdapterDescription> {
+    let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
+        backends,
+        ..Default::default()
+    });
+    instance
+        .enumerate_adapters(backends)
+        .iter()
+        .map(AdapterDescription::from)
+        .collect()
+}
+
+/// オプションに従ってアダプタを選択します。
+pub(crate) async fn request_adapter(
+    instance: &wgpu::Instance,
+    options: &ImageGeneratorOptions,
+) -> Result<wgpu::Adapter> {
+    let Some(selector) = &options.adapter else {
+        // 明示的な指定がなければwgpuに選択を任せる
+        return instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: options.power_preference,
+                force_fallback_adapter: options.force_fallback_adapter,
+                compatible_surface: None,
+            })
+            .await
+            .context("Failed to find an appropriate adapter");
+    };
+
+    let adapters = instance.enumerate_adapters(options.backends);
+    let adapter = match selector {
+        AdapterSelector::Name(name) => {
+            let name = name.to_lowercase();
+            adapters
+                .into_iter()
+                .find(|a| a.get_info().name.to_lowercase().contains(&name))
+        }
+        AdapterSelector::Index(index) => adapters.into_iter().nth(*index),
+    };
+    let Some(adapter) = adapter else {
+        bail!(GpuUtilError::device(
+            None,
+            format!(
+                "No adapter matched {:?} on backends {:?}",
+                selector, options.backends
+            ),
+        ));
+    };
+
+    // フォールバックが強制されている場合はソフトウェアアダプタ以外を拒否する
+    let info = adapter.get_info();
+    if options.force_fallback_adapter && info.device_type != wgpu::DeviceType::Cpu {
+        bail!(GpuUtilError::device(
+            None,
+            format!(
+                "Adapter \"{}\" is not a fallback (software) adapter, but force_fallback_adapter is set",
+                info.name
+            ),
+        ));
+    }
+
+    Ok(adapter)
+}
@@ -1,8 +1,71 @@
 // image_generate_builder.rs
 
+use crate::color_space::{AlphaMode, ColorSpace};
 use crate::compiled_func::CompiledFunc;
 use crate::compiled_wgsl::CompiledWgsl;
-use std::sync::Arc;
+use crate::image_generator::wgsl_process::PreparedWgslStep;
+use crate::pipeline_graph::PipelineGraph;
+use std::{collections::HashMap, sync::Arc};
+
+/// WGSLステップのディスパッチ方法。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Dispatch {
+    /// 出力の1ピクセルにつき1回シェーダーを呼び出します (デフォルト)。
+    #[default]
+    PerPixel,
+    /// シェーダーの呼び出し回数 (x, y, z) を指定します。
+    /// ワークグループ数はシェーダーの`@workgroup_size`で割って切り上げた値になります。
+    /// 行ごとの処理や、zをレイヤーに対応させた処理などに使います。
+    Invocations(u32, u32, u32),
+    /// ワークグループ数 (x, y, z) を直接指定します。リダクションなどに使います。
+    Workgroups(u32, u32, u32),
+}
+
+impl Dispatch {
+    /// ディスパッチするワークグループ数を計算します。
+    pub(crate) fn workgroup_counts(
+        &self,
+        output_width: u32,
+        output_height: u32,
+        workgroup_size: [u32; 3],
+    ) -> [u32; 3] {
+        let invocations = |x: u32, y: u32, z: u32| {
+            [
+                x.div_ceil(workgroup_size[0]),
+                y.div_ceil(workgroup_size[1]),
+                z.div_ceil(workgroup_size[2]),
+            ]
+        };
+        match *self {
+            Self::PerPixel => invocations(output_width, output_height, 1),
+            Self::Invocations(x, y, z) => invocations(x, y, z),
+            Self::Workgroups(x, y, z) => [x, y, z],
+        }
+    }
+}
+
+/// WGSLステップの追加の設定。
+#[derive(Clone, Debug, Default)]
+pub struct WgslStepOptions {
+    /// バッファの変数名と、そのバッファに渡すバイト列。
+    pub buffers: HashMap<String, Vec<u8>>,
+    /// ディスパッチ方法。
+    pub dispatch: Dispatch,
+    /// 使用するエントリーポイント。`None`の場合は`main`、エントリーポイントが1つだけならそれを使います。
+    pub entry_point: Option<String>,
+    /// シェーダーが書き込む値の色空間。`None`の場合は`ImageGenerator`の作業用の色空間です。
+    /// 作業用の色空間と異なる場合、次のステップに渡す前に自動で変換されます。
+    pub color_space: Option<ColorSpace>,
+    /// シェーダーが扱うアルファの形式。入力はこの形式に変換されてから渡され、出力もこの形式として扱われます。
+    pub alpha_mode: AlphaMode,
+    /// ステップの名前。`ExecutablePlan`の実行時にパラメータを書き換えるステップを指定するのに使います。
+    /// グラフのノードでは`None`の場合にノードの名前が使われます。
+    pub name: Option<String>,
+    /// `true`の場合、入力とパラメータが以前の実行と同じであれば結果キャッシュの結果を返します。
+    /// 結果のテクスチャはキャッシュが保持し続けるため、毎回内容が変わるステップには指定しないでください。
+    /// パイプラインの最後のステップの結果はキャッシュしません。
+    pub cache: bool,
+}
 
 /// パイプラインの各ステップを表すenum。
 #[derive(Clone)]
@@ -11,6 +74,7 @@ pub enum PipelineStep {
     Wgsl {
         wgsl: Arc<CompiledWgsl>,
         params: Option<Vec<u8>>,
+        options: WgslStepOptions,
         output_width: u32,
         output_height: u32,
     },
@@ -27,6 +91,57 @@ pub enum PipelineStep {
         output_width: u32,
         output_height: u32,
     },
+    /// 名前付きのノードからなるグラフを実行するステップ。
+    Graph { graph: Arc<PipelineGraph> },
+    /// 入力の内容とキーが前回と同じ場合に、サブパイプラインを実行せず前回の結果を返すステップ。
+    Cached {
+        key: String,
+        pipeline: ImageGenerateBuilder,
+    },
+    /// `ImageGenerator::prepare`でリソースを作成済みのWGSLステップ。`ExecutablePlan`の中でのみ使われます。
+    Prepared { step: Arc<PreparedWgslStep> },
+}
+
+impl PipelineStep {
+    /// ステップが出力する画像の解像度を返します。単一の画像にならない場合は`None`を返します。
+    pub(crate) fn output_size(&self) -> Option<(u32, u32)> {
+        match self {
+            PipelineStep::Wgsl {
+                output_width,
+                output_height,
+                ..
+            }
+            | PipelineStep::CpuFunc {
+                output_width,
+                output_height,
+                ..
+            } => Some((*output_width, *output_height)),
+            PipelineStep::Parallel { pipelines } => match pipelines.as_slice() {
+                [pipeline] => pipeline.output_size(),
+                _ => None,
+            },
+            PipelineStep::Graph { graph } => graph.output_size(),
+            PipelineStep::Cached { pipeline, .. } => pipeline.output_size(),
+            PipelineStep::Prepared { step } => step.output_size(),
+        }
+    }
+
+    /// ステップがCPU関数の処理を含むかどうかを、入れ子のパイプラインも含めて返します。
+    pub(crate) fn contains_cpu_func(&self) -> bool {
+        match self {
+            PipelineStep::Wgsl { .. } | PipelineStep::Prepared { .. } => false,
+            PipelineStep::CpuFunc { .. } => true,
+            PipelineStep::Parallel { pipelines } => pipelines
+                .iter()
+                .any(|pipeline| pipeline.steps.iter().any(|step| step.contains_cpu_func())),
+            PipelineStep::Graph { graph } => {
+                graph.nodes.iter().any(|node| node.step.contains_cpu_func())
+            }
+            PipelineStep::Cached { pipeline, .. } => {
+                pipeline.steps.iter().any(|step| step.contains_cpu_func())
+            }
+        }
+    }
 }
 
 /// 画像生成パイプラインを構築するためのビルダー。
@@ -38,6 +153,12 @@ pub struct ImageGenerateBuilder {
     pub(crate) steps: Arc<Vec<PipelineStep>>,
 }
 
+impl Default for ImageGenerateBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ImageGenerateBuilder {
     /// 新しいImageGenerateBuilderインスタンスを作成します。
     pub fn new() -> Self {
@@ -51,13 +172,38 @@ impl ImageGenerateBuilder {
     /// # Arguments
     ///
     /// * `wgsl` - `CompiledWgsl`のArc参照。
-    /// * `params` - シェーダーのStorage Bufferに渡すパラメータ。`bytemuck`でシリアライズされたバイト列を渡します。
+    /// * `params` - シェーダーのバッファに渡すパラメータ。`bytemuck`でシリアライズされたバイト列を渡します。
+    ///   シェーダーが宣言したバッファがちょうど1つの場合にそのバッファに渡されます。
     pub fn add_wgsl(
         self,
         wgsl: CompiledWgsl,
         params: Option<Vec<u8>>,
         output_width: u32,
         output_height: u32,
+    ) -> Self {
+        self.add_wgsl_with_options(
+            wgsl,
+            params,
+            WgslStepOptions::default(),
+            output_width,
+            output_height,
+        )
+    }
+
+    /// 追加の設定を指定してWGSL処理ステップをパイプラインに追加します（直列実行）。
+    ///
+    /// # Arguments
+    ///
+    /// * `wgsl` - `CompiledWgsl`のArc参照。
+    /// * `params` - `options.buffers`で指定されなかった唯一のバッファに渡すパラメータ。
+    /// * `options` - 名前を指定したバッファやディスパッチ方法。
+    pub fn add_wgsl_with_options(
+        self,
+        wgsl: CompiledWgsl,
+        params: Option<Vec<u8>>,
+        options: WgslStepOptions,
+        output_width: u32,
+        output_height: u32,
     ) -> Self {
         let wgsl = Arc::new(wgsl);
 
@@ -66,6 +212,7 @@ impl ImageGenerateBuilder {
         new_steps.push(PipelineStep::Wgsl {
             wgsl,
             params,
+            options,
             output_width,
             output_height,
         });
@@ -115,4 +262,47 @@ impl ImageGenerateBuilder {
             steps: Arc::new(new_steps),
         }
     }
+
+    /// 処理グラフをステップとしてパイプラインに追加します。
+    /// グラフには直前のステップの出力が渡され、グラフの出力が次のステップの入力になります。
+    pub fn add_graph(self, graph: PipelineGraph) -> Self {
+        // Copy-on-Write: 新しいVecを作成して要素を追加
+        let mut new_steps = (*self.steps).clone();
+        new_steps.push(PipelineStep::Graph {
+            graph: Arc::new(graph),
+        });
+
+        Self {
+            steps: Arc::new(new_steps),
+        }
+    }
+
+    /// 結果をキャッシュするサブパイプラインをステップとしてパイプラインに追加します。
+    ///
+    /// サブパイプラインには直前のステップの出力が渡されます。keyと入力の内容が以前の生成と同じで、
+    /// 結果がキャッシュに残っている場合は、サブパイプラインを実行せずに前回の結果を返します。
+    /// CPU関数のように入力以外から結果が決まる処理は、その内容をkeyに含めてください。
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - サブパイプラインの内容を表す文字列。内容が変わった場合は異なるkeyを指定します。
+    /// * `pipeline` - 結果をキャッシュするサブパイプライン。
+    pub fn add_cached(self, key: &str, pipeline: ImageGenerateBuilder) -> Self {
+        // Copy-on-Write: 新しいVecを作成して要素を追加
+        let mut new_steps = (*self.steps).clone();
+        new_steps.push(PipelineStep::Cached {
+            key: key.to_string(),
+            pipeline,
+        });
+
+        Self {
+            steps: Arc::new(new_steps),
+        }
+    }
+
+    /// パイプラインが最終的に出力する画像の解像度を返します。
+    /// 最後のステップが複数の出力を持つ並列ステップの場合など、単一の画像にならない場合は`None`を返します。
+    pub fn output_size(&self) -> Option<(u32, u32)> {
+        self.steps.last()?.output_size()
+    }
 }
@@ -1,42 +1,104 @@
 // image_generator.rs
+pub mod cached_process;
+pub mod color_process;
 pub mod cpu_func_process;
+pub mod device_poller;
+pub mod error_scope;
 pub mod final_process;
+pub mod graph_process;
 pub mod parallel_process;
+pub mod profiler;
+pub mod result_cache;
+pub mod transfer;
+pub mod transient_resources;
 pub mod wgsl_process;
 
 use crate::{
+    color_space::{AlphaMode, ColorSpace},
+    compiled_wgsl::CompiledWgsl,
+    error::GpuUtilError,
+    executable_plan::ExecutablePlan,
+    execution_report::{CacheStatus, ExecutionReport, StepKind},
+    generator_options::{request_adapter, AdapterDescription, ImageGeneratorOptions},
     image_generate_builder::{ImageGenerateBuilder, PipelineStep},
     image_generator::{
-        cpu_func_process::handle_cpu_func_step, final_process::handle_final_process,
-        parallel_process::handle_parallel_step, wgsl_process::handle_wgsl_step,
+        cached_process::handle_cached_step,
+        color_process::ColorConvertPipeline,
+        cpu_func_process::handle_cpu_func_step,
+        device_poller::DevicePoller,
+        error_scope::{capture_errors, to_gpu_util_error},
+        final_process::{handle_final_process, record_final_process, RecordedOutput},
+        graph_process::handle_graph_step,
+        parallel_process::handle_parallel_step,
+        profiler::Profiler,
+        result_cache::{ContentKey, ResultCache},
+        transfer::{MapReceiver, PendingDownload},
+        transient_resources::TransientResources,
+        wgsl_process::handle_wgsl_step,
     },
+    output_format::OutputOptions,
+    pipeline_disk_cache::PipelineDiskCache,
+    shader_reflection::{workgroup_size, OUTPUT_FORMATS},
 };
 use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, VecDeque},
-    sync::{Arc, Mutex},
+    hash::Hash,
+    sync::{atomic::AtomicBool, Arc, Mutex},
 };
-use wgpu::{Features, include_wgsl};
-
-// WGSLの後処理シェーダー（f32 RGBA -> u32 RRGGBBAA）
-const POST_PROCESS_WGSL: wgpu::ShaderModuleDescriptor<'_> =
-    include_wgsl!("shaders/post_process.wgsl");
+use tracing::{debug, error, info, instrument, trace, trace_span, warn, Instrument};
+use wgpu::Features;
+
+// binding arrayによる入力に必要な機能
+const BINDING_ARRAY_FEATURES: Features = Features::TEXTURE_BINDING_ARRAY
+    .union(Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING);
+// アダプタが対応していれば有効化する機能
+// binding arrayがなければ代替の入力規約のみ、CLAMP_TO_BORDERがなければそのサンプラーは作成できず、
+// FLOAT32_FILTERABLEがなければnearestサンプリングになり、PIPELINE_CACHEがなければドライバのキャッシュは保存されず、
+// TIMESTAMP_QUERYがなければプロファイリングでGPUの実行時間は計測されない
+const OPTIONAL_FEATURES: Features = BINDING_ARRAY_FEATURES
+    .union(Features::ADDRESS_MODE_CLAMP_TO_BORDER)
+    .union(Features::FLOAT32_FILTERABLE)
+    .union(Features::PIPELINE_CACHE)
+    .union(Features::TIMESTAMP_QUERY);
+
+// generate_batchで同時に読み戻しを待つフレームの数
+// 読み戻し用のバッファはこの数だけ使い回される
+pub const BATCH_READBACK_RING_SIZE: usize = 3;
+
+// 色空間の変換関数。後処理と色空間の変換シェーダーの先頭に連結する
+pub(crate) const COLOR_WGSL: &str = include_str!("shaders/color.wgsl");
+// WGSLの後処理シェーダー（f32 RGBA -> 出力フォーマットのバイト列）
+const POST_PROCESS_WGSL: &str = concat!(
+    include_str!("shaders/color.wgsl"),
+    include_str!("shaders/post_process.wgsl")
+);
 
 // パイプラインキャッシュのキーとなる構造体
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+// ディスクキャッシュのマニフェストにも保存される
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
+// レイアウトはシェーダーのリフレクションから決まるため、実行時に変わるのは入力数のみ
 pub(crate) struct PipelineCacheKey {
     id: String,
-    input_texture_count: usize,
-    has_storage: bool,
-    has_sampler: bool,
+    source_hash: u64,
+    // 以前のマニフェストにはエントリーポイントが記録されていない
+    #[serde(default = "default_entry_point")]
+    entry_point: String,
+    // 長さが固定されていないbinding_arrayの要素数。それ以外のシェーダーでは0
+    input_array_len: usize,
+}
+
+fn default_entry_point() -> String {
+    "main".to_string()
 }
 
 // テクスチャキャッシュのキーとなる構造体
 #[derive(Eq, PartialEq, Hash, Clone, Debug)]
 pub(crate) struct TextureCacheKey {
-    step: usize,
     width: u32,
     height: u32,
+    layers: u32,
     format: wgpu::TextureFormat,
     usage: wgpu::TextureUsages,
 }
@@ -48,6 +110,63 @@ pub(crate) struct BufferCacheKey {
     usage: wgpu::BufferUsages,
 }
 
+/// 1回の生成で貸し出されているテクスチャとバッファ。
+/// 生成が終わるまで参照を保持し、その間は同じリソースを他の生成 (別スレッドからの同時呼び出しを含む) や
+/// 同じ生成の別のステップに貸し出さないようにする。
+#[derive(Default)]
+pub(crate) struct ResourceLease {
+    textures: Vec<Arc<wgpu::Texture>>,
+    buffers: Vec<Arc<wgpu::Buffer>>,
+    // 生成が終わったときに結果キャッシュに登録するステップの結果
+    results: Vec<(ContentKey, ProcessingState)>,
+}
+
+/// キャッシュ以外から参照されていないリソースは貸し出し可能
+fn is_idle<T>(resource: &Arc<T>) -> bool {
+    Arc::strong_count(resource) == 1
+}
+
+// 同じキーでキャッシュに保持するテクスチャの最大数。同時に貸し出されている分はこれを超えても保持する
+const MAX_TEXTURES_PER_KEY: usize = 8;
+
+/// resourcesから貸し出されていないものを最大count個取り除き、取り除いた数を返します。
+fn drop_idle<T>(resources: &mut Vec<Arc<T>>, count: usize) -> usize {
+    let mut dropped = 0;
+    resources.retain(|resource| {
+        if dropped < count && is_idle(resource) {
+            dropped += 1;
+            false
+        } else {
+            true
+        }
+    });
+    dropped
+}
+
+/// キャッシュのリソースの総数がmaxを超えている間、LRU順序の古いキーから貸し出されていないものを破棄します。
+/// 貸し出し中のリソースは返却後に再利用できるよう残し、リソースがなくなったキーは順序からも取り除きます。
+fn evict_idle<K: Eq + Hash + Clone, T>(
+    cache: &mut HashMap<K, Vec<Arc<T>>>,
+    order: &mut VecDeque<K>,
+    max: usize,
+) {
+    let mut total: usize = cache.values().map(Vec::len).sum();
+    let mut i = order.len();
+    while total > max && i > 0 {
+        i -= 1;
+        let key = order[i].clone();
+        let Some(resources) = cache.get_mut(&key) else {
+            order.remove(i);
+            continue;
+        };
+        total -= drop_idle(resources, total - max);
+        if resources.is_empty() {
+            cache.remove(&key);
+            order.remove(i);
+        }
+    }
+}
+
 // キャッシュされる値
 #[derive(Clone)]
 pub(crate) struct CachedPipeline {
@@ -56,17 +175,22 @@ pub(crate) struct CachedPipeline {
 
 /// パイプラインの各ステップの単一の出力を表すenum。
 /// データがGPU上にあるか、CPU上にあるかを示します。
+/// どちらも保持している値の色空間とアルファの扱いを持ち、必要に応じて次のステップの前に変換されます。
 #[derive(Clone, Debug)]
 pub enum StepOutput {
     Gpu {
         texture: Arc<wgpu::Texture>,
         width: u32,
         height: u32,
+        color_space: ColorSpace,
+        alpha_mode: AlphaMode,
     },
     Cpu {
         data: Arc<Vec<f32>>,
         width: u32,
         height: u32,
+        color_space: ColorSpace,
+        alpha_mode: AlphaMode,
     },
 }
 
@@ -75,15 +199,46 @@ pub enum StepOutput {
 /// 並列処理後は複数の要素を持つことがあります。
 pub(crate) type ProcessingState = Vec<StepOutput>;
 
+// generate_batchで読み戻しを待っているフレーム
+struct BatchFrame {
+    index: usize,
+    // 読み戻しが終わるまでリソースの貸し出しを保持する
+    generator: ImageGenerator,
+    output: BatchOutput,
+}
+
+enum BatchOutput {
+    // CPUで変換済み
+    Ready(Vec<u8>),
+    // 記録済みでサブミット前
+    Recorded(PendingDownload),
+    // サブミット済みでマッピングを待っている
+    Mapping {
+        readback: PendingDownload,
+        receiver: MapReceiver,
+        submission: wgpu::SubmissionIndex,
+    },
+}
+
 /// wgpuのインスタンス、アダプタ、デバイス、キューを管理し、
 /// 画像生成パイプラインを実行するクラス。
 #[derive(Clone)]
 pub struct ImageGenerator {
     pub(crate) device: Arc<wgpu::Device>,
     pub(crate) queue: Arc<wgpu::Queue>,
+    // 使用中のアダプタの情報
+    pub(crate) adapter_info: Arc<AdapterDescription>,
     // 後処理用のパイプラインと関連リソース
     pub(crate) post_process_pipeline: Arc<wgpu::ComputePipeline>,
     pub(crate) post_process_bind_group_layout: Arc<wgpu::BindGroupLayout>,
+    pub(crate) post_process_workgroup_size: [u32; 3],
+    // アダプタがストレージテクスチャとして書き込める出力フォーマット
+    output_formats: Arc<Vec<wgpu::TextureFormat>>,
+    // WGSLのステップが入出力に使う色空間
+    pub(crate) working_space: ColorSpace,
+    // 色空間の変換パイプライン (出力フォーマットごと)
+    pub(crate) color_convert_pipelines:
+        Arc<Mutex<HashMap<wgpu::TextureFormat, ColorConvertPipeline>>>,
 
     // --- パイプラインキャッシュシステム用のフィールド ---
     // 本体。キーとパイプラインオブジェクトを格納
@@ -92,56 +247,116 @@ pub struct ImageGenerator {
     cache_order: Arc<Mutex<VecDeque<PipelineCacheKey>>>,
     // キャッシュの最大サイズ
     max_cache_size: usize,
+    // ディスクに永続化されるキャッシュ (cache_dirが指定された場合のみ)
+    disk_cache: Option<Arc<PipelineDiskCache>>,
 
     // --- テクスチャキャッシュシステム用のフィールド ---
-    // テクスチャキャッシュ本体
-    texture_cache: Arc<Mutex<HashMap<TextureCacheKey, Arc<wgpu::Texture>>>>,
+    // テクスチャキャッシュ本体。同じキーのテクスチャを複数保持し、使用中でないものを貸し出す
+    texture_cache: Arc<Mutex<HashMap<TextureCacheKey, Vec<Arc<wgpu::Texture>>>>>,
     // テクスチャキャッシュのLRU順序
     texture_cache_order: Arc<Mutex<VecDeque<TextureCacheKey>>>,
-    // テクスチャキャッシュの最大サイズ
+    // テクスチャキャッシュに保持するテクスチャの最大数
     max_texture_cache_size: usize,
 
     // --- バッファキャッシュシステム用のフィールド ---
-    // バッファキャッシュ本体
-    buffer_cache: Arc<Mutex<HashMap<BufferCacheKey, Arc<wgpu::Buffer>>>>,
+    // バッファキャッシュ本体。同じキーのバッファを複数保持し、使用中でないものを貸し出す
+    buffer_cache: Arc<Mutex<HashMap<BufferCacheKey, Vec<Arc<wgpu::Buffer>>>>>,
     // バッファキャッシュのLRU順序
     buffer_cache_order: Arc<Mutex<VecDeque<BufferCacheKey>>>,
     // バッファキャッシュの最大サイズ
     max_buffer_cache_size: usize,
+
+    // 内容のキーで引けるステップの結果のキャッシュ
+    result_cache: Arc<Mutex<ResultCache>>,
+
+    // wgpuのエラースコープを使用中のステップ。スコープはデバイス全体で共有されるため、同時に1つだけ使う
+    error_scope_lock: Arc<Mutex<()>>,
+    // サブミットの完了を待つためのポーリングスレッド
+    pub(crate) poller: Arc<DevicePoller>,
+
+    // 生成ごとに処理時間を計測するかどうか
+    profiling: Arc<AtomicBool>,
+    // 実行中の生成の計測結果。プロファイリングが有効な生成ごとに作られ、それ以外では`None`
+    profiler: Option<Arc<Mutex<Profiler>>>,
+
+    // 実行中の生成に貸し出しているリソース。生成ごとに作られ、ルートのインスタンスでは`None`
+    lease: Option<Arc<Mutex<ResourceLease>>>,
+    // 実行中のパイプラインの一時的なテクスチャ。パイプラインごとに作られ、パイプラインの外では`None`
+    transients: Option<Arc<Mutex<TransientResources>>>,
 }
 
 impl ImageGenerator {
     /// 新しいImageGeneratorインスタンスを非同期で作成します。
-    pub async fn new() -> Result<Self> {
-        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions::default())
-            .await
-            .context("Failed to find an appropriate adapter")?;
+    pub async fn new(options: &ImageGeneratorOptions) -> Result<Self> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
+            backends: options.backends,
+            ..Default::default()
+        });
+        let adapter = request_adapter(&instance, options).await?;
+        let adapter_info = AdapterDescription::from(&adapter);
+        info!(
+            adapter = %adapter_info.name,
+            backend = ?adapter_info.backend,
+            device_type = ?adapter_info.device_type,
+            "Selected GPU adapter"
+        );
+
+        // 上限値はアダプタが対応する範囲に収める
+        let adapter_limits = adapter.limits();
         let (device, queue) = adapter
             .request_device(&wgpu::DeviceDescriptor {
                 label: Some("ImageGenerator Device"),
-                required_features: Features::TEXTURE_BINDING_ARRAY
-                    | Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING
-                    | Features::ADDRESS_MODE_CLAMP_TO_BORDER
-                    | Features::FLOAT32_FILTERABLE,
+                required_features: OPTIONAL_FEATURES.intersection(adapter_info.features),
                 required_limits: wgpu::Limits {
-                    max_binding_array_elements_per_shader_stage: 1000, // 必要に応じて調整
-                    max_storage_buffer_binding_size: 2147483647,       // 2GB
-                    ..wgpu::Limits::defaults()
+                    max_binding_array_elements_per_shader_stage: adapter_limits
+                        .max_binding_array_elements_per_shader_stage
+                        .min(1000), // 必要に応じて調整
+                    max_storage_buffer_binding_size: adapter_limits
+                        .max_storage_buffer_binding_size
+                        .min(2147483647), // 2GB
+                    ..adapter_limits
                 },
                 experimental_features: wgpu::ExperimentalFeatures::disabled(),
                 memory_hints: wgpu::MemoryHints::Performance,
                 trace: wgpu::Trace::Off,
             })
             .await
-            .context("Failed to create device")?;
+            .map_err(|e| GpuUtilError::device(None, format!("Failed to create device: {}", e)))?;
 
+        // エラースコープの外で発生したエラーは、パニックせずにログに出力する
+        device.on_uncaptured_error(Arc::new(|error| {
+            error!(%error, "Uncaptured wgpu error");
+        }));
         let device = Arc::new(device);
         let queue = Arc::new(queue);
+        let poller = Arc::new(DevicePoller::new(device.clone())?);
+
+        let output_formats = OUTPUT_FORMATS
+            .into_iter()
+            .filter(|&format| {
+                adapter
+                    .get_texture_format_features(format)
+                    .allowed_usages
+                    .contains(wgpu::TextureUsages::STORAGE_BINDING)
+            })
+            .collect();
+
+        let disk_cache = options
+            .cache_dir
+            .as_ref()
+            .map(|dir| PipelineDiskCache::open(&device, &adapter.get_info(), dir))
+            .transpose()?
+            .map(Arc::new);
 
         // --- 後処理パイプラインの事前コンパイル ---
-        let post_process_shader = device.create_shader_module(POST_PROCESS_WGSL);
+        let post_process_module = naga::front::wgsl::parse_str(POST_PROCESS_WGSL)
+            .context("Failed to parse the post process shader")?;
+        let post_process_workgroup_size = workgroup_size(&post_process_module, "main")
+            .context("Post process shader has no fixed workgroup size")?;
+        let post_process_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Post Process Shader"),
+            source: wgpu::ShaderSource::Wgsl(POST_PROCESS_WGSL.into()),
+        });
 
         let post_process_bind_group_layout = Arc::new(device.create_bind_group_layout(
             &wgpu::BindGroupLayoutDescriptor {
@@ -158,7 +373,7 @@ impl ImageGenerator {
                         },
                         count: None,
                     },
-                    // @group(0) @binding(1) var<storage, read_write> output_pixels: array<u32>;
+                    // @group(0) @binding(1) var<storage, read_write> output_words: array<u32>;
                     wgpu::BindGroupLayoutEntry {
                         binding: 1,
                         visibility: wgpu::ShaderStages::COMPUTE,
@@ -169,6 +384,17 @@ impl ImageGenerator {
                         },
                         count: None,
                     },
+                    // @group(0) @binding(2) var<uniform> params: Params;
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
             },
         ));
@@ -186,33 +412,100 @@ impl ImageGenerator {
                 module: &post_process_shader,
                 entry_point: Some("main"),
                 compilation_options: wgpu::PipelineCompilationOptions::default(),
-                cache: None, // TODO: キャッシュを実装
+                cache: disk_cache.as_ref().and_then(|c| c.wgpu_cache()),
             },
         ));
 
         Ok(Self {
             device,
             queue,
+            adapter_info: Arc::new(adapter_info),
             post_process_pipeline,
             post_process_bind_group_layout,
+            post_process_workgroup_size,
+            output_formats: Arc::new(output_formats),
+            working_space: options.working_space,
+            color_convert_pipelines: Arc::new(Mutex::new(HashMap::new())),
 
             // キャッシュフィールドの初期化
             pipeline_cache: Arc::new(Mutex::new(HashMap::new())),
             cache_order: Arc::new(Mutex::new(VecDeque::new())),
             max_cache_size: 100, // デフォルトのキャッシュサイズ
+            disk_cache,
 
             // テクスチャキャッシュの初期化
             texture_cache: Arc::new(Mutex::new(HashMap::new())),
             texture_cache_order: Arc::new(Mutex::new(VecDeque::new())),
-            max_texture_cache_size: 100, // デフォルトのテクスチャキャッシュサイズ
+            max_texture_cache_size: 100, // デフォルトでキャッシュに保持するテクスチャの数
 
             // バッファキャッシュの初期化
             buffer_cache: Arc::new(Mutex::new(HashMap::new())),
             buffer_cache_order: Arc::new(Mutex::new(VecDeque::new())),
             max_buffer_cache_size: 100, // デフォルトのバッファキャッシュサイズ
+
+            result_cache: Arc::new(Mutex::new(ResultCache::default())),
+
+            error_scope_lock: Arc::new(Mutex::new(())),
+            poller,
+
+            profiling: Arc::new(AtomicBool::new(false)),
+            profiler: None,
+
+            lease: None,
+            transients: None,
         })
     }
 
+    /// 使用中のアダプタの情報を取得
+    pub fn adapter_info(&self) -> &AdapterDescription {
+        &self.adapter_info
+    }
+
+    /// WGSLのステップが入出力に使う作業用の色空間
+    pub fn working_space(&self) -> ColorSpace {
+        self.working_space
+    }
+
+    /// 以前の実行で同じシェーダーに対して生成されたパイプラインを事前に生成します。
+    /// ディスクキャッシュが無効な場合や、記録がない場合は何もしません。
+    pub fn prewarm(&self, wgsl: &CompiledWgsl) -> Result<()> {
+        let Some(disk_cache) = &self.disk_cache else {
+            return Ok(());
+        };
+        let result = {
+            // パイプラインの作成はエラースコープを使うため、実行中のステップと重ならないようにする
+            let _guard = self.error_scope_lock.lock().unwrap();
+            disk_cache
+                .known_pipelines(wgsl.source_hash)
+                .into_iter()
+                // 同じソースでもIDが異なればラベルが変わるため、同じIDのものだけを生成する
+                .filter(|key| key.id == wgsl.id)
+                .try_for_each(|key| self.get_or_create_pipeline(&key, wgsl).map(|_| ()))
+        };
+        self.flush_disk_cache();
+        result
+    }
+
+    /// 新しく生成したパイプラインの記録をディスクに書き出します。
+    /// ステップの実行中は呼び出さず、生成の終わりなどにロックを持たない状態で呼び出します。
+    pub(crate) fn flush_disk_cache(&self) {
+        if let Some(disk_cache) = &self.disk_cache {
+            if let Err(e) = disk_cache.flush() {
+                warn!(error = ?e, "Failed to persist pipeline cache");
+            }
+        }
+    }
+
+    /// binding arrayによる入力規約 (`InputLayout::BindingArray`) が使えるかどうか
+    pub fn supports_binding_array(&self) -> bool {
+        self.device.features().contains(BINDING_ARRAY_FEATURES)
+    }
+
+    /// シェーダーの出力テクスチャとして使えるフォーマットかどうか
+    pub fn supports_output_format(&self, format: wgpu::TextureFormat) -> bool {
+        self.output_formats.contains(&format)
+    }
+
     // --- キャッシュ管理用のメソッド ---
 
     /// 現在のキャッシュの最大サイズを取得
@@ -236,23 +529,18 @@ impl ImageGenerator {
 
     // --- テクスチャキャッシュ管理用のメソッド ---
 
-    /// 現在のテクスチャキャッシュの最大サイズを取得
+    /// 現在のテクスチャキャッシュに保持するテクスチャの最大数を取得
     pub fn max_texture_cache_size(&self) -> usize {
         self.max_texture_cache_size
     }
 
-    /// テクスチャキャッシュの最大サイズを設定
-    /// 新しいサイズが現在のキャッシュ数より小さい場合、古いエントリが削除されます。
+    /// テクスチャキャッシュに保持するテクスチャの最大数を設定
+    /// 新しいサイズが現在のテクスチャ数より小さい場合、古いキーの使用中でないテクスチャから削除されます。
     pub fn set_max_texture_cache_size(&mut self, size: usize) {
         self.max_texture_cache_size = size;
-        let mut cache_order = self.texture_cache_order.lock().unwrap();
-
-        // キャッシュが新しい上限を超えている場合は、古いものから削除
-        while cache_order.len() > self.max_texture_cache_size {
-            if let Some(oldest_key) = cache_order.pop_back() {
-                self.texture_cache.lock().unwrap().remove(&oldest_key);
-            }
-        }
+        let mut cache = self.texture_cache.lock().unwrap();
+        let mut order = self.texture_cache_order.lock().unwrap();
+        evict_idle(&mut cache, &mut order, self.max_texture_cache_size);
     }
 
     // --- バッファキャッシュ管理用のメソッド ---
@@ -276,68 +564,99 @@ impl ImageGenerator {
         }
     }
 
+    /// 1回の生成のためのインスタンスを作成します。
+    /// キャッシュは共有したまま、生成中に貸し出したリソースをこのインスタンスが破棄されるまで保持します。
+    pub(crate) fn with_lease(&self) -> Self {
+        Self {
+            lease: Some(Arc::new(Mutex::new(ResourceLease::default()))),
+            ..self.clone()
+        }
+    }
+
+    /// 1つのパイプラインを実行するためのインスタンスを作成します。
+    /// このインスタンスで確保したテクスチャは、寿命が終わると同じパイプラインの後続のステップで再利用されます。
+    fn with_transients(&self) -> Self {
+        Self {
+            transients: Some(Arc::new(Mutex::new(TransientResources::default()))),
+            ..self.clone()
+        }
+    }
+
     /// テクスチャを取得または作成するためのヘルパーメソッド
+    /// 取得したテクスチャは生成が終わるまで貸し出され、他のステップや同時に実行中の生成とは共有されません。
+    /// テクスチャを作成する場合があるため、`with_error_scope`の中で呼び出してください。
     pub(crate) fn get_or_create_texture(
         &self,
-        step_index: usize,
-        width: u32,
-        height: u32,
+        size: wgpu::Extent3d,
         format: wgpu::TextureFormat,
         usage: wgpu::TextureUsages,
         label: Option<&str>,
     ) -> Arc<wgpu::Texture> {
         let key = TextureCacheKey {
-            step: step_index,
-            width,
-            height,
+            width: size.width,
+            height: size.height,
+            layers: size.depth_or_array_layers,
             format,
             usage,
         };
 
+        // --- 0. 同じパイプラインで寿命が終わったテクスチャがあれば再利用する ---
+        if let Some(transients) = &self.transients {
+            if let Some(texture) = transients.lock().unwrap().reuse(&key) {
+                return texture;
+            }
+        }
+
         // --- 1. キャッシュ検索とLRU更新 ---
         let mut cache = self.texture_cache.lock().unwrap();
         let mut order = self.texture_cache_order.lock().unwrap();
 
-        if let Some(cached_texture) = cache.get(&key) {
-            // ヒットした場合、LRU順序を更新
-            if let Some(pos) = order.iter().position(|k| k == &key) {
-                order.remove(pos);
-            }
-            order.push_front(key.clone());
-            return cached_texture.clone();
+        if let Some(pos) = order.iter().position(|k| k == &key) {
+            // 同じキーのエントリがある場合、LRU順序を更新
+            order.remove(pos);
         }
-
-        // --- 2. キャッシュミス: 新しくテクスチャを作成 ---
-        let texture = Arc::new(self.device.create_texture(&wgpu::TextureDescriptor {
-            label,
-            size: wgpu::Extent3d {
-                width,
-                height,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format,
-            usage,
-            view_formats: &[],
-        }));
-
-        // --- 3. 新しいテクスチャをキャッシュに保存 & LRU更新 ---
-        cache.insert(key.clone(), texture.clone());
         order.push_front(key.clone());
 
-        // --- 4. キャッシュサイズを超えていたら古いものを削除 ---
-        if order.len() > self.max_texture_cache_size {
-            if let Some(oldest_key) = order.pop_back() {
-                cache.remove(&oldest_key);
+        let textures = cache.entry(key.clone()).or_default();
+        let texture = match textures.iter().find(|t| is_idle(t)) {
+            Some(texture) => texture.clone(),
+            None => {
+                // --- 2. 貸し出せるものがない: 新しくテクスチャを作成してキャッシュに保存 ---
+                let texture = Arc::new(self.device.create_texture(&wgpu::TextureDescriptor {
+                    label,
+                    size,
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format,
+                    usage,
+                    view_formats: &[],
+                }));
+                textures.push(texture.clone());
+                texture
             }
+        };
+        // --- 3. 生成が終わるまで貸し出す ---
+        if let Some(lease) = &self.lease {
+            lease.lock().unwrap().textures.push(texture.clone());
+        }
+
+        // --- 4. 同じキーのテクスチャや全体の数が上限を超えていたら、使用中でないものを削除 ---
+        if let Some(textures) = cache.get_mut(&key) {
+            let excess = textures.len().saturating_sub(MAX_TEXTURES_PER_KEY);
+            drop_idle(textures, excess);
+        }
+        evict_idle(&mut cache, &mut order, self.max_texture_cache_size);
+        if let Some(transients) = &self.transients {
+            transients.lock().unwrap().track(key, texture.clone());
         }
 
         texture
     }
 
     /// バッファを取得または作成するためのヘルパーメソッド
+    /// 取得したバッファは生成が終わるまで貸し出され、同時に実行中の生成とは共有されません。
+    /// バッファを作成する場合があるため、`with_error_scope`の中で呼び出してください。
     pub(crate) fn get_or_create_buffer(
         &self,
         size: u64,
@@ -350,27 +669,32 @@ impl ImageGenerator {
         let mut cache = self.buffer_cache.lock().unwrap();
         let mut order = self.buffer_cache_order.lock().unwrap();
 
-        if let Some(cached_buffer) = cache.get(&key) {
-            // ヒットした場合、LRU順序を更新
-            if let Some(pos) = order.iter().position(|k| k == &key) {
-                order.remove(pos);
-            }
-            order.push_front(key.clone());
-            return cached_buffer.clone();
+        if let Some(pos) = order.iter().position(|k| k == &key) {
+            // 同じキーのエントリがある場合、LRU順序を更新
+            order.remove(pos);
         }
-
-        // --- 2. キャッシュミス: 新しくバッファを作成 ---
-        let buffer = Arc::new(self.device.create_buffer(&wgpu::BufferDescriptor {
-            label,
-            size,
-            usage,
-            mapped_at_creation: false,
-        }));
-
-        // --- 3. 新しいバッファをキャッシュに保存 & LRU更新 ---
-        cache.insert(key.clone(), buffer.clone());
         order.push_front(key.clone());
 
+        let buffers = cache.entry(key).or_default();
+        let buffer = match buffers.iter().find(|b| is_idle(b)) {
+            Some(buffer) => buffer.clone(),
+            None => {
+                // --- 2. 貸し出せるものがない: 新しくバッファを作成してキャッシュに保存 ---
+                let buffer = Arc::new(self.device.create_buffer(&wgpu::BufferDescriptor {
+                    label,
+                    size,
+                    usage,
+                    mapped_at_creation: false,
+                }));
+                buffers.push(buffer.clone());
+                buffer
+            }
+        };
+        // --- 3. 生成が終わるまで貸し出す ---
+        if let Some(lease) = &self.lease {
+            lease.lock().unwrap().buffers.push(buffer.clone());
+        }
+
         // --- 4. キャッシュサイズを超えていたら古いものを削除 ---
         if order.len() > self.max_buffer_cache_size {
             if let Some(oldest_key) = order.pop_back() {
@@ -382,78 +706,327 @@ impl ImageGenerator {
     }
 
     /// 指定されたステップリストを、与えられた初期状態から実行する内部関数。
-    /// 最終的な状態と、生成されたコマンドエンコーダを返す。
+    /// 最終的な状態と、記録したコマンドバッファを返す。
     pub(crate) async fn execute_pipeline(
         &self,
         steps: &[PipelineStep],
         initial_state: ProcessingState,
-    ) -> Result<(ProcessingState, Vec<wgpu::CommandEncoder>)> {
+    ) -> Result<(ProcessingState, Vec<wgpu::CommandBuffer>)> {
+        // 親のパイプラインがない場合、最後のステップの出力がそのまま後処理に渡される
+        let root = self.transients.is_none();
+        // このパイプラインで確保したテクスチャは、寿命が終わると後続のステップで再利用する
+        let chain = self.with_transients();
         let mut state = initial_state;
-        let mut all_encoders = Vec::new();
+        let mut all_commands = Vec::new();
 
         for (i, step) in steps.iter().enumerate() {
-            // 各ハンドラは `&self` を受け取るように変更する必要がある
+            let (new_state, mut commands) = chain
+                .execute_step(
+                    step,
+                    &mut state,
+                    i,
+                    root && i + 1 == steps.len(),
+                    &mut all_commands,
+                )
+                .instrument(trace_span!("step", index = i))
+                .await?;
+            state = new_state;
+            all_commands.append(&mut commands);
+            chain.end_transient_step(&state);
+        }
 
-            let (new_state, mut encoder_opt) = match step {
-                PipelineStep::Wgsl {
-                    wgsl,
-                    params,
-                    output_height,
-                    output_width,
-                } => {
-                    handle_wgsl_step(self, &state, wgsl, params, i, *output_width, *output_height)?
-                }
-                PipelineStep::Parallel { pipelines } => {
-                    // ここが新しいロジック
-                    handle_parallel_step(self, &mut state, pipelines, i, &mut all_encoders).await?
-                }
-                PipelineStep::CpuFunc {
+        // サブパイプラインの場合は、残りのテクスチャを親のパイプラインに引き渡す
+        if let (Some(parent), Some(transients)) = (&self.transients, chain.transients) {
+            let transients = std::mem::take(&mut *transients.lock().unwrap());
+            parent.lock().unwrap().absorb(transients);
+        }
+
+        Ok((state, all_commands))
+    }
+
+    /// 1つのステップを、stateを入力として実行する内部関数。
+    /// final_stepは出力がそのまま後処理に渡されるステップかどうかを表す。
+    /// all_commandsはまだサブミットしていないコマンドバッファで、CPUでの処理の前にサブミットされる。
+    pub(crate) async fn execute_step(
+        &self,
+        step: &PipelineStep,
+        state: &mut ProcessingState,
+        step_index: usize,
+        final_step: bool,
+        all_commands: &mut Vec<wgpu::CommandBuffer>,
+    ) -> Result<(ProcessingState, Vec<wgpu::CommandBuffer>)> {
+        match step {
+            PipelineStep::Wgsl {
+                wgsl,
+                params,
+                options,
+                output_height,
+                output_width,
+            } => handle_wgsl_step(
+                self,
+                state,
+                wgsl,
+                params.as_deref(),
+                options,
+                step_index,
+                final_step,
+                *output_width,
+                *output_height,
+            ),
+            PipelineStep::Parallel { pipelines } => {
+                handle_parallel_step(self, state, pipelines, step_index, all_commands).await
+            }
+            PipelineStep::CpuFunc {
+                func,
+                params,
+                output_height,
+                output_width,
+            } => {
+                handle_cpu_func_step(
+                    self,
+                    state,
                     func,
                     params,
-                    output_height,
-                    output_width,
-                } => {
-                    handle_cpu_func_step(
-                        self,
-                        &mut state,
-                        func,
-                        params,
-                        *output_width,
-                        *output_height,
-                        &mut all_encoders,
-                    )
-                    .await?
-                }
-            };
-            state = new_state;
-            all_encoders.append(&mut encoder_opt);
+                    step_index,
+                    *output_width,
+                    *output_height,
+                    all_commands,
+                )
+                .await
+            }
+            PipelineStep::Graph { graph } => {
+                // グラフのノードからこの関数を再帰的に呼び出すため、Futureをヒープに置く
+                Box::pin(handle_graph_step(
+                    self,
+                    state,
+                    graph,
+                    step_index,
+                    all_commands,
+                ))
+                .await
+            }
+            PipelineStep::Cached { key, pipeline } => {
+                // サブパイプラインからこの関数を再帰的に呼び出すため、Futureをヒープに置く
+                Box::pin(handle_cached_step(
+                    self,
+                    state,
+                    key,
+                    pipeline,
+                    step_index,
+                    all_commands,
+                ))
+                .await
+            }
+            PipelineStep::Prepared { step } => {
+                let span = self.profile_span(
+                    StepKind::Wgsl,
+                    &step.wgsl.id,
+                    Some(step_index),
+                    step.output_size(),
+                );
+                let result = self.with_error_scope(Some(step_index), Some(&step.wgsl.id), || {
+                    step.record(self, state, &span)
+                });
+                span.finish(None);
+                result
+            }
         }
+    }
 
-        Ok((state, all_encoders))
+    /// ステップの記録が終わったときに呼び出し、liveに含まれないこのパイプラインの一時的なテクスチャを
+    /// 後続のステップで再利用できるようにします。
+    pub(crate) fn end_transient_step(&self, live: &ProcessingState) {
+        if let Some(transients) = &self.transients {
+            transients.lock().unwrap().end_step(live);
+        }
     }
 
-    /// ImageGenerateBuilderで構築されたパイプラインを実行し、画像を生成します。
+    /// ImageGenerateBuilderで構築されたパイプラインを実行し、sRGBのRGBA8の画像を生成します。
     pub async fn generate(&self, builder: ImageGenerateBuilder) -> Result<Vec<u8>> {
-        let (final_state_vec, encoders) = self.execute_pipeline(&builder.steps, Vec::new()).await?;
+        self.generate_with_output(builder, &OutputOptions::default())
+            .await
+    }
 
-        self.queue.submit(encoders.into_iter().map(|e| e.finish()));
+    /// ImageGenerateBuilderで構築されたパイプラインを実行し、指定したフォーマットと色空間の画像を生成します。
+    pub async fn generate_with_output(
+        &self,
+        builder: ImageGenerateBuilder,
+        output: &OutputOptions,
+    ) -> Result<Vec<u8>> {
+        let (data, _) = self.generate_with_report(builder, output).await?;
+        Ok(data)
+    }
+
+    /// `generate_with_output`と同じく画像を生成し、プロファイリングが有効な場合は各ステップの処理時間を計測したレポートも返します。
+    #[instrument(level = "debug", skip_all, fields(steps = builder.steps.len()))]
+    pub async fn generate_with_report(
+        &self,
+        builder: ImageGenerateBuilder,
+        output: &OutputOptions,
+    ) -> Result<(Vec<u8>, Option<ExecutionReport>)> {
+        // 使用したテクスチャとバッファは、読み出しが終わってgeneratorが破棄されるまで他の生成に貸し出さない
+        let generator = self.with_lease().with_profiler();
+        let (final_state_vec, commands) = generator
+            .execute_pipeline(&builder.steps, Vec::new())
+            .await?;
 
         // final_state_vecは単一の要素を持つはず
         if final_state_vec.len() != 1 {
-            bail!(
-                "Final processing state should have exactly one element, but has {}",
-                final_state_vec.len()
-            );
+            bail!(GpuUtilError::binding(
+                None,
+                format!(
+                    "Final processing state should have exactly one element, but has {}",
+                    final_state_vec.len()
+                ),
+            ));
+        }
+
+        let data = handle_final_process(&generator, final_state_vec, output, commands).await?;
+        // 読み出しが終わり、この生成のコマンドはすべて完了している
+        generator.publish_results();
+        generator.flush_disk_cache();
+        let report = generator.finish_profile().await?;
+        Ok((data, report))
+    }
+
+    /// パイプラインを検証し、WGSLステップの出力テクスチャ、パイプライン、バインドグループ、バッファを作成した実行計画を返します。
+    /// 同じパイプラインをパラメータだけ変えて繰り返し実行する場合、`generate`より1回あたりの処理が少なくなります。
+    pub fn prepare(&self, builder: ImageGenerateBuilder) -> Result<ExecutablePlan> {
+        ExecutablePlan::new(self, &builder)
+    }
+
+    /// 複数のフレームのパイプラインを記録してまとめてサブミットし、読み戻しが完了したフレームから順に
+    /// フレームの番号と出力のバイト列をon_frameに渡します。
+    /// 読み戻し用のバッファは`BATCH_READBACK_RING_SIZE`個を使い回すため、GPUが後続のフレームを処理している間に
+    /// 先に完了したフレームを受け取れます。on_frameがエラーを返した場合は残りのフレームを破棄して中断します。
+    #[instrument(level = "debug", skip_all, fields(frames = builders.len()))]
+    pub async fn generate_batch<F>(
+        &self,
+        builders: Vec<ImageGenerateBuilder>,
+        output: &OutputOptions,
+        mut on_frame: F,
+    ) -> Result<()>
+    where
+        F: FnMut(usize, Vec<u8>) -> Result<()>,
+    {
+        let mut in_flight = VecDeque::with_capacity(BATCH_READBACK_RING_SIZE);
+        let result = self
+            .run_batch(builders, output, &mut in_flight, &mut on_frame)
+            .await;
+        self.flush_disk_cache();
+
+        if let Err(e) = &result {
+            warn!(error = %e, in_flight = in_flight.len(), "Batch generation failed; discarding frames");
+            // マッピング中のバッファを残すと次の生成で使えないため、完了を待ってから解除する
+            self.poller.wait(None).await?;
+            let discarded = self.with_error_scope(None, None, || {
+                for frame in in_flight.drain(..) {
+                    if let BatchOutput::Mapping { readback, .. } = frame.output {
+                        readback.discard();
+                    }
+                }
+                Ok(())
+            });
+            if let Err(e) = discarded {
+                warn!(error = %e, "Failed to discard the batch readbacks");
+            }
         }
+        result
+    }
+
+    async fn run_batch(
+        &self,
+        builders: Vec<ImageGenerateBuilder>,
+        output: &OutputOptions,
+        in_flight: &mut VecDeque<BatchFrame>,
+        on_frame: &mut impl FnMut(usize, Vec<u8>) -> Result<()>,
+    ) -> Result<()> {
+        let mut builders = builders.into_iter().enumerate();
+
+        loop {
+            // --- 1. リングに空きがある分だけフレームを記録する ---
+            let mut command_buffers = Vec::new();
+            let mut recorded = Vec::new();
+            while in_flight.len() + recorded.len() < BATCH_READBACK_RING_SIZE {
+                let Some((index, builder)) = builders.next() else {
+                    break;
+                };
+                // 読み戻しが終わるまで、フレームごとにリソースを貸し出したままにする
+                let generator = self.with_lease();
+                let (final_state, commands) = generator
+                    .execute_pipeline(&builder.steps, Vec::new())
+                    .await?;
+                command_buffers.extend(commands);
+                let recorded_output = match record_final_process(&generator, final_state, output)? {
+                    RecordedOutput::Ready(data) => BatchOutput::Ready(data),
+                    RecordedOutput::Readback { commands, readback } => {
+                        command_buffers.push(commands);
+                        BatchOutput::Recorded(readback)
+                    }
+                };
+                recorded.push((index, generator, recorded_output));
+            }
+
+            // --- 2. 記録したフレームをまとめてサブミットし、マッピングを要求する ---
+            if !recorded.is_empty() {
+                let submission =
+                    self.with_error_scope(None, None, || Ok(self.queue.submit(command_buffers)))?;
+                // サブミットが拒否された場合にマッピングしたままのバッファが残らないよう、成功してからマッピングする
+                let frames =
+                    self.with_error_scope(None, Some(StepKind::Output.as_str()), || {
+                        Ok(recorded
+                            .into_iter()
+                            .map(|(index, generator, output)| {
+                                let output = match output {
+                                    BatchOutput::Recorded(readback) => BatchOutput::Mapping {
+                                        receiver: readback.map(),
+                                        readback,
+                                        submission: submission.clone(),
+                                    },
+                                    output => output,
+                                };
+                                BatchFrame {
+                                    index,
+                                    generator,
+                                    output,
+                                }
+                            })
+                            .collect::<Vec<_>>())
+                    })?;
+                in_flight.extend(frames);
+            }
 
-        handle_final_process(self, final_state_vec).await
+            // --- 3. 最も古いフレームの読み戻しを待って渡す ---
+            let Some(frame) = in_flight.pop_front() else {
+                return Ok(());
+            };
+            let data = match frame.output {
+                BatchOutput::Ready(data) => data,
+                BatchOutput::Mapping {
+                    readback,
+                    receiver,
+                    submission,
+                } => {
+                    // 後続のフレームの処理を待たないよう、このフレームのサブミットだけを待つ
+                    self.wait_for_downloads(submission, [receiver]).await?;
+                    self.with_error_scope(None, Some(StepKind::Output.as_str()), || {
+                        Ok(readback.read())
+                    })?
+                }
+                BatchOutput::Recorded(_) => unreachable!(),
+            };
+            // 読み戻したリソースを返却してから次のフレームに進む
+            frame.generator.publish_results();
+            drop(frame.generator);
+            on_frame(frame.index, data)?;
+        }
     }
 
     // --- パイプラインを取得または生成するためのヘルパーメソッドを追加 ---
     pub(crate) fn get_or_create_pipeline(
         &self,
         key: &PipelineCacheKey,
-        shader_module: &wgpu::ShaderModule,
+        wgsl: &CompiledWgsl,
     ) -> Result<CachedPipeline> {
         // --- 1. キャッシュ検索とLRU更新 ---
         let mut cache = self.pipeline_cache.lock().unwrap();
@@ -465,100 +1038,72 @@ impl ImageGenerator {
                 order.remove(pos);
             }
             order.push_front(key.clone());
+            trace!(id = %key.id, entry_point = %key.entry_point, "Pipeline cache hit");
             return Ok(cached.clone());
         }
 
         // --- 2. キャッシュミス: 新しくパイプラインを生成 ---
+        debug!(id = %key.id, entry_point = %key.entry_point, "Compiling pipeline");
+        let span = self.profile_span(StepKind::PipelineCompile, &key.id, None, None);
 
-        // --- バインドグループ0 (入力/出力テクスチャ) ---
-        let mut bgl_entries_group0 = Vec::new();
-
-        // Binding 0: 入力テクスチャの配列 (存在する場合)
-        if key.input_texture_count > 0 {
-            bgl_entries_group0.push(wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Texture {
-                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    view_dimension: wgpu::TextureViewDimension::D2,
-                    multisampled: false,
-                },
-                count: core::num::NonZeroU32::new(key.input_texture_count as u32),
-            });
-        }
+        // FLOAT32_FILTERABLEがない場合、Rgba32Floatはフィルタリングできない
+        let filterable = self
+            .device
+            .features()
+            .contains(Features::FLOAT32_FILTERABLE);
+
+        // --- バインドグループレイアウト (シェーダーのリフレクションから構築) ---
+        // 使われていないグループも空のレイアウトとして作成する
+        let reflection = wgsl.reflection(Some(&key.entry_point))?;
+        // 作成に失敗したパイプラインはキャッシュせず、次の実行で作り直す
+        let (pipeline, error) = capture_errors(&self.device, || {
+            let bind_group_layouts: Vec<_> = (0..reflection.group_count())
+                .map(|group| {
+                    let entries: Vec<_> = reflection
+                        .group(group)
+                        .map(|b| b.layout_entry(key.input_array_len, filterable))
+                        .collect();
+                    self.device
+                        .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                            label: Some(&format!("BGL Group {} for {}", group, key.id)),
+                            entries: &entries,
+                        })
+                })
+                .collect();
+
+            let pipeline_layout =
+                self.device
+                    .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        label: Some(&format!("PL for {}", key.id)),
+                        bind_group_layouts: &bind_group_layouts.iter().collect::<Vec<_>>(),
+                        push_constant_ranges: &[],
+                    });
 
-        // Binding 0 or 1: 出力テクスチャ (常に存在)
-        // binding番号は、入力テクスチャの有無で変わる
-        bgl_entries_group0.push(wgpu::BindGroupLayoutEntry {
-            binding: if key.input_texture_count > 0 { 1 } else { 0 },
-            visibility: wgpu::ShaderStages::COMPUTE,
-            ty: wgpu::BindingType::StorageTexture {
-                access: wgpu::StorageTextureAccess::WriteOnly,
-                format: wgpu::TextureFormat::Rgba32Float,
-                view_dimension: wgpu::TextureViewDimension::D2,
-            },
-            count: None,
+            Arc::new(
+                self.device
+                    .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                        label: Some(&format!("Pipeline for {}::{}", key.id, key.entry_point)),
+                        layout: Some(&pipeline_layout),
+                        module: &wgsl.module,
+                        entry_point: Some(&key.entry_point),
+                        compilation_options: wgpu::PipelineCompilationOptions::default(),
+                        cache: self.disk_cache.as_ref().and_then(|c| c.wgpu_cache()),
+                    }),
+            )
         });
-
-        // Binding 1 or 2: サンプラー (存在する場合)
-        if key.has_sampler {
-            bgl_entries_group0.push(wgpu::BindGroupLayoutEntry {
-                binding: if key.input_texture_count > 0 { 2 } else { 1 },
-                visibility: wgpu::ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                count: None,
-            });
+        if let Some(error) = error {
+            span.finish(None);
+            bail!(to_gpu_util_error(error, Some(&key.id), None));
         }
 
-        let bind_group_layout_0 =
-            self.device
-                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    label: Some(&format!("BGL Group 0 for {}", key.id)),
-                    entries: &bgl_entries_group0,
-                });
-
-        // --- バインドグループ1 (Storageパラメータ) ---
-        let mut bind_group_layouts = vec![bind_group_layout_0];
-        if key.has_storage {
-            let bind_group_layout_1 =
-                self.device
-                    .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                        label: Some(&format!("BGL Group 1 for {}", key.id)),
-                        entries: &[wgpu::BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Storage { read_only: true },
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        }],
-                    });
-            bind_group_layouts.push(bind_group_layout_1);
+        // 次回起動時に事前生成できるよう記録する。ディスクへの書き出しはロックを解放してから行う
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.record(key.source_hash, key);
         }
 
-        let pipeline_layout = self
-            .device
-            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some(&format!("PL for {}", key.id)),
-                bind_group_layouts: &bind_group_layouts.iter().collect::<Vec<_>>(),
-                push_constant_ranges: &[],
-            });
-
-        let pipeline = Arc::new(self.device.create_compute_pipeline(
-            &wgpu::ComputePipelineDescriptor {
-                label: Some(&format!("Pipeline for {}", key.id)),
-                layout: Some(&pipeline_layout),
-                module: shader_module,
-                entry_point: Some("main"),
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-                cache: None,
-            },
-        ));
-
         // CachedPipelineも複数のレイアウトを保持できるように更新が必要
         let new_item = CachedPipeline { pipeline };
+        span.finish(Some(CacheStatus::Miss));
 
         // --- 3. 新しいアイテムをキャッシュに保存 & LRU更新 ---
         cache.insert(key.clone(), new_item.clone());
@@ -567,6 +1112,7 @@ impl ImageGenerator {
         // --- 4. キャッシュサイズを超えていたら古いものを削除 ---
         if order.len() > self.max_cache_size {
             if let Some(oldest_key) = order.pop_back() {
+                trace!(id = %oldest_key.id, "Evicted pipeline from cache");
                 cache.remove(&oldest_key);
             }
         }
@@ -574,3 +1120,46 @@ impl ImageGenerator {
         Ok(new_item)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_idle_keeps_lent_resources() {
+        let lent = Arc::new(1);
+        let mut resources = vec![Arc::new(0), lent.clone(), Arc::new(2), Arc::new(3)];
+        assert_eq!(drop_idle(&mut resources, 2), 2);
+        assert_eq!(
+            resources.iter().map(|r| **r).collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+        // 貸し出し中のものしか残っていなければ取り除かない
+        assert_eq!(drop_idle(&mut resources, 2), 1);
+        assert_eq!(resources.len(), 1);
+        assert!(Arc::ptr_eq(&resources[0], &lent));
+    }
+
+    #[test]
+    fn evict_idle_bounds_the_total_from_the_oldest_key() {
+        let lent = Arc::new(0);
+        let mut cache = HashMap::from([
+            ("old", vec![lent.clone(), Arc::new(1)]),
+            ("mid", vec![Arc::new(2), Arc::new(3)]),
+            ("new", vec![Arc::new(4), Arc::new(5)]),
+        ]);
+        let mut order = VecDeque::from(["new", "mid", "old"]);
+
+        evict_idle(&mut cache, &mut order, 4);
+        // 古いキーの使用中でないものを捨て、使用中のものは残す
+        assert_eq!(cache["old"].len(), 1);
+        assert!(Arc::ptr_eq(&cache["old"][0], &lent));
+        assert_eq!(cache["mid"].len(), 1);
+        assert_eq!(order, VecDeque::from(["new", "mid", "old"]));
+
+        evict_idle(&mut cache, &mut order, 2);
+        assert!(!cache.contains_key("mid"));
+        assert_eq!(cache["new"].len(), 1);
+        assert_eq!(order, VecDeque::from(["new", "old"]));
+    }
+}
@@ -0,0 +1,39 @@
+use crate::{
+    image_generate_builder::ImageGenerateBuilder,
+    image_generator::{
+        parallel_process::handle_parallel_step, result_cache::content_key, ImageGenerator,
+        ProcessingState,
+    },
+};
+use anyhow::Result;
+
+pub async fn handle_cached_step(
+    generator: &ImageGenerator,
+    state: &mut ProcessingState,
+    key: &str,
+    pipeline: &ImageGenerateBuilder,
+    step_index: usize,
+    all_commands: &mut Vec<wgpu::CommandBuffer>,
+) -> Result<(ProcessingState, Vec<wgpu::CommandBuffer>)> {
+    // 入力の内容が分からない場合 (キャッシュされていないCPUのデータなど) は毎回実行する
+    let cache_key = generator
+        .state_content_key(state)
+        .map(|inputs| content_key((key, inputs)));
+
+    if let Some(outputs) = cache_key.and_then(|key| generator.lookup_result(key)) {
+        return Ok((outputs, Vec::new()));
+    }
+
+    let (outputs, commands) = handle_parallel_step(
+        generator,
+        state,
+        std::slice::from_ref(pipeline),
+        step_index,
+        all_commands,
+    )
+    .await?;
+    if let Some(cache_key) = cache_key {
+        generator.store_result(cache_key, &outputs);
+    }
+    Ok((outputs, commands))
+}
@@ -0,0 +1,225 @@
+// image_generator/color_process.rs
+
+use std::sync::Arc;
+
+use crate::{
+    color_space::ColorConversion,
+    image_generator::{profiler::PassTimestamps, ImageGenerator, COLOR_WGSL},
+    shader_reflection::workgroup_size,
+};
+use anyhow::{bail, Context, Result};
+use wgpu::util::DeviceExt;
+
+const COLOR_CONVERT_WGSL: &str = include_str!("../shaders/color_convert.wgsl");
+
+/// 出力フォーマットごとの色空間の変換パイプライン
+#[derive(Clone)]
+pub(crate) struct ColorConvertPipeline {
+    pipeline: Arc<wgpu::ComputePipeline>,
+    bind_group_layout: Arc<wgpu::BindGroupLayout>,
+    workgroup_size: [u32; 3],
+}
+
+/// 変換後のテクスチャのフォーマット。
+/// 線形の値を8bitで持つと暗部の階調が失われるため、8bitのテクスチャは16bit floatに変換する
+fn converted_format(format: wgpu::TextureFormat) -> wgpu::TextureFormat {
+    match format {
+        wgpu::TextureFormat::Rgba8Unorm => wgpu::TextureFormat::Rgba16Float,
+        format => format,
+    }
+}
+
+/// ストレージテクスチャとして宣言する際のWGSLでのフォーマット名
+fn wgsl_format_name(format: wgpu::TextureFormat) -> Option<&'static str> {
+    match format {
+        wgpu::TextureFormat::Rgba32Float => Some("rgba32float"),
+        wgpu::TextureFormat::Rgba16Float => Some("rgba16float"),
+        wgpu::TextureFormat::Rg32Float => Some("rg32float"),
+        wgpu::TextureFormat::R32Float => Some("r32float"),
+        _ => None,
+    }
+}
+
+fn get_or_create_pipeline(
+    generator: &ImageGenerator,
+    format: wgpu::TextureFormat,
+) -> Result<ColorConvertPipeline> {
+    let mut pipelines = generator.color_convert_pipelines.lock().unwrap();
+    if let Some(pipeline) = pipelines.get(&format) {
+        return Ok(pipeline.clone());
+    }
+
+    let Some(format_name) = wgsl_format_name(format) else {
+        bail!("Textures of format {:?} cannot be color converted", format);
+    };
+    let source = format!(
+        "{}{}",
+        COLOR_WGSL,
+        COLOR_CONVERT_WGSL.replace("OUTPUT_FORMAT", format_name)
+    );
+    let module = naga::front::wgsl::parse_str(&source)
+        .context("Failed to parse the color convert shader")?;
+    let workgroup_size = workgroup_size(&module, "main")
+        .context("Color convert shader has no fixed workgroup size")?;
+
+    let device = &generator.device;
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Color Convert Shader"),
+        source: wgpu::ShaderSource::Wgsl(source.into()),
+    });
+    let bind_group_layout = Arc::new(device.create_bind_group_layout(
+        &wgpu::BindGroupLayoutDescriptor {
+            label: Some("Color Convert Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::WriteOnly,
+                        format,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        },
+    ));
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Color Convert Pipeline Layout"),
+        bind_group_layouts: &[&bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    let pipeline = Arc::new(
+        device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(&format!("Color Convert Pipeline ({})", format_name)),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: Some("main"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            cache: None,
+        }),
+    );
+
+    let pipeline = ColorConvertPipeline {
+        pipeline,
+        bind_group_layout,
+        workgroup_size,
+    };
+    pipelines.insert(format, pipeline.clone());
+    Ok(pipeline)
+}
+
+/// 作成済みの色空間の変換パス。
+/// 同じ入力テクスチャに対しては`record`を繰り返し呼び出して再利用できます。
+pub(crate) struct PreparedConversion {
+    pipeline: Arc<wgpu::ComputePipeline>,
+    bind_group: wgpu::BindGroup,
+    output: Arc<wgpu::Texture>,
+    workgroups: [u32; 2],
+}
+
+impl PreparedConversion {
+    /// 変換後のテクスチャ。
+    pub(crate) fn output(&self) -> &Arc<wgpu::Texture> {
+        &self.output
+    }
+
+    /// 変換パスをencoderに記録します。timestampsを指定した場合はパスの開始と終了の時刻を書き込みます。
+    pub(crate) fn record(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        timestamps: Option<&PassTimestamps>,
+    ) {
+        let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: None,
+            timestamp_writes: timestamps.map(|t| t.writes()),
+        });
+        cpass.set_pipeline(&self.pipeline);
+        cpass.set_bind_group(0, &self.bind_group, &[]);
+        cpass.dispatch_workgroups(self.workgroups[0], self.workgroups[1], 1);
+    }
+}
+
+/// テクスチャの色空間とアルファの扱いを変換するための出力テクスチャとバインドグループを作成します。
+/// 恒等変換の場合は`None`を返します。
+pub(crate) fn prepare_conversion(
+    generator: &ImageGenerator,
+    texture: &Arc<wgpu::Texture>,
+    conversion: &ColorConversion,
+    step_index: usize,
+) -> Result<Option<PreparedConversion>> {
+    if conversion.is_identity() {
+        return Ok(None);
+    }
+
+    let format = converted_format(texture.format());
+    let pipeline = get_or_create_pipeline(generator, format)?;
+
+    let output = generator.get_or_create_texture(
+        texture.size(),
+        format,
+        wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::STORAGE_BINDING
+            | wgpu::TextureUsages::COPY_SRC,
+        Some(&format!("Step {} Color Converted Input", step_index)),
+    );
+
+    let conversion_buffer =
+        generator
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Color Conversion"),
+                contents: &conversion.to_uniform_bytes(),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+    let input_view = texture.create_view(&Default::default());
+    let output_view = output.create_view(&Default::default());
+    let bind_group = generator
+        .device
+        .create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Color Convert Bind Group"),
+            layout: &pipeline.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&input_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&output_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: conversion_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+    let [wx, wy, _] = pipeline.workgroup_size;
+    Ok(Some(PreparedConversion {
+        pipeline: pipeline.pipeline,
+        bind_group,
+        output,
+        workgroups: [texture.width().div_ceil(wx), texture.height().div_ceil(wy)],
+    }))
+}
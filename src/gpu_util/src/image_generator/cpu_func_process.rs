@@ -1,111 +1,91 @@
 use std::collections::VecDeque;
 use std::sync::Arc;
 
+use crate::color_space::{AlphaMode, ColorConversion, ColorSpace};
 use crate::compiled_func::{CompiledFunc, CpuInputImage};
+use crate::error::{GpuUtilError, WithStepIndex};
+use crate::execution_report::StepKind;
 use crate::image_generator::{ImageGenerator, ProcessingState, StepOutput};
-use anyhow::Result;
-use futures::channel::oneshot;
-use futures::future::join_all;
-use futures::FutureExt;
-
-async fn download_gpu_texture(
-    device: &wgpu::Device,
-    queue: &wgpu::Queue,
-    texture_to_read: &Arc<wgpu::Texture>,
-) -> Result<(Vec<f32>, u32, u32)> {
-    let (width, height) = (texture_to_read.width(), texture_to_read.height());
-    let row_size = width * std::mem::size_of::<[f32; 4]>() as u32;
-    let bytes_per_row = ((row_size + 255) / 256) * 256;
-    let readback_buffer_size = (bytes_per_row * height) as u64;
-
-    let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("Readback Buffer"),
-        size: readback_buffer_size,
-        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
-    });
-
-    let mut encoder = device.create_command_encoder(&Default::default());
-    // bytes_per_rowを256の倍数に揃える
-    encoder.copy_texture_to_buffer(
-        texture_to_read.as_image_copy(),
-        wgpu::TexelCopyBufferInfo {
-            buffer: &readback_buffer,
-            layout: wgpu::TexelCopyBufferLayout {
-                offset: 0,
-                bytes_per_row: Some(bytes_per_row),
-                rows_per_image: Some(height),
-            },
-        },
-        wgpu::Extent3d {
-            width,
-            height,
-            depth_or_array_layers: 1,
-        },
-    );
-
-    queue.submit(Some(encoder.finish()));
-
-    let buffer_slice = readback_buffer.slice(..);
-    let (tx, rx) = oneshot::channel();
-    buffer_slice.map_async(wgpu::MapMode::Read, move |result| tx.send(result).unwrap());
-    device.poll(wgpu::PollType::Wait {
-        submission_index: None,
-        timeout: None,
-    })?;
-    rx.await??;
-
-    let data = buffer_slice.get_mapped_range();
+use anyhow::{bail, Context, Result};
+
+/// テクセルのバイト列をRGBAのf32に変換します。
+/// チャンネルが足りないフォーマットは、シェーダーで読んだ場合と同じく (0, 0, 1) で補います。
+fn decode_texel(format: wgpu::TextureFormat, texel: &[u8], out: &mut Vec<f32>) -> Result<()> {
+    let f32_at =
+        |i: usize| f32::from_le_bytes([texel[i], texel[i + 1], texel[i + 2], texel[i + 3]]);
+    let rgba = match format {
+        wgpu::TextureFormat::Rgba32Float => [f32_at(0), f32_at(4), f32_at(8), f32_at(12)],
+        wgpu::TextureFormat::Rgba16Float => std::array::from_fn(|c| {
+            half::f16::from_le_bytes([texel[c * 2], texel[c * 2 + 1]]).to_f32()
+        }),
+        wgpu::TextureFormat::Rgba8Unorm => std::array::from_fn(|c| texel[c] as f32 / 255.0),
+        wgpu::TextureFormat::Rg32Float => [f32_at(0), f32_at(4), 0.0, 1.0],
+        wgpu::TextureFormat::R32Float => [f32_at(0), 0.0, 0.0, 1.0],
+        _ => bail!(GpuUtilError::readback(format!(
+            "Texture format {:?} cannot be read back to the CPU",
+            format
+        ))),
+    };
+    out.extend_from_slice(&rgba);
+    Ok(())
+}
 
-    // パディングを外して詰め直し
-    let mut pixels = vec![0u8; (row_size * height) as usize];
-    for y in 0..height as usize {
-        let src = &data[y * bytes_per_row as usize..y * bytes_per_row as usize + row_size as usize];
-        let dst = &mut pixels[y * row_size as usize..(y + 1) * row_size as usize];
-        dst.copy_from_slice(src);
+/// ダウンロードしたテクセルのバイト列をRGBAのf32の列に変換します。
+fn decode_texels(format: wgpu::TextureFormat, data: &[u8]) -> Result<Vec<f32>> {
+    let texel_size = format
+        .block_copy_size(None)
+        .with_context(|| format!("Texture format {:?} cannot be copied", format))?;
+    let mut pixels = Vec::with_capacity(data.len() / texel_size as usize * 4);
+    for texel in data.chunks_exact(texel_size as usize) {
+        decode_texel(format, texel, &mut pixels)?;
     }
-    Ok((bytemuck::cast_slice(&data).to_vec(), width, height))
+    Ok(pixels)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_cpu_func_step(
     generator: &ImageGenerator,
     state: &mut ProcessingState,
     func: &CompiledFunc,
     params: &Option<Vec<u8>>,
+    step_index: usize,
     output_width: u32,
     output_height: u32,
-    all_encoders: &mut Vec<wgpu::CommandEncoder>,
-) -> Result<(ProcessingState, Vec<wgpu::CommandEncoder>)> {
-    // CPU関数処理では事前にすべてのエンコーダをsubmitする
-    if !all_encoders.is_empty() {
-        generator
-            .queue
-            .submit(all_encoders.drain(..).map(|e| e.finish()));
-    }
-
+    all_commands: &mut Vec<wgpu::CommandBuffer>,
+) -> Result<(ProcessingState, Vec<wgpu::CommandBuffer>)> {
     // --- 入力データの準備 ---
-    let mut download_futures = Vec::new();
+    // GPUの入力はすべてのダウンロードを1回にまとめ、これまでのコマンドバッファと一緒にサブミットする
+    let mut download_textures = Vec::new();
     // 元の順序を保持しつつ、CPUデータとGPUダウンロード結果を区別する
     enum TempInput {
         Cpu(StepOutput),
-        GpuDownload(), // downloaded_dataのインデックス
+        GpuDownload {
+            format: wgpu::TextureFormat,
+            width: u32,
+            height: u32,
+            color_space: ColorSpace,
+            alpha_mode: AlphaMode,
+        },
     }
     let mut temp_inputs: Vec<TempInput> = Vec::with_capacity(state.len());
 
     for input in state.drain(..) {
         match input {
-            StepOutput::Gpu { texture, .. } => {
-                // generatorの中身をcloneして使う
-                let device = &generator.device;
-                let queue = &generator.queue;
-
-                // ダウンロード処理をFutureとして登録
-                let texture_clone = texture.clone();
-                let future =
-                    async move { download_gpu_texture(device, queue, &texture_clone).await };
-                download_futures.push(future.boxed());
-                // プレースホルダーを登録
-                temp_inputs.push(TempInput::GpuDownload());
+            StepOutput::Gpu {
+                texture,
+                width,
+                height,
+                color_space,
+                alpha_mode,
+            } => {
+                temp_inputs.push(TempInput::GpuDownload {
+                    format: texture.format(),
+                    width,
+                    height,
+                    color_space,
+                    alpha_mode,
+                });
+                download_textures.push(texture);
             }
             cpu_output @ StepOutput::Cpu { .. } => {
                 // CPUデータはそのままプレースホルダーとして登録
@@ -114,28 +94,85 @@ pub async fn handle_cpu_func_step(
         }
     }
 
-    // GPUからのダウンロードを並列実行
-    let downloaded_data_results = join_all(download_futures).await;
-    let mut downloaded_data: VecDeque<_> = downloaded_data_results
-        .into_iter()
-        .collect::<Result<Vec<_>>>()?
+    // ダウンロードを待つ。ダウンロードがなくても、これまでのコマンドバッファはここでサブミットされる
+    let download_span = generator.profile_span(
+        StepKind::Download,
+        StepKind::Download.as_str(),
+        Some(step_index),
+        None,
+    );
+    let mut downloaded_data: VecDeque<_> = generator
+        .download_textures(step_index, &download_textures, all_commands.drain(..))
+        .await
+        .with_step_index(step_index)?
         .into();
+    download_span.finish(None);
+
+    let span = generator.profile_span(
+        StepKind::CpuFunc,
+        StepKind::CpuFunc.as_str(),
+        Some(step_index),
+        Some((output_width, output_height)),
+    );
 
     // --- すべての入力を CpuInputImage にまとめる ---
     let mut owned_cpu_data: Vec<StepOutput> = Vec::with_capacity(temp_inputs.len()); // 所有権を保持
 
     for temp_input in temp_inputs {
         match temp_input {
-            TempInput::Cpu(cpu_output) => {
-                owned_cpu_data.push(cpu_output);
+            TempInput::Cpu(StepOutput::Cpu {
+                data,
+                width,
+                height,
+                color_space,
+                alpha_mode,
+            }) => {
+                // 関数が扱う色空間とアルファの形式に変換する
+                let conversion = ColorConversion::new(
+                    color_space,
+                    alpha_mode,
+                    func.color_space,
+                    func.alpha_mode,
+                );
+                let data = if conversion.is_identity() {
+                    data
+                } else {
+                    let mut data = data.to_vec();
+                    conversion.convert_cpu(&mut data);
+                    Arc::new(data)
+                };
+                owned_cpu_data.push(StepOutput::Cpu {
+                    data,
+                    width,
+                    height,
+                    color_space: func.color_space,
+                    alpha_mode: func.alpha_mode,
+                });
             }
-            TempInput::GpuDownload() => {
-                // ダウンロード結果を先頭から取り出し、所有権を移す
-                let downloaded = downloaded_data.pop_front().unwrap();
+            TempInput::Cpu(StepOutput::Gpu { .. }) => unreachable!(),
+            TempInput::GpuDownload {
+                format,
+                width,
+                height,
+                color_space,
+                alpha_mode,
+            } => {
+                // ダウンロード結果を先頭から取り出してf32に変換する
+                let Some(bytes) = downloaded_data.pop_front() else {
+                    bail!(
+                        GpuUtilError::readback("Missing downloaded texture data".to_string())
+                            .at_step(step_index)
+                    );
+                };
+                let mut data = decode_texels(format, &bytes).with_step_index(step_index)?;
+                ColorConversion::new(color_space, alpha_mode, func.color_space, func.alpha_mode)
+                    .convert_cpu(&mut data);
                 owned_cpu_data.push(StepOutput::Cpu {
-                    data: Arc::new(downloaded.0),
-                    width: downloaded.1,
-                    height: downloaded.2,
+                    data: Arc::new(data),
+                    width,
+                    height,
+                    color_space: func.color_space,
+                    alpha_mode: func.alpha_mode,
                 });
             }
         }
@@ -148,6 +185,7 @@ pub async fn handle_cpu_func_step(
                 data,
                 width,
                 height,
+                ..
             } = step_output
             {
                 CpuInputImage {
@@ -162,13 +200,34 @@ pub async fn handle_cpu_func_step(
         .collect();
 
     // --- CPU関数の実行 ---
-    let cpu_output_data = (*func.func)(&cpu_inputs, params.as_deref())?;
+    let cpu_output_data =
+        (*func.func)(&cpu_inputs, params.as_deref()).map_err(|error| GpuUtilError::CpuFunc {
+            step_index: Some(step_index),
+            error,
+        })?;
+    let expected_len = output_width as usize * output_height as usize * 4;
+    if cpu_output_data.data.len() != expected_len {
+        bail!(GpuUtilError::size(
+            None,
+            format!(
+                "the CPU function returned {} values, but a {}x{} RGBA image needs {}",
+                cpu_output_data.data.len(),
+                output_width,
+                output_height,
+                expected_len
+            ),
+        )
+        .at_step(step_index));
+    }
 
     let new_state = vec![StepOutput::Cpu {
         data: Arc::new(cpu_output_data.data),
         width: output_width,
         height: output_height,
+        color_space: func.color_space,
+        alpha_mode: func.alpha_mode,
     }];
+    span.finish(None);
 
     Ok((new_state, Vec::new()))
 }
@@ -0,0 +1,61 @@
+// image_generator/device_poller.rs
+
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use crate::error::GpuUtilError;
+use anyhow::{Context, Result};
+use futures::channel::oneshot;
+
+// 待機の要求。submissionが`None`の場合は、それまでにサブミットされたすべての処理を待つ
+struct PollRequest {
+    submission: Option<wgpu::SubmissionIndex>,
+    done: oneshot::Sender<Result<(), wgpu::PollError>>,
+}
+
+/// 専用のスレッドでデバイスをポーリングし、サブミットの完了を非同期に待てるようにします。
+///
+/// `device.poll`で完了を待つと呼び出したスレッドが止まるため、非同期の処理から直接呼ぶと
+/// 同じスレッドで動いている他のタスク (並列処理の兄弟のパイプラインなど) まで待たされます。
+/// ポーリングはこのスレッドだけで行い、非同期の側は完了の通知とマッピングの結果を待つだけにします。
+pub(crate) struct DevicePoller {
+    sender: mpsc::Sender<PollRequest>,
+}
+
+impl DevicePoller {
+    /// ポーリング用のスレッドを起動します。スレッドはこのインスタンスが破棄されると終了します。
+    pub(crate) fn new(device: Arc<wgpu::Device>) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<PollRequest>();
+        thread::Builder::new()
+            .name("gpu_util device poller".to_string())
+            .spawn(move || {
+                while let Ok(request) = receiver.recv() {
+                    // 完了したサブミットのmap_asyncのコールバックはこのポーリングの中で呼ばれる
+                    let result = device
+                        .poll(wgpu::PollType::Wait {
+                            submission_index: request.submission,
+                            timeout: None,
+                        })
+                        .map(|_| ());
+                    let _ = request.done.send(result);
+                }
+            })
+            .context("Failed to start the device poller thread")?;
+        Ok(Self { sender })
+    }
+
+    /// 指定したサブミット (`None`の場合はそれまでのすべてのサブミット) の完了を、スレッドをブロックせずに待ちます。
+    pub(crate) async fn wait(&self, submission: Option<wgpu::SubmissionIndex>) -> Result<()> {
+        let (done, receiver) = oneshot::channel();
+        self.sender
+            .send(PollRequest { submission, done })
+            .map_err(|_| GpuUtilError::readback("The device poller has stopped".to_string()))?;
+        receiver
+            .await
+            .map_err(|_| GpuUtilError::readback("The device poller has stopped".to_string()))?
+            .map_err(|e| {
+                GpuUtilError::readback(format!("Failed to wait for the GPU to finish: {}", e))
+            })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+// image_generator/error_scope.rs
+
+use crate::{error::GpuUtilError, image_generator::ImageGenerator};
+use anyhow::Result;
+use futures::FutureExt;
+
+impl ImageGenerator {
+    /// fの中でwgpuに発生したエラーをエラースコープで捕捉し、ステップの位置とシェーダーのIDを持つエラーとして返します。
+    /// コマンドの検証エラーは`CommandEncoder::finish`で報告されるため、fの中でエンコーダを終了してください。
+    /// ステップに属さない処理 (最終的な出力への変換など) では`step_index`を`None`にします。
+    ///
+    /// エラースコープはデバイス全体で1つのスタックなので、他の生成のエラーがスコープに混ざらないよう、
+    /// リソースの作成、コマンドの記録、サブミット、書き込み、アンマップなどのデバイスの操作はすべてこの中で行います。
+    /// スコープの中の処理は直列に実行されるため、fの中でGPUの完了を待ったり、`with_error_scope`を呼び出したりしないでください。
+    pub(crate) fn with_error_scope<T>(
+        &self,
+        step_index: Option<usize>,
+        shader_id: Option<&str>,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let _guard = self.error_scope_lock.lock().unwrap();
+        let (result, error) = capture_errors(&self.device, f);
+        // fが返したエラーはwgpuのエラーの結果であることが多いため、wgpuのエラーを優先する
+        match error {
+            Some(error) => {
+                let error = to_gpu_util_error(error, shader_id, None);
+                Err(match step_index {
+                    Some(step_index) => error.at_step(step_index),
+                    None => error,
+                }
+                .into())
+            }
+            None => result,
+        }
+    }
+}
+
+/// fの中でwgpuに発生したエラーを捕捉します。
+/// `with_error_scope`の中で、パイプラインやバインディングなどのステップより細かい位置を特定するために使います。
+pub(crate) fn capture_errors<T>(
+    device: &wgpu::Device,
+    f: impl FnOnce() -> T,
+) -> (T, Option<wgpu::Error>) {
+    device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+    device.push_error_scope(wgpu::ErrorFilter::Internal);
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let value = f();
+    let validation = pop_error_scope(device);
+    let internal = pop_error_scope(device);
+    let out_of_memory = pop_error_scope(device);
+
+    // バックエンドでのシェーダーのコンパイルの失敗などの内部エラーの後は、
+    // 無効なオブジェクトを使った検証エラーが続くため、内部エラーを優先する
+    (value, internal.or(validation).or(out_of_memory))
+}
+
+/// wgpuのエラーをGpuUtilErrorに変換します。
+pub(crate) fn to_gpu_util_error(
+    error: wgpu::Error,
+    shader_id: Option<&str>,
+    binding: Option<String>,
+) -> GpuUtilError {
+    match error {
+        wgpu::Error::OutOfMemory { .. } => {
+            GpuUtilError::device(shader_id, "the device ran out of memory".to_string())
+        }
+        // 内部エラーはバックエンドがシェーダーを拒否した場合などで、検証エラーと同じく入力が原因になる
+        wgpu::Error::Validation { description, .. } | wgpu::Error::Internal { description, .. } => {
+            GpuUtilError::validation(shader_id, binding, description.trim().to_string())
+        }
+    }
+}
+
+fn pop_error_scope(device: &wgpu::Device) -> Option<wgpu::Error> {
+    // ネイティブのバックエンドでは、スコープを取り出した時点で結果が確定している
+    device.pop_error_scope().now_or_never().flatten()
+}
@@ -1,31 +1,81 @@
 // image_generator/final_process.rs
 
-use std::time::Instant;
-
-use crate::image_generator::{ImageGenerator, ProcessingState, StepOutput};
-use anyhow::{Context, Result, bail};
-use futures::channel::oneshot;
-use rayon::{
-    iter::{IndexedParallelIterator, ParallelIterator},
-    slice::{ParallelSlice, ParallelSliceMut},
+use std::sync::Arc;
+
+use crate::{
+    color_space::{AlphaMode, ColorConversion, ColorSpace},
+    error::GpuUtilError,
+    execution_report::StepKind,
+    image_generator::{
+        profiler::ProfileSpan, transfer::PendingDownload, ImageGenerator, ProcessingState,
+        StepOutput,
+    },
+    output_format::OutputOptions,
 };
+use anyhow::{bail, Context, Result};
+use wgpu::util::DeviceExt;
 
-#[inline(always)]
-fn f32_to_u8_clamped(x: f32) -> u8 {
-    // 0..255 にクリップしてから u8 へ（切り捨て）
-    let y = (x * 255.0).max(0.0).min(255.0);
-    y as u8
+/// 最終処理を記録した結果。
+pub(crate) enum RecordedOutput {
+    /// CPUで変換済みの出力
+    Ready(Vec<u8>),
+    /// GPUでの変換と読み戻し用バッファへのコピーを記録したコマンドバッファ
+    Readback {
+        commands: wgpu::CommandBuffer,
+        readback: PendingDownload,
+    },
 }
 
 /// パイプライン全体の最終処理を担当します。
+/// precedingはまだサブミットしていないパイプラインのコマンドバッファで、最終処理と一緒にサブミットされます。
 pub async fn handle_final_process(
     generator: &ImageGenerator,
     final_state: ProcessingState,
+    output: &OutputOptions,
+    preceding: Vec<wgpu::CommandBuffer>,
 ) -> Result<Vec<u8>> {
+    match record_final_process(generator, final_state, output)? {
+        RecordedOutput::Ready(result) => {
+            generator.with_error_scope(None, None, || Ok(generator.queue.submit(preceding)))?;
+            Ok(result)
+        }
+        RecordedOutput::Readback { commands, readback } => {
+            // コマンドをサブミットし、マッピングを待つ
+            let span =
+                generator.profile_span(StepKind::Download, StepKind::Download.as_str(), None, None);
+            let result = generator
+                .submit_and_read(
+                    None,
+                    Some(StepKind::Output.as_str()),
+                    preceding.into_iter().chain(std::iter::once(commands)),
+                    std::slice::from_ref(&readback),
+                )
+                .await?
+                .pop()
+                .context("Failed to read back the output")?;
+            span.finish(None);
+
+            Ok(result)
+        }
+    }
+}
+
+/// 最終処理のうち、出力のバイト列への変換を記録します。
+/// GPUの出力はサブミットせずにコマンドバッファとして返すため、複数のフレームをまとめてサブミットできます。
+/// GPUでの変換の記録はエラースコープの中で行い、失敗した場合は`output`のエラーとして返します。
+pub(crate) fn record_final_process(
+    generator: &ImageGenerator,
+    final_state: ProcessingState,
+    output: &OutputOptions,
+) -> Result<RecordedOutput> {
+    let format = output.format;
     // このコードは、元の image_generator.rs の generate メソッドの
     // ループ後の最終処理部分から移動したものです。
     let final_state = if final_state.len() != 1 {
-        bail!("Final processing state must contain exactly one item.");
+        bail!(GpuUtilError::binding(
+            None,
+            "Final processing state must contain exactly one item.".to_string(),
+        ));
     } else {
         final_state
             .into_iter()
@@ -33,122 +83,189 @@ pub async fn handle_final_process(
             .context("Failed to get final state item")?
     };
 
-    match final_state {
+    let resolution = match &final_state {
+        StepOutput::Gpu { width, height, .. } | StepOutput::Cpu { width, height, .. } => {
+            (*width, *height)
+        }
+    };
+    let span = generator.profile_span(
+        StepKind::Output,
+        StepKind::Output.as_str(),
+        None,
+        Some(resolution),
+    );
+
+    let recorded = match final_state {
         StepOutput::Gpu {
             texture,
             width,
             height,
-        } => {
-            // --- 最終的なGPUテクスチャをu8配列に変換する ---
-
-            // 1. シェーダーが書き込むためのu32ストレージバッファを作成（キャッシュ使用）
-            let u32_buffer_size = (width * height * std::mem::size_of::<u32>() as u32) as u64;
-            let final_u32_buffer = generator.get_or_create_buffer(
-                u32_buffer_size,
-                wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
-                Some("Final U32 Buffer"),
+            color_space,
+            alpha_mode,
+        } => generator.with_error_scope(None, Some(StepKind::Output.as_str()), || {
+            let output = PreparedOutput::new(
+                generator,
+                &texture,
+                (width, height),
+                (color_space, alpha_mode),
+                output,
             );
-
-            // 2. バインドグループを作成
-            // ImageGenerator::newで作成したレイアウトに適合させる
-            let input_texture_view = texture.create_view(&Default::default());
-            let bind_group = generator
-                .device
-                .create_bind_group(&wgpu::BindGroupDescriptor {
-                    label: Some("Post Process Bind Group"),
-                    layout: &generator.post_process_bind_group_layout,
-                    entries: &[
-                        wgpu::BindGroupEntry {
-                            binding: 0,
-                            resource: wgpu::BindingResource::TextureView(&input_texture_view),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 1,
-                            resource: final_u32_buffer.as_entire_binding(),
-                        },
-                    ],
-                });
-
             let mut encoder =
                 generator
                     .device
                     .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                         label: Some("Final Process Encoder"),
                     });
-
-            // 3. コンピュートパスを実行して、テクスチャ->u32バッファ変換を行う
-            {
-                let mut cpass = encoder.begin_compute_pass(&Default::default());
-                cpass.set_pipeline(&generator.post_process_pipeline);
-                cpass.set_bind_group(0, &bind_group, &[]);
-                // ディスパッチサイズは最終的な画像の解像度に基づく
-                cpass.dispatch_workgroups((width + 15) / 16, (height + 15) / 16, 1);
-            }
-
-            // 4. 結果をCPUに読み戻す（キャッシュ使用）
-            let readback_buffer = generator.get_or_create_buffer(
-                u32_buffer_size,
-                wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-                Some("Final Readback Buffer"),
-            );
-
-            encoder.copy_buffer_to_buffer(
-                &final_u32_buffer,
-                0,
-                &readback_buffer,
-                0,
-                u32_buffer_size,
+            output.record(generator, &mut encoder, &span);
+            Ok(RecordedOutput::Readback {
+                commands: encoder.finish(),
+                readback: output.readback,
+            })
+        })?,
+        StepOutput::Cpu {
+            data,
+            width,
+            height,
+            color_space,
+            alpha_mode,
+        } => {
+            let conversion = ColorConversion::new(
+                color_space,
+                alpha_mode,
+                output.color_space,
+                output.alpha_mode,
             );
+            let converted;
+            let data = if conversion.is_identity() {
+                data.as_slice()
+            } else {
+                let mut copy = data.to_vec();
+                conversion.convert_cpu(&mut copy);
+                converted = copy;
+                converted.as_slice()
+            };
 
-            // 5. コマンドをサブミットし、マッピングを待つ
-            generator.queue.submit(Some(encoder.finish()));
+            RecordedOutput::Ready(format.convert_cpu(data, width, height))
+        }
+    };
+    span.finish(None);
+    Ok(recorded)
+}
 
-            let buffer_slice = readback_buffer.slice(..);
-            let (tx, rx) = oneshot::channel();
-            buffer_slice.map_async(wgpu::MapMode::Read, move |result| tx.send(result).unwrap());
+/// GPUの最終的なテクスチャを出力のフォーマットと色空間のバイト列に変換するためのリソース。
+/// 同じテクスチャと出力の設定に対しては、`record`を繰り返し呼び出して再利用できます。
+pub(crate) struct PreparedOutput {
+    // シェーダーが書き込むu32のストレージバッファ
+    output_buffer: Arc<wgpu::Buffer>,
+    bind_group: wgpu::BindGroup,
+    word_count: u32,
+    /// output_bufferを読み戻すステージングバッファ
+    pub(crate) readback: PendingDownload,
+}
 
-            generator.device.poll(wgpu::PollType::Wait {
-                submission_index: None,
-                timeout: None,
-            })?;
+impl PreparedOutput {
+    /// 変換に使うバッファとバインドグループを作成します。
+    /// リソースを作成するため、`with_error_scope`の中で呼び出してください。
+    pub(crate) fn new(
+        generator: &ImageGenerator,
+        texture: &wgpu::Texture,
+        (width, height): (u32, u32),
+        (color_space, alpha_mode): (ColorSpace, AlphaMode),
+        output: &OutputOptions,
+    ) -> Self {
+        let format = output.format;
+        let conversion = ColorConversion::new(
+            color_space,
+            alpha_mode,
+            output.color_space,
+            output.alpha_mode,
+        );
 
-            rx.await
-                .context("Failed to receive buffer mapping result")??;
+        // 1. シェーダーが書き込むためのu32ストレージバッファを作成（キャッシュ使用）
+        // YUVなど4の倍数にならないフォーマットもあるため、u32単位に切り上げる
+        let byte_size = format.byte_size(width, height);
+        let word_count = byte_size.div_ceil(4) as u32;
+        let u32_buffer_size = word_count as u64 * std::mem::size_of::<u32>() as u64;
+        let output_buffer = generator.get_or_create_buffer(
+            u32_buffer_size,
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            Some("Final U32 Buffer"),
+        );
+        let params_buffer =
+            generator
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Post Process Params"),
+                    contents: &[
+                        bytemuck::cast_slice(&[format.shader_id(), width, height, word_count]),
+                        &conversion.to_uniform_bytes()[..],
+                    ]
+                    .concat(),
+                    usage: wgpu::BufferUsages::UNIFORM,
+                });
 
-            let data = buffer_slice.get_mapped_range();
-            let result = data.to_vec();
-            drop(data); // get_mapped_rangeの借用を解除
-            readback_buffer.unmap();
+        // 2. バインドグループを作成
+        // ImageGenerator::newで作成したレイアウトに適合させる
+        let input_texture_view = texture.create_view(&Default::default());
+        let bind_group = generator
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Post Process Bind Group"),
+                layout: &generator.post_process_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&input_texture_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: output_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: params_buffer.as_entire_binding(),
+                    },
+                ],
+            });
 
-            Ok(result)
+        let readback = generator.buffer_download(byte_size as u64);
+        Self {
+            output_buffer,
+            bind_group,
+            word_count,
+            readback,
         }
-        StepOutput::Cpu {
-            data,
-            width,
-            height,
-        } => {
-            // パフォーマンス計測
-            let start_time = Instant::now();
-
-            let pixel_count = (width as usize) * (height as usize);
-            let n = pixel_count * 4;
-            let mut result_bytes = vec![0u8; n];
-
-            result_bytes
-                .par_chunks_exact_mut(4)
-                .zip_eq(data.par_chunks_exact(4))
-                .for_each(|(dst, src)| {
-                    dst[0] = f32_to_u8_clamped(src[0]);
-                    dst[1] = f32_to_u8_clamped(src[1]);
-                    dst[2] = f32_to_u8_clamped(src[2]);
-                    dst[3] = f32_to_u8_clamped(src[3]);
-                });
+    }
 
-            println!(
-                "Final CPU post-processing completed in {:.2?}.",
-                start_time.elapsed()
-            );
-            Ok(result_bytes)
+    /// 変換のコンピュートパスと、結果をステージングバッファに読み戻すコピーをencoderに記録します。
+    pub(crate) fn record(
+        &self,
+        generator: &ImageGenerator,
+        encoder: &mut wgpu::CommandEncoder,
+        span: &ProfileSpan,
+    ) {
+        // 3. コンピュートパスを実行して、テクスチャ->u32バッファ変換を行う
+        {
+            let timestamps = span.pass_timestamps(&generator.device);
+            let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Post Process Compute Pass"),
+                timestamp_writes: timestamps.as_ref().map(|t| t.writes()),
+            });
+            cpass.set_pipeline(&generator.post_process_pipeline);
+            cpass.set_bind_group(0, &self.bind_group, &[]);
+            // 出力のu32ごとに1回呼び出す。1次元の上限を超える分はyに分ける
+            let [wx, _, _] = generator.post_process_workgroup_size;
+            let workgroups = self.word_count.div_ceil(wx);
+            let max_workgroups = generator
+                .device
+                .limits()
+                .max_compute_workgroups_per_dimension;
+            let x = workgroups.clamp(1, max_workgroups);
+            cpass.dispatch_workgroups(x, workgroups.div_ceil(x), 1);
         }
+
+        // 4. 結果をステージングバッファに読み戻す処理を記録する
+        self.readback
+            .record_buffer_copy(encoder, &self.output_buffer);
     }
 }
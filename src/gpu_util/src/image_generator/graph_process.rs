@@ -0,0 +1,79 @@
+use crate::{
+    error::GpuUtilError,
+    image_generator::{ImageGenerator, ProcessingState},
+    pipeline_graph::{PipelineGraph, ResolvedInput},
+};
+use anyhow::{bail, Context, Result};
+
+pub async fn handle_graph_step(
+    generator: &ImageGenerator,
+    state: &mut ProcessingState,
+    graph: &PipelineGraph,
+    step_index: usize,
+    all_commands: &mut Vec<wgpu::CommandBuffer>,
+) -> Result<(ProcessingState, Vec<wgpu::CommandBuffer>)> {
+    // グラフに渡された画像はノードから位置で参照される
+    let previous = std::mem::take(state);
+    // ノードの記録順がそのままサブミットの順序になるよう、まだサブミットしていないコマンドバッファを引き継ぐ
+    let mut commands = std::mem::take(all_commands);
+    // 実行順のノードごとの出力。寿命が終わったものは`None`にする
+    let mut outputs: Vec<Option<ProcessingState>> = vec![None; graph.nodes.len()];
+
+    for (position, node) in graph.nodes.iter().enumerate() {
+        // --- 入力の収集 ---
+        let mut inputs = ProcessingState::new();
+        for input in &node.inputs {
+            match *input {
+                ResolvedInput::Node(producer) => {
+                    let output = outputs[producer].as_ref().with_context(|| {
+                        format!(
+                            "Step {}: output of graph node `{}` is no longer available",
+                            step_index, graph.nodes[producer].name
+                        )
+                    })?;
+                    inputs.extend(output.iter().cloned());
+                }
+                ResolvedInput::Previous(index) => {
+                    let Some(image) = previous.get(index) else {
+                        bail!(GpuUtilError::binding(
+                            None,
+                            format!(
+                                "graph node `{}` takes input {}, but the graph received only {} images",
+                                node.name,
+                                index,
+                                previous.len()
+                            ),
+                        )
+                        .at_step(step_index));
+                    };
+                    inputs.push(image.clone());
+                }
+            }
+        }
+
+        // --- 既存のステップの処理でノードを実行 ---
+        let (output, mut node_commands) = generator
+            .execute_step(&node.step, &mut inputs, step_index, false, &mut commands)
+            .await
+            .with_context(|| format!("Graph node `{}` failed", node.name))?;
+        commands.append(&mut node_commands);
+        outputs[position] = Some(output);
+
+        // --- 後続のノードが読まない出力の寿命を終わらせる ---
+        for (i, node) in graph.nodes.iter().enumerate().take(position + 1) {
+            if node.last_use <= position {
+                outputs[i] = None;
+            }
+        }
+        let mut live = previous.clone();
+        live.extend(outputs.iter().flatten().flatten().cloned());
+        generator.end_transient_step(&live);
+    }
+
+    let result = graph
+        .outputs
+        .iter()
+        .flat_map(|&i| outputs[i].iter().flatten().cloned())
+        .collect();
+    Ok((result, commands))
+}
@@ -1,5 +1,5 @@
 use crate::{
-    image_generate_builder::{ImageGenerateBuilder, PipelineStep},
+    image_generate_builder::ImageGenerateBuilder,
     image_generator::{ImageGenerator, ProcessingState},
 };
 use anyhow::Result;
@@ -9,22 +9,19 @@ pub async fn handle_parallel_step(
     generator: &ImageGenerator,
     state: &mut ProcessingState,
     pipelines: &[ImageGenerateBuilder],
-    _step_index: usize,
-    all_encoders: &mut Vec<wgpu::CommandEncoder>,
-) -> Result<(ProcessingState, Vec<wgpu::CommandEncoder>)> {
+    step_index: usize,
+    all_commands: &mut Vec<wgpu::CommandBuffer>,
+) -> Result<(ProcessingState, Vec<wgpu::CommandBuffer>)> {
     // CPU処理が含まれるかどうかをチェック
-    let has_cpu_processing = pipelines.iter().any(|pipeline| {
-        pipeline
-            .steps
-            .iter()
-            .any(|step| matches!(step, PipelineStep::CpuFunc { .. }))
-    });
-
-    // CPU処理が含まれる場合は事前にエンコーダをsubmit
-    if has_cpu_processing && !all_encoders.is_empty() {
-        generator
-            .queue
-            .submit(all_encoders.drain(..).map(|e| e.finish()));
+    let has_cpu_processing = pipelines
+        .iter()
+        .any(|pipeline| pipeline.steps.iter().any(|step| step.contains_cpu_func()));
+
+    // CPU処理が含まれる場合は事前にコマンドバッファをsubmit
+    if has_cpu_processing && !all_commands.is_empty() {
+        generator.with_error_scope(Some(step_index), None, || {
+            Ok(generator.queue.submit(all_commands.drain(..)))
+        })?;
     }
 
     // この並列ブロックに入る前の状態を、すべてのサブパイプラインの初期状態として使用する
@@ -47,19 +44,19 @@ pub async fn handle_parallel_step(
     // すべてのサブパイプラインを並列に実行
     let results = join_all(execution_futures).await;
 
-    // すべての結果を収集して、一つの状態とエンコーダリストにまとめる
+    // すべての結果を収集して、一つの状態とコマンドバッファのリストにまとめる
     let mut combined_state = ProcessingState::new();
-    let mut result_encoders: Vec<wgpu::CommandEncoder> = Vec::new();
+    let mut result_commands: Vec<wgpu::CommandBuffer> = Vec::new();
 
     for result in results {
         match result {
-            Ok((mut sub_pipeline_state, mut sub_pipeline_encoders)) => {
+            Ok((mut sub_pipeline_state, mut sub_pipeline_commands)) => {
                 combined_state.append(&mut sub_pipeline_state);
-                result_encoders.append(&mut sub_pipeline_encoders);
+                result_commands.append(&mut sub_pipeline_commands);
             }
             Err(e) => return Err(e), // エラーが発生した場合は即座に返す
         }
     }
 
-    Ok((combined_state, result_encoders))
+    Ok((combined_state, result_commands))
 }
@@ -0,0 +1,241 @@
+// image_generator/profiler.rs
+
+use std::{
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{
+    execution_report::{CacheStatus, ExecutionReport, StepKind, StepReport},
+    image_generator::ImageGenerator,
+};
+use anyhow::Result;
+
+// 1つのクエリセットのタイムスタンプの数。パスごとに開始と終了の2つを使う
+const TIMESTAMP_QUERY_SET_SIZE: u32 = 256;
+
+/// 1回の生成の計測結果を集めるプロファイラ。
+pub(crate) struct Profiler {
+    start: Instant,
+    steps: Vec<StepReport>,
+    // タイムスタンプを書き込むクエリセット。TIMESTAMP_QUERYがない場合は使わない
+    timestamps: bool,
+    query_sets: Vec<wgpu::QuerySet>,
+    // タイムスタンプを書き込んだパスごとの、項目の位置
+    passes: Vec<usize>,
+}
+
+/// 計測中の項目。`finish`で処理時間を記録します。プロファイリングが無効な場合は何もしません。
+pub(crate) struct ProfileSpan {
+    profiler: Option<Arc<Mutex<Profiler>>>,
+    index: usize,
+    start: Instant,
+}
+
+/// コンピュートパスの開始と終了のタイムスタンプを書き込む位置。
+pub(crate) struct PassTimestamps {
+    query_set: wgpu::QuerySet,
+    index: u32,
+}
+
+impl PassTimestamps {
+    pub(crate) fn writes(&self) -> wgpu::ComputePassTimestampWrites<'_> {
+        wgpu::ComputePassTimestampWrites {
+            query_set: &self.query_set,
+            beginning_of_pass_write_index: Some(self.index),
+            end_of_pass_write_index: Some(self.index + 1),
+        }
+    }
+}
+
+impl ProfileSpan {
+    /// この項目のコンピュートパスのタイムスタンプを書き込む位置を返します。
+    /// プロファイリングが無効な場合や、アダプタがタイムスタンプに対応していない場合は`None`を返します。
+    pub(crate) fn pass_timestamps(&self, device: &wgpu::Device) -> Option<PassTimestamps> {
+        let mut profiler = self.profiler.as_ref()?.lock().unwrap();
+        if !profiler.timestamps {
+            return None;
+        }
+        let pass = profiler.passes.len() as u32;
+        let index = pass * 2 % TIMESTAMP_QUERY_SET_SIZE;
+        if index == 0 {
+            profiler
+                .query_sets
+                .push(device.create_query_set(&wgpu::QuerySetDescriptor {
+                    label: Some("Profiler Timestamps"),
+                    ty: wgpu::QueryType::Timestamp,
+                    count: TIMESTAMP_QUERY_SET_SIZE,
+                }));
+        }
+        let query_set = profiler.query_sets.last()?.clone();
+        let step = self.index;
+        profiler.passes.push(step);
+        Some(PassTimestamps { query_set, index })
+    }
+
+    /// 項目の処理時間とキャッシュを引いた結果を記録します。
+    pub(crate) fn finish(self, cache: Option<CacheStatus>) {
+        if let Some(profiler) = &self.profiler {
+            let mut profiler = profiler.lock().unwrap();
+            let step = &mut profiler.steps[self.index];
+            step.cpu_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+            step.cache = cache;
+        }
+    }
+}
+
+impl ImageGenerator {
+    /// プロファイリングが有効かどうか
+    pub fn profiling(&self) -> bool {
+        self.profiling.load(Ordering::Relaxed)
+    }
+
+    /// プロファイリングを有効にすると、`generate_with_report`が各ステップの処理時間を計測したレポートを返します。
+    /// 計測のために生成ごとに読み戻しが1回増えるため、必要なときだけ有効にしてください。
+    pub fn set_profiling(&self, enabled: bool) {
+        self.profiling.store(enabled, Ordering::Relaxed);
+    }
+
+    /// GPUのタイムスタンプでコンピュートパスの実行時間を計測できるかどうか
+    pub fn supports_gpu_timestamps(&self) -> bool {
+        self.device
+            .features()
+            .contains(wgpu::Features::TIMESTAMP_QUERY)
+    }
+
+    /// プロファイリングが有効な場合、1回の生成の計測結果を集めるインスタンスを作成します。
+    pub(crate) fn with_profiler(&self) -> Self {
+        if !self.profiling() {
+            return self.clone();
+        }
+        Self {
+            profiler: Some(Arc::new(Mutex::new(Profiler {
+                start: Instant::now(),
+                steps: Vec::new(),
+                timestamps: self.supports_gpu_timestamps(),
+                query_sets: Vec::new(),
+                passes: Vec::new(),
+            }))),
+            ..self.clone()
+        }
+    }
+
+    /// 項目の計測を開始します。
+    pub(crate) fn profile_span(
+        &self,
+        kind: StepKind,
+        id: &str,
+        step_index: Option<usize>,
+        resolution: Option<(u32, u32)>,
+    ) -> ProfileSpan {
+        let start = Instant::now();
+        let Some(profiler) = &self.profiler else {
+            return ProfileSpan {
+                profiler: None,
+                index: 0,
+                start,
+            };
+        };
+
+        let mut locked = profiler.lock().unwrap();
+        let index = locked.steps.len();
+        let cpu_start_ms = start.duration_since(locked.start).as_secs_f64() * 1000.0;
+        locked.steps.push(StepReport {
+            id: id.to_string(),
+            kind,
+            step_index,
+            resolution,
+            cpu_start_ms,
+            cpu_ms: 0.0,
+            gpu_start_ms: None,
+            gpu_ms: None,
+            cache: None,
+        });
+        ProfileSpan {
+            profiler: Some(profiler.clone()),
+            index,
+            start,
+        }
+    }
+
+    /// 計測結果をレポートにまとめます。プロファイリングが無効な場合は`None`を返します。
+    /// タイムスタンプを読み戻すため、生成のコマンドをすべてサブミットしてから呼び出してください。
+    pub(crate) async fn finish_profile(&self) -> Result<Option<ExecutionReport>> {
+        let Some(profiler) = &self.profiler else {
+            return Ok(None);
+        };
+        let (start, mut steps, timestamps, query_sets, passes) = {
+            let mut profiler = profiler.lock().unwrap();
+            (
+                profiler.start,
+                std::mem::take(&mut profiler.steps),
+                profiler.timestamps,
+                std::mem::take(&mut profiler.query_sets),
+                std::mem::take(&mut profiler.passes),
+            )
+        };
+
+        if !passes.is_empty() {
+            let ticks = self
+                .read_timestamps(&query_sets, passes.len() as u32)
+                .await?;
+            // タイムスタンプの単位はティックで、1ティックあたりのナノ秒はキューから取得する
+            let period = self.queue.get_timestamp_period() as f64;
+            let origin = ticks.chunks_exact(2).map(|t| t[0]).min().unwrap_or(0);
+            for (step, pass) in passes.iter().zip(ticks.chunks_exact(2)) {
+                let to_ms = |tick: u64| tick.saturating_sub(origin) as f64 * period / 1_000_000.0;
+                let (begin, end) = (to_ms(pass[0]), to_ms(pass[1]));
+                let report = &mut steps[*step];
+                report.gpu_start_ms = Some(report.gpu_start_ms.map_or(begin, |s| s.min(begin)));
+                report.gpu_ms = Some(report.gpu_ms.unwrap_or(0.0) + (end - begin).max(0.0));
+            }
+        }
+
+        Ok(Some(ExecutionReport {
+            steps,
+            total_ms: start.elapsed().as_secs_f64() * 1000.0,
+            gpu_timestamps: timestamps,
+        }))
+    }
+
+    // 書き込んだタイムスタンプをクエリセットごとに解決して読み戻す
+    async fn read_timestamps(
+        &self,
+        query_sets: &[wgpu::QuerySet],
+        passes: u32,
+    ) -> Result<Vec<u64>> {
+        let (commands, downloads) = self.with_error_scope(None, None, || {
+            let mut encoder = self
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Profiler Resolve Encoder"),
+                });
+            let mut downloads = Vec::with_capacity(query_sets.len());
+            let mut remaining = passes * 2;
+            for query_set in query_sets {
+                let count = remaining.min(TIMESTAMP_QUERY_SET_SIZE);
+                remaining -= count;
+                let size = count as u64 * size_of::<u64>() as u64;
+                let resolve = self.get_or_create_buffer(
+                    size,
+                    wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+                    Some("Profiler Resolve Buffer"),
+                );
+                encoder.resolve_query_set(query_set, 0..count, &resolve, 0);
+                downloads.push(self.record_buffer_download(&mut encoder, &resolve, size));
+            }
+            Ok((encoder.finish(), downloads))
+        })?;
+        let data = self
+            .submit_and_read(None, None, Some(commands), &downloads)
+            .await?;
+        Ok(data
+            .iter()
+            .flat_map(|d| {
+                d.chunks_exact(size_of::<u64>())
+                    .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+                    .collect::<Vec<_>>()
+            })
+            .collect())
+    }
+}
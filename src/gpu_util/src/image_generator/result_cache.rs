@@ -0,0 +1,362 @@
+// image_generator/result_cache.rs
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use crate::image_generator::{ImageGenerator, ProcessingState, StepOutput};
+use tracing::{debug, trace};
+
+/// 結果キャッシュのデフォルトの容量 (バイト)
+pub const DEFAULT_RESULT_CACHE_BYTES: u64 = 256 * 1024 * 1024;
+
+/// ステップの出力の内容を表すキー。
+/// 同じキーの出力は、同じ処理を同じ入力に対して行った結果であり、内容が一致します。
+pub(crate) type ContentKey = u64;
+
+/// 値のハッシュからキーを作ります。
+pub(crate) fn content_key(value: impl Hash) -> ContentKey {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// 結果キャッシュの統計。
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResultCacheStats {
+    /// キャッシュから結果を返したステップの数。
+    pub hits: u64,
+    /// キャッシュになく、実行したステップの数。
+    pub misses: u64,
+    /// 容量を超えたために破棄した結果の数。
+    pub evictions: u64,
+    /// 保持している結果の数。
+    pub entries: usize,
+    /// 保持している結果の合計のバイト数。
+    pub bytes: u64,
+}
+
+// キャッシュされた結果
+struct CachedResult {
+    outputs: ProcessingState,
+    bytes: u64,
+}
+
+/// 内容のキーで引ける、ステップの出力のキャッシュ。
+///
+/// 保存されたテクスチャはキャッシュが参照を持つため、テクスチャキャッシュから他の生成に貸し出されず、内容が保たれます。
+/// 生成の途中の結果はサブミットの前に読まれないよう、その生成が終わってから`publish_results`で登録します。
+pub(crate) struct ResultCache {
+    entries: HashMap<ContentKey, CachedResult>,
+    // LRU順序 (先頭が最新、末尾が最も古い)
+    order: VecDeque<ContentKey>,
+    // 保持している出力のテクスチャ・CPUデータのアドレスから、その内容のキー
+    keys: HashMap<usize, ContentKey>,
+    max_bytes: u64,
+    stats: ResultCacheStats,
+}
+
+impl Default for ResultCache {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            keys: HashMap::new(),
+            max_bytes: DEFAULT_RESULT_CACHE_BYTES,
+            stats: ResultCacheStats::default(),
+        }
+    }
+}
+
+impl ResultCache {
+    fn insert(&mut self, key: ContentKey, outputs: ProcessingState) {
+        let bytes = outputs.iter().map(output_bytes).sum();
+        if bytes > self.max_bytes || self.entries.contains_key(&key) {
+            return;
+        }
+        for (i, output) in outputs.iter().enumerate() {
+            // サブパイプラインとその最後のステップのように、同じ出力を複数の結果が持つ場合は先に登録したキーを使う
+            self.keys
+                .entry(output_address(output))
+                .or_insert_with(|| content_key((key, i)));
+        }
+        self.entries.insert(key, CachedResult { outputs, bytes });
+        self.order.push_front(key);
+        self.stats.bytes += bytes;
+        self.evict(self.max_bytes);
+    }
+
+    // 結果を返し、LRU順序と統計を更新する
+    fn get(&mut self, key: ContentKey) -> Option<ProcessingState> {
+        let Some(result) = self.entries.get(&key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        let outputs = result.outputs.clone();
+        if let Some(pos) = self.order.iter().position(|k| *k == key) {
+            self.order.remove(pos);
+        }
+        self.order.push_front(key);
+        self.stats.hits += 1;
+        Some(outputs)
+    }
+
+    fn stats(&self) -> ResultCacheStats {
+        ResultCacheStats {
+            entries: self.entries.len(),
+            ..self.stats
+        }
+    }
+
+    // 合計のバイト数がmax_bytes以下になるまで古いものから破棄する
+    fn evict(&mut self, max_bytes: u64) {
+        while self.stats.bytes > max_bytes {
+            let Some(oldest) = self.order.pop_back() else {
+                break;
+            };
+            if let Some(result) = self.entries.remove(&oldest) {
+                debug!(
+                    key = oldest,
+                    bytes = result.bytes,
+                    "Evicted result from cache"
+                );
+                for (i, output) in result.outputs.iter().enumerate() {
+                    let address = output_address(output);
+                    if self.keys.get(&address) == Some(&content_key((oldest, i))) {
+                        self.keys.remove(&address);
+                    }
+                }
+                self.stats.bytes -= result.bytes;
+                self.stats.evictions += 1;
+            }
+        }
+    }
+}
+
+// 出力を識別するアドレス。キャッシュが参照を持つ間は他の出力と重ならない
+fn output_address(output: &StepOutput) -> usize {
+    match output {
+        StepOutput::Gpu { texture, .. } => Arc::as_ptr(texture) as usize,
+        StepOutput::Cpu { data, .. } => Arc::as_ptr(data) as usize,
+    }
+}
+
+fn output_bytes(output: &StepOutput) -> u64 {
+    match output {
+        StepOutput::Gpu { texture, .. } => {
+            let texel_size = texture.format().block_copy_size(None).unwrap_or(16) as u64;
+            texel_size
+                * texture.width() as u64
+                * texture.height() as u64
+                * texture.depth_or_array_layers() as u64
+        }
+        StepOutput::Cpu { data, .. } => (data.len() * size_of::<f32>()) as u64,
+    }
+}
+
+impl ImageGenerator {
+    /// 結果キャッシュの統計を返します。
+    pub fn result_cache_stats(&self) -> ResultCacheStats {
+        self.result_cache.lock().unwrap().stats()
+    }
+
+    /// 結果キャッシュの容量 (バイト)
+    pub fn max_result_cache_bytes(&self) -> u64 {
+        self.result_cache.lock().unwrap().max_bytes
+    }
+
+    /// 結果キャッシュの容量を設定します。0の場合は結果をキャッシュしません。
+    /// 新しい容量が現在の合計より小さい場合、古い結果から破棄されます。
+    pub fn set_max_result_cache_bytes(&self, bytes: u64) {
+        let mut cache = self.result_cache.lock().unwrap();
+        cache.max_bytes = bytes;
+        cache.evict(bytes);
+    }
+
+    /// 結果キャッシュのすべての結果を破棄します。統計はそのまま残ります。
+    pub fn clear_result_cache(&self) {
+        self.result_cache.lock().unwrap().evict(0);
+    }
+
+    /// 出力の内容のキーを返します。キャッシュの結果か、この生成で保存した結果でなければ`None`を返します。
+    pub(crate) fn output_content_key(&self, output: &StepOutput) -> Option<ContentKey> {
+        let address = output_address(output);
+        if let Some(lease) = &self.lease {
+            let lease = lease.lock().unwrap();
+            if let Some((key, i)) = lease.results.iter().find_map(|(key, outputs)| {
+                outputs
+                    .iter()
+                    .position(|o| output_address(o) == address)
+                    .map(|i| (*key, i))
+            }) {
+                return Some(content_key((key, i)));
+            }
+        }
+        self.result_cache
+            .lock()
+            .unwrap()
+            .keys
+            .get(&address)
+            .copied()
+    }
+
+    /// すべての入力の内容のキーと色空間を返します。キーのない入力がある場合は`None`を返します。
+    pub(crate) fn state_content_key(&self, state: &ProcessingState) -> Option<ContentKey> {
+        let mut keys = Vec::with_capacity(state.len());
+        for output in state {
+            let (color_space, alpha_mode) = match output {
+                StepOutput::Gpu {
+                    color_space,
+                    alpha_mode,
+                    ..
+                }
+                | StepOutput::Cpu {
+                    color_space,
+                    alpha_mode,
+                    ..
+                } => (*color_space, *alpha_mode),
+            };
+            keys.push((self.output_content_key(output)?, color_space, alpha_mode));
+        }
+        Some(content_key(keys))
+    }
+
+    /// キャッシュされた結果を返します。結果はこの生成が終わるまで貸し出されます。
+    pub(crate) fn lookup_result(&self, key: ContentKey) -> Option<ProcessingState> {
+        let mut cache = self.result_cache.lock().unwrap();
+        if cache.max_bytes == 0 {
+            return None;
+        }
+        let Some(outputs) = cache.get(key) else {
+            trace!(key, "Result cache miss");
+            return None;
+        };
+        drop(cache);
+        trace!(key, "Result cache hit");
+
+        // キャッシュから破棄されても、読み出しが終わるまでは他の生成に貸し出さない
+        if let Some(lease) = &self.lease {
+            let mut lease = lease.lock().unwrap();
+            for output in &outputs {
+                if let StepOutput::Gpu { texture, .. } = output {
+                    lease.textures.push(texture.clone());
+                }
+            }
+        }
+        Some(outputs)
+    }
+
+    /// ステップの結果を、この生成が終わったときにキャッシュに登録するよう記録します。
+    /// 結果のテクスチャは、このパイプラインの後続のステップでは再利用されなくなります。
+    pub(crate) fn store_result(&self, key: ContentKey, outputs: &ProcessingState) {
+        let Some(lease) = &self.lease else {
+            return;
+        };
+        if self.result_cache.lock().unwrap().max_bytes == 0 {
+            return;
+        }
+        if let Some(transients) = &self.transients {
+            let mut transients = transients.lock().unwrap();
+            for output in outputs {
+                if let StepOutput::Gpu { texture, .. } = output {
+                    transients.forget(texture);
+                }
+            }
+        }
+        lease.lock().unwrap().results.push((key, outputs.clone()));
+    }
+
+    /// この生成で記録した結果をキャッシュに登録します。
+    /// 結果を書き込むコマンドがすべて完了してから呼び出してください。
+    pub(crate) fn publish_results(&self) {
+        let Some(lease) = &self.lease else {
+            return;
+        };
+        let results = std::mem::take(&mut lease.lock().unwrap().results);
+        if !results.is_empty() {
+            debug!(results = results.len(), "Publishing results to cache");
+        }
+        let mut cache = self.result_cache.lock().unwrap();
+        for (key, outputs) in results {
+            cache.insert(key, outputs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color_space::{AlphaMode, ColorSpace};
+
+    // 画素数pixelsのCPUの出力。バイト数はpixels * 16
+    fn output(pixels: usize) -> StepOutput {
+        StepOutput::Cpu {
+            data: Arc::new(vec![0.0; pixels * 4]),
+            width: pixels as u32,
+            height: 1,
+            color_space: ColorSpace::SRGB,
+            alpha_mode: AlphaMode::Straight,
+        }
+    }
+
+    fn cache(max_bytes: u64) -> ResultCache {
+        ResultCache {
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn insert_and_get_count_hits_and_misses() {
+        let mut cache = cache(1024);
+        let stored = output(2);
+        cache.insert(1, vec![stored.clone()]);
+        assert!(cache.get(2).is_none());
+        let hit = cache.get(1).unwrap();
+        assert_eq!(output_address(&hit[0]), output_address(&stored));
+        // 出力のアドレスから内容のキーを引ける
+        assert_eq!(
+            cache.keys.get(&output_address(&stored)),
+            Some(&content_key((1u64, 0usize)))
+        );
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!((stats.entries, stats.bytes), (1, 32));
+    }
+
+    #[test]
+    fn insert_ignores_duplicates_and_oversized_results() {
+        let mut cache = cache(64);
+        cache.insert(1, vec![output(2)]);
+        cache.insert(1, vec![output(3)]);
+        cache.insert(2, vec![output(5)]);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (1, 32, 0));
+        assert_eq!(output_bytes(&cache.get(1).unwrap()[0]), 32);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_results() {
+        let mut cache = cache(96);
+        cache.insert(1, vec![output(2)]);
+        cache.insert(2, vec![output(2)]);
+        cache.insert(3, vec![output(2)]);
+        // 1を使ったので、次に容量を超えたときは2が破棄される
+        cache.get(1);
+        cache.insert(4, vec![output(2)]);
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(1).is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (3, 96, 1));
+
+        cache.evict(0);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (0, 0, 4));
+        // 破棄した出力のキーも残さない
+        assert!(cache.keys.is_empty());
+        assert!(cache.order.is_empty());
+    }
+}
@@ -0,0 +1,348 @@
+// image_generator/transfer.rs
+
+use std::sync::Arc;
+
+use crate::error::GpuUtilError;
+use crate::image_generator::ImageGenerator;
+use anyhow::{Context, Result};
+use futures::channel::oneshot;
+
+// ステージングバッファのサイズの単位。近いサイズの転送で同じバッファを使い回せるように切り上げる
+const STAGING_SIZE_GRANULARITY: u64 = 64 * 1024;
+
+impl ImageGenerator {
+    /// 転送用のステージングバッファを取得します。
+    /// サイズは`STAGING_SIZE_GRANULARITY`単位に切り上げてプールされ、生成が終わるまで貸し出されます。
+    pub(crate) fn get_staging_buffer(
+        &self,
+        size: u64,
+        usage: wgpu::BufferUsages,
+        label: Option<&str>,
+    ) -> Arc<wgpu::Buffer> {
+        let size = size.max(1).div_ceil(STAGING_SIZE_GRANULARITY) * STAGING_SIZE_GRANULARITY;
+        self.get_or_create_buffer(size, usage, label)
+    }
+
+    /// CPUのデータをテクスチャにアップロードするコピーをencoderに記録します。
+    /// dataは行の間に余白のないテクセルの列で、行ごとに256バイト境界へ揃えてステージングバッファに書き込みます。
+    pub(crate) fn upload_texture(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+        data: &[u8],
+    ) -> Result<()> {
+        let layout = RowLayout::for_texture(texture)?;
+        let staging = self.get_staging_buffer(
+            layout.padded_size(),
+            wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+            Some("Upload Staging Buffer"),
+        );
+
+        // write_bufferは次のサブミットの前に実行されるため、encoderのコピーより先に反映される
+        self.queue.write_buffer(&staging, 0, &layout.pad(data));
+
+        encoder.copy_buffer_to_texture(
+            layout.buffer_copy(&staging),
+            texture.as_image_copy(),
+            texture.size(),
+        );
+        Ok(())
+    }
+
+    /// テクスチャの内容をステージングバッファにコピーする処理をencoderに記録します。
+    /// encoderをサブミットした後、返り値の`PendingDownload`から読み出せます。
+    pub(crate) fn record_texture_download(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+    ) -> Result<PendingDownload> {
+        let layout = RowLayout::for_texture(texture)?;
+        let staging = self.get_staging_buffer(
+            layout.padded_size(),
+            wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            Some("Download Staging Buffer"),
+        );
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            layout.buffer_copy(&staging),
+            texture.size(),
+        );
+        Ok(PendingDownload {
+            buffer: staging,
+            layout,
+        })
+    }
+
+    /// バッファの先頭からsizeバイトをステージングバッファにコピーする処理をencoderに記録します。
+    /// コピーは4バイト単位に切り上げて行い、読み出し時にsizeバイトに切り詰めます。
+    pub(crate) fn record_buffer_download(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        buffer: &wgpu::Buffer,
+        size: u64,
+    ) -> PendingDownload {
+        let download = self.buffer_download(size);
+        download.record_buffer_copy(encoder, buffer);
+        download
+    }
+
+    /// sizeバイトのバッファを読み戻すためのステージングバッファを用意します。
+    /// コピーは`PendingDownload::record_buffer_copy`で記録し、同じステージングバッファに繰り返し読み戻せます。
+    pub(crate) fn buffer_download(&self, size: u64) -> PendingDownload {
+        let layout = RowLayout::for_buffer(size);
+        let staging = self.get_staging_buffer(
+            layout.padded_size(),
+            wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            Some("Download Staging Buffer"),
+        );
+        PendingDownload {
+            buffer: staging,
+            layout,
+        }
+    }
+
+    /// テクスチャの内容を読み戻し、texturesの順に返します。
+    /// 読み戻しのコピーは先行するコマンドバッファと一緒に1回でサブミットされ、完了を待ってから返ります。
+    pub(crate) async fn download_textures(
+        &self,
+        step_index: usize,
+        textures: &[Arc<wgpu::Texture>],
+        preceding: impl IntoIterator<Item = wgpu::CommandBuffer>,
+    ) -> Result<Vec<Vec<u8>>> {
+        let (commands, downloads) = self.with_error_scope(Some(step_index), None, || {
+            let mut encoder = self
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Download Encoder"),
+                });
+            let downloads = textures
+                .iter()
+                .map(|texture| self.record_texture_download(&mut encoder, texture))
+                .collect::<Result<Vec<_>>>()?;
+            Ok((encoder.finish(), downloads))
+        })?;
+        self.submit_and_read(
+            Some(step_index),
+            None,
+            preceding.into_iter().chain(std::iter::once(commands)),
+            &downloads,
+        )
+        .await
+    }
+
+    /// コマンドバッファをサブミットし、完了を待ってからdownloadsを追加した順に読み出します。
+    /// サブミット、マッピング、読み出しはそれぞれエラースコープの中で行います。
+    pub(crate) async fn submit_and_read(
+        &self,
+        step_index: Option<usize>,
+        shader_id: Option<&str>,
+        commands: impl IntoIterator<Item = wgpu::CommandBuffer>,
+        downloads: &[PendingDownload],
+    ) -> Result<Vec<Vec<u8>>> {
+        let submission =
+            self.with_error_scope(step_index, shader_id, || Ok(self.queue.submit(commands)))?;
+        // サブミットが拒否された場合にマッピングしたままのバッファが残らないよう、成功してからマッピングする
+        let receivers = self.with_error_scope(step_index, shader_id, || {
+            Ok(downloads.iter().map(|d| d.map()).collect::<Vec<_>>())
+        })?;
+        self.wait_for_downloads(submission, receivers).await?;
+        self.with_error_scope(step_index, shader_id, || {
+            Ok(downloads.iter().map(|d| d.read()).collect())
+        })
+    }
+
+    /// 指定したサブミットの完了と、それに含まれる読み戻しのマッピングを待ちます。
+    /// デバイス全体ではなくそのサブミットまでの処理だけを待つため、後からサブミットされた処理は待ちません。
+    /// ポーリングは専用のスレッドで行うため、待っている間も呼び出したスレッドはブロックされません。
+    pub(crate) async fn wait_for_downloads(
+        &self,
+        submission: wgpu::SubmissionIndex,
+        receivers: impl IntoIterator<Item = MapReceiver>,
+    ) -> Result<()> {
+        self.poller.wait(Some(submission)).await?;
+        for receiver in receivers {
+            receiver
+                .await
+                .map_err(|_| {
+                    GpuUtilError::readback("Failed to receive buffer mapping result".to_string())
+                })?
+                .map_err(|e| GpuUtilError::readback(format!("Failed to map buffer: {}", e)))?;
+        }
+        Ok(())
+    }
+}
+
+/// 読み戻しのマッピングの完了を受け取るレシーバー
+pub(crate) type MapReceiver = oneshot::Receiver<Result<(), wgpu::BufferAsyncError>>;
+
+// コピー元・先の行の配置
+#[derive(Debug, Clone, Copy)]
+struct RowLayout {
+    row_size: u32,
+    padded_row_size: u32,
+    rows: u32,
+}
+
+impl RowLayout {
+    fn for_texture(texture: &wgpu::Texture) -> Result<Self> {
+        Self::for_texels(texture.format(), texture.width(), texture.height())
+    }
+
+    fn for_texels(format: wgpu::TextureFormat, width: u32, height: u32) -> Result<Self> {
+        let texel_size = format
+            .block_copy_size(None)
+            .with_context(|| format!("Texture format {:?} cannot be copied", format))?;
+        let row_size = width * texel_size;
+        Ok(Self {
+            row_size,
+            // bytes_per_rowは256の倍数である必要がある
+            padded_row_size: row_size.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+                * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT,
+            rows: height,
+        })
+    }
+
+    // バッファのコピーは1行として扱い、サイズを4バイトの倍数に切り上げる
+    fn for_buffer(size: u64) -> Self {
+        Self {
+            row_size: size as u32,
+            padded_row_size: size.next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT) as u32,
+            rows: 1,
+        }
+    }
+
+    fn padded_size(&self) -> u64 {
+        self.padded_row_size as u64 * self.rows as u64
+    }
+
+    /// 行の間に余白のないデータに、行ごとの余白を入れたバイト列を作ります。
+    fn pad(&self, data: &[u8]) -> Vec<u8> {
+        let mut padded = vec![0u8; self.padded_size() as usize];
+        for (src, dst) in data
+            .chunks_exact(self.row_size as usize)
+            .zip(padded.chunks_exact_mut(self.padded_row_size as usize))
+        {
+            dst[..src.len()].copy_from_slice(src);
+        }
+        padded
+    }
+
+    /// 行ごとの余白を除いたバイト列を作ります。
+    fn unpad(&self, data: &[u8]) -> Vec<u8> {
+        if self.row_size == self.padded_row_size {
+            data.to_vec()
+        } else {
+            data.chunks_exact(self.padded_row_size as usize)
+                .take(self.rows as usize)
+                .flat_map(|row| &row[..self.row_size as usize])
+                .copied()
+                .collect()
+        }
+    }
+
+    fn buffer_copy<'a>(&self, buffer: &'a wgpu::Buffer) -> wgpu::TexelCopyBufferInfo<'a> {
+        wgpu::TexelCopyBufferInfo {
+            buffer,
+            layout: wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(self.padded_row_size),
+                rows_per_image: Some(self.rows),
+            },
+        }
+    }
+}
+
+/// サブミット後に読み戻すステージングバッファ。
+pub(crate) struct PendingDownload {
+    buffer: Arc<wgpu::Buffer>,
+    layout: RowLayout,
+}
+
+impl PendingDownload {
+    /// バッファの先頭からステージングバッファへのコピーをencoderに記録します。`buffer_download`で用意したものに使います。
+    pub(crate) fn record_buffer_copy(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        buffer: &wgpu::Buffer,
+    ) {
+        let size = self.layout.padded_size();
+        encoder.copy_buffer_to_buffer(buffer, 0, &self.buffer, 0, size);
+    }
+
+    /// ステージングバッファのマッピングを要求します。コピーを記録したエンコーダをサブミットしてから呼び出してください。
+    pub(crate) fn map(&self) -> MapReceiver {
+        let (tx, rx) = oneshot::channel();
+        self.buffer.slice(..self.layout.padded_size()).map_async(
+            wgpu::MapMode::Read,
+            move |result| {
+                let _ = tx.send(result);
+            },
+        );
+        rx
+    }
+
+    /// マッピングが完了したバッファから行の余白を除いたバイト列をコピーし、アンマップします。
+    pub(crate) fn read(&self) -> Vec<u8> {
+        let data = self
+            .buffer
+            .slice(..self.layout.padded_size())
+            .get_mapped_range();
+        let result = self.layout.unpad(&data);
+        drop(data); // get_mapped_rangeの借用を解除
+        self.buffer.unmap();
+        result
+    }
+
+    /// 読み出さずにマッピングを解除します。マッピングの完了を待ってから呼び出してください。
+    pub(crate) fn discard(&self) {
+        self.buffer.unmap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn texture_rows_are_padded_to_256_bytes() {
+        // 3テクセル x 4バイト = 12バイトの行は256バイトに揃える
+        let layout = RowLayout::for_texels(wgpu::TextureFormat::Rgba8Unorm, 3, 2).unwrap();
+        assert_eq!(layout.row_size, 12);
+        assert_eq!(layout.padded_row_size, 256);
+        assert_eq!(layout.padded_size(), 512);
+
+        // ちょうど256バイトの行には余白を入れない
+        let layout = RowLayout::for_texels(wgpu::TextureFormat::Rgba32Float, 16, 3).unwrap();
+        assert_eq!(layout.padded_row_size, 256);
+        assert_eq!(layout.padded_size(), 768);
+
+        assert!(RowLayout::for_texels(wgpu::TextureFormat::Depth24Plus, 4, 4).is_err());
+    }
+
+    #[test]
+    fn pad_and_unpad_round_trip() {
+        let layout = RowLayout::for_texels(wgpu::TextureFormat::Rgba8Unorm, 3, 2).unwrap();
+        let data: Vec<u8> = (0..24).collect();
+        let padded = layout.pad(&data);
+        assert_eq!(padded.len(), 512);
+        assert_eq!(&padded[..12], &data[..12]);
+        assert!(padded[12..256].iter().all(|&b| b == 0));
+        assert_eq!(&padded[256..268], &data[12..]);
+        assert_eq!(layout.unpad(&padded), data);
+    }
+
+    #[test]
+    fn buffer_copies_are_rounded_to_four_bytes() {
+        let layout = RowLayout::for_buffer(6);
+        assert_eq!(layout.padded_size(), 8);
+        // 読み戻すときは切り上げた分を除く
+        assert_eq!(
+            layout.unpad(&[1, 2, 3, 4, 5, 6, 0, 0]),
+            vec![1, 2, 3, 4, 5, 6]
+        );
+
+        let layout = RowLayout::for_buffer(8);
+        assert_eq!(layout.padded_size(), 8);
+        assert_eq!(layout.unpad(&[0; 8]).len(), 8);
+    }
+}
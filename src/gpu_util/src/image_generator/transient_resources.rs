@@ -0,0 +1,140 @@
+// image_generator/transient_resources.rs
+
+use std::sync::Arc;
+
+use crate::image_generator::{ProcessingState, StepOutput, TextureCacheKey};
+
+/// 1つのパイプライン (並列処理のサブパイプラインを含む) の中で確保した一時的なテクスチャの寿命を管理します。
+///
+/// ステップの出力は次のステップ (サブパイプラインの最後の出力は並列処理の次のステップ) だけが読むため、
+/// ステップを記録し終えた時点で状態に残っていないテクスチャは寿命が終わっています。
+/// 同じパイプラインのコマンドバッファは記録した順にサブミットされるので、寿命が終わったテクスチャは
+/// 後続のステップで別の中間結果のメモリとして再利用できます。
+/// 並列に記録されるサブパイプライン同士はサブミットの順序が記録の順序と一致しないため、
+/// 再利用はそれぞれのパイプラインの中だけで行い、サブパイプラインが終わってから親に引き渡します。
+/// テストではテクスチャの代わりに任意の型を管理できます。
+pub(crate) struct TransientResources<T = wgpu::Texture> {
+    // このパイプラインで確保し、まだ寿命が終わっていないテクスチャ
+    live: Vec<(TextureCacheKey, Arc<T>)>,
+    // 寿命が終わり、このパイプラインの後続のステップで再利用できるテクスチャ
+    free: Vec<(TextureCacheKey, Arc<T>)>,
+}
+
+impl<T> Default for TransientResources<T> {
+    fn default() -> Self {
+        Self {
+            live: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+}
+
+impl TransientResources {
+    /// ステップの記録が終わったときに呼び出し、次のステップに渡す状態に含まれないテクスチャの寿命を終わらせます。
+    pub(crate) fn end_step(&mut self, state: &ProcessingState) {
+        self.retain_live(|texture| {
+            state.iter().any(|output| {
+                matches!(output, StepOutput::Gpu { texture: t, .. } if Arc::ptr_eq(t, texture))
+            })
+        });
+    }
+}
+
+impl<T> TransientResources<T> {
+    /// 寿命が終わったテクスチャのうち、キーが一致するものを取り出します。
+    pub(crate) fn reuse(&mut self, key: &TextureCacheKey) -> Option<Arc<T>> {
+        let pos = self.free.iter().position(|(k, _)| k == key)?;
+        let (key, texture) = self.free.swap_remove(pos);
+        self.live.push((key, texture.clone()));
+        Some(texture)
+    }
+
+    /// 新しく確保したテクスチャを登録します。
+    pub(crate) fn track(&mut self, key: TextureCacheKey, texture: Arc<T>) {
+        self.live.push((key, texture));
+    }
+
+    /// テクスチャを管理の対象から外し、後続のステップで再利用されないようにします。
+    pub(crate) fn forget(&mut self, texture: &Arc<T>) {
+        self.live.retain(|(_, t)| !Arc::ptr_eq(t, texture));
+        self.free.retain(|(_, t)| !Arc::ptr_eq(t, texture));
+    }
+
+    // keepがfalseを返すテクスチャの寿命を終わらせる
+    fn retain_live(&mut self, keep: impl Fn(&Arc<T>) -> bool) {
+        let (live, dead): (Vec<_>, Vec<_>) =
+            self.live.drain(..).partition(|(_, texture)| keep(texture));
+        self.live = live;
+        self.free.extend(dead);
+    }
+
+    /// 終了したサブパイプラインのテクスチャを引き継ぎます。
+    /// サブパイプラインの出力は親の次のステップで読まれ、それ以外は親の後続のステップで再利用できます。
+    pub(crate) fn absorb(&mut self, child: TransientResources<T>) {
+        self.live.extend(child.live);
+        self.free.extend(child.free);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(width: u32) -> TextureCacheKey {
+        TextureCacheKey {
+            width,
+            height: 1,
+            layers: 1,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::STORAGE_BINDING,
+        }
+    }
+
+    #[test]
+    fn retired_textures_are_reused_by_key() {
+        let mut resources = TransientResources::default();
+        let (a, b) = (Arc::new("a"), Arc::new("b"));
+        resources.track(key(1), a.clone());
+        resources.track(key(2), b.clone());
+        assert!(resources.reuse(&key(1)).is_none());
+
+        // 次のステップに渡すbだけが生き残る
+        resources.retain_live(|t| Arc::ptr_eq(t, &b));
+        assert!(resources.reuse(&key(2)).is_none());
+        let reused = resources.reuse(&key(1)).unwrap();
+        assert!(Arc::ptr_eq(&reused, &a));
+        // 再利用したものは再び寿命が終わるまで貸し出さない
+        assert!(resources.reuse(&key(1)).is_none());
+        resources.retain_live(|_| false);
+        assert!(resources.reuse(&key(1)).is_some());
+        assert!(resources.reuse(&key(2)).is_some());
+    }
+
+    #[test]
+    fn forgotten_textures_are_not_reused() {
+        let mut resources = TransientResources::default();
+        let a = Arc::new("a");
+        resources.track(key(1), a.clone());
+        resources.forget(&a);
+        resources.retain_live(|_| false);
+        assert!(resources.reuse(&key(1)).is_none());
+    }
+
+    #[test]
+    fn absorb_takes_over_live_and_retired_textures() {
+        let mut parent = TransientResources::default();
+        let mut child = TransientResources::default();
+        let (output, scratch) = (Arc::new("output"), Arc::new("scratch"));
+        child.track(key(1), scratch.clone());
+        child.track(key(1), output.clone());
+        child.retain_live(|t| Arc::ptr_eq(t, &output));
+
+        parent.absorb(child);
+        // サブパイプラインの中で寿命が終わったものは親の後続のステップで再利用できる
+        assert!(Arc::ptr_eq(&parent.reuse(&key(1)).unwrap(), &scratch));
+        assert!(parent.reuse(&key(1)).is_none());
+        // 出力は親の次のステップで読まれるまで生きている
+        parent.retain_live(|t| !Arc::ptr_eq(t, &output));
+        assert!(Arc::ptr_eq(&parent.reuse(&key(1)).unwrap(), &output));
+    }
+}
@@ -1,68 +1,657 @@
+use std::sync::Arc;
+
 use crate::{
+    color_space::{AlphaMode, ColorConversion, ColorSpace},
     compiled_wgsl::CompiledWgsl,
-    image_generator::{ImageGenerator, PipelineCacheKey, ProcessingState, StepOutput},
+    error::{GpuUtilError, WithStepIndex},
+    execution_report::{CacheStatus, StepKind},
+    image_generate_builder::WgslStepOptions,
+    image_generator::{
+        color_process::{prepare_conversion, PreparedConversion},
+        error_scope::{capture_errors, to_gpu_util_error},
+        profiler::ProfileSpan,
+        result_cache::{content_key, ContentKey},
+        ImageGenerator, PipelineCacheKey, ProcessingState, StepOutput,
+    },
+    shader_reflection::{BindingRole, InputLayout, ShaderReflection},
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
 use wgpu::util::DeviceExt;
 
+/// `InputLayout::TextureArray`用に、すべての入力を詰める配列テクスチャ。
+/// 配列テクスチャの解像度は入力の最大値に揃え、各入力の実際の解像度をストレージバッファに持ちます。
+struct PackedInputs {
+    array_texture: Arc<wgpu::Texture>,
+    sizes_buffer: wgpu::Buffer,
+    sources: Vec<Arc<wgpu::Texture>>,
+}
+
+impl PackedInputs {
+    fn new(
+        generator: &ImageGenerator,
+        input_textures: &[Arc<wgpu::Texture>],
+        format: wgpu::TextureFormat,
+        step_index: usize,
+    ) -> Self {
+        let max_width = input_textures.iter().map(|t| t.width()).max().unwrap_or(1);
+        let max_height = input_textures.iter().map(|t| t.height()).max().unwrap_or(1);
+        // GLバックエンドではレイヤー数1のテクスチャは2Dテクスチャとして作成され、配列として扱えないため最低2レイヤー確保する
+        let layers = (input_textures.len() as u32).max(2);
+
+        let array_texture = generator.get_or_create_texture(
+            wgpu::Extent3d {
+                width: max_width,
+                height: max_height,
+                depth_or_array_layers: layers,
+            },
+            format,
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            Some(&format!("Step {} Packed Input Array", step_index)),
+        );
+
+        let mut sizes: Vec<[u32; 2]> = input_textures
+            .iter()
+            .map(|t| [t.width(), t.height()])
+            .collect();
+        // 空のストレージバッファはバインドできないため、入力がない場合は解像度0の要素を1つ入れる
+        if sizes.is_empty() {
+            sizes.push([0, 0]);
+        }
+
+        let sizes_buffer = generator
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("Step {} Input Sizes", step_index)),
+                contents: bytemuck::cast_slice(&sizes),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+
+        Self {
+            array_texture,
+            sizes_buffer,
+            sources: input_textures.to_vec(),
+        }
+    }
+
+    /// 入力を配列テクスチャのレイヤーへコピーする処理を記録します。
+    fn record(&self, encoder: &mut wgpu::CommandEncoder) {
+        for (layer, texture) in self.sources.iter().enumerate() {
+            encoder.copy_texture_to_texture(
+                texture.as_image_copy(),
+                wgpu::TexelCopyTextureInfo {
+                    texture: &self.array_texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer as u32,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::Extent3d {
+                    width: texture.width(),
+                    height: texture.height(),
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+    }
+}
+
+// 準備済みのステップの入力
+enum PreparedInput {
+    // 前のステップのテクスチャを、必要なら作業用の色空間に変換して使う
+    Gpu {
+        source: Arc<wgpu::Texture>,
+        color_space: ColorSpace,
+        alpha_mode: AlphaMode,
+        conversion: Option<PreparedConversion>,
+    },
+    // CPUのデータを変換してからアップロードする
+    Cpu {
+        width: u32,
+        height: u32,
+        color_space: ColorSpace,
+        alpha_mode: AlphaMode,
+        conversion: ColorConversion,
+        upload: Arc<wgpu::Texture>,
+    },
+}
+
+// 準備済みのステップがバインドするバッファ
+struct PreparedBuffer {
+    name: String,
+    // paramsのデータを渡したバッファかどうか
+    from_params: bool,
+    // 渡されたデータのバイト数
+    size: usize,
+    buffer: wgpu::Buffer,
+}
+
+/// 入力の検証を終え、出力テクスチャ、パイプライン、バインドグループ、バッファまで作成済みのWGSLステップ。
+/// 準備したときと同じ入力に対しては、`record`を繰り返し呼び出して再利用できます。
+pub struct PreparedWgslStep {
+    pub(crate) name: Option<String>,
+    pub(crate) wgsl: Arc<CompiledWgsl>,
+    pub(crate) entry_point: String,
+    pub(crate) step_index: usize,
+    inputs: Vec<PreparedInput>,
+    packed: Option<PackedInputs>,
+    pipeline: Arc<wgpu::ComputePipeline>,
+    bind_groups: Vec<wgpu::BindGroup>,
+    buffers: Vec<PreparedBuffer>,
+    workgroups: [u32; 3],
+    pub(crate) output: StepOutput,
+}
+
+impl PreparedWgslStep {
+    /// 出力の解像度
+    pub(crate) fn output_size(&self) -> Option<(u32, u32)> {
+        match self.output {
+            StepOutput::Gpu { width, height, .. } | StepOutput::Cpu { width, height, .. } => {
+                Some((width, height))
+            }
+        }
+    }
+
+    /// 入力のアップロードと変換、コンピュートパスを記録します。
+    /// stateは準備したときと同じテクスチャと、同じ解像度のCPUデータである必要があります。
+    /// コンピュートパスの実行時間はspanの項目に記録されます。
+    pub(crate) fn record(
+        &self,
+        generator: &ImageGenerator,
+        state: &ProcessingState,
+        span: &ProfileSpan,
+    ) -> Result<(ProcessingState, Vec<wgpu::CommandBuffer>)> {
+        if state.len() != self.inputs.len() {
+            bail!(GpuUtilError::binding(
+                Some(&self.wgsl.id),
+                format!(
+                    "the step was prepared for {} inputs, but received {}",
+                    self.inputs.len(),
+                    state.len()
+                ),
+            )
+            .at_step(self.step_index));
+        }
+
+        let mut encoder = generator.device.create_command_encoder(&Default::default());
+        for (i, (prepared, input)) in self.inputs.iter().zip(state).enumerate() {
+            match (prepared, input) {
+                (
+                    PreparedInput::Gpu {
+                        source,
+                        color_space,
+                        alpha_mode,
+                        conversion,
+                    },
+                    StepOutput::Gpu {
+                        texture,
+                        color_space: cs,
+                        alpha_mode: am,
+                        ..
+                    },
+                ) if Arc::ptr_eq(source, texture) && color_space == cs && alpha_mode == am => {
+                    if let Some(conversion) = conversion {
+                        let timestamps = span.pass_timestamps(&generator.device);
+                        conversion.record(&mut encoder, timestamps.as_ref());
+                    }
+                }
+                (
+                    PreparedInput::Cpu {
+                        width,
+                        height,
+                        color_space,
+                        alpha_mode,
+                        conversion,
+                        upload,
+                    },
+                    StepOutput::Cpu {
+                        data,
+                        width: w,
+                        height: h,
+                        color_space: cs,
+                        alpha_mode: am,
+                    },
+                ) if width == w && height == h && color_space == cs && alpha_mode == am => {
+                    let upload_span = generator.profile_span(
+                        StepKind::Upload,
+                        StepKind::Upload.as_str(),
+                        Some(self.step_index),
+                        Some((*w, *h)),
+                    );
+                    // アップロード前にCPU上で作業用の色空間とシェーダーのアルファの形式に変換する
+                    let converted;
+                    let data = if conversion.is_identity() {
+                        data.as_slice()
+                    } else {
+                        let mut copy = data.to_vec();
+                        conversion.convert_cpu(&mut copy);
+                        converted = copy;
+                        converted.as_slice()
+                    };
+                    // ステージングバッファ経由のコピーをこのステップのエンコーダに記録する
+                    generator.upload_texture(&mut encoder, upload, bytemuck::cast_slice(data))?;
+                    upload_span.finish(None);
+                }
+                _ => bail!(GpuUtilError::binding(
+                    Some(&self.wgsl.id),
+                    format!(
+                        "input {} differs from the input the step was prepared for",
+                        i
+                    ),
+                )
+                .at_step(self.step_index)),
+            }
+        }
+        self.record_pass(generator, &mut encoder, span);
+
+        Ok((vec![self.output.clone()], vec![encoder.finish()]))
+    }
+
+    /// 入力がすべて準備したときのGPUのテクスチャである場合に、入力の変換とコンピュートパスをencoderに記録します。
+    /// 入力はその都度渡さず、準備したときのテクスチャをそのまま使います。
+    pub(crate) fn record_direct(
+        &self,
+        generator: &ImageGenerator,
+        encoder: &mut wgpu::CommandEncoder,
+        span: &ProfileSpan,
+    ) -> Result<()> {
+        for (i, prepared) in self.inputs.iter().enumerate() {
+            match prepared {
+                PreparedInput::Gpu { conversion, .. } => {
+                    if let Some(conversion) = conversion {
+                        let timestamps = span.pass_timestamps(&generator.device);
+                        conversion.record(encoder, timestamps.as_ref());
+                    }
+                }
+                PreparedInput::Cpu { .. } => bail!(GpuUtilError::binding(
+                    Some(&self.wgsl.id),
+                    format!(
+                        "input {} is CPU data and must be uploaded on every execution",
+                        i
+                    ),
+                )
+                .at_step(self.step_index)),
+            }
+        }
+        self.record_pass(generator, encoder, span);
+        Ok(())
+    }
+
+    /// 入力がすべてGPUのテクスチャかどうかを返します。
+    pub(crate) fn has_gpu_inputs_only(&self) -> bool {
+        self.inputs
+            .iter()
+            .all(|input| matches!(input, PreparedInput::Gpu { .. }))
+    }
+
+    // まとめたバッファのコピーとコンピュートパスを記録する
+    fn record_pass(
+        &self,
+        generator: &ImageGenerator,
+        encoder: &mut wgpu::CommandEncoder,
+        span: &ProfileSpan,
+    ) {
+        if let Some(packed) = &self.packed {
+            packed.record(encoder);
+        }
+
+        // --- コンピュートパスの実行 ---
+        let timestamps = span.pass_timestamps(&generator.device);
+        let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(&format!("Step {} Compute Pass", self.step_index)),
+            timestamp_writes: timestamps.as_ref().map(|t| t.writes()),
+        });
+        cpass.set_pipeline(&self.pipeline);
+        for (group, bind_group) in self.bind_groups.iter().enumerate() {
+            cpass.set_bind_group(group as u32, bind_group, &[]);
+        }
+
+        let [x, y, z] = self.workgroups;
+        cpass.dispatch_workgroups(x, y, z);
+    }
+
+    /// バッファの変数名がparamsのデータを渡したバッファかどうかを返します。
+    pub(crate) fn is_params_buffer(&self, name: &str) -> bool {
+        self.buffers.iter().any(|b| b.name == name && b.from_params)
+    }
+
+    /// bufferで指定したバッファ (`None`の場合はparamsを渡したバッファ) を、len バイトのデータで書き換えられるか検証し、
+    /// 書き込み先のバッファを返します。バッファの大きさは準備したときに渡したデータから変えられません。
+    pub(crate) fn update_targets(
+        &self,
+        buffer: Option<&str>,
+        len: usize,
+    ) -> Result<Vec<&wgpu::Buffer>> {
+        let targets: Vec<_> = self
+            .buffers
+            .iter()
+            .filter(|b| match buffer {
+                Some(name) => b.name == name,
+                None => b.from_params,
+            })
+            .collect();
+        if targets.is_empty() {
+            match buffer {
+                Some(name) => bail!(GpuUtilError::binding(
+                    Some(&self.wgsl.id),
+                    format!("there is no buffer named `{}`", name),
+                )
+                .at_step(self.step_index)),
+                None => bail!(GpuUtilError::binding(
+                    Some(&self.wgsl.id),
+                    "the step was prepared without params".to_string(),
+                )
+                .at_step(self.step_index)),
+            }
+        }
+        for target in &targets {
+            if target.size != len {
+                bail!(GpuUtilError::size(
+                    Some(&self.wgsl.id),
+                    format!(
+                        "buffer `{}` was prepared with {} bytes, but {} bytes were given",
+                        target.name, target.size, len
+                    ),
+                )
+                .at_step(self.step_index));
+            }
+        }
+        Ok(targets.into_iter().map(|b| &b.buffer).collect())
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn handle_wgsl_step(
+    generator: &ImageGenerator,
+    state: &ProcessingState,
+    wgsl: &Arc<CompiledWgsl>,
+    params: Option<&[u8]>,
+    options: &WgslStepOptions,
+    step_index: usize,
+    final_step: bool,
+    output_width: u32,
+    output_height: u32,
+) -> Result<(ProcessingState, Vec<wgpu::CommandBuffer>)> {
+    let span = generator.profile_span(
+        StepKind::Wgsl,
+        &wgsl.id,
+        Some(step_index),
+        Some((output_width, output_height)),
+    );
+
+    // キャッシュが有効で入力の内容が分かる場合、同じ入力とパラメータで実行した結果があればそれを返す。
+    // パイプラインの最後のステップは毎回後処理に渡されるだけなので、結果を保持しない
+    let cache_key = if !options.cache || final_step {
+        None
+    } else {
+        result_key(
+            generator,
+            state,
+            wgsl,
+            params,
+            options,
+            output_width,
+            output_height,
+        )
+    };
+    if let Some(outputs) = cache_key.and_then(|key| generator.lookup_result(key)) {
+        span.finish(Some(CacheStatus::Hit));
+        return Ok((outputs, Vec::new()));
+    }
+
+    let (new_state, commands) =
+        generator.with_error_scope(Some(step_index), Some(&wgsl.id), || {
+            prepare_wgsl_step(
+                generator,
+                state,
+                wgsl,
+                params,
+                options,
+                step_index,
+                output_width,
+                output_height,
+            )?
+            .record(generator, state, &span)
+        })?;
+    if let Some(key) = cache_key {
+        generator.store_result(key, &new_state);
+    }
+    span.finish(cache_key.map(|_| CacheStatus::Miss));
+    Ok((new_state, commands))
+}
+
+/// 結果キャッシュのキーを、シェーダー、パラメータ、設定、入力の内容から作ります。
+/// 内容が分からない入力がある場合は`None`を返します。
+fn result_key(
     generator: &ImageGenerator,
     state: &ProcessingState,
     wgsl: &CompiledWgsl,
-    params: &Option<Vec<u8>>,
+    params: Option<&[u8]>,
+    options: &WgslStepOptions,
+    output_width: u32,
+    output_height: u32,
+) -> Option<ContentKey> {
+    let inputs = generator.state_content_key(state)?;
+    let mut buffers: Vec<_> = options.buffers.iter().collect();
+    buffers.sort_unstable();
+    Some(content_key((
+        (
+            &wgsl.id,
+            wgsl.source_hash,
+            wgsl.sampler_hash,
+            &options.entry_point,
+        ),
+        (params, buffers),
+        (
+            options.dispatch,
+            options.color_space,
+            options.alpha_mode,
+            generator.working_space,
+        ),
+        (output_width, output_height),
+        inputs,
+    )))
+}
+
+/// WGSLステップの入力を検証し、出力テクスチャ、パイプライン、バインドグループ、バッファを作成します。
+/// CPUのデータの入力は解像度と色空間だけを使い、データのアップロードは`PreparedWgslStep::record`で行います。
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn prepare_wgsl_step(
+    generator: &ImageGenerator,
+    state: &ProcessingState,
+    wgsl: &Arc<CompiledWgsl>,
+    params: Option<&[u8]>,
+    options: &WgslStepOptions,
     step_index: usize,
     output_width: u32,
     output_height: u32,
-) -> Result<(ProcessingState, Vec<wgpu::CommandEncoder>)> {
+) -> Result<PreparedWgslStep> {
     // --- 入力データの準備 ---
-    // すべての入力をGPUバッファに変換する。
-    let mut encoder = generator.device.create_command_encoder(&Default::default());
-    let mut input_texture_views: Vec<wgpu::TextureView> = Vec::with_capacity(state.len());
+    // すべての入力を作業用の色空間のGPUテクスチャに変換する。
+    let mut inputs = Vec::with_capacity(state.len());
+    let mut input_textures: Vec<Arc<wgpu::Texture>> = Vec::with_capacity(state.len());
 
     for (i, input) in state.iter().enumerate() {
         match input {
-            StepOutput::Gpu { texture, .. } => {
-                input_texture_views.push(texture.create_view(&Default::default()));
+            StepOutput::Gpu {
+                texture,
+                color_space,
+                alpha_mode,
+                ..
+            } => {
+                let conversion = ColorConversion::new(
+                    *color_space,
+                    *alpha_mode,
+                    generator.working_space,
+                    options.alpha_mode,
+                );
+                let conversion = prepare_conversion(generator, texture, &conversion, step_index)?;
+                input_textures.push(conversion.as_ref().map_or(texture, |c| c.output()).clone());
+                inputs.push(PreparedInput::Gpu {
+                    source: texture.clone(),
+                    color_space: *color_space,
+                    alpha_mode: *alpha_mode,
+                    conversion,
+                });
             }
             StepOutput::Cpu {
-                data,
                 width,
                 height,
+                color_space,
+                alpha_mode,
+                ..
             } => {
+                // アップロード前にCPU上で作業用の色空間とシェーダーのアルファの形式に変換する
+                let conversion = ColorConversion::new(
+                    *color_space,
+                    *alpha_mode,
+                    generator.working_space,
+                    options.alpha_mode,
+                );
+
                 // CPUデータをGPUにアップロード - キャッシュされたテクスチャを使用
                 let texture = generator.get_or_create_texture(
-                    step_index,
-                    *width,
-                    *height,
-                    wgpu::TextureFormat::Rgba32Float,
-                    wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-                    Some(&format!("Step {} WGSL Input Upload {}", step_index, i)),
-                );
-                generator.queue.write_texture(
-                    texture.as_image_copy(),
-                    bytemuck::cast_slice(data),
-                    wgpu::TexelCopyBufferLayout {
-                        offset: 0,
-                        bytes_per_row: Some(4 * 4 * *width), // 4 (bytes/f32) * 4 (components) * width
-                        rows_per_image: None,
-                    },
                     wgpu::Extent3d {
                         width: *width,
                         height: *height,
                         depth_or_array_layers: 1,
                     },
+                    wgpu::TextureFormat::Rgba32Float,
+                    wgpu::TextureUsages::TEXTURE_BINDING
+                        | wgpu::TextureUsages::COPY_DST
+                        | wgpu::TextureUsages::COPY_SRC,
+                    Some(&format!("Step {} WGSL Input Upload {}", step_index, i)),
                 );
-                input_texture_views.push(texture.create_view(&Default::default()));
+                input_textures.push(texture.clone());
+                inputs.push(PreparedInput::Cpu {
+                    width: *width,
+                    height: *height,
+                    color_space: *color_space,
+                    alpha_mode: *alpha_mode,
+                    conversion,
+                    upload: texture,
+                });
+            }
+        }
+    }
+
+    let reflection = wgsl.reflection(options.entry_point.as_deref())?;
+    match reflection.input_layout {
+        InputLayout::BindingArray => {
+            if !generator.supports_binding_array() {
+                bail!(GpuUtilError::device(
+                    Some(&wgsl.id),
+                    "the shader takes its inputs as a binding_array, which is not supported by the current adapter".to_string(),
+                )
+                .at_step(step_index));
+            }
+        }
+        InputLayout::TextureArray => {
+            // 配列テクスチャへのコピーはフォーマットが一致している必要がある
+            if let Some(first) = input_textures.first() {
+                if let Some(other) = input_textures.iter().find(|t| t.format() != first.format()) {
+                    bail!(GpuUtilError::binding(
+                        Some(&wgsl.id),
+                        format!(
+                            "the shader takes its inputs as a texture_2d_array, which requires all inputs to have the same format, but received {:?} and {:?}",
+                            first.format(),
+                            other.format()
+                        ),
+                    )
+                    .at_step(step_index));
+                }
+            }
+        }
+        InputLayout::Individual => {
+            let declared: Vec<_> = reflection.individual_inputs().collect();
+            if declared.len() != input_textures.len() {
+                bail!(GpuUtilError::binding(
+                    Some(&wgsl.id),
+                    format!(
+                        "the shader declares {} input textures [{}], but received {} inputs",
+                        declared.len(),
+                        declared
+                            .iter()
+                            .map(|b| b.describe())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        input_textures.len()
+                    ),
+                )
+                .at_step(step_index));
             }
         }
     }
+    // 入力はすべて`texture_2d<f32>`としてバインドされる
+    let filterable = generator
+        .device
+        .features()
+        .contains(wgpu::Features::FLOAT32_FILTERABLE);
+    for (i, texture) in input_textures.iter().enumerate() {
+        match texture
+            .format()
+            .sample_type(None, Some(generator.device.features()))
+        {
+            Some(wgpu::TextureSampleType::Float { filterable: f }) if f || !filterable => {}
+            _ => bail!(GpuUtilError::binding(
+                Some(&wgsl.id),
+                format!(
+                    "input {} has format {:?}, which cannot be bound as texture_2d<f32>",
+                    i,
+                    texture.format()
+                ),
+            )
+            .at_step(step_index)),
+        }
+    }
+
+    let output_format = reflection.output_format();
+    if !generator.supports_output_format(output_format) {
+        bail!(GpuUtilError::device(
+            Some(&wgsl.id),
+            format!(
+                "the shader writes its output as {:?}, which cannot be used as a storage texture on the current adapter",
+                output_format
+            ),
+        )
+        .at_step(step_index));
+    }
+
+    // ステップを記録する前に、バッファに渡すデータを確定させる
+    let buffer_data = reflection
+        .resolve_buffers(&wgsl.id, params, &options.buffers)
+        .with_step_index(step_index)?;
+
+    // ワークグループ数はシェーダーの@workgroup_sizeから計算する
+    let workgroups =
+        options
+            .dispatch
+            .workgroup_counts(output_width, output_height, reflection.workgroup_size);
+    let max_workgroups = generator
+        .device
+        .limits()
+        .max_compute_workgroups_per_dimension;
+    if workgroups.iter().any(|&n| n > max_workgroups) {
+        bail!(GpuUtilError::size(
+            Some(&wgsl.id),
+            format!(
+                "the shader dispatches {:?} workgroups, which exceeds the adapter limit of {} per dimension",
+                workgroups, max_workgroups
+            ),
+        )
+        .at_step(step_index));
+    }
 
     // --- 出力テクスチャの作成 ---
     let output_texture = generator.get_or_create_texture(
-        step_index,
-        output_width,
-        output_height,
-        wgpu::TextureFormat::Rgba32Float,
+        wgpu::Extent3d {
+            width: output_width,
+            height: output_height,
+            depth_or_array_layers: 1,
+        },
+        output_format,
         wgpu::TextureUsages::TEXTURE_BINDING
             | wgpu::TextureUsages::STORAGE_BINDING
             | wgpu::TextureUsages::COPY_SRC,
@@ -73,86 +662,209 @@ pub fn handle_wgsl_step(
     // --- パイプラインの取得 ---
     let key = PipelineCacheKey {
         id: wgsl.id.clone(),
-        input_texture_count: input_texture_views.len(),
-        has_storage: params.is_some(),
-        has_sampler: wgsl.sampler.is_some(),
+        source_hash: wgsl.source_hash,
+        entry_point: reflection.entry_point.clone(),
+        input_array_len: if reflection.has_unsized_input_array() {
+            input_textures.len()
+        } else {
+            0
+        },
     };
-    let cached_pipeline = generator.get_or_create_pipeline(&key, &wgsl.module)?;
-
-    // --- バインドグループ0 (テクスチャ) の構築 ---
-    let mut bg_entries_group0 = Vec::new();
-    let input_texture_view_refs: Vec<_> = input_texture_views.iter().collect();
-    if !input_texture_views.is_empty() {
-        bg_entries_group0.push(wgpu::BindGroupEntry {
-            binding: 0,
-            resource: wgpu::BindingResource::TextureViewArray(&input_texture_view_refs),
-        });
+    let cached_pipeline = generator
+        .get_or_create_pipeline(&key, wgsl)
+        .with_step_index(step_index)?;
+
+    // --- バインドグループに渡すリソースの準備 ---
+    // 入力の渡し方は入力規約によって異なる (詳細は`InputLayout`を参照)
+    let input_texture_views: Vec<_> = input_textures
+        .iter()
+        .map(|t| t.create_view(&Default::default()))
+        .collect();
+
+    // binding_arrayの要素数に満たない分は空のテクスチャで埋める
+    let array_len = reflection
+        .bindings
+        .iter()
+        .find_map(|b| match b.role {
+            BindingRole::InputArray { size } => Some(
+                size.map(|s| s.get() as usize)
+                    .unwrap_or(input_textures.len().max(1)),
+            ),
+            _ => None,
+        })
+        .unwrap_or(0);
+    if input_texture_views.len() > array_len && array_len > 0 {
+        bail!(GpuUtilError::binding(
+            Some(&wgsl.id),
+            format!(
+                "the shader accepts at most {} inputs, but received {}",
+                array_len,
+                input_texture_views.len()
+            ),
+        )
+        .at_step(step_index));
     }
-    bg_entries_group0.push(wgpu::BindGroupEntry {
-        // inputがなければbinding=0、あればbinding=1になる想定
-        binding: if input_texture_views.is_empty() { 0 } else { 1 },
-        resource: wgpu::BindingResource::TextureView(&output_texture_view),
+    let empty_input_view = (input_texture_views.len() < array_len).then(|| {
+        generator
+            .get_or_create_texture(
+                wgpu::Extent3d {
+                    width: 1,
+                    height: 1,
+                    depth_or_array_layers: 1,
+                },
+                wgpu::TextureFormat::Rgba32Float,
+                wgpu::TextureUsages::TEXTURE_BINDING,
+                Some(&format!("Step {} Empty Input", step_index)),
+            )
+            .create_view(&Default::default())
     });
-
-    // サンプラーのバインディング (存在する場合)
-    if let Some(sampler) = &wgsl.sampler {
-        bg_entries_group0.push(wgpu::BindGroupEntry {
-            binding: if input_texture_views.is_empty() { 1 } else { 2 },
-            resource: wgpu::BindingResource::Sampler(sampler.as_ref()),
-        });
+    let mut input_texture_view_refs: Vec<_> = input_texture_views.iter().collect();
+    if let Some(view) = &empty_input_view {
+        input_texture_view_refs.resize(array_len, view);
     }
 
-    let bind_group_0 = generator
-        .device
-        .create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some(&format!("Step {} BG Group 0", step_index)),
-            layout: &cached_pipeline.pipeline.get_bind_group_layout(0),
-            entries: &bg_entries_group0,
-        });
+    let packed = (reflection.input_layout == InputLayout::TextureArray).then(|| {
+        let format = input_textures
+            .first()
+            .map_or(wgpu::TextureFormat::Rgba32Float, |t| t.format());
+        PackedInputs::new(generator, &input_textures, format, step_index)
+    });
+    let packed_view = packed.as_ref().map(|packed| {
+        packed
+            .array_texture
+            .create_view(&wgpu::TextureViewDescriptor {
+                dimension: Some(wgpu::TextureViewDimension::D2Array),
+                ..Default::default()
+            })
+    });
 
-    // --- コンピュートパスの実行 ---
-    {
-        let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-            label: Some(&format!("Step {} Compute Pass", step_index)),
-            ..Default::default()
-        });
-        cpass.set_pipeline(&cached_pipeline.pipeline);
-        cpass.set_bind_group(0, &bind_group_0, &[]);
-
-        // --- バインドグループ1 (Storage Bufferパラメータ) の構築とセット ---
-        if let Some(p) = params {
-            let storage_buffer =
-                generator
-                    .device
-                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                        label: Some(&format!("Step {} Storage Buffer", step_index)),
-                        contents: p,
-                        usage: wgpu::BufferUsages::STORAGE,
-                    });
-            let bind_group_1 = generator
+    // 準備したステップを再利用するときに内容を書き換えられるよう、COPY_DSTを付けておく
+    let data_buffers: Vec<_> = buffer_data
+        .iter()
+        .map(|(binding, data)| {
+            let usage = match binding.role {
+                BindingRole::Buffer { uniform: true, .. } => wgpu::BufferUsages::UNIFORM,
+                _ => wgpu::BufferUsages::STORAGE,
+            };
+            let buffer = generator
                 .device
-                .create_bind_group(&wgpu::BindGroupDescriptor {
-                    label: Some(&format!("Step {} BG Group 1", step_index)),
-                    layout: &cached_pipeline.pipeline.get_bind_group_layout(1),
-                    entries: &[wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                            buffer: &storage_buffer,
-                            offset: 0,
-                            size: None,
-                        }),
-                    }],
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(&format!("Step {} Buffer {}", step_index, binding.name)),
+                    contents: data,
+                    usage: usage | wgpu::BufferUsages::COPY_DST,
                 });
-            cpass.set_bind_group(1, &bind_group_1, &[]);
+            PreparedBuffer {
+                name: binding.name.clone(),
+                from_params: !options.buffers.contains_key(&binding.name),
+                size: data.len(),
+                buffer,
+            }
+        })
+        .collect();
+
+    // --- バインドグループの構築 ---
+    // リフレクションで得たバインディングごとに、役割に応じたリソースを割り当てる
+    let mut bind_groups = Vec::with_capacity(reflection.group_count() as usize);
+    for group in 0..reflection.group_count() {
+        let mut entries = Vec::new();
+        for binding in reflection.group(group) {
+            let resource = match &binding.role {
+                BindingRole::Input(index) => {
+                    wgpu::BindingResource::TextureView(&input_texture_views[*index])
+                }
+                BindingRole::InputArray { .. } => {
+                    wgpu::BindingResource::TextureViewArray(&input_texture_view_refs)
+                }
+                BindingRole::PackedInputs => match &packed_view {
+                    Some(array_view) => wgpu::BindingResource::TextureView(array_view),
+                    None => unreachable!("packed inputs are prepared for TextureArray"),
+                },
+                BindingRole::InputSizes => match &packed {
+                    Some(packed) => packed.sizes_buffer.as_entire_binding(),
+                    None => unreachable!("packed inputs are prepared for TextureArray"),
+                },
+                BindingRole::Output(_) => wgpu::BindingResource::TextureView(&output_texture_view),
+                BindingRole::Sampler => match wgsl.sampler_for(&binding.name) {
+                    Some(sampler) => wgpu::BindingResource::Sampler(sampler),
+                    None => bail!(GpuUtilError::binding(
+                        Some(&wgsl.id),
+                        format!(
+                            "there are no sampler options for sampler {}",
+                            binding.describe()
+                        ),
+                    )
+                    .at_step(step_index)),
+                },
+                BindingRole::Buffer { .. } => {
+                    // resolve_buffersですべてのバッファにデータが割り当てられている
+                    data_buffers
+                        .iter()
+                        .find(|b| b.name == binding.name)
+                        .expect("every buffer binding is resolved")
+                        .buffer
+                        .as_entire_binding()
+                }
+            };
+            entries.push(wgpu::BindGroupEntry {
+                binding: binding.binding,
+                resource,
+            });
         }
 
-        cpass.dispatch_workgroups((output_width + 15) / 16, (output_height + 15) / 16, 1);
+        let (bind_group, error) = capture_errors(&generator.device, || {
+            generator
+                .device
+                .create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some(&format!("Step {} BG Group {}", step_index, group)),
+                    layout: &cached_pipeline.pipeline.get_bind_group_layout(group),
+                    entries: &entries,
+                })
+        });
+        if let Some(error) = error {
+            let binding = failed_binding(reflection, group, &error.to_string());
+            bail!(to_gpu_util_error(error, Some(&wgsl.id), Some(binding)).at_step(step_index));
+        }
+        bind_groups.push(bind_group);
     }
 
-    let new_state = vec![StepOutput::Gpu {
-        texture: output_texture,
-        width: output_width,
-        height: output_height,
-    }];
-    Ok((new_state, vec![encoder]))
+    Ok(PreparedWgslStep {
+        name: options.name.clone(),
+        wgsl: wgsl.clone(),
+        entry_point: reflection.entry_point.clone(),
+        step_index,
+        inputs,
+        packed,
+        pipeline: cached_pipeline.pipeline,
+        bind_groups,
+        buffers: data_buffers,
+        workgroups,
+        output: StepOutput::Gpu {
+            texture: output_texture,
+            width: output_width,
+            height: output_height,
+            color_space: options.color_space.unwrap_or(generator.working_space),
+            alpha_mode: options.alpha_mode,
+        },
+    })
+}
+
+/// バインドグループの作成に失敗したときのwgpuのエラーから、原因のバインディングを特定します。
+/// エラーがバインディングを示していない場合はグループを返します。
+fn failed_binding(reflection: &ShaderReflection, group: u32, message: &str) -> String {
+    let message = message.to_lowercase();
+    // wgpuのエラーはバインディングを番号 (`binding 1`) か、バッファのラベルで示す
+    let mentions = |needle: &str| {
+        message.match_indices(needle).any(|(i, _)| {
+            !message[i + needle.len()..]
+                .starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        })
+    };
+    let mut bindings = reflection.group(group).filter(|b| {
+        mentions(&format!("binding {}", b.binding))
+            || mentions(&format!("buffer {}", b.name.to_lowercase()))
+    });
+    match (bindings.next(), bindings.next()) {
+        (Some(binding), None) => binding.describe(),
+        _ => format!("@group({})", group),
+    }
 }
@@ -1,21 +1,45 @@
 use anyhow::Result;
-use numpy::{PyReadonlyArray1, ToPyArray};
-use pyo3::{exceptions::PyValueError, prelude::*, types::*};
+use numpy::{PyArray1, PyArrayMethods, PyReadonlyArray1, ToPyArray};
+use pyo3::{
+    buffer::PyBuffer,
+    exceptions::{PyRuntimeError, PyTypeError, PyValueError},
+    prelude::*,
+    types::*,
+};
 use pyo3_stub_gen::{
     define_stub_info_gatherer,
-    derive::{gen_stub_pyclass, gen_stub_pymethods},
+    derive::{gen_stub_pyclass, gen_stub_pyfunction, gen_stub_pymethods},
 };
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 use tokio::runtime::Runtime;
 
 use crate::{
+    color_space::{AlphaMode, ColorSpace},
     compiled_func::{CpuFunction, CpuInputImage, CpuOutput},
-    image_generate_builder::ImageGenerateBuilder,
+    executable_plan::{ExecutablePlan, ParamUpdate},
+    execution_report::{ExecutionReport, StepReport},
+    generator_options::{AdapterDescription, AdapterSelector, ImageGeneratorOptions},
+    image_generate_builder::{Dispatch, ImageGenerateBuilder, WgslStepOptions},
+    image_generator::result_cache::ResultCacheStats,
+    output_format::{OutputFormat, OutputOptions},
+    param_layout::{ParamLayout, ParamType, ParamValue},
+    pipeline_graph::{GraphBuilder, GraphInput},
 };
 
+pub mod color_space;
 pub mod compiled_func;
 pub mod compiled_wgsl;
+pub mod error;
+pub mod executable_plan;
+pub mod execution_report;
+pub mod generator_options;
 pub mod image_generate_builder;
 pub mod image_generator;
+pub mod output_format;
+pub mod param_layout;
+mod pipeline_disk_cache;
+pub mod pipeline_graph;
+pub mod shader_reflection;
 
 // Pythonで動かすためのライブラリのラッパーを作る
 #[gen_stub_pyclass]
@@ -44,11 +68,256 @@ pub struct PyImageGenerateBuilder {
     pub inner: image_generate_builder::ImageGenerateBuilder,
 }
 
+#[gen_stub_pyclass]
+#[pyclass]
+#[derive(Clone)]
+pub struct PyImageGraphBuilder {
+    pub inner: pipeline_graph::GraphBuilder,
+}
+
+#[gen_stub_pyclass]
+#[pyclass]
+pub struct PyExecutablePlan {
+    pub inner: Arc<ExecutablePlan>,
+    rt: Arc<Runtime>,
+}
+
+/// 他の例外の基底クラスになる例外を作成します。
+/// pyo3_stub_genの`create_exception!`はスタブで型を`builtins`のものとして参照するため、
+/// サブクラスの基底クラスとして使えるようにモジュール内の名前で参照させます。
+macro_rules! create_base_exception {
+    ($module: ident, $name: ident, $base: ty, $doc: expr) => {
+        pyo3::create_exception!($module, $name, $base, $doc);
+
+        impl pyo3_stub_gen::PyStubType for $name {
+            fn type_output() -> pyo3_stub_gen::TypeInfo {
+                pyo3_stub_gen::TypeInfo::unqualified(stringify!($name))
+            }
+        }
+
+        pyo3_stub_gen::inventory::submit! {
+            pyo3_stub_gen::type_info::PyClassInfo {
+                pyclass_name: stringify!($name),
+                struct_id: std::any::TypeId::of::<$name>,
+                getters: &[],
+                setters: &[],
+                module: Some(stringify!($module)),
+                doc: $doc,
+                bases: &[|| <$base as pyo3_stub_gen::PyStubType>::type_output()],
+                has_eq: false,
+                has_ord: false,
+                has_hash: false,
+                has_str: false,
+                subclass: true,
+            }
+        }
+    };
+}
+
+create_base_exception!(
+    gpu_util,
+    GpuUtilError,
+    pyo3::exceptions::PyException,
+    "gpu_utilの処理で発生する例外の基底クラス。\n\n\
+     属性としてstep_index (エラーが発生したステップの位置) とshader_id (シェーダーのID) を持ち、\
+     分からない場合はNoneになります。"
+);
+pyo3_stub_gen::create_exception!(
+    gpu_util,
+    DeviceError,
+    GpuUtilError,
+    "アダプタやデバイスの取得に失敗した場合や、アダプタが必要な機能に対応していない場合の例外。"
+);
+create_base_exception!(
+    gpu_util,
+    ShaderError,
+    GpuUtilError,
+    "シェーダーのコンパイルに失敗した場合の例外。"
+);
+pyo3_stub_gen::create_exception!(
+    gpu_util,
+    BindingError,
+    GpuUtilError,
+    "ステップの入力、バッファ、サンプラーなどがシェーダーの宣言と一致しない場合の例外。"
+);
+pyo3_stub_gen::create_exception!(
+    gpu_util,
+    SizeError,
+    GpuUtilError,
+    "データの大きさや解像度、ディスパッチの数が期待と一致しない、または上限を超えた場合の例外。"
+);
+pyo3_stub_gen::create_exception!(
+    gpu_util,
+    CpuFuncError,
+    GpuUtilError,
+    "CPU関数が失敗した場合の例外。\n\n\
+     Pythonの関数が例外を送出した場合は、その例外が__cause__に設定されます。"
+);
+pyo3_stub_gen::create_exception!(
+    gpu_util,
+    ValidationError,
+    GpuUtilError,
+    "wgpuやバックエンドがリソースの作成やコマンドを拒否した場合の例外。\n\n\
+     属性としてbinding (原因のバインディング。特定できない場合はNone) も持ちます。"
+);
+pyo3_stub_gen::create_exception!(
+    gpu_util,
+    ReadbackError,
+    GpuUtilError,
+    "GPUの処理の完了待ちや、結果の読み戻しに失敗した場合の例外。"
+);
+pyo3_stub_gen::create_exception!(
+    gpu_util,
+    ShaderCompileError,
+    ShaderError,
+    "WGSLの解析・検証に失敗した場合の例外。\n\n\
+     属性としてfile_id, line, column, message, snippetを持ちます。"
+);
+
+impl From<compiled_wgsl::ShaderCompileError> for PyErr {
+    fn from(e: compiled_wgsl::ShaderCompileError) -> Self {
+        shader_compile_err(&e, e.to_string())
+    }
+}
+
+fn shader_compile_err(e: &compiled_wgsl::ShaderCompileError, message: String) -> PyErr {
+    Python::attach(|py| {
+        let err = ShaderCompileError::new_err(message);
+        let value = err.value(py);
+        // 属性の設定に失敗するのはメモリ不足などの場合のみなので、失敗しても元のエラーを返す
+        let _ = value.setattr("file_id", &e.file_id);
+        let _ = value.setattr("line", e.line);
+        let _ = value.setattr("column", e.column);
+        let _ = value.setattr("message", &e.message);
+        let _ = value.setattr("snippet", &e.snippet);
+        err
+    })
+}
+
+/// `GpuUtilError`を含むエラーを、対応するPythonの例外に変換します。
+/// それ以外のエラーはpyo3の変換に任せます。
+fn to_py_err(e: anyhow::Error) -> PyErr {
+    let Some(error) = e.downcast_ref::<error::GpuUtilError>() else {
+        return PyErr::from(e);
+    };
+    // contextで付けられた説明も含めたメッセージ
+    let message = format!("{:#}", e);
+    Python::attach(|py| {
+        let err = match error {
+            error::GpuUtilError::Device { .. } => DeviceError::new_err(message),
+            error::GpuUtilError::ShaderCompile { error, .. } => shader_compile_err(error, message),
+            error::GpuUtilError::Binding { .. } => BindingError::new_err(message),
+            error::GpuUtilError::Size { .. } => SizeError::new_err(message),
+            error::GpuUtilError::CpuFunc { error, .. } => {
+                let err = CpuFuncError::new_err(message);
+                if let Some(cause) = error.downcast_ref::<PyErr>() {
+                    err.set_cause(py, Some(cause.clone_ref(py)));
+                }
+                err
+            }
+            error::GpuUtilError::Validation { binding, .. } => {
+                let err = ValidationError::new_err(message);
+                let _ = err.value(py).setattr("binding", binding);
+                err
+            }
+            error::GpuUtilError::Readback { .. } => ReadbackError::new_err(message),
+        };
+        let value = err.value(py);
+        let _ = value.setattr("step_index", error.step_index());
+        let _ = value.setattr("shader_id", error.shader_id());
+        err
+    })
+}
+
+#[gen_stub_pyclass]
+#[pyclass]
+pub struct PyImageGeneratorOptions {
+    pub inner: ImageGeneratorOptions,
+}
+
+#[gen_stub_pyclass]
+#[pyclass]
+pub struct PyAdapterInfo {
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub backend: String,
+    #[pyo3(get)]
+    pub device_type: String,
+    #[pyo3(get)]
+    pub driver: String,
+    #[pyo3(get)]
+    pub driver_info: String,
+    #[pyo3(get)]
+    pub features: Vec<String>,
+}
+
+/// 実行レポートの1つの項目
+#[gen_stub_pyclass]
+#[pyclass]
+pub struct PyStepReport {
+    /// WGSLステップとパイプラインの作成ではシェーダーのID、それ以外では種類の名前
+    #[pyo3(get)]
+    pub id: String,
+    /// wgsl, cpu_func, upload, download, pipeline_compile, outputのいずれか
+    #[pyo3(get)]
+    pub kind: String,
+    /// パイプラインの中でのステップの位置
+    #[pyo3(get)]
+    pub step_index: Option<usize>,
+    /// 出力の解像度 (幅, 高さ)
+    #[pyo3(get)]
+    pub resolution: Option<(u32, u32)>,
+    /// 生成の開始からCPUで処理を始めるまでの時間 (ミリ秒)
+    #[pyo3(get)]
+    pub cpu_start_ms: f64,
+    /// CPUでの処理時間 (ミリ秒)
+    #[pyo3(get)]
+    pub cpu_ms: f64,
+    /// 最初のコンピュートパスの開始からGPUで処理を始めるまでの時間 (ミリ秒)
+    #[pyo3(get)]
+    pub gpu_start_ms: Option<f64>,
+    /// GPUでのコンピュートパスの実行時間の合計 (ミリ秒)
+    #[pyo3(get)]
+    pub gpu_ms: Option<f64>,
+    /// キャッシュを引いた結果 (hitまたはmiss)
+    #[pyo3(get)]
+    pub cache: Option<String>,
+}
+
+/// プロファイリングを有効にした生成の実行レポート
+#[gen_stub_pyclass]
+#[pyclass]
+pub struct PyExecutionReport {
+    pub inner: ExecutionReport,
+}
+
+/// 結果キャッシュの統計
+#[gen_stub_pyclass]
+#[pyclass]
+pub struct PyResultCacheStats {
+    /// キャッシュから結果を返したステップの数
+    #[pyo3(get)]
+    pub hits: u64,
+    /// キャッシュになく、実行したステップの数
+    #[pyo3(get)]
+    pub misses: u64,
+    /// 容量を超えたために破棄した結果の数
+    #[pyo3(get)]
+    pub evictions: u64,
+    /// 保持している結果の数
+    #[pyo3(get)]
+    pub entries: usize,
+    /// 保持している結果の合計のバイト数
+    #[pyo3(get)]
+    pub bytes: u64,
+}
+
 #[gen_stub_pyclass]
 #[pyclass]
 pub struct PyImageGenerator {
     pub inner: image_generator::ImageGenerator,
-    rt: Runtime,
+    rt: Arc<Runtime>,
 }
 
 #[gen_stub_pymethods]
@@ -79,37 +348,467 @@ impl PySamplerOptions {
         };
 
         Ok(Self {
-            inner: compiled_wgsl::SamplerOptions { address_mode, filter },
+            inner: compiled_wgsl::SamplerOptions {
+                address_mode,
+                filter,
+            },
         })
     }
 }
 
+fn parse_backends(backend: &str) -> PyResult<wgpu::Backends> {
+    Ok(match backend {
+        "all" => wgpu::Backends::all(),
+        "primary" => wgpu::Backends::PRIMARY,
+        "secondary" => wgpu::Backends::SECONDARY,
+        "vulkan" => wgpu::Backends::VULKAN,
+        "gl" => wgpu::Backends::GL,
+        "metal" => wgpu::Backends::METAL,
+        "dx12" => wgpu::Backends::DX12,
+        _ => {
+            return Err(PyValueError::new_err(
+                "Invalid backend. Must be one of: all, primary, secondary, vulkan, gl, metal, dx12",
+            ));
+        }
+    })
+}
+
+const COLOR_SPACE_NAMES: &str = "srgb, linear_srgb, rec709, display_p3, linear_display_p3, rec2020_pq, rec2020_hlg, linear_rec2020";
+
+fn parse_color_space(color_space: &str) -> PyResult<ColorSpace> {
+    Ok(match color_space {
+        "srgb" => ColorSpace::SRGB,
+        "linear_srgb" => ColorSpace::LINEAR_SRGB,
+        "rec709" => ColorSpace::REC709,
+        "display_p3" => ColorSpace::DISPLAY_P3,
+        "linear_display_p3" => ColorSpace::LINEAR_DISPLAY_P3,
+        "rec2020_pq" => ColorSpace::REC2020_PQ,
+        "rec2020_hlg" => ColorSpace::REC2020_HLG,
+        "linear_rec2020" => ColorSpace::LINEAR_REC2020,
+        _ => {
+            return Err(PyValueError::new_err(format!(
+                "Invalid color space. Must be one of: {}",
+                COLOR_SPACE_NAMES
+            )));
+        }
+    })
+}
+
+fn parse_alpha_mode(alpha_mode: &str) -> PyResult<AlphaMode> {
+    match alpha_mode {
+        "straight" => Ok(AlphaMode::Straight),
+        "premultiplied" => Ok(AlphaMode::Premultiplied),
+        _ => Err(PyValueError::new_err(
+            "Invalid alpha mode. Must be one of: straight, premultiplied",
+        )),
+    }
+}
+
+fn color_space_name(color_space: ColorSpace) -> &'static str {
+    match color_space {
+        ColorSpace::SRGB => "srgb",
+        ColorSpace::LINEAR_SRGB => "linear_srgb",
+        ColorSpace::REC709 => "rec709",
+        ColorSpace::DISPLAY_P3 => "display_p3",
+        ColorSpace::LINEAR_DISPLAY_P3 => "linear_display_p3",
+        ColorSpace::REC2020_PQ => "rec2020_pq",
+        ColorSpace::REC2020_HLG => "rec2020_hlg",
+        ColorSpace::LINEAR_REC2020 => "linear_rec2020",
+        _ => "custom",
+    }
+}
+
+fn parse_output_format(format: &str) -> PyResult<OutputFormat> {
+    Ok(match format {
+        "rgba8" => OutputFormat::Rgba8,
+        "bgra8" => OutputFormat::Bgra8,
+        "rgba16" => OutputFormat::Rgba16Unorm,
+        "rgba32f" => OutputFormat::Rgba32Float,
+        "i420" => OutputFormat::I420,
+        "nv12" => OutputFormat::Nv12,
+        _ => {
+            return Err(PyValueError::new_err(
+                "Invalid format. Must be one of: rgba8, bgra8, rgba16, rgba32f, i420, nv12",
+            ));
+        }
+    })
+}
+
+#[gen_stub_pymethods]
+#[pymethods]
+impl PyImageGeneratorOptions {
+    #[new]
+    /// working_spaceはWGSLのステップが入出力に使う色空間です (デフォルトはlinear_srgb)。
+    #[pyo3(signature = (backend=None, power_preference=None, force_fallback_adapter=false, adapter_name=None, adapter_index=None, cache_dir=None, working_space="linear_srgb"))]
+    pub fn new(
+        backend: Option<&str>,
+        power_preference: Option<&str>,
+        force_fallback_adapter: bool,
+        adapter_name: Option<String>,
+        adapter_index: Option<usize>,
+        cache_dir: Option<PathBuf>,
+        working_space: &str,
+    ) -> PyResult<Self> {
+        let backends = match backend {
+            Some(backend) => parse_backends(backend)?,
+            None => wgpu::Backends::all(),
+        };
+
+        let power_preference = match power_preference {
+            None | Some("none") => wgpu::PowerPreference::None,
+            Some("low_power") => wgpu::PowerPreference::LowPower,
+            Some("high_performance") => wgpu::PowerPreference::HighPerformance,
+            _ => {
+                return Err(PyValueError::new_err(
+                    "Invalid power_preference. Must be one of: none, low_power, high_performance",
+                ));
+            }
+        };
+
+        let adapter = match (adapter_name, adapter_index) {
+            (Some(_), Some(_)) => {
+                return Err(PyValueError::new_err(
+                    "adapter_name and adapter_index cannot be specified at the same time",
+                ));
+            }
+            (Some(name), None) => Some(AdapterSelector::Name(name)),
+            (None, Some(index)) => Some(AdapterSelector::Index(index)),
+            (None, None) => None,
+        };
+
+        Ok(Self {
+            inner: ImageGeneratorOptions {
+                backends,
+                power_preference,
+                force_fallback_adapter,
+                adapter,
+                cache_dir,
+                working_space: parse_color_space(working_space)?,
+            },
+        })
+    }
+}
+
+/// シェーダーのパラメータ構造体のメンバー
+#[gen_stub_pyclass]
+#[pyclass]
+pub struct PyParamField {
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub offset: u32,
+    #[pyo3(get)]
+    pub size: u32,
+    #[pyo3(get)]
+    pub wgsl_type: String,
+}
+
+/// シェーダーのバッファに渡すパラメータのレイアウト。
+/// 配列の場合、fieldsとstrideは要素のものになります。
+#[gen_stub_pyclass]
+#[pyclass]
+pub struct PyParamLayout {
+    #[pyo3(get)]
+    pub buffer: String,
+    #[pyo3(get)]
+    pub wgsl_type: String,
+    #[pyo3(get)]
+    pub size: u32,
+    #[pyo3(get)]
+    pub align: u32,
+    /// 実行時に長さが決まる配列かどうか
+    #[pyo3(get)]
+    pub runtime_sized: bool,
+    #[pyo3(get)]
+    pub stride: u32,
+    #[pyo3(get)]
+    pub fields: Vec<Py<PyParamField>>,
+}
+
+impl PyParamLayout {
+    fn new(py: Python<'_>, buffer: &str, layout: &ParamLayout) -> PyResult<Self> {
+        let (element, runtime_sized, stride) = match &layout.ty {
+            ParamType::Array {
+                element,
+                count,
+                stride,
+            } => (element.as_ref(), count.is_none(), *stride),
+            _ => (layout, false, layout.size),
+        };
+        let fields = match &element.ty {
+            ParamType::Struct { fields } => fields
+                .iter()
+                .map(|f| {
+                    Py::new(
+                        py,
+                        PyParamField {
+                            name: f.name.clone(),
+                            offset: f.offset,
+                            size: f.layout.size,
+                            wgsl_type: f.layout.wgsl_type.clone(),
+                        },
+                    )
+                })
+                .collect::<PyResult<_>>()?,
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            buffer: buffer.to_string(),
+            wgsl_type: layout.wgsl_type.clone(),
+            size: layout.size,
+            align: layout.align,
+            runtime_sized,
+            stride,
+            fields,
+        })
+    }
+}
+
+#[gen_stub_pymethods]
+#[pymethods]
+impl PyParamField {
+    pub fn __repr__(&self) -> String {
+        format!(
+            "PyParamField(name={:?}, offset={}, size={}, wgsl_type={:?})",
+            self.name, self.offset, self.size, self.wgsl_type
+        )
+    }
+}
+
+#[gen_stub_pymethods]
+#[pymethods]
+impl PyParamLayout {
+    pub fn __repr__(&self) -> String {
+        format!(
+            "PyParamLayout(buffer={:?}, wgsl_type={:?}, size={}, stride={})",
+            self.buffer, self.wgsl_type, self.size, self.stride
+        )
+    }
+}
+
+/// Pythonの値をパラメータの値に変換する
+fn to_param_value(obj: &Bound<'_, PyAny>) -> PyResult<ParamValue> {
+    if let Ok(dict) = obj.cast::<PyDict>() {
+        let mut map = HashMap::with_capacity(dict.len());
+        for (key, value) in dict.iter() {
+            let key: String = key.extract().map_err(|_| {
+                PyTypeError::new_err(format!("params keys must be str, got {:?}", key))
+            })?;
+            map.insert(key, to_param_value(&value)?);
+        }
+        Ok(ParamValue::Map(map))
+    } else if obj.is_instance_of::<PyInt>() {
+        Ok(ParamValue::Int(obj.extract()?))
+    } else if obj.is_instance_of::<PyFloat>() {
+        Ok(ParamValue::Float(obj.extract()?))
+    } else if obj.is_instance_of::<PyList>() || obj.is_instance_of::<PyTuple>() {
+        Ok(ParamValue::List(
+            obj.try_iter()?
+                .map(|item| to_param_value(&item?))
+                .collect::<PyResult<_>>()?,
+        ))
+    } else if obj.hasattr("tolist")? {
+        // numpyの配列やスカラー
+        to_param_value(&obj.call_method0("tolist")?)
+    } else {
+        Err(PyTypeError::new_err(format!(
+            "Unsupported params value of type {}",
+            obj.get_type().name()?
+        )))
+    }
+}
+
+/// bytesはそのまま、それ以外はシェーダーのレイアウトに従って詰める
+fn params_to_bytes(
+    wgsl: &compiled_wgsl::CompiledWgsl,
+    entry_point: Option<&str>,
+    buffer: Option<&str>,
+    value: &Bound<'_, PyAny>,
+    is_named: impl Fn(&str) -> bool,
+) -> PyResult<Vec<u8>> {
+    if let Ok(bytes) = value.cast::<PyBytes>() {
+        return Ok(bytes.as_bytes().to_vec());
+    }
+    wgsl.pack_params_for(entry_point, buffer, &to_param_value(value)?, is_named)
+        .map_err(to_py_err)
+}
+
+impl From<&StepReport> for PyStepReport {
+    fn from(step: &StepReport) -> Self {
+        Self {
+            id: step.id.clone(),
+            kind: step.kind.as_str().to_string(),
+            step_index: step.step_index,
+            resolution: step.resolution,
+            cpu_start_ms: step.cpu_start_ms,
+            cpu_ms: step.cpu_ms,
+            gpu_start_ms: step.gpu_start_ms,
+            gpu_ms: step.gpu_ms,
+            cache: step.cache.map(|c| c.as_str().to_string()),
+        }
+    }
+}
+
+impl From<ResultCacheStats> for PyResultCacheStats {
+    fn from(stats: ResultCacheStats) -> Self {
+        Self {
+            hits: stats.hits,
+            misses: stats.misses,
+            evictions: stats.evictions,
+            entries: stats.entries,
+            bytes: stats.bytes,
+        }
+    }
+}
+
+impl From<&AdapterDescription> for PyAdapterInfo {
+    fn from(desc: &AdapterDescription) -> Self {
+        Self {
+            name: desc.name.clone(),
+            backend: desc.backend.to_str().to_string(),
+            device_type: format!("{:?}", desc.device_type),
+            driver: desc.driver.clone(),
+            driver_info: desc.driver_info.clone(),
+            features: desc
+                .features
+                .iter_names()
+                .map(|(name, _)| name.to_string())
+                .collect(),
+        }
+    }
+}
+
+#[gen_stub_pymethods]
+#[pymethods]
+impl PyStepReport {
+    pub fn __repr__(&self) -> String {
+        format!(
+            "PyStepReport(id={:?}, kind={:?}, cpu_ms={:.3}, gpu_ms={:?})",
+            self.id, self.kind, self.cpu_ms, self.gpu_ms
+        )
+    }
+}
+
+#[gen_stub_pymethods]
+#[pymethods]
+impl PyExecutionReport {
+    /// 処理を始めた順に並べた項目
+    #[getter]
+    pub fn steps(&self) -> Vec<PyStepReport> {
+        self.inner.steps.iter().map(PyStepReport::from).collect()
+    }
+
+    /// 生成全体にかかった時間 (ミリ秒)
+    #[getter]
+    pub fn total_ms(&self) -> f64 {
+        self.inner.total_ms
+    }
+
+    /// すべての項目のGPUでの実行時間の合計 (ミリ秒)。タイムスタンプが使えない場合はNone
+    #[getter]
+    pub fn gpu_ms(&self) -> Option<f64> {
+        self.inner.gpu_ms()
+    }
+
+    /// GPUのタイムスタンプを計測できたかどうか
+    #[getter]
+    pub fn gpu_timestamps(&self) -> bool {
+        self.inner.gpu_timestamps
+    }
+
+    /// レポートをJSONの文字列に変換します。
+    pub fn to_json(&self) -> Result<String> {
+        self.inner.to_json()
+    }
+
+    /// Chromeのトレースイベント形式 (chrome://tracingやPerfettoで開けるJSON) の文字列に変換します。
+    pub fn to_chrome_trace(&self) -> Result<String> {
+        self.inner.to_chrome_trace()
+    }
+}
+
+#[gen_stub_pymethods]
+#[pymethods]
+impl PyAdapterInfo {
+    pub fn __repr__(&self) -> String {
+        format!(
+            "PyAdapterInfo(name={:?}, backend={:?}, device_type={:?})",
+            self.name, self.backend, self.device_type
+        )
+    }
+}
+
 #[gen_stub_pymethods]
 #[pymethods]
 impl PyCompiledWgsl {
+    /// WGSLをコンパイルします。
+    /// sampler_optionsはすべてのsamplerに、samplersは名前が一致するsamplerにのみ使われます。
     #[new]
+    #[pyo3(signature = (id, wgsl_code, generator, sampler_options=None, samplers=None))]
     pub fn new(
         id: &str,
         wgsl_code: &str,
         generator: &PyImageGenerator,
         sampler_options: Option<&PySamplerOptions>,
+        samplers: Option<HashMap<String, PyRef<PySamplerOptions>>>,
     ) -> Result<Self, PyErr> {
-        let inner = compiled_wgsl::CompiledWgsl::new(
-            id,
-            wgsl_code,
-            &generator.inner.device,
-            sampler_options.map(|s| &s.inner),
-        )?;
+        // シェーダーモジュールとサンプラーの作成も、実行中の生成のエラースコープと重ならないようにする
+        let inner = generator
+            .inner
+            .with_error_scope(None, Some(id), || {
+                let mut inner = compiled_wgsl::CompiledWgsl::new(
+                    id,
+                    wgsl_code,
+                    &generator.inner.device,
+                    sampler_options.map(|s| &s.inner),
+                )?;
+                for (name, options) in samplers.unwrap_or_default() {
+                    inner = inner.with_sampler(&generator.inner.device, &name, &options.inner)?;
+                }
+                Ok(inner)
+            })
+            .map_err(to_py_err)?;
+        // 以前の実行で使われたパイプラインがあれば、初回の生成を待たずに作っておく
+        generator.inner.prewarm(&inner).map_err(to_py_err)?;
 
         Ok(Self { inner })
     }
+
+    /// コンピュートシェーダーのエントリーポイント名 (宣言順)
+    #[getter]
+    pub fn entry_points(&self) -> Vec<String> {
+        self.inner.entry_points().map(str::to_string).collect()
+    }
+
+    /// バッファに渡すパラメータのレイアウトを取得します。
+    /// bufferを省略した場合は、エントリーポイントが使用する唯一のバッファのレイアウトを返します。
+    /// entry_pointを省略した場合は`main`、エントリーポイントが1つだけならそれを使います。
+    #[pyo3(signature = (buffer=None, entry_point=None))]
+    pub fn param_layout(
+        &self,
+        py: Python<'_>,
+        buffer: Option<&str>,
+        entry_point: Option<&str>,
+    ) -> PyResult<PyParamLayout> {
+        let (binding, layout) = self
+            .inner
+            .param_layout_for(entry_point, buffer, |_| false)
+            .map_err(to_py_err)?;
+        PyParamLayout::new(py, &binding.name, layout)
+    }
 }
 
 #[gen_stub_pymethods]
 #[pymethods]
 impl PyCompiledFunc {
+    /// CPUで実行する関数を登録します。
+    /// color_spaceは関数が受け取る入力と返す出力の色空間です (デフォルトはsrgb)。
+    /// alpha_modeは入出力のアルファの形式で、straightかpremultipliedのいずれかです (デフォルトはstraight)。
     #[new]
-    pub fn new(id: &str, func: Py<PyAny>) -> PyResult<Self> {
+    #[pyo3(signature = (id, func, color_space="srgb", alpha_mode="straight"))]
+    pub fn new(id: &str, func: Py<PyAny>, color_space: &str, alpha_mode: &str) -> PyResult<Self> {
+        let color_space = parse_color_space(color_space)?;
+        let alpha_mode = parse_alpha_mode(alpha_mode)?;
         let func_ref = func;
         let func: Box<CpuFunction> =
             Box::new(move |data: &[CpuInputImage], params: Option<&[u8]>| {
@@ -154,7 +853,9 @@ impl PyCompiledFunc {
                 })
             });
 
-        let inner = compiled_func::CompiledFunc::new(func);
+        let inner = compiled_func::CompiledFunc::new(func)
+            .with_color_space(color_space)
+            .with_alpha_mode(alpha_mode);
 
         Ok(Self {
             _id: id.to_string(),
@@ -163,6 +864,12 @@ impl PyCompiledFunc {
     }
 }
 
+impl Default for PyImageGenerateBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[gen_stub_pymethods]
 #[pymethods]
 impl PyImageGenerateBuilder {
@@ -173,21 +880,58 @@ impl PyImageGenerateBuilder {
         Self { inner }
     }
 
+    /// WGSL処理ステップを追加します。
+    /// paramsはbuffersで指定されなかった唯一のバッファに、buffersは変数名が一致するバッファに渡されます。
+    /// 値にはbytesのほか、シェーダーで宣言された型に従って自動で詰められるdictやlistを渡せます。
+    /// dispatchにはシェーダーの呼び出し回数 (x, y, z) を、workgroupsにはワークグループ数 (x, y, z) を指定できます。
+    /// どちらも指定しない場合は出力の1ピクセルにつき1回呼び出されます。
+    /// entry_pointを省略した場合は`main`、エントリーポイントが1つだけならそれを使います。
+    /// color_spaceはシェーダーが書き込む値の色空間で、省略した場合は作業用の色空間として扱われます。
+    /// alpha_modeはシェーダーが入出力に使うアルファの形式で、straightかpremultipliedのいずれかです (デフォルトはpremultiplied)。
+    /// nameは`ExecutablePlan.execute`でパラメータを書き換えるときにステップを指定する名前です。
+    /// cacheをTrueにすると、入力とパラメータが以前の実行と同じ場合に結果キャッシュの結果を再利用します。
+    /// 結果のテクスチャはキャッシュに保持されるため、毎フレーム内容が変わるステップには指定しないでください。
+    #[pyo3(signature = (wgsl, params, output_width, output_height, buffers=None, dispatch=None, workgroups=None, entry_point=None, color_space=None, alpha_mode="premultiplied", name=None, cache=false))]
+    #[allow(clippy::too_many_arguments)]
     pub fn add_wgsl<'py>(
         &self,
         wgsl: &PyCompiledWgsl,
-        params: Option<&Bound<'py, PyBytes>>,
+        #[gen_stub(override_type(type_repr = "bytes | dict | list | None"))] params: Option<
+            &Bound<'py, PyAny>,
+        >,
         output_width: u32,
         output_height: u32,
-    ) -> Self {
-        let params = params.map(|p| p.as_bytes().to_vec());
-
-        let new_inner =
-            self.inner
-                .clone()
-                .add_wgsl(wgsl.inner.clone(), params, output_width, output_height);
+        #[gen_stub(override_type(type_repr = "dict[str, bytes | dict | list] | None"))]
+        buffers: Option<HashMap<String, Bound<'py, PyAny>>>,
+        dispatch: Option<(u32, u32, u32)>,
+        workgroups: Option<(u32, u32, u32)>,
+        entry_point: Option<String>,
+        color_space: Option<&str>,
+        alpha_mode: &str,
+        name: Option<String>,
+        cache: bool,
+    ) -> PyResult<Self> {
+        let (params, mut options) = wgsl_step_args(
+            wgsl,
+            params,
+            buffers,
+            dispatch,
+            workgroups,
+            entry_point,
+            color_space,
+            alpha_mode,
+            cache,
+        )?;
+        options.name = name;
+        let new_inner = self.inner.clone().add_wgsl_with_options(
+            wgsl.inner.clone(),
+            params,
+            options,
+            output_width,
+            output_height,
+        );
 
-        Self { inner: new_inner }
+        Ok(Self { inner: new_inner })
     }
 
     pub fn add_parallel_wgsl<'py>(
@@ -216,16 +960,7 @@ impl PyImageGenerateBuilder {
         output_width: u32,
         output_height: u32,
     ) -> PyResult<Self> {
-        let params = if let Some(p) = params {
-            let pickle = py.import("pickle")?;
-            let pickle_dumps = pickle.getattr("dumps")?;
-            let dumped: Py<PyAny> = pickle_dumps.call1((p,))?.unbind();
-            let dumped = dumped.bind(py);
-            let dumped: Vec<u8> = dumped.extract()?;
-            Some(dumped)
-        } else {
-            None
-        };
+        let params = pickle_params(py, params)?;
 
         let new_inner =
             self.inner
@@ -234,41 +969,727 @@ impl PyImageGenerateBuilder {
 
         Ok(Self { inner: new_inner })
     }
+
+    /// 処理グラフをステップとして追加します。
+    /// グラフには直前のステップの出力が渡され、グラフの出力が次のステップの入力になります。
+    /// ノード名の重複や存在しないノードの参照、循環する依存関係がある場合はBindingErrorを送出します。
+    /// どの出力にも使われないノードは実行されません。
+    pub fn add_graph(&self, graph: &PyImageGraphBuilder) -> PyResult<Self> {
+        let graph = graph.inner.clone().build().map_err(to_py_err)?;
+        let new_inner = self.inner.clone().add_graph(graph);
+
+        Ok(Self { inner: new_inner })
+    }
+
+    /// 結果をキャッシュするサブパイプラインをステップとして追加します。
+    /// keyと入力の内容が以前の生成と同じ場合は、サブパイプラインを実行せずに前回の結果を使います。
+    /// CPU関数のように入力以外から結果が決まる処理は、その内容をkeyに含めてください。
+    pub fn add_cached(&self, key: &str, pipeline: &PyImageGenerateBuilder) -> PyResult<Self> {
+        let new_inner = self.inner.clone().add_cached(key, pipeline.inner.clone());
+
+        Ok(Self { inner: new_inner })
+    }
+}
+
+impl Default for PyImageGraphBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[gen_stub_pymethods]
+#[pymethods]
+impl PyImageGraphBuilder {
+    #[new]
+    pub fn new() -> Self {
+        Self {
+            inner: GraphBuilder::new(),
+        }
+    }
+
+    /// WGSL処理のノードを追加します。
+    /// inputsには入力にするノードの名前か、グラフに渡された画像の位置を、シェーダーに渡す順に指定します。
+    /// ノードの名前を指定した場合は、そのノードのすべての出力が入力になります。
+    /// その他の引数はPyImageGenerateBuilder.add_wgslと同じです。
+    #[pyo3(signature = (name, wgsl, params, output_width, output_height, inputs=None, buffers=None, dispatch=None, workgroups=None, entry_point=None, color_space=None, alpha_mode="premultiplied", cache=false))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_wgsl<'py>(
+        &self,
+        name: &str,
+        wgsl: &PyCompiledWgsl,
+        #[gen_stub(override_type(type_repr = "bytes | dict | list | None"))] params: Option<
+            &Bound<'py, PyAny>,
+        >,
+        output_width: u32,
+        output_height: u32,
+        #[gen_stub(override_type(type_repr = "list[str | int] | None"))] inputs: Option<
+            Vec<Bound<'py, PyAny>>,
+        >,
+        #[gen_stub(override_type(type_repr = "dict[str, bytes | dict | list] | None"))]
+        buffers: Option<HashMap<String, Bound<'py, PyAny>>>,
+        dispatch: Option<(u32, u32, u32)>,
+        workgroups: Option<(u32, u32, u32)>,
+        entry_point: Option<String>,
+        color_space: Option<&str>,
+        alpha_mode: &str,
+        cache: bool,
+    ) -> PyResult<Self> {
+        let inputs = parse_graph_inputs(inputs)?;
+        let (params, options) = wgsl_step_args(
+            wgsl,
+            params,
+            buffers,
+            dispatch,
+            workgroups,
+            entry_point,
+            color_space,
+            alpha_mode,
+            cache,
+        )?;
+        let new_inner = self.inner.clone().add_wgsl_with_options(
+            name,
+            wgsl.inner.clone(),
+            params,
+            options,
+            inputs,
+            output_width,
+            output_height,
+        );
+
+        Ok(Self { inner: new_inner })
+    }
+
+    /// CPU関数処理のノードを追加します。inputsはadd_wgslと同じです。
+    #[pyo3(signature = (name, func, params, output_width, output_height, inputs=None))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_func<'py>(
+        &self,
+        py: Python<'py>,
+        name: &str,
+        func: &PyCompiledFunc,
+        params: Option<Py<PyAny>>,
+        output_width: u32,
+        output_height: u32,
+        #[gen_stub(override_type(type_repr = "list[str | int] | None"))] inputs: Option<
+            Vec<Bound<'py, PyAny>>,
+        >,
+    ) -> PyResult<Self> {
+        let inputs = parse_graph_inputs(inputs)?;
+        let params = pickle_params(py, params)?;
+        let new_inner = self.inner.clone().add_func(
+            name,
+            func.inner.clone(),
+            params,
+            inputs,
+            output_width,
+            output_height,
+        );
+
+        Ok(Self { inner: new_inner })
+    }
+
+    /// 直列のパイプラインをノードとして追加します。入力はパイプラインの最初のステップに渡されます。
+    #[pyo3(signature = (name, pipeline, inputs=None))]
+    pub fn add_pipeline<'py>(
+        &self,
+        name: &str,
+        pipeline: &PyImageGenerateBuilder,
+        #[gen_stub(override_type(type_repr = "list[str | int] | None"))] inputs: Option<
+            Vec<Bound<'py, PyAny>>,
+        >,
+    ) -> PyResult<Self> {
+        let inputs = parse_graph_inputs(inputs)?;
+        let new_inner = self
+            .inner
+            .clone()
+            .add_pipeline(name, pipeline.inner.clone(), inputs);
+
+        Ok(Self { inner: new_inner })
+    }
+
+    /// グラフの出力にするノードを追加します。複数指定した場合は、指定した順に出力が並びます。
+    pub fn output(&self, name: &str) -> Self {
+        Self {
+            inner: self.inner.clone().output(name),
+        }
+    }
+}
+
+/// add_wgslの引数を、パラメータのバイト列とWgslStepOptionsに変換します。
+#[allow(clippy::too_many_arguments)]
+fn wgsl_step_args<'py>(
+    wgsl: &PyCompiledWgsl,
+    params: Option<&Bound<'py, PyAny>>,
+    buffers: Option<HashMap<String, Bound<'py, PyAny>>>,
+    dispatch: Option<(u32, u32, u32)>,
+    workgroups: Option<(u32, u32, u32)>,
+    entry_point: Option<String>,
+    color_space: Option<&str>,
+    alpha_mode: &str,
+    cache: bool,
+) -> PyResult<(Option<Vec<u8>>, WgslStepOptions)> {
+    let color_space = color_space.map(parse_color_space).transpose()?;
+    let alpha_mode = parse_alpha_mode(alpha_mode)?;
+    let dispatch = match (dispatch, workgroups) {
+        (Some(_), Some(_)) => {
+            return Err(PyValueError::new_err(
+                "dispatch and workgroups cannot be specified at the same time",
+            ));
+        }
+        (Some((x, y, z)), None) => Dispatch::Invocations(x, y, z),
+        (None, Some((x, y, z))) => Dispatch::Workgroups(x, y, z),
+        (None, None) => Dispatch::PerPixel,
+    };
+
+    let buffers = buffers.unwrap_or_default();
+    let params = params
+        .map(|p| {
+            params_to_bytes(&wgsl.inner, entry_point.as_deref(), None, p, |name| {
+                buffers.contains_key(name)
+            })
+        })
+        .transpose()?;
+    let buffers = buffers
+        .iter()
+        .map(|(name, value)| {
+            Ok((
+                name.clone(),
+                params_to_bytes(
+                    &wgsl.inner,
+                    entry_point.as_deref(),
+                    Some(name),
+                    value,
+                    |_| false,
+                )?,
+            ))
+        })
+        .collect::<PyResult<_>>()?;
+
+    Ok((
+        params,
+        WgslStepOptions {
+            buffers,
+            dispatch,
+            entry_point,
+            color_space,
+            alpha_mode,
+            name: None,
+            cache,
+        },
+    ))
+}
+
+/// CPU関数に渡すパラメータをpickleでバイト列に変換します。
+fn pickle_params(py: Python<'_>, params: Option<Py<PyAny>>) -> PyResult<Option<Vec<u8>>> {
+    let params = if let Some(p) = params {
+        let pickle = py.import("pickle")?;
+        let pickle_dumps = pickle.getattr("dumps")?;
+        let dumped: Py<PyAny> = pickle_dumps.call1((p,))?.unbind();
+        let dumped = dumped.bind(py);
+        let dumped: Vec<u8> = dumped.extract()?;
+        Some(dumped)
+    } else {
+        None
+    };
+    Ok(params)
+}
+
+/// グラフのノードの入力を、ノードの名前 (str) と前の画像の位置 (int) から変換します。
+fn parse_graph_inputs(inputs: Option<Vec<Bound<'_, PyAny>>>) -> PyResult<Vec<GraphInput>> {
+    inputs
+        .unwrap_or_default()
+        .iter()
+        .map(|input| {
+            if let Ok(name) = input.extract::<String>() {
+                Ok(GraphInput::Node(name))
+            } else if let Ok(index) = input.extract::<usize>() {
+                Ok(GraphInput::Previous(index))
+            } else {
+                Err(PyTypeError::new_err(
+                    "graph inputs must be node names (str) or indices of the previous images (int)",
+                ))
+            }
+        })
+        .collect()
 }
 
 #[gen_stub_pymethods]
 #[pymethods]
-// TODO: experimental-asyncを使った非同期処理
 impl PyImageGenerator {
     #[new]
-    pub fn new() -> Result<Self> {
-        let rt = Runtime::new()?;
-        let inner = rt.block_on(async { image_generator::ImageGenerator::new().await })?;
+    #[pyo3(signature = (options=None))]
+    pub fn new(options: Option<&PyImageGeneratorOptions>) -> PyResult<Self> {
+        let rt = Arc::new(Runtime::new()?);
+        let options = options.map(|o| o.inner.clone()).unwrap_or_default();
+        let inner = rt
+            .block_on(async { image_generator::ImageGenerator::new(&options).await })
+            .map_err(to_py_err)?;
         Ok(Self { inner, rt })
     }
 
-    pub fn generate(&self, builder: &PyImageGenerateBuilder, buffer_ptr: usize) -> PyResult<()> {
-        let result = self
+    /// 利用可能なアダプタの一覧を取得します。
+    #[staticmethod]
+    #[pyo3(signature = (backend=None))]
+    pub fn enumerate_adapters(backend: Option<&str>) -> PyResult<Vec<PyAdapterInfo>> {
+        let backends = match backend {
+            Some(backend) => parse_backends(backend)?,
+            None => wgpu::Backends::all(),
+        };
+        Ok(generator_options::enumerate_adapters(backends)
+            .iter()
+            .map(PyAdapterInfo::from)
+            .collect())
+    }
+
+    /// 使用中のアダプタの情報を取得します。
+    pub fn adapter_info(&self) -> PyAdapterInfo {
+        PyAdapterInfo::from(self.inner.adapter_info())
+    }
+
+    /// binding_arrayで入力を受け取るシェーダーが使えるかどうかを返します。
+    /// Falseの場合は`texture_2d_array`または個別の`texture_2d`で入力を受け取る規約を使用してください。
+    pub fn supports_binding_array(&self) -> bool {
+        self.inner.supports_binding_array()
+    }
+
+    /// プロファイリングが有効かどうか。有効にすると、generateとgenerate_asyncが各ステップの処理時間を計測した
+    /// PyExecutionReportを返します。計測のために生成ごとに読み戻しが1回増えるため、必要なときだけ有効にしてください。
+    #[getter]
+    pub fn profiling(&self) -> bool {
+        self.inner.profiling()
+    }
+
+    #[setter]
+    pub fn set_profiling(&self, enabled: bool) {
+        self.inner.set_profiling(enabled);
+    }
+
+    /// GPUのタイムスタンプでコンピュートパスの実行時間を計測できるかどうかを返します。
+    /// Falseの場合、実行レポートにはCPUでの処理時間のみが記録されます。
+    pub fn supports_gpu_timestamps(&self) -> bool {
+        self.inner.supports_gpu_timestamps()
+    }
+
+    /// 結果キャッシュの統計を返します。
+    pub fn result_cache_stats(&self) -> PyResultCacheStats {
+        self.inner.result_cache_stats().into()
+    }
+
+    /// 結果キャッシュの容量 (バイト)。0にすると結果をキャッシュしません。
+    #[getter]
+    pub fn max_result_cache_bytes(&self) -> u64 {
+        self.inner.max_result_cache_bytes()
+    }
+
+    #[setter]
+    pub fn set_max_result_cache_bytes(&self, bytes: u64) {
+        self.inner.set_max_result_cache_bytes(bytes);
+    }
+
+    /// 結果キャッシュのすべての結果を破棄します。
+    pub fn clear_result_cache(&self) {
+        self.inner.clear_result_cache();
+    }
+
+    /// WGSLのステップが入出力に使う作業用の色空間の名前を返します。
+    #[getter]
+    pub fn working_space(&self) -> String {
+        color_space_name(self.inner.working_space()).to_string()
+    }
+
+    /// 指定したフォーマットで出力した場合のバイト数を返します。
+    /// generateに渡すバッファはこのサイズ以上確保してください。
+    #[staticmethod]
+    #[pyo3(signature = (width, height, format="rgba8"))]
+    pub fn output_size(width: u32, height: u32, format: &str) -> PyResult<usize> {
+        Ok(parse_output_format(format)?.byte_size(width, height))
+    }
+
+    /// パイプラインを実行し、結果をbufferに書き込みます。
+    /// bufferはbytearrayやmemoryview、uint8のnumpy配列など、書き込み可能で連続したバッファプロトコルのオブジェクトです。
+    /// formatはrgba8, bgra8, rgba16, rgba32f, i420, nv12のいずれかです。
+    /// color_spaceは出力の色空間で、作業用の色空間から自動で変換されます。
+    /// alpha_modeは出力のアルファの形式で、straightかpremultipliedのいずれかです。
+    /// 実行中はGILを解放するため、複数のスレッドから同時に呼び出すと並列に処理されます。
+    /// profilingが有効な場合は実行レポートを、それ以外の場合はNoneを返します。
+    #[pyo3(signature = (builder, buffer, format="rgba8", color_space="srgb", alpha_mode="straight"))]
+    pub fn generate(
+        &self,
+        py: Python<'_>,
+        builder: &PyImageGenerateBuilder,
+        #[gen_stub(override_type(type_repr = "collections.abc.Buffer", imports = ("collections.abc")))]
+        buffer: &Bound<'_, PyAny>,
+        format: &str,
+        color_space: &str,
+        alpha_mode: &str,
+    ) -> PyResult<Option<PyExecutionReport>> {
+        let output = parse_output_options(format, color_space, alpha_mode)?;
+        let buffer = get_output_buffer(buffer, builder.inner.output_size(), &output)?;
+        let (result, report) = self.run(py, builder, &output)?;
+        write_output_buffer(&buffer, &result)?;
+        Ok(report.map(|inner| PyExecutionReport { inner }))
+    }
+
+    /// generateの非同期版です。awaitしている間、GPUの処理はバックグラウンドのスレッドで実行されるため、
+    /// asyncioのイベントループをブロックせずに複数のフレームの処理を重ねることができます。
+    /// bufferは処理が完了するまで変更しないでください。
+    #[pyo3(signature = (builder, buffer, format="rgba8".to_string(), color_space="srgb".to_string(), alpha_mode="straight".to_string()))]
+    pub async fn generate_async(
+        &self,
+        builder: Py<PyImageGenerateBuilder>,
+        #[gen_stub(override_type(type_repr = "collections.abc.Buffer", imports = ("collections.abc")))]
+        buffer: Py<PyAny>,
+        format: String,
+        color_space: String,
+        alpha_mode: String,
+    ) -> PyResult<Option<PyExecutionReport>> {
+        let output = parse_output_options(&format, &color_space, &alpha_mode)?;
+        let (builder, buffer) = Python::attach(|py| -> PyResult<_> {
+            let builder = builder.borrow(py).inner.clone();
+            let buffer = get_output_buffer(buffer.bind(py), builder.output_size(), &output)?;
+            Ok((builder, buffer))
+        })?;
+
+        // tokioのランタイムで実行し、完了を待つ間はイベントループに制御を返す
+        let inner = self.inner.clone();
+        let (result, report) = self
             .rt
-            .block_on(async { self.inner.generate(builder.inner.clone()).await })?;
+            .spawn(async move { inner.generate_with_report(builder, &output).await })
+            .await
+            .map_err(|e| PyRuntimeError::new_err(format!("Generation task failed: {}", e)))?
+            .map_err(to_py_err)?;
 
-        // 直接メモリコピー
-        unsafe {
-            std::ptr::copy_nonoverlapping(result.as_ptr(), buffer_ptr as *mut u8, result.len());
+        write_output_buffer(&buffer, &result)?;
+        Ok(report.map(|inner| PyExecutionReport { inner }))
+    }
+
+    /// 複数のフレームのパイプラインをまとめてサブミットし、結果をbuffersの対応するバッファに書き込みます。
+    /// 読み戻しが完了したフレームから順に書き込み、callbackが指定されていればそのフレームの番号を渡して呼び出します。
+    /// buffersはbuildersと同じ数だけ必要です。format, color_space, alpha_modeはgenerateと同じです。
+    /// callbackが例外を送出した場合は残りのフレームを破棄して中断します。
+    #[pyo3(signature = (builders, buffers, callback=None, format="rgba8", color_space="srgb", alpha_mode="straight"))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_batch(
+        &self,
+        py: Python<'_>,
+        builders: Vec<PyRef<'_, PyImageGenerateBuilder>>,
+        #[gen_stub(override_type(type_repr = "collections.abc.Sequence[collections.abc.Buffer]", imports = ("collections.abc")))]
+        buffers: Vec<Bound<'_, PyAny>>,
+        #[gen_stub(override_type(type_repr = "typing.Callable[[int], None] | None", imports = ("typing")))]
+        callback: Option<Py<PyAny>>,
+        format: &str,
+        color_space: &str,
+        alpha_mode: &str,
+    ) -> PyResult<()> {
+        if builders.len() != buffers.len() {
+            return Err(PyValueError::new_err(format!(
+                "buffers must have the same length as builders: {} builders but got {} buffers",
+                builders.len(),
+                buffers.len()
+            )));
         }
+        let output = parse_output_options(format, color_space, alpha_mode)?;
+        let buffers = builders
+            .iter()
+            .zip(&buffers)
+            .map(|(builder, buffer)| {
+                get_output_buffer(buffer, builder.inner.output_size(), &output)
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        let builders = builders.iter().map(|b| b.inner.clone()).collect();
 
+        // GILを解放して実行し、フレームを受け取るたびにGILを取得して書き込む
+        py.detach(|| {
+            self.rt.block_on(async {
+                self.inner
+                    .generate_batch(builders, &output, |index, result| {
+                        Python::attach(|py| -> PyResult<()> {
+                            write_output_buffer(&buffers[index], &result)?;
+                            if let Some(callback) = &callback {
+                                callback.call1(py, (index,))?;
+                            }
+                            Ok(())
+                        })?;
+                        Ok(())
+                    })
+                    .await
+            })
+        })
+        .map_err(to_py_err)?;
         Ok(())
     }
+
+    /// パイプラインを検証し、WGSLステップのテクスチャ、バインドグループ、バッファを作成した実行計画を返します。
+    /// 同じパイプラインをパラメータだけ変えて繰り返し実行する場合は、generateより1回あたりの処理が少なくなります。
+    pub fn prepare(&self, builder: &PyImageGenerateBuilder) -> PyResult<PyExecutablePlan> {
+        let plan = self
+            .inner
+            .prepare(builder.inner.clone())
+            .map_err(to_py_err)?;
+        Ok(PyExecutablePlan {
+            inner: Arc::new(plan),
+            rt: self.rt.clone(),
+        })
+    }
+
+    /// パイプラインを実行し、結果をnumpy配列として返します。
+    /// rgba8とbgra8は(H, W, 4)のuint8、rgba16は(H, W, 4)のuint16、rgba32fは(H, W, 4)のfloat32、
+    /// i420とnv12は1次元のuint8の配列になります。
+    #[pyo3(signature = (builder, format="rgba8", color_space="srgb", alpha_mode="straight"))]
+    #[gen_stub(override_return_type(type_repr = "numpy.typing.NDArray[typing.Any]", imports = ("numpy.typing", "typing")))]
+    pub fn generate_array<'py>(
+        &self,
+        py: Python<'py>,
+        builder: &PyImageGenerateBuilder,
+        format: &str,
+        color_space: &str,
+        alpha_mode: &str,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let output = parse_output_options(format, color_space, alpha_mode)?;
+        let (width, height) = builder.inner.output_size().ok_or_else(|| {
+            PyValueError::new_err("The pipeline must end with a single output image")
+        })?;
+        let (result, _) = self.run(py, builder, &output)?;
+
+        let shape = [height as usize, width as usize, 4];
+        let array = match output.format {
+            OutputFormat::Rgba8 | OutputFormat::Bgra8 => {
+                PyArray1::from_vec(py, result).reshape(shape)?.into_any()
+            }
+            OutputFormat::Rgba16Unorm => {
+                let values: Vec<u16> = result
+                    .chunks_exact(2)
+                    .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                    .collect();
+                PyArray1::from_vec(py, values).reshape(shape)?.into_any()
+            }
+            OutputFormat::Rgba32Float => {
+                let values: Vec<f32> = result
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect();
+                PyArray1::from_vec(py, values).reshape(shape)?.into_any()
+            }
+            OutputFormat::I420 | OutputFormat::Nv12 => PyArray1::from_vec(py, result).into_any(),
+        };
+        Ok(array)
+    }
+}
+
+impl PyImageGenerator {
+    /// GILを解放してパイプラインを実行します。
+    /// CPU関数のステップはPythonの関数を呼び出す際にGILを再取得します。
+    fn run(
+        &self,
+        py: Python<'_>,
+        builder: &PyImageGenerateBuilder,
+        output: &OutputOptions,
+    ) -> PyResult<(Vec<u8>, Option<ExecutionReport>)> {
+        let builder = builder.inner.clone();
+        let result = py
+            .detach(|| {
+                self.rt
+                    .block_on(async { self.inner.generate_with_report(builder, output).await })
+            })
+            .map_err(to_py_err)?;
+        Ok(result)
+    }
+}
+
+#[gen_stub_pymethods]
+#[pymethods]
+impl PyExecutablePlan {
+    /// paramsやbuffersを書き換えられる、名前を持つステップの一覧を返します。
+    pub fn step_names(&self) -> Vec<String> {
+        self.inner
+            .step_names()
+            .into_iter()
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// ステップのバッファを書き換えてから計画を実行し、結果をbufferに書き込みます。
+    /// paramsはステップの名前とparamsを渡したバッファの新しい値、buffersはステップの名前と
+    /// 変数名ごとのバッファの新しい値です。値の形式はadd_wgslと同じで、準備したときと同じバイト数である必要があります。
+    /// 書き換えた内容は以降の実行でも保持されます。buffer, format, color_space, alpha_modeはgenerateと同じです。
+    #[pyo3(signature = (buffer, params=None, buffers=None, format="rgba8", color_space="srgb", alpha_mode="straight"))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn execute<'py>(
+        &self,
+        py: Python<'py>,
+        #[gen_stub(override_type(type_repr = "collections.abc.Buffer", imports = ("collections.abc")))]
+        buffer: &Bound<'py, PyAny>,
+        #[gen_stub(override_type(type_repr = "dict[str, bytes | dict | list] | None"))]
+        params: Option<HashMap<String, Bound<'py, PyAny>>>,
+        #[gen_stub(override_type(type_repr = "dict[str, dict[str, bytes | dict | list]] | None"))]
+        buffers: Option<HashMap<String, HashMap<String, Bound<'py, PyAny>>>>,
+        format: &str,
+        color_space: &str,
+        alpha_mode: &str,
+    ) -> PyResult<()> {
+        let output = parse_output_options(format, color_space, alpha_mode)?;
+        let buffer = get_output_buffer(buffer, self.inner.output_size(), &output)?;
+
+        let mut updates = Vec::new();
+        for (step, value) in params.unwrap_or_default() {
+            let data = self.update_bytes(&step, None, &value)?;
+            updates.push(ParamUpdate::params(&step, data));
+        }
+        for (step, step_buffers) in buffers.unwrap_or_default() {
+            for (name, value) in step_buffers {
+                let data = self.update_bytes(&step, Some(&name), &value)?;
+                updates.push(ParamUpdate::buffer(&step, &name, data));
+            }
+        }
+
+        let plan = self.inner.clone();
+        let result = py
+            .detach(|| {
+                self.rt
+                    .block_on(async { plan.execute_with_output(&updates, &output).await })
+            })
+            .map_err(to_py_err)?;
+        write_output_buffer(&buffer, &result)
+    }
+}
+
+impl PyExecutablePlan {
+    /// 書き換えの値を、名前を指定したステップのシェーダーのレイアウトに従ってバイト列に変換します。
+    fn update_bytes(
+        &self,
+        step: &str,
+        buffer: Option<&str>,
+        value: &Bound<'_, PyAny>,
+    ) -> PyResult<Vec<u8>> {
+        let prepared = self.inner.step(step).ok_or_else(|| {
+            PyValueError::new_err(format!("The plan has no step named `{}`", step))
+        })?;
+        params_to_bytes(
+            &prepared.wgsl,
+            Some(&prepared.entry_point),
+            buffer,
+            value,
+            |name| !prepared.is_params_buffer(name),
+        )
+    }
+}
+
+fn parse_output_options(
+    format: &str,
+    color_space: &str,
+    alpha_mode: &str,
+) -> PyResult<OutputOptions> {
+    Ok(OutputOptions {
+        format: parse_output_format(format)?,
+        color_space: parse_color_space(color_space)?,
+        alpha_mode: parse_alpha_mode(alpha_mode)?,
+    })
+}
+
+/// 出力先のバッファを取得し、書き込み可能で連続しているか、解像度が分かる場合はサイズが足りるかを確認します。
+fn get_output_buffer(
+    buffer: &Bound<'_, PyAny>,
+    output_size: Option<(u32, u32)>,
+    output: &OutputOptions,
+) -> PyResult<PyBuffer<u8>> {
+    let buffer = PyBuffer::<u8>::get(buffer)?;
+    if buffer.readonly() {
+        return Err(PyValueError::new_err("buffer must be writable"));
+    }
+    if !buffer.is_c_contiguous() {
+        return Err(PyValueError::new_err("buffer must be C-contiguous"));
+    }
+    // 解像度が分かる場合は実行前にサイズを確認する
+    if let Some((width, height)) = output_size {
+        check_buffer_size(&buffer, output.format.byte_size(width, height))?;
+    }
+    Ok(buffer)
+}
+
+fn write_output_buffer(buffer: &PyBuffer<u8>, result: &[u8]) -> PyResult<()> {
+    check_buffer_size(buffer, result.len())?;
+
+    // サイズと連続性を確認済みのバッファに直接メモリコピー
+    unsafe {
+        std::ptr::copy_nonoverlapping(result.as_ptr(), buffer.buf_ptr() as *mut u8, result.len());
+    }
+    Ok(())
+}
+
+fn check_buffer_size(buffer: &PyBuffer<u8>, size: usize) -> PyResult<()> {
+    if buffer.len_bytes() < size {
+        return Err(PyValueError::new_err(format!(
+            "buffer is too small: {} bytes are required but got {} bytes",
+            size,
+            buffer.len_bytes()
+        )));
+    }
+    Ok(())
+}
+
+/// Pythonの`logging`のレコードを、tracingのイベントとして出力します。
+/// levelはPythonのログレベル (`logging.DEBUG`など)、loggerはロガーの名前です。
+#[gen_stub_pyfunction]
+#[pyfunction]
+fn emit_log(level: u32, logger: &str, message: &str) {
+    match level {
+        50.. => tracing::error!(target: "python", logger, critical = true, "{}", message),
+        40.. => tracing::error!(target: "python", logger, "{}", message),
+        30.. => tracing::warn!(target: "python", logger, "{}", message),
+        20.. => tracing::info!(target: "python", logger, "{}", message),
+        10.. => tracing::debug!(target: "python", logger, "{}", message),
+        _ => tracing::trace!(target: "python", logger, "{}", message),
+    }
+}
+
+/// tracingで出力される最も詳細なレベルを、Pythonのログレベルで返します。
+/// Pythonのロガーのレベルに設定すると、出力されないレコードを作らずに済みます。
+#[gen_stub_pyfunction]
+#[pyfunction]
+fn max_log_level() -> u32 {
+    use tracing::level_filters::LevelFilter;
+    match LevelFilter::current() {
+        LevelFilter::TRACE => 5,
+        LevelFilter::DEBUG => 10,
+        LevelFilter::INFO => 20,
+        LevelFilter::WARN => 30,
+        LevelFilter::ERROR => 40,
+        // CRITICALより上のレベルにして、すべてのレコードを捨てる
+        _ => 60,
+    }
 }
 
 #[pymodule]
 pub fn gpu_util(m: &Bound<PyModule>) -> PyResult<()> {
-    println!("gpu_util: Initializing gpu_util module");
+    tracing::debug!("Initializing gpu_util module");
     m.add_class::<PySamplerOptions>()?;
+    m.add_class::<PyImageGeneratorOptions>()?;
+    m.add_class::<PyAdapterInfo>()?;
+    m.add_class::<PyResultCacheStats>()?;
+    m.add_class::<PyStepReport>()?;
+    m.add_class::<PyExecutionReport>()?;
+    m.add_class::<PyParamField>()?;
+    m.add_class::<PyParamLayout>()?;
     m.add_class::<PyCompiledWgsl>()?;
     m.add_class::<PyCompiledFunc>()?;
     m.add_class::<PyImageGenerateBuilder>()?;
+    m.add_class::<PyImageGraphBuilder>()?;
     m.add_class::<PyImageGenerator>()?;
+    m.add_class::<PyExecutablePlan>()?;
+    m.add_function(wrap_pyfunction!(emit_log, m)?)?;
+    m.add_function(wrap_pyfunction!(max_log_level, m)?)?;
+    m.add("GpuUtilError", m.py().get_type::<GpuUtilError>())?;
+    m.add("DeviceError", m.py().get_type::<DeviceError>())?;
+    m.add("ShaderError", m.py().get_type::<ShaderError>())?;
+    m.add("BindingError", m.py().get_type::<BindingError>())?;
+    m.add("SizeError", m.py().get_type::<SizeError>())?;
+    m.add("CpuFuncError", m.py().get_type::<CpuFuncError>())?;
+    m.add("ValidationError", m.py().get_type::<ValidationError>())?;
+    m.add("ReadbackError", m.py().get_type::<ReadbackError>())?;
+    m.add(
+        "ShaderCompileError",
+        m.py().get_type::<ShaderCompileError>(),
+    )?;
     Ok(())
 }
 
@@ -0,0 +1,300 @@
+// output_format.rs
+
+use crate::color_space::{AlphaMode, ColorSpace};
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::{ParallelSlice, ParallelSliceMut},
+};
+
+/// `generate`が出力するピクセルフォーマット。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    /// 1ピクセル4バイトのRGBA (各8bit)。
+    #[default]
+    Rgba8,
+    /// 1ピクセル4バイトのBGRA (各8bit)。
+    Bgra8,
+    /// 1ピクセル8バイトのRGBA (各16bit unorm, リトルエンディアン)。
+    Rgba16Unorm,
+    /// 1ピクセル16バイトのRGBA (各f32, リトルエンディアン)。値を量子化せずに出力します。
+    Rgba32Float,
+    /// BT.709 limited rangeのYUV 4:2:0。Y, U, Vの各平面を順に並べます。
+    I420,
+    /// BT.709 limited rangeのYUV 4:2:0。Y平面の後にUVを交互に並べた平面が続きます。
+    Nv12,
+}
+
+/// `generate`の出力の設定。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OutputOptions {
+    pub format: OutputFormat,
+    /// 出力の色空間。作業用の色空間から自動で変換されます。
+    pub color_space: ColorSpace,
+    /// 出力のアルファの形式。
+    pub alpha_mode: AlphaMode,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::Rgba8,
+            color_space: ColorSpace::SRGB,
+            alpha_mode: AlphaMode::Straight,
+        }
+    }
+}
+
+impl OutputFormat {
+    /// `post_process.wgsl`の`params.format`に渡す値
+    pub(crate) fn shader_id(self) -> u32 {
+        match self {
+            Self::Rgba8 => 0,
+            Self::Bgra8 => 1,
+            Self::Rgba16Unorm => 2,
+            Self::Rgba32Float => 3,
+            Self::I420 => 4,
+            Self::Nv12 => 5,
+        }
+    }
+
+    /// 指定した解像度の画像をこのフォーマットで出力した場合のバイト数。
+    /// YUVの色差平面は奇数の解像度では切り上げたサイズになります。
+    pub fn byte_size(self, width: u32, height: u32) -> usize {
+        let pixels = width as usize * height as usize;
+        match self {
+            Self::Rgba8 | Self::Bgra8 => pixels * 4,
+            Self::Rgba16Unorm => pixels * 8,
+            Self::Rgba32Float => pixels * 16,
+            Self::I420 | Self::Nv12 => {
+                let chroma = width.div_ceil(2) as usize * height.div_ceil(2) as usize;
+                pixels + chroma * 2
+            }
+        }
+    }
+
+    /// CPU上のRGBA f32の画像をこのフォーマットに変換します。
+    /// `post_process.wgsl`と同じ結果になるようにしています。
+    pub(crate) fn convert_cpu(self, data: &[f32], width: u32, height: u32) -> Vec<u8> {
+        let mut result = vec![0u8; self.byte_size(width, height)];
+        match self {
+            Self::Rgba8 | Self::Bgra8 => {
+                let order = if self == Self::Rgba8 {
+                    [0, 1, 2, 3]
+                } else {
+                    [2, 1, 0, 3]
+                };
+                result
+                    .par_chunks_exact_mut(4)
+                    .zip_eq(data.par_chunks_exact(4))
+                    .for_each(|(dst, src)| {
+                        for (d, &c) in dst.iter_mut().zip(&order) {
+                            *d = unorm8(src[c]);
+                        }
+                    });
+            }
+            Self::Rgba16Unorm => {
+                result
+                    .par_chunks_exact_mut(8)
+                    .zip_eq(data.par_chunks_exact(4))
+                    .for_each(|(dst, src)| {
+                        for (d, &c) in dst.chunks_exact_mut(2).zip(src) {
+                            d.copy_from_slice(&unorm16(c).to_le_bytes());
+                        }
+                    });
+            }
+            Self::Rgba32Float => {
+                result
+                    .par_chunks_exact_mut(16)
+                    .zip_eq(data.par_chunks_exact(4))
+                    .for_each(|(dst, src)| {
+                        for (d, &c) in dst.chunks_exact_mut(4).zip(src) {
+                            d.copy_from_slice(&c.to_le_bytes());
+                        }
+                    });
+            }
+            Self::I420 | Self::Nv12 => {
+                let (w, h) = (width as usize, height as usize);
+                let (chroma_w, chroma_h) = (w.div_ceil(2), h.div_ceil(2));
+                let pixel = |x: usize, y: usize| &data[(y * w + x) * 4..(y * w + x) * 4 + 4];
+
+                let (luma_plane, chroma_planes) = result.split_at_mut(w * h);
+                luma_plane
+                    .par_chunks_exact_mut(w.max(1))
+                    .enumerate()
+                    .for_each(|(y, row)| {
+                        for (x, dst) in row.iter_mut().enumerate() {
+                            *dst = y_code(pixel(x, y));
+                        }
+                    });
+
+                // 2x2ブロックの平均色から色差を計算する (画像の端ではブロック内の画素のみ)
+                let chroma = |cx: usize, cy: usize| {
+                    let mut sum = [0.0f32; 3];
+                    let mut count = 0.0;
+                    for y in cy * 2..(cy * 2 + 2).min(h) {
+                        for x in cx * 2..(cx * 2 + 2).min(w) {
+                            let p = pixel(x, y);
+                            for c in 0..3 {
+                                sum[c] += p[c].clamp(0.0, 1.0);
+                            }
+                            count += 1.0;
+                        }
+                    }
+                    chroma_codes(sum.map(|s| s / count))
+                };
+
+                if self == Self::I420 {
+                    let (u_plane, v_plane) = chroma_planes.split_at_mut(chroma_w * chroma_h);
+                    u_plane
+                        .par_chunks_exact_mut(chroma_w.max(1))
+                        .zip_eq(v_plane.par_chunks_exact_mut(chroma_w.max(1)))
+                        .enumerate()
+                        .for_each(|(cy, (u_row, v_row))| {
+                            for cx in 0..chroma_w {
+                                [u_row[cx], v_row[cx]] = chroma(cx, cy);
+                            }
+                        });
+                } else {
+                    chroma_planes
+                        .par_chunks_exact_mut((chroma_w * 2).max(1))
+                        .enumerate()
+                        .for_each(|(cy, row)| {
+                            for (cx, uv) in row.chunks_exact_mut(2).enumerate() {
+                                uv.copy_from_slice(&chroma(cx, cy));
+                            }
+                        });
+                }
+            }
+        }
+        result
+    }
+}
+
+#[inline(always)]
+fn unorm8(x: f32) -> u8 {
+    // 0..255 にクリップしてから u8 へ（切り捨て）
+    (x * 255.0).clamp(0.0, 255.0) as u8
+}
+
+#[inline(always)]
+fn unorm16(x: f32) -> u16 {
+    (x * 65535.0).clamp(0.0, 65535.0) as u16
+}
+
+// BT.709の輝度係数
+const KR: f32 = 0.2126;
+const KB: f32 = 0.0722;
+const KG: f32 = 1.0 - KR - KB;
+
+#[inline(always)]
+fn luma(rgb: [f32; 3]) -> f32 {
+    KR * rgb[0] + KG * rgb[1] + KB * rgb[2]
+}
+
+/// limited rangeの輝度 (16..235)
+#[inline(always)]
+fn y_code(rgba: &[f32]) -> u8 {
+    let rgb = [rgba[0], rgba[1], rgba[2]].map(|c| c.clamp(0.0, 1.0));
+    (16.0 + 219.0 * luma(rgb) + 0.5).floor() as u8
+}
+
+/// limited rangeの色差 (16..240)。`[Cb, Cr]`の順
+#[inline(always)]
+fn chroma_codes(rgb: [f32; 3]) -> [u8; 2] {
+    let y = luma(rgb);
+    let cb = (rgb[2] - y) / (2.0 * (1.0 - KB));
+    let cr = (rgb[0] - y) / (2.0 * (1.0 - KR));
+    [cb, cr].map(|c| (128.0 + 224.0 * c + 0.5).floor() as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+    const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+    const RED: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+    const GREEN: [f32; 4] = [0.0, 1.0, 0.0, 1.0];
+    const BLUE: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+
+    fn image(pixels: &[[f32; 4]]) -> Vec<f32> {
+        pixels.concat()
+    }
+
+    // 3x3の画像。右下の2x2ブロックは画素1つ、右端と下端のブロックは画素2つになる
+    fn odd_image() -> Vec<f32> {
+        image(&[
+            RED, BLACK, BLUE, //
+            BLACK, RED, BLUE, //
+            RED, RED, GREEN,
+        ])
+    }
+
+    #[test]
+    fn byte_size_rounds_chroma_up() {
+        assert_eq!(OutputFormat::Rgba8.byte_size(3, 3), 36);
+        assert_eq!(OutputFormat::Rgba16Unorm.byte_size(3, 3), 72);
+        assert_eq!(OutputFormat::Rgba32Float.byte_size(3, 3), 144);
+        for format in [OutputFormat::I420, OutputFormat::Nv12] {
+            assert_eq!(format.byte_size(4, 2), 8 + 2 * 2);
+            assert_eq!(format.byte_size(3, 3), 9 + 2 * 2 * 2);
+            assert_eq!(format.byte_size(5, 1), 5 + 3 * 2);
+            assert_eq!(format.byte_size(1, 1), 1 + 2);
+        }
+    }
+
+    #[test]
+    fn bt709_limited_range_codes() {
+        // [Y, Cb, Cr]
+        let cases = [
+            (BLACK, [16, 128, 128]),
+            (WHITE, [235, 128, 128]),
+            (RED, [63, 102, 240]),
+            (GREEN, [173, 42, 26]),
+            (BLUE, [32, 240, 118]),
+        ];
+        for (color, expected) in cases {
+            let out = OutputFormat::I420.convert_cpu(&color, 1, 1);
+            assert_eq!(out, expected, "{color:?}");
+            let out = OutputFormat::Nv12.convert_cpu(&color, 1, 1);
+            assert_eq!(out, expected, "{color:?}");
+        }
+        // 範囲外の値はクリップされる
+        let out = OutputFormat::I420.convert_cpu(&[2.0, 2.0, 2.0, 1.0], 1, 1);
+        assert_eq!(out, [235, 128, 128]);
+    }
+
+    #[test]
+    fn i420_with_odd_size_averages_partial_blocks() {
+        let out = OutputFormat::I420.convert_cpu(&odd_image(), 3, 3);
+        assert_eq!(out.len(), OutputFormat::I420.byte_size(3, 3));
+        let (y, chroma) = out.split_at(9);
+        let (u, v) = chroma.split_at(4);
+        assert_eq!(y, [63, 16, 32, 16, 63, 32, 63, 63, 173]);
+        // 左上は赤と黒の平均、右端は青2画素、下端は赤2画素、右下は緑1画素のみ
+        assert_eq!(u, [115, 240, 102, 42]);
+        assert_eq!(v, [184, 118, 240, 26]);
+    }
+
+    #[test]
+    fn nv12_with_odd_size_interleaves_chroma() {
+        let out = OutputFormat::Nv12.convert_cpu(&odd_image(), 3, 3);
+        assert_eq!(out.len(), OutputFormat::Nv12.byte_size(3, 3));
+        let (y, uv) = out.split_at(9);
+        assert_eq!(y, [63, 16, 32, 16, 63, 32, 63, 63, 173]);
+        assert_eq!(uv, [115, 184, 240, 118, 102, 240, 42, 26]);
+    }
+
+    #[test]
+    fn bgra_swaps_red_and_blue() {
+        let data = image(&[[1.0, 0.5, 0.0, 0.25], BLUE]);
+        assert_eq!(
+            OutputFormat::Rgba8.convert_cpu(&data, 2, 1),
+            [255, 127, 0, 63, 0, 0, 255, 255]
+        );
+        assert_eq!(
+            OutputFormat::Bgra8.convert_cpu(&data, 2, 1),
+            [0, 127, 255, 63, 255, 0, 0, 255]
+        );
+    }
+}
@@ -0,0 +1,677 @@
+// param_layout.rs
+
+use crate::error::GpuUtilError;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+/// パラメータとして扱えるスカラー型
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScalarType {
+    F32,
+    I32,
+    U32,
+}
+
+impl ScalarType {
+    fn from_naga(scalar: naga::Scalar) -> Option<Self> {
+        match (scalar.kind, scalar.width) {
+            (naga::ScalarKind::Float, 4) => Some(Self::F32),
+            (naga::ScalarKind::Sint, 4) => Some(Self::I32),
+            (naga::ScalarKind::Uint, 4) => Some(Self::U32),
+            _ => None,
+        }
+    }
+
+    fn wgsl_name(self) -> &'static str {
+        match self {
+            Self::F32 => "f32",
+            Self::I32 => "i32",
+            Self::U32 => "u32",
+        }
+    }
+}
+
+/// パラメータの型
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParamType {
+    Scalar(ScalarType),
+    Vector {
+        size: u32,
+        scalar: ScalarType,
+    },
+    /// 列優先 (column-major) の行列
+    Matrix {
+        columns: u32,
+        rows: u32,
+        scalar: ScalarType,
+    },
+    /// `count`が`None`の場合は実行時に長さが決まる配列
+    Array {
+        element: Box<ParamLayout>,
+        count: Option<u32>,
+        stride: u32,
+    },
+    Struct {
+        fields: Vec<ParamField>,
+    },
+}
+
+/// 構造体のメンバー
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParamField {
+    pub name: String,
+    pub offset: u32,
+    pub layout: ParamLayout,
+}
+
+/// シェーダーのバッファに渡すパラメータのメモリレイアウト。
+/// オフセットとパディングはWGSLのレイアウト規則に従い、naga が計算したものを使います。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParamLayout {
+    pub ty: ParamType,
+    /// 型のサイズ。実行時に長さが決まる配列を含む場合は要素1つ分のサイズになります。
+    pub size: u32,
+    pub align: u32,
+    /// WGSLでの型名
+    pub wgsl_type: String,
+}
+
+/// パラメータの値。Pythonのdict/list/数値から変換されます。
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParamValue {
+    Int(i64),
+    Float(f64),
+    List(Vec<ParamValue>),
+    Map(HashMap<String, ParamValue>),
+}
+
+impl ParamValue {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Int(_) => "int",
+            Self::Float(_) => "float",
+            Self::List(_) => "list",
+            Self::Map(_) => "dict",
+        }
+    }
+}
+
+impl ParamLayout {
+    /// naga の型からレイアウトを作成します。
+    /// パラメータとして詰められない型 (f16, boolなど) を含む場合は`None`を返します。
+    pub(crate) fn from_naga(
+        module: &naga::Module,
+        layouter: &naga::proc::Layouter,
+        ty: naga::Handle<naga::Type>,
+    ) -> Option<Self> {
+        let layout = &layouter[ty];
+        let size = layout.size;
+        // Alignmentは値を直接取り出せないため、1を切り上げた値として得る
+        let align = layout.alignment.round_up(1);
+
+        let (ty, wgsl_type) = match &module.types[ty].inner {
+            naga::TypeInner::Scalar(scalar) | naga::TypeInner::Atomic(scalar) => {
+                let scalar = ScalarType::from_naga(*scalar)?;
+                (ParamType::Scalar(scalar), scalar.wgsl_name().to_string())
+            }
+            naga::TypeInner::Vector { size, scalar } => {
+                let scalar = ScalarType::from_naga(*scalar)?;
+                let size = *size as u32;
+                (
+                    ParamType::Vector { size, scalar },
+                    format!("vec{}<{}>", size, scalar.wgsl_name()),
+                )
+            }
+            naga::TypeInner::Matrix {
+                columns,
+                rows,
+                scalar,
+            } => {
+                let scalar = ScalarType::from_naga(*scalar)?;
+                let (columns, rows) = (*columns as u32, *rows as u32);
+                (
+                    ParamType::Matrix {
+                        columns,
+                        rows,
+                        scalar,
+                    },
+                    format!("mat{}x{}<{}>", columns, rows, scalar.wgsl_name()),
+                )
+            }
+            naga::TypeInner::Array {
+                base,
+                size: array_size,
+                stride,
+            } => {
+                let element = Self::from_naga(module, layouter, *base)?;
+                let count = match array_size {
+                    naga::ArraySize::Constant(count) => Some(count.get()),
+                    naga::ArraySize::Dynamic => None,
+                    naga::ArraySize::Pending(_) => return None,
+                };
+                let wgsl_type = match count {
+                    Some(count) => format!("array<{}, {}>", element.wgsl_type, count),
+                    None => format!("array<{}>", element.wgsl_type),
+                };
+                (
+                    ParamType::Array {
+                        element: Box::new(element),
+                        count,
+                        stride: *stride,
+                    },
+                    wgsl_type,
+                )
+            }
+            naga::TypeInner::Struct { members, .. } => {
+                let fields = members
+                    .iter()
+                    .map(|member| {
+                        Some(ParamField {
+                            name: member.name.clone().unwrap_or_default(),
+                            offset: member.offset,
+                            layout: Self::from_naga(module, layouter, member.ty)?,
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                (
+                    ParamType::Struct { fields },
+                    module.types[ty].name.clone().unwrap_or_default(),
+                )
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            ty,
+            size,
+            align,
+            wgsl_type,
+        })
+    }
+
+    /// 値をこのレイアウトに従ってバイト列に詰めます。
+    /// `name`はエラーメッセージでの値の名前 (通常はバッファの変数名) です。
+    /// 値の種類やフィールドが型と一致しない場合は`GpuUtilError::Binding`、
+    /// 要素数が一致しない場合や整数が範囲外の場合は`GpuUtilError::Size`を返します。
+    pub fn pack(&self, value: &ParamValue, name: &str) -> Result<Vec<u8>> {
+        // 固定サイズの型は末尾のパディングまで、実行時に長さが決まる配列は要素数ちょうどまで書き込まれる。
+        // arrayLengthはバッファサイズから求まるため、アラインメントに揃える切り上げはしない。
+        // ただしバインディングには要素1つ分を含む型のサイズが最低限必要なため、要素が足りない場合はそこまで0で埋める
+        let mut out = Vec::new();
+        self.write(value, &mut out, 0, name)?;
+        ensure_len(&mut out, self.size as usize);
+        Ok(out)
+    }
+
+    /// 実行時に長さが決まる配列を含むかどうか
+    fn is_runtime_sized(&self) -> bool {
+        match &self.ty {
+            ParamType::Array { count: None, .. } => true,
+            ParamType::Struct { fields } => {
+                fields.last().is_some_and(|f| f.layout.is_runtime_sized())
+            }
+            _ => false,
+        }
+    }
+
+    fn write(
+        &self,
+        value: &ParamValue,
+        out: &mut Vec<u8>,
+        offset: usize,
+        path: &str,
+    ) -> Result<()> {
+        // 固定サイズの型は、値が書き込まれない部分 (パディング) も含めて確保しておく
+        if !self.is_runtime_sized() {
+            ensure_len(out, offset + self.size as usize);
+        }
+
+        match &self.ty {
+            ParamType::Scalar(scalar) => write_scalar(*scalar, value, out, offset, path),
+            ParamType::Vector { size, scalar } => {
+                let items = expect_list(value, *size as usize, &self.wgsl_type, path)?;
+                for (i, item) in items.iter().enumerate() {
+                    write_scalar(
+                        *scalar,
+                        item,
+                        out,
+                        offset + i * 4,
+                        &format!("{}[{}]", path, i),
+                    )?;
+                }
+                Ok(())
+            }
+            ParamType::Matrix {
+                columns,
+                rows,
+                scalar,
+            } => {
+                let (columns, rows) = (*columns as usize, *rows as usize);
+                // 列はvecNとして配置されるため、vec3/vec4は16バイト、vec2は8バイトごとに並ぶ
+                let column_stride = if rows == 2 { 8 } else { 16 };
+                // 列のリストのリスト、または列優先で平坦化したリストを受け付ける
+                let ParamValue::List(items) = value else {
+                    bail!(GpuUtilError::binding(
+                        None,
+                        format!(
+                            "{}: expected a list for {}, got {}",
+                            path,
+                            self.wgsl_type,
+                            value.kind()
+                        ),
+                    ));
+                };
+                let flat: Vec<&ParamValue> =
+                    if items.iter().all(|v| matches!(v, ParamValue::List(_))) {
+                        if items.len() != columns {
+                            bail!(GpuUtilError::size(
+                                None,
+                                format!(
+                                    "{}: expected {} columns for {}, got {}",
+                                    path,
+                                    columns,
+                                    self.wgsl_type,
+                                    items.len()
+                                ),
+                            ));
+                        }
+                        let mut flat = Vec::with_capacity(columns * rows);
+                        for (c, column) in items.iter().enumerate() {
+                            flat.extend(expect_list(
+                                column,
+                                rows,
+                                &self.wgsl_type,
+                                &format!("{}[{}]", path, c),
+                            )?);
+                        }
+                        flat
+                    } else {
+                        expect_list(value, columns * rows, &self.wgsl_type, path)?
+                            .iter()
+                            .collect()
+                    };
+                for (i, item) in flat.into_iter().enumerate() {
+                    let (c, r) = (i / rows, i % rows);
+                    write_scalar(
+                        *scalar,
+                        item,
+                        out,
+                        offset + c * column_stride + r * 4,
+                        &format!("{}[{}][{}]", path, c, r),
+                    )?;
+                }
+                Ok(())
+            }
+            ParamType::Array {
+                element,
+                count,
+                stride,
+            } => {
+                let ParamValue::List(items) = value else {
+                    bail!(GpuUtilError::binding(
+                        None,
+                        format!(
+                            "{}: expected a list for {}, got {}",
+                            path,
+                            self.wgsl_type,
+                            value.kind()
+                        ),
+                    ));
+                };
+                if let Some(count) = count {
+                    if items.len() != *count as usize {
+                        bail!(GpuUtilError::size(
+                            None,
+                            format!(
+                                "{}: expected {} elements for {}, got {}",
+                                path,
+                                count,
+                                self.wgsl_type,
+                                items.len()
+                            ),
+                        ));
+                    }
+                }
+                // arrayLengthはバッファサイズ / strideで求まるため、要素数ちょうどの長さを確保する
+                ensure_len(out, offset + items.len() * *stride as usize);
+                for (i, item) in items.iter().enumerate() {
+                    element.write(
+                        item,
+                        out,
+                        offset + i * *stride as usize,
+                        &format!("{}[{}]", path, i),
+                    )?;
+                }
+                Ok(())
+            }
+            ParamType::Struct { fields } => {
+                let ParamValue::Map(map) = value else {
+                    bail!(GpuUtilError::binding(
+                        None,
+                        format!(
+                            "{}: expected a dict for {}, got {}",
+                            path,
+                            self.wgsl_type,
+                            value.kind()
+                        ),
+                    ));
+                };
+                if let Some(unknown) = map.keys().find(|k| !fields.iter().any(|f| &f.name == *k)) {
+                    bail!(GpuUtilError::binding(
+                        None,
+                        format!(
+                            "{}: unknown field `{}` for {} (fields: {})",
+                            path,
+                            unknown,
+                            self.wgsl_type,
+                            fields
+                                .iter()
+                                .map(|f| f.name.as_str())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                    ));
+                }
+                for field in fields {
+                    let field_path = format!("{}.{}", path, field.name);
+                    let Some(field_value) = map.get(&field.name) else {
+                        bail!(GpuUtilError::binding(
+                            None,
+                            format!(
+                                "{}: missing field of type {}",
+                                field_path, field.layout.wgsl_type
+                            ),
+                        ));
+                    };
+                    field.layout.write(
+                        field_value,
+                        out,
+                        offset + field.offset as usize,
+                        &field_path,
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn ensure_len(out: &mut Vec<u8>, len: usize) {
+    if out.len() < len {
+        out.resize(len, 0);
+    }
+}
+
+fn expect_list<'a>(
+    value: &'a ParamValue,
+    len: usize,
+    wgsl_type: &str,
+    path: &str,
+) -> Result<&'a [ParamValue]> {
+    match value {
+        ParamValue::List(items) if items.len() == len => Ok(items),
+        ParamValue::List(items) => bail!(GpuUtilError::size(
+            None,
+            format!(
+                "{}: expected {} elements for {}, got {}",
+                path,
+                len,
+                wgsl_type,
+                items.len()
+            ),
+        )),
+        _ => bail!(GpuUtilError::binding(
+            None,
+            format!(
+                "{}: expected a list for {}, got {}",
+                path,
+                wgsl_type,
+                value.kind()
+            ),
+        )),
+    }
+}
+
+fn write_scalar(
+    scalar: ScalarType,
+    value: &ParamValue,
+    out: &mut Vec<u8>,
+    offset: usize,
+    path: &str,
+) -> Result<()> {
+    let bytes = match (scalar, value) {
+        (ScalarType::F32, ParamValue::Float(v)) => (*v as f32).to_le_bytes(),
+        (ScalarType::F32, ParamValue::Int(v)) => (*v as f32).to_le_bytes(),
+        (ScalarType::I32, ParamValue::Int(v)) => match i32::try_from(*v) {
+            Ok(v) => v.to_le_bytes(),
+            Err(_) => bail!(GpuUtilError::size(
+                None,
+                format!("{}: {} is out of range for i32", path, v)
+            )),
+        },
+        (ScalarType::U32, ParamValue::Int(v)) => match u32::try_from(*v) {
+            Ok(v) => v.to_le_bytes(),
+            Err(_) => bail!(GpuUtilError::size(
+                None,
+                format!("{}: {} is out of range for u32", path, v)
+            )),
+        },
+        _ => bail!(GpuUtilError::binding(
+            None,
+            format!(
+                "{}: expected {} for {}, got {}",
+                path,
+                if scalar == ScalarType::F32 {
+                    "a number"
+                } else {
+                    "an int"
+                },
+                scalar.wgsl_name(),
+                value.kind()
+            ),
+        )),
+    };
+    ensure_len(out, offset + 4);
+    out[offset..offset + 4].copy_from_slice(&bytes);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // WGSLを解析し、指定した名前のバッファのレイアウトを作る
+    fn layout_of(source: &str, name: &str) -> ParamLayout {
+        let module = naga::front::wgsl::parse_str(source).unwrap();
+        let mut layouter = naga::proc::Layouter::default();
+        layouter.update(module.to_ctx()).unwrap();
+        let (_, var) = module
+            .global_variables
+            .iter()
+            .find(|(_, var)| var.name.as_deref() == Some(name))
+            .unwrap();
+        ParamLayout::from_naga(&module, &layouter, var.ty).unwrap()
+    }
+
+    fn float(v: f64) -> ParamValue {
+        ParamValue::Float(v)
+    }
+
+    fn floats(values: &[f64]) -> ParamValue {
+        ParamValue::List(values.iter().copied().map(ParamValue::Float).collect())
+    }
+
+    fn map(fields: Vec<(&str, ParamValue)>) -> ParamValue {
+        ParamValue::Map(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        )
+    }
+
+    // f32の列をリトルエンディアンのバイト列にする
+    fn f32_bytes(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn pack_error(layout: &ParamLayout, value: &ParamValue) -> GpuUtilError {
+        layout
+            .pack(value, "p")
+            .unwrap_err()
+            .downcast::<GpuUtilError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn matrix_columns_are_aligned_to_vec4() {
+        let layout = layout_of("@group(0) @binding(0) var<uniform> p: mat3x3<f32>;", "p");
+        assert_eq!(layout.size, 48);
+        let value = ParamValue::List(vec![
+            floats(&[1.0, 2.0, 3.0]),
+            floats(&[4.0, 5.0, 6.0]),
+            floats(&[7.0, 8.0, 9.0]),
+        ]);
+        let expected = f32_bytes(&[1.0, 2.0, 3.0, 0.0, 4.0, 5.0, 6.0, 0.0, 7.0, 8.0, 9.0, 0.0]);
+        assert_eq!(layout.pack(&value, "p").unwrap(), expected);
+        // 列優先で平坦化したリストも同じ配置になる
+        let flat = floats(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+        assert_eq!(layout.pack(&flat, "p").unwrap(), expected);
+    }
+
+    #[test]
+    fn matrix_with_two_rows_is_tightly_packed() {
+        let layout = layout_of("@group(0) @binding(0) var<uniform> p: mat3x2<f32>;", "p");
+        assert_eq!(layout.size, 24);
+        let value = floats(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(
+            layout.pack(&value, "p").unwrap(),
+            f32_bytes(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0])
+        );
+    }
+
+    #[test]
+    fn struct_members_follow_wgsl_offsets() {
+        let layout = layout_of(
+            "struct P { a: f32, b: vec2<f32>, c: vec3<f32>, d: f32, e: vec3<f32> }
+             @group(0) @binding(0) var<uniform> p: P;",
+            "p",
+        );
+        // a@0, b@8, c@16, dはvec3の末尾の余白@28, e@32, サイズは16の倍数
+        assert_eq!(layout.size, 48);
+        let value = map(vec![
+            ("a", float(1.0)),
+            ("b", floats(&[2.0, 3.0])),
+            ("c", floats(&[4.0, 5.0, 6.0])),
+            ("d", float(7.0)),
+            ("e", floats(&[8.0, 9.0, 10.0])),
+        ]);
+        assert_eq!(
+            layout.pack(&value, "p").unwrap(),
+            f32_bytes(&[1.0, 0.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 0.0])
+        );
+    }
+
+    #[test]
+    fn integers_are_written_as_i32_and_u32() {
+        let layout = layout_of(
+            "struct P { a: i32, b: u32 }
+             @group(0) @binding(0) var<uniform> p: P;",
+            "p",
+        );
+        let value = map(vec![
+            ("a", ParamValue::Int(-2)),
+            ("b", ParamValue::Int(u32::MAX as i64)),
+        ]);
+        let mut expected = (-2i32).to_le_bytes().to_vec();
+        expected.extend(u32::MAX.to_le_bytes());
+        assert_eq!(layout.pack(&value, "p").unwrap(), expected);
+    }
+
+    #[test]
+    fn out_of_range_integers_are_size_errors() {
+        let layout = layout_of("@group(0) @binding(0) var<uniform> p: vec2<u32>;", "p");
+        let value = ParamValue::List(vec![ParamValue::Int(0), ParamValue::Int(-1)]);
+        let error = pack_error(&layout, &value);
+        assert!(matches!(error, GpuUtilError::Size { .. }), "{error}");
+        assert!(error.to_string().contains("p[1]"), "{error}");
+
+        let layout = layout_of("@group(0) @binding(0) var<uniform> p: i32;", "p");
+        let error = pack_error(&layout, &ParamValue::Int(i32::MAX as i64 + 1));
+        assert!(matches!(error, GpuUtilError::Size { .. }), "{error}");
+    }
+
+    #[test]
+    fn mismatched_values_are_binding_errors() {
+        let layout = layout_of(
+            "struct P { a: i32 }
+             @group(0) @binding(0) var<uniform> p: P;",
+            "p",
+        );
+        let error = pack_error(&layout, &map(vec![("a", float(1.5))]));
+        assert!(matches!(error, GpuUtilError::Binding { .. }), "{error}");
+        let error = pack_error(
+            &layout,
+            &map(vec![("a", ParamValue::Int(1)), ("b", float(0.0))]),
+        );
+        assert!(matches!(error, GpuUtilError::Binding { .. }), "{error}");
+        let error = pack_error(&layout, &map(vec![]));
+        assert!(matches!(error, GpuUtilError::Binding { .. }), "{error}");
+    }
+
+    #[test]
+    fn fixed_arrays_require_exact_length() {
+        let layout = layout_of(
+            "@group(0) @binding(0) var<uniform> p: array<vec4<f32>, 2>;",
+            "p",
+        );
+        let error = pack_error(&layout, &ParamValue::List(vec![floats(&[0.0; 4])]));
+        assert!(matches!(error, GpuUtilError::Size { .. }), "{error}");
+    }
+
+    #[test]
+    fn runtime_arrays_are_packed_to_their_length() {
+        let layout = layout_of(
+            "@group(0) @binding(0) var<storage, read> p: array<vec3<f32>>;",
+            "p",
+        );
+        // vec3の要素は16バイトごとに並ぶ
+        let value = ParamValue::List(vec![floats(&[1.0, 2.0, 3.0]), floats(&[4.0, 5.0, 6.0])]);
+        assert_eq!(
+            layout.pack(&value, "p").unwrap(),
+            f32_bytes(&[1.0, 2.0, 3.0, 0.0, 4.0, 5.0, 6.0, 0.0])
+        );
+    }
+
+    #[test]
+    fn runtime_sized_struct_is_padded_to_binding_minimum() {
+        let layout = layout_of(
+            "struct P { count: u32, items: array<vec4<f32>> }
+             @group(0) @binding(0) var<storage, read> p: P;",
+            "p",
+        );
+        // 要素1つ分を含むサイズがバインディングの最小サイズになる
+        assert_eq!(layout.size, 32);
+
+        let empty = map(vec![
+            ("count", ParamValue::Int(0)),
+            ("items", ParamValue::List(vec![])),
+        ]);
+        assert_eq!(layout.pack(&empty, "p").unwrap(), vec![0; 32]);
+
+        let two = map(vec![
+            ("count", ParamValue::Int(2)),
+            (
+                "items",
+                ParamValue::List(vec![floats(&[1.0; 4]), floats(&[2.0; 4])]),
+            ),
+        ]);
+        let packed = layout.pack(&two, "p").unwrap();
+        assert_eq!(packed.len(), 48);
+        assert_eq!(packed[..4], 2u32.to_le_bytes());
+        assert_eq!(
+            packed[16..],
+            f32_bytes(&[1.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 2.0])
+        );
+    }
+}
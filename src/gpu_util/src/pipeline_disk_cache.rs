@@ -0,0 +1,185 @@
+// pipeline_disk_cache.rs
+
+use crate::image_generator::PipelineCacheKey;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+use tracing::{debug, warn};
+
+/// 再起動をまたいでも値が変わらないハッシュ (FNV-1a 64bit)。
+/// ファイル名や永続化するキーに使うため、実行ごとに変わる`DefaultHasher`は使わない。
+pub(crate) fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// 過去に生成に成功したパイプラインの記録。
+/// WGSLソースのハッシュごとに、使用されたパイプラインキーを保持する。
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    modules: HashMap<String, Vec<PipelineCacheKey>>,
+}
+
+/// ディスクに永続化されるパイプラインキャッシュ。
+///
+/// - アダプタが`PIPELINE_CACHE`に対応していれば、ドライバのコンパイル結果を`wgpu::PipelineCache`として保存します。
+/// - 検証とパイプライン生成に成功したシェーダーモジュールを (WGSLソースのハッシュ + `PipelineCacheKey`) で記録し、
+///   次回起動時に同じシェーダーが読み込まれた時点でパイプラインを事前生成できるようにします。
+///
+/// 記録はメモリ上で行い、ディスクへの書き出しは`flush`と破棄時にまとめて行います。
+pub(crate) struct PipelineDiskCache {
+    wgpu_cache: Option<wgpu::PipelineCache>,
+    wgpu_cache_path: Option<PathBuf>,
+    manifest: Mutex<Manifest>,
+    manifest_path: PathBuf,
+    // 最後に書き出してから新しいパイプラインが記録されたかどうか
+    dirty: AtomicBool,
+    // 同時に書き出すと一時ファイルが競合するため、書き出しを直列にする
+    write_lock: Mutex<()>,
+}
+
+impl PipelineDiskCache {
+    /// キャッシュディレクトリからキャッシュを読み込みます。存在しない場合は空のキャッシュを作成します。
+    pub(crate) fn open(
+        device: &wgpu::Device,
+        adapter_info: &wgpu::AdapterInfo,
+        dir: &Path,
+    ) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create pipeline cache dir {:?}", dir))?;
+
+        // ドライバのキャッシュはアダプタ・ドライバごとに別ファイルにする
+        let wgpu_cache_path = if device.features().contains(wgpu::Features::PIPELINE_CACHE) {
+            wgpu::util::pipeline_cache_key(adapter_info).map(|key| dir.join(key))
+        } else {
+            None
+        };
+        let wgpu_cache = wgpu_cache_path.as_ref().map(|path| {
+            let data = fs::read(path).ok();
+            // SAFETY: dataはこのキャッシュが過去にget_dataで書き出したもの。
+            // 互換性のないデータの場合はfallbackにより空のキャッシュが作成される。
+            unsafe {
+                device.create_pipeline_cache(&wgpu::PipelineCacheDescriptor {
+                    label: Some("ImageGenerator Pipeline Cache"),
+                    data: data.as_deref(),
+                    fallback: true,
+                })
+            }
+        });
+
+        // 記録したキーの構成が使えるかはアダプタに依存するため、マニフェストもアダプタごとに分ける
+        // 壊れたマニフェストは無視して作り直す
+        let adapter_hash = stable_hash(
+            format!(
+                "{:?}-{}-{}-{}-{}",
+                adapter_info.backend,
+                adapter_info.vendor,
+                adapter_info.device,
+                adapter_info.name,
+                adapter_info.driver_info
+            )
+            .as_bytes(),
+        );
+        let manifest_path = dir.join(format!("pipelines_{:016x}.json", adapter_hash));
+        let manifest = match fs::read(&manifest_path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!(path = ?manifest_path, error = %e, "Discarding corrupted pipeline manifest");
+                Manifest::default()
+            }),
+            Err(_) => Manifest::default(),
+        };
+        debug!(
+            dir = ?dir,
+            driver_cache = wgpu_cache.is_some(),
+            modules = manifest.modules.len(),
+            "Opened pipeline disk cache"
+        );
+
+        Ok(Self {
+            wgpu_cache,
+            wgpu_cache_path,
+            manifest: Mutex::new(manifest),
+            manifest_path,
+            dirty: AtomicBool::new(false),
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// パイプライン生成時に渡すwgpuのキャッシュ
+    pub(crate) fn wgpu_cache(&self) -> Option<&wgpu::PipelineCache> {
+        self.wgpu_cache.as_ref()
+    }
+
+    /// 指定したソースのシェーダーについて、過去に生成したパイプラインのキーを返します。
+    pub(crate) fn known_pipelines(&self, source_hash: u64) -> Vec<PipelineCacheKey> {
+        self.manifest
+            .lock()
+            .unwrap()
+            .modules
+            .get(&format!("{:016x}", source_hash))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// 新しく生成したパイプラインを記録します。ディスクには`flush`で書き出されます。
+    pub(crate) fn record(&self, source_hash: u64, key: &PipelineCacheKey) {
+        let mut manifest = self.manifest.lock().unwrap();
+        let keys = manifest
+            .modules
+            .entry(format!("{:016x}", source_hash))
+            .or_default();
+        if keys.contains(key) {
+            return;
+        }
+        keys.push(key.clone());
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// 前回の書き出し以降に記録されたパイプラインがあれば、マニフェストとドライバのキャッシュをディスクに書き出します。
+    pub(crate) fn flush(&self) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        let manifest_data = serde_json::to_vec(&*self.manifest.lock().unwrap())?;
+        write_atomic(&self.manifest_path, &manifest_data)?;
+
+        if let (Some(cache), Some(path)) = (&self.wgpu_cache, &self.wgpu_cache_path) {
+            if let Some(data) = cache.get_data() {
+                write_atomic(path, &data)?;
+            }
+        }
+        debug!(path = ?self.manifest_path, "Flushed pipeline disk cache");
+
+        Ok(())
+    }
+}
+
+impl Drop for PipelineDiskCache {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!(error = ?e, "Failed to persist pipeline cache");
+        }
+    }
+}
+
+/// 書き込み途中で終了してもファイルが壊れないよう、一時ファイルに書いてからリネームする
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data).with_context(|| format!("Failed to write {:?}", tmp_path))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Failed to write {:?}", path))?;
+    Ok(())
+}
@@ -0,0 +1,480 @@
+// pipeline_graph.rs
+
+use crate::compiled_func::CompiledFunc;
+use crate::compiled_wgsl::CompiledWgsl;
+use crate::error::GpuUtilError;
+use crate::image_generate_builder::{ImageGenerateBuilder, PipelineStep, WgslStepOptions};
+use anyhow::{bail, Result};
+use std::{collections::HashMap, sync::Arc};
+
+/// グラフのノードの入力。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GraphInput {
+    /// 名前を指定したノードのすべての出力。
+    Node(String),
+    /// グラフのステップに渡された画像のうち、指定した位置のもの。
+    Previous(usize),
+}
+
+impl From<&str> for GraphInput {
+    fn from(name: &str) -> Self {
+        Self::Node(name.to_string())
+    }
+}
+
+impl From<usize> for GraphInput {
+    fn from(index: usize) -> Self {
+        Self::Previous(index)
+    }
+}
+
+// 名前を解決したノードの入力
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ResolvedInput {
+    // 実行順に並べたノードの位置
+    Node(usize),
+    Previous(usize),
+}
+
+// 構築中のノード
+#[derive(Clone)]
+struct GraphNodeDesc {
+    name: String,
+    step: PipelineStep,
+    inputs: Vec<GraphInput>,
+}
+
+/// 実行順に並べたグラフのノード。
+#[derive(Clone)]
+pub(crate) struct GraphNode {
+    pub(crate) name: String,
+    pub(crate) step: PipelineStep,
+    pub(crate) inputs: Vec<ResolvedInput>,
+    // このノードの出力を最後に読むノードの位置。グラフの出力になるノードは`usize::MAX`
+    pub(crate) last_use: usize,
+}
+
+/// 名前付きのノードと入力の辺からなる、検証済みの処理グラフ。
+/// `ImageGenerateBuilder::add_graph`でパイプラインのステップとして追加します。
+#[derive(Clone)]
+pub struct PipelineGraph {
+    // トポロジカル順に並べたノード
+    pub(crate) nodes: Vec<GraphNode>,
+    // グラフの出力になるノードの位置
+    pub(crate) outputs: Vec<usize>,
+}
+
+impl PipelineGraph {
+    /// グラフが最終的に出力する画像の解像度を返します。
+    /// 単一の画像にならない場合は`None`を返します。
+    pub fn output_size(&self) -> Option<(u32, u32)> {
+        match self.outputs.as_slice() {
+            [output] => self.nodes[*output].step.output_size(),
+            _ => None,
+        }
+    }
+}
+
+/// 処理グラフを構築するためのビルダー。
+///
+/// 各ノードは名前と入力の一覧を持ち、入力には他のノードの名前か、グラフのステップに渡された画像の位置を指定します。
+/// ノードは追加した順序に関係なく、入力の依存関係に従って実行されます。
+/// 1つのノードの出力を複数のノードの入力に使うことや、前の画像の一部だけを入力にすることができます。
+#[derive(Clone, Default)]
+pub struct GraphBuilder {
+    nodes: Vec<GraphNodeDesc>,
+    outputs: Vec<String>,
+}
+
+impl GraphBuilder {
+    /// 新しいGraphBuilderインスタンスを作成します。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// WGSL処理のノードを追加します。
+    pub fn add_wgsl(
+        self,
+        name: &str,
+        wgsl: CompiledWgsl,
+        params: Option<Vec<u8>>,
+        inputs: Vec<GraphInput>,
+        output_width: u32,
+        output_height: u32,
+    ) -> Self {
+        self.add_wgsl_with_options(
+            name,
+            wgsl,
+            params,
+            WgslStepOptions::default(),
+            inputs,
+            output_width,
+            output_height,
+        )
+    }
+
+    /// 追加の設定を指定してWGSL処理のノードを追加します。
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_wgsl_with_options(
+        self,
+        name: &str,
+        wgsl: CompiledWgsl,
+        params: Option<Vec<u8>>,
+        options: WgslStepOptions,
+        inputs: Vec<GraphInput>,
+        output_width: u32,
+        output_height: u32,
+    ) -> Self {
+        self.add_node(
+            name,
+            PipelineStep::Wgsl {
+                wgsl: Arc::new(wgsl),
+                params,
+                options,
+                output_width,
+                output_height,
+            },
+            inputs,
+        )
+    }
+
+    /// CPU関数処理のノードを追加します。
+    pub fn add_func(
+        self,
+        name: &str,
+        func: CompiledFunc,
+        params: Option<Vec<u8>>,
+        inputs: Vec<GraphInput>,
+        output_width: u32,
+        output_height: u32,
+    ) -> Self {
+        self.add_node(
+            name,
+            PipelineStep::CpuFunc {
+                func,
+                params,
+                output_width,
+                output_height,
+            },
+            inputs,
+        )
+    }
+
+    /// 直列のパイプラインをノードとして追加します。入力はパイプラインの最初のステップに渡されます。
+    pub fn add_pipeline(
+        self,
+        name: &str,
+        pipeline: ImageGenerateBuilder,
+        inputs: Vec<GraphInput>,
+    ) -> Self {
+        self.add_node(
+            name,
+            PipelineStep::Parallel {
+                pipelines: vec![pipeline],
+            },
+            inputs,
+        )
+    }
+
+    /// グラフの出力にするノードを追加します。
+    /// 複数指定した場合は、指定した順に出力が並びます。
+    pub fn output(mut self, name: &str) -> Self {
+        self.outputs.push(name.to_string());
+        self
+    }
+
+    fn add_node(mut self, name: &str, step: PipelineStep, inputs: Vec<GraphInput>) -> Self {
+        self.nodes.push(GraphNodeDesc {
+            name: name.to_string(),
+            step,
+            inputs,
+        });
+        self
+    }
+
+    /// ノードの名前と入力を検証し、依存関係に従って実行順を決めたグラフを作成します。
+    /// 名前の重複、存在しないノードの参照、循環する依存関係がある場合は`GpuUtilError::Binding`を返します。
+    /// どの出力にも使われないノードは実行されないよう取り除かれます。
+    pub fn build(self) -> Result<PipelineGraph> {
+        let mut index_of = HashMap::with_capacity(self.nodes.len());
+        for (i, node) in self.nodes.iter().enumerate() {
+            if index_of.insert(node.name.as_str(), i).is_some() {
+                bail!(GpuUtilError::binding(
+                    None,
+                    format!("Graph node `{}` is defined more than once", node.name),
+                ));
+            }
+        }
+
+        // 入力の名前を追加順の位置に解決する
+        let mut dependencies = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let mut deps = Vec::new();
+            for input in &node.inputs {
+                if let GraphInput::Node(name) = input {
+                    let Some(&dep) = index_of.get(name.as_str()) else {
+                        bail!(GpuUtilError::binding(
+                            None,
+                            format!(
+                                "Graph node `{}` takes `{}` as an input, but no such node exists",
+                                node.name, name
+                            ),
+                        ));
+                    };
+                    deps.push(dep);
+                }
+            }
+            dependencies.push(deps);
+        }
+
+        if self.outputs.is_empty() {
+            bail!(GpuUtilError::binding(
+                None,
+                "Graph has no outputs".to_string()
+            ));
+        }
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|name| match index_of.get(name.as_str()) {
+                Some(&i) => Ok(i),
+                None => bail!(GpuUtilError::binding(
+                    None,
+                    format!("Graph output `{}` does not exist", name),
+                )),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // --- トポロジカルソート ---
+        // 実行できるノードのうち、追加した順に早いものから実行する
+        let mut order: Vec<usize> = Vec::with_capacity(self.nodes.len());
+        let mut position = vec![usize::MAX; self.nodes.len()];
+        while order.len() < self.nodes.len() {
+            let next = (0..self.nodes.len()).find(|&i| {
+                position[i] == usize::MAX
+                    && dependencies[i].iter().all(|&d| position[d] != usize::MAX)
+            });
+            let Some(next) = next else {
+                let cycle: Vec<_> = (0..self.nodes.len())
+                    .filter(|&i| position[i] == usize::MAX)
+                    .map(|i| format!("`{}`", self.nodes[i].name))
+                    .collect();
+                bail!(GpuUtilError::binding(
+                    None,
+                    format!(
+                        "Graph contains a cycle among the nodes {}",
+                        cycle.join(", ")
+                    ),
+                ));
+            };
+            position[next] = order.len();
+            order.push(next);
+        }
+
+        // --- どの出力にも使われないノードを取り除く ---
+        let mut used = vec![false; self.nodes.len()];
+        let mut pending = outputs.clone();
+        while let Some(i) = pending.pop() {
+            if !std::mem::replace(&mut used[i], true) {
+                pending.extend(&dependencies[i]);
+            }
+        }
+        order.retain(|&i| used[i]);
+        position.fill(usize::MAX);
+        for (p, &i) in order.iter().enumerate() {
+            position[i] = p;
+        }
+        let outputs: Vec<usize> = outputs.iter().map(|&i| position[i]).collect();
+
+        // --- 実行順のノードを作成し、各ノードの出力の寿命を求める ---
+        let mut nodes: Vec<GraphNode> = order
+            .iter()
+            .map(|&i| {
+                let desc = &self.nodes[i];
+                GraphNode {
+                    name: desc.name.clone(),
+                    step: desc.step.clone(),
+                    inputs: desc
+                        .inputs
+                        .iter()
+                        .map(|input| match input {
+                            GraphInput::Node(name) => {
+                                ResolvedInput::Node(position[index_of[name.as_str()]])
+                            }
+                            GraphInput::Previous(index) => ResolvedInput::Previous(*index),
+                        })
+                        .collect(),
+                    last_use: position[i],
+                }
+            })
+            .collect();
+        for consumer in 0..nodes.len() {
+            for input in nodes[consumer].inputs.clone() {
+                if let ResolvedInput::Node(producer) = input {
+                    nodes[producer].last_use = nodes[producer].last_use.max(consumer);
+                }
+            }
+        }
+        for &output in &outputs {
+            nodes[output].last_use = usize::MAX;
+        }
+
+        Ok(PipelineGraph { nodes, outputs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // GPUを使わずに作れる空のパイプラインをノードとして追加する
+    fn node(builder: GraphBuilder, name: &str, inputs: &[GraphInput]) -> GraphBuilder {
+        builder.add_pipeline(name, ImageGenerateBuilder::new(), inputs.to_vec())
+    }
+
+    fn names(graph: &PipelineGraph) -> Vec<&str> {
+        graph.nodes.iter().map(|node| node.name.as_str()).collect()
+    }
+
+    fn build_error(builder: GraphBuilder) -> String {
+        match builder.build() {
+            Ok(_) => panic!("build should fail"),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let builder = node(GraphBuilder::new(), "a", &[0.into()]);
+        let builder = node(builder, "a", &[0.into()]).output("a");
+        assert_eq!(
+            build_error(builder),
+            "Graph node `a` is defined more than once"
+        );
+    }
+
+    #[test]
+    fn missing_input_is_rejected() {
+        let builder = node(GraphBuilder::new(), "a", &["b".into()]).output("a");
+        assert_eq!(
+            build_error(builder),
+            "Graph node `a` takes `b` as an input, but no such node exists"
+        );
+    }
+
+    #[test]
+    fn missing_output_is_rejected() {
+        let builder = node(GraphBuilder::new(), "a", &[0.into()]);
+        assert_eq!(build_error(builder.clone()), "Graph has no outputs");
+        assert_eq!(
+            build_error(builder.output("b")),
+            "Graph output `b` does not exist"
+        );
+    }
+
+    #[test]
+    fn cycle_is_rejected() {
+        // `a`は循環に含まれないため、エラーには残りのノードだけが並ぶ
+        let builder = node(GraphBuilder::new(), "a", &[0.into()]);
+        let builder = node(builder, "b", &["a".into(), "d".into()]);
+        let builder = node(builder, "c", &["b".into()]);
+        let builder = node(builder, "d", &["c".into()]).output("d");
+        assert_eq!(
+            build_error(builder),
+            "Graph contains a cycle among the nodes `b`, `c`, `d`"
+        );
+
+        let builder = node(GraphBuilder::new(), "a", &["a".into()]).output("a");
+        assert_eq!(
+            build_error(builder),
+            "Graph contains a cycle among the nodes `a`"
+        );
+    }
+
+    #[test]
+    fn nodes_run_in_dependency_order_then_insertion_order() {
+        let builder = node(
+            GraphBuilder::new(),
+            "blend",
+            &["blur".into(), "sharpen".into()],
+        );
+        let builder = node(builder, "sharpen", &[0.into()]);
+        let builder = node(builder, "blur", &[1.into()]);
+        let builder = node(builder, "unused", &[0.into()]).output("blend");
+        let graph = builder.build().unwrap();
+        assert_eq!(names(&graph), ["sharpen", "blur", "blend"]);
+        assert_eq!(graph.outputs, [2]);
+
+        let blend = &graph.nodes[2];
+        assert!(matches!(
+            blend.inputs.as_slice(),
+            [ResolvedInput::Node(1), ResolvedInput::Node(0)]
+        ));
+        assert!(matches!(
+            graph.nodes[1].inputs.as_slice(),
+            [ResolvedInput::Previous(1)]
+        ));
+
+        // 同じ定義からは常に同じ順序になる
+        for _ in 0..4 {
+            let again = node(
+                GraphBuilder::new(),
+                "blend",
+                &["blur".into(), "sharpen".into()],
+            );
+            let again = node(again, "sharpen", &[0.into()]);
+            let again = node(again, "blur", &[1.into()]);
+            let again = node(again, "unused", &[0.into()]).output("blend");
+            assert_eq!(names(&again.build().unwrap()), names(&graph));
+        }
+    }
+
+    #[test]
+    fn last_use_is_the_last_consumer() {
+        // a -> b -> d, a -> c -> d, a -> d。出力はdとc
+        let builder = node(GraphBuilder::new(), "a", &[0.into()]);
+        let builder = node(builder, "b", &["a".into()]);
+        let builder = node(builder, "c", &["a".into()]);
+        let builder = node(builder, "d", &["b".into(), "c".into(), "a".into()]);
+        let graph = builder.output("d").output("c").build().unwrap();
+        assert_eq!(names(&graph), ["a", "b", "c", "d"]);
+        assert_eq!(graph.outputs, [3, 2]);
+
+        let last_use: Vec<_> = graph.nodes.iter().map(|node| node.last_use).collect();
+        // 出力になるノードはusize::MAX
+        assert_eq!(last_use, [3, 3, usize::MAX, usize::MAX]);
+    }
+
+    #[test]
+    fn nodes_not_used_by_any_output_are_pruned() {
+        // a -> b -> c、a -> d -> e。出力はcだけなので、dとeは実行しない
+        let builder = node(GraphBuilder::new(), "a", &[0.into()]);
+        let builder = node(builder, "d", &["a".into()]);
+        let builder = node(builder, "b", &["a".into()]);
+        let builder = node(builder, "e", &["d".into()]);
+        let builder = node(builder, "c", &["b".into()]);
+        let graph = builder.output("c").build().unwrap();
+        assert_eq!(names(&graph), ["a", "b", "c"]);
+        assert_eq!(graph.outputs, [2]);
+        assert!(matches!(
+            graph.nodes[1].inputs.as_slice(),
+            [ResolvedInput::Node(0)]
+        ));
+        assert!(matches!(
+            graph.nodes[2].inputs.as_slice(),
+            [ResolvedInput::Node(1)]
+        ));
+        // 取り除いたdはaの寿命を延ばさない
+        assert_eq!(graph.nodes[0].last_use, 1);
+    }
+
+    #[test]
+    fn build_errors_are_binding_errors() {
+        let builder = node(GraphBuilder::new(), "a", &["b".into()]).output("a");
+        let error = builder.build().err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<GpuUtilError>(),
+            Some(GpuUtilError::Binding { .. })
+        ));
+    }
+}
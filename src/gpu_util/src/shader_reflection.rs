@@ -0,0 +1,610 @@
+// shader_reflection.rs
+
+use crate::{compiled_wgsl::ShaderCompileError, error::GpuUtilError, param_layout::ParamLayout};
+use anyhow::{bail, Result};
+use std::{collections::HashMap, num::NonZeroU32};
+
+/// `TextureArray`の入力規約で、各入力の実際の解像度を受け取るバッファの名前
+pub const INPUT_SIZES_NAME: &str = "input_sizes";
+
+/// 入力テクスチャをシェーダーに渡す方法。
+/// シェーダーが宣言した入力テクスチャの型から自動的に判別されます。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InputLayout {
+    /// `binding_array<texture_2d<f32>>`ですべての入力を受け取ります。
+    /// TEXTURE_BINDING_ARRAYに対応したアダプタでのみ使用できます。
+    BindingArray,
+    /// `texture_2d_array<f32>`ですべての入力を受け取ります。すべてのアダプタで動作します。
+    /// 入力は最大の入力解像度に揃えた配列テクスチャの各レイヤーに詰められ、
+    /// 各入力の実際の解像度は`var<storage, read> input_sizes: array<vec2<u32>>`で参照します。
+    TextureArray,
+    /// 入力ごとの`texture_2d<f32>`で受け取ります。すべてのアダプタで動作します。
+    /// 入力は`(group, binding)`の順に割り当てられます。入力を受け取らないシェーダーもこの規約になります。
+    Individual,
+}
+
+/// 出力テクスチャに使えるフォーマット (エラーメッセージ用)
+const OUTPUT_FORMAT_NAMES: &str = "rgba32float, rgba16float, rgba8unorm, rg32float, r32float";
+
+/// 出力テクスチャに使えるフォーマット
+pub(crate) const OUTPUT_FORMATS: [wgpu::TextureFormat; 5] = [
+    wgpu::TextureFormat::Rgba32Float,
+    wgpu::TextureFormat::Rgba16Float,
+    wgpu::TextureFormat::Rgba8Unorm,
+    wgpu::TextureFormat::Rg32Float,
+    wgpu::TextureFormat::R32Float,
+];
+
+/// 出力テクスチャのフォーマットを変換します。対応していないフォーマットの場合は`None`を返します。
+/// どのフォーマットも次のステップからは`texture_2d<f32>`として読めます。
+fn output_format(format: naga::StorageFormat) -> Option<wgpu::TextureFormat> {
+    match format {
+        naga::StorageFormat::Rgba32Float => Some(wgpu::TextureFormat::Rgba32Float),
+        naga::StorageFormat::Rgba16Float => Some(wgpu::TextureFormat::Rgba16Float),
+        naga::StorageFormat::Rgba8Unorm => Some(wgpu::TextureFormat::Rgba8Unorm),
+        naga::StorageFormat::Rg32Float => Some(wgpu::TextureFormat::Rg32Float),
+        naga::StorageFormat::R32Float => Some(wgpu::TextureFormat::R32Float),
+        _ => None,
+    }
+}
+
+/// シェーダー内でのバインディングの役割
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindingRole {
+    /// 入力テクスチャ (`texture_2d<f32>`)。値は入力のインデックス
+    Input(usize),
+    /// すべての入力 (`binding_array<texture_2d<f32>>`)。`size`は固定長の場合の要素数
+    InputArray {
+        size: Option<NonZeroU32>,
+    },
+    /// すべての入力を詰めた配列テクスチャ (`texture_2d_array<f32>`)
+    PackedInputs,
+    /// `PackedInputs`の各レイヤーの実際の解像度
+    InputSizes,
+    /// 出力のストレージテクスチャ。値はシェーダーで宣言されたフォーマット
+    Output(wgpu::TextureFormat),
+    Sampler,
+    /// パラメータなどのバッファ。`min_size`はシェーダーが必要とする最小のバイト数。
+    /// `layout`は値を自動で詰める際のレイアウトで、詰められない型の場合は`None`
+    Buffer {
+        uniform: bool,
+        read_only: bool,
+        min_size: u64,
+        layout: Option<ParamLayout>,
+    },
+}
+
+/// シェーダーが宣言したバインディング一つ分の情報
+#[derive(Clone, Debug)]
+pub struct ReflectedBinding {
+    pub name: String,
+    pub group: u32,
+    pub binding: u32,
+    pub role: BindingRole,
+}
+
+impl ReflectedBinding {
+    /// エラーメッセージ用の表記
+    pub(crate) fn describe(&self) -> String {
+        format!(
+            "`{}` (@group({}) @binding({}))",
+            self.name, self.group, self.binding
+        )
+    }
+
+    /// このバインディングのレイアウト。
+    /// `input_count`は長さが固定されていない`binding_array`の要素数に使われます。
+    pub(crate) fn layout_entry(
+        &self,
+        input_count: usize,
+        filterable: bool,
+    ) -> wgpu::BindGroupLayoutEntry {
+        let input_texture = |view_dimension| wgpu::BindingType::Texture {
+            sample_type: wgpu::TextureSampleType::Float { filterable },
+            view_dimension,
+            multisampled: false,
+        };
+
+        let (ty, count) = match &self.role {
+            BindingRole::Input(_) => (input_texture(wgpu::TextureViewDimension::D2), None),
+            BindingRole::InputArray { size } => (
+                input_texture(wgpu::TextureViewDimension::D2),
+                // 入力がない場合もダミーのテクスチャを1つ渡す
+                size.or(NonZeroU32::new(input_count.max(1) as u32)),
+            ),
+            BindingRole::PackedInputs => (input_texture(wgpu::TextureViewDimension::D2Array), None),
+            BindingRole::InputSizes => (
+                wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                None,
+            ),
+            BindingRole::Output(format) => (
+                wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: *format,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                None,
+            ),
+            BindingRole::Sampler => (
+                wgpu::BindingType::Sampler(if filterable {
+                    wgpu::SamplerBindingType::Filtering
+                } else {
+                    wgpu::SamplerBindingType::NonFiltering
+                }),
+                None,
+            ),
+            BindingRole::Buffer {
+                uniform, read_only, ..
+            } => (
+                wgpu::BindingType::Buffer {
+                    ty: if *uniform {
+                        wgpu::BufferBindingType::Uniform
+                    } else {
+                        wgpu::BufferBindingType::Storage {
+                            read_only: *read_only,
+                        }
+                    },
+                    has_dynamic_offset: false,
+                    // サイズはステップの実行時に検証する
+                    min_binding_size: None,
+                },
+                None,
+            ),
+        };
+
+        wgpu::BindGroupLayoutEntry {
+            binding: self.binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty,
+            count,
+        }
+    }
+}
+
+/// naga で解析したシェーダーのバインディング情報。
+/// エントリーポイントが実際に使用するバインディングのみを含み、`(group, binding)`順に並びます。
+#[derive(Clone, Debug)]
+pub struct ShaderReflection {
+    /// エントリーポイントの関数名
+    pub entry_point: String,
+    pub bindings: Vec<ReflectedBinding>,
+    pub input_layout: InputLayout,
+    /// エントリーポイントの`@workgroup_size`
+    pub workgroup_size: [u32; 3],
+}
+
+/// モジュールに含まれるコンピュートシェーダーのエントリーポイント名を宣言順に返します。
+pub(crate) fn compute_entry_points(module: &naga::Module) -> impl Iterator<Item = &str> {
+    module
+        .entry_points
+        .iter()
+        .filter(|ep| ep.stage == naga::ShaderStage::Compute)
+        .map(|ep| ep.name.as_str())
+}
+
+/// エントリーポイントの`@workgroup_size`を取得します。
+/// overrideで指定されていて値が決まらない場合は`None`を返します。
+pub(crate) fn workgroup_size(module: &naga::Module, entry_point: &str) -> Option<[u32; 3]> {
+    let entry_point = module
+        .entry_points
+        .iter()
+        .find(|ep| ep.name == entry_point && ep.stage == naga::ShaderStage::Compute)?;
+    let size = entry_point.workgroup_size;
+    (entry_point.workgroup_size_overrides.is_none() && size.iter().all(|&n| n > 0)).then_some(size)
+}
+
+impl ShaderReflection {
+    pub(crate) fn new(
+        id: &str,
+        source: &str,
+        module: &naga::Module,
+        info: &naga::valid::ModuleInfo,
+        entry_point: &str,
+    ) -> Result<Self, ShaderCompileError> {
+        let error = |message: String, span: naga::Span| {
+            let location = span.is_defined().then(|| span.location(source));
+            ShaderCompileError::new(id, source, message, location)
+        };
+
+        let Some(entry_index) = module
+            .entry_points
+            .iter()
+            .position(|ep| ep.name == entry_point && ep.stage == naga::ShaderStage::Compute)
+        else {
+            return Err(error(
+                format!("Compute entry point `{}` was not found", entry_point),
+                naga::Span::default(),
+            ));
+        };
+        let entry_info = info.get_entry_point(entry_index);
+        let Some(workgroup_size) = workgroup_size(module, entry_point) else {
+            return Err(error(
+                format!(
+                    "Entry point `{}`: @workgroup_size set by override is not supported",
+                    entry_point
+                ),
+                naga::Span::default(),
+            ));
+        };
+
+        // 型のサイズ計算 (バッファの最小サイズに使う)
+        let mut layouter = naga::proc::Layouter::default();
+        layouter
+            .update(module.to_ctx())
+            .map_err(|e| error(e.to_string(), naga::Span::default()))?;
+
+        let mut bindings = Vec::new();
+        let mut spans = Vec::new();
+        for (handle, var) in module.global_variables.iter() {
+            let Some(resource) = &var.binding else {
+                continue;
+            };
+            if entry_info[handle].is_empty() {
+                continue;
+            }
+
+            let name = var.name.clone().unwrap_or_else(|| "<unnamed>".to_string());
+            let span = module.global_variables.get_span(handle);
+            let unsupported = |what: &str| {
+                error(
+                    format!("Binding `{}`: {} is not supported", name, what),
+                    span,
+                )
+            };
+
+            let role = match &module.types[var.ty].inner {
+                naga::TypeInner::Image {
+                    dim,
+                    arrayed,
+                    class,
+                } => match class {
+                    naga::ImageClass::Sampled {
+                        kind: naga::ScalarKind::Float,
+                        multi: false,
+                    } if *dim == naga::ImageDimension::D2 => {
+                        if *arrayed {
+                            BindingRole::PackedInputs
+                        } else {
+                            // インデックスは後で(group, binding)順に振り直す
+                            BindingRole::Input(0)
+                        }
+                    }
+                    naga::ImageClass::Storage { format, access }
+                        if *dim == naga::ImageDimension::D2
+                            && !*arrayed
+                            && access.contains(naga::StorageAccess::STORE) =>
+                    {
+                        let Some(format) = output_format(*format) else {
+                            return Err(error(
+                                format!(
+                                    "Output `{}` must use one of the formats: {}",
+                                    name, OUTPUT_FORMAT_NAMES
+                                ),
+                                span,
+                            ));
+                        };
+                        BindingRole::Output(format)
+                    }
+                    _ => return Err(unsupported("this texture type")),
+                },
+                naga::TypeInner::BindingArray { base, size } => {
+                    match module.types[*base].inner {
+                        naga::TypeInner::Image {
+                            dim: naga::ImageDimension::D2,
+                            arrayed: false,
+                            class:
+                                naga::ImageClass::Sampled {
+                                    kind: naga::ScalarKind::Float,
+                                    multi: false,
+                                },
+                        } => {}
+                        _ => return Err(unsupported("binding_array of this type")),
+                    }
+                    let size = match size {
+                        naga::ArraySize::Constant(size) => Some(*size),
+                        naga::ArraySize::Dynamic => None,
+                        naga::ArraySize::Pending(_) => {
+                            return Err(unsupported("binding_array sized by an override"))
+                        }
+                    };
+                    BindingRole::InputArray { size }
+                }
+                naga::TypeInner::Sampler { comparison: false } => BindingRole::Sampler,
+                naga::TypeInner::Sampler { comparison: true } => {
+                    return Err(unsupported("sampler_comparison"))
+                }
+                _ => {
+                    let min_size = layouter[var.ty].size as u64;
+                    let layout = ParamLayout::from_naga(module, &layouter, var.ty);
+                    match var.space {
+                        naga::AddressSpace::Uniform => BindingRole::Buffer {
+                            uniform: true,
+                            read_only: true,
+                            min_size,
+                            layout,
+                        },
+                        naga::AddressSpace::Storage { access } => BindingRole::Buffer {
+                            uniform: false,
+                            read_only: !access.contains(naga::StorageAccess::STORE),
+                            min_size,
+                            layout,
+                        },
+                        _ => return Err(unsupported("this address space")),
+                    }
+                }
+            };
+
+            bindings.push(ReflectedBinding {
+                name,
+                group: resource.group,
+                binding: resource.binding,
+                role,
+            });
+            spans.push(span);
+        }
+
+        // (group, binding)順に並べ替える
+        let mut order: Vec<usize> = (0..bindings.len()).collect();
+        order.sort_by_key(|&i| (bindings[i].group, bindings[i].binding));
+        let mut bindings: Vec<_> = order.iter().map(|&i| bindings[i].clone()).collect();
+        let spans: Vec<_> = order.iter().map(|&i| spans[i]).collect();
+
+        // --- 入力の規約を判別する ---
+        let count_role =
+            |f: fn(&BindingRole) -> bool| bindings.iter().filter(|b| f(&b.role)).count();
+        let input_arrays = count_role(|r| matches!(r, BindingRole::InputArray { .. }));
+        let packed_inputs = count_role(|r| matches!(r, BindingRole::PackedInputs));
+        let individual_inputs = count_role(|r| matches!(r, BindingRole::Input(_)));
+        let input_layout = match (input_arrays, packed_inputs, individual_inputs) {
+            (1, 0, 0) => InputLayout::BindingArray,
+            (0, 1, 0) => InputLayout::TextureArray,
+            (0, 0, _) => InputLayout::Individual,
+            _ => {
+                // 最初に規約が衝突したバインディングを指す
+                let index = bindings
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, b)| {
+                        matches!(
+                            b.role,
+                            BindingRole::InputArray { .. }
+                                | BindingRole::PackedInputs
+                                | BindingRole::Input(_)
+                        )
+                    })
+                    .nth(1)
+                    .map(|(i, _)| i)
+                    .unwrap_or_default();
+                return Err(error(
+                    format!(
+                        "Binding {}: input textures must be a single binding_array, \
+                         a single texture_2d_array, or only texture_2d bindings",
+                        bindings[index].describe()
+                    ),
+                    spans[index],
+                ));
+            }
+        };
+
+        let mut next_input = 0;
+        for (binding, span) in bindings.iter_mut().zip(&spans) {
+            match &mut binding.role {
+                BindingRole::Input(index) => {
+                    *index = next_input;
+                    next_input += 1;
+                }
+                // 配列テクスチャの規約では`input_sizes`は予約されたバッファになる
+                BindingRole::Buffer { read_only, .. }
+                    if input_layout == InputLayout::TextureArray
+                        && binding.name == INPUT_SIZES_NAME =>
+                {
+                    if !*read_only {
+                        return Err(error(
+                            format!("`{}` must be var<storage, read>", INPUT_SIZES_NAME),
+                            *span,
+                        ));
+                    }
+                    binding.role = BindingRole::InputSizes;
+                }
+                _ => {}
+            }
+        }
+
+        let outputs: Vec<_> = bindings
+            .iter()
+            .filter(|b| matches!(b.role, BindingRole::Output(_)))
+            .collect();
+        match outputs.len() {
+            1 => {}
+            0 => {
+                return Err(error(
+                    format!(
+                        "No output texture. Declare a texture_storage_2d<format, write> \
+                         with one of the formats: {}",
+                        OUTPUT_FORMAT_NAMES
+                    ),
+                    naga::Span::default(),
+                ))
+            }
+            _ => {
+                let index = bindings
+                    .iter()
+                    .position(|b| matches!(b.role, BindingRole::Output(_)))
+                    .unwrap_or_default();
+                return Err(error(
+                    format!(
+                        "Only one output texture is supported, but {} are declared: {}",
+                        outputs.len(),
+                        outputs
+                            .iter()
+                            .map(|b| b.describe())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                    spans[index],
+                ));
+            }
+        }
+
+        Ok(Self {
+            entry_point: entry_point.to_string(),
+            bindings,
+            input_layout,
+            workgroup_size,
+        })
+    }
+
+    /// 使用するバインドグループの数 (最大のgroup + 1)
+    pub(crate) fn group_count(&self) -> u32 {
+        self.bindings.iter().map(|b| b.group + 1).max().unwrap_or(0)
+    }
+
+    /// 指定したグループのバインディング
+    pub(crate) fn group(&self, group: u32) -> impl Iterator<Item = &ReflectedBinding> {
+        self.bindings.iter().filter(move |b| b.group == group)
+    }
+
+    /// 出力テクスチャのフォーマット
+    pub fn output_format(&self) -> wgpu::TextureFormat {
+        self.bindings
+            .iter()
+            .find_map(|b| match b.role {
+                BindingRole::Output(format) => Some(format),
+                _ => None,
+            })
+            .unwrap_or(wgpu::TextureFormat::Rgba32Float)
+    }
+
+    /// 個別のテクスチャとして宣言された入力
+    pub(crate) fn individual_inputs(&self) -> impl Iterator<Item = &ReflectedBinding> {
+        self.bindings
+            .iter()
+            .filter(|b| matches!(b.role, BindingRole::Input(_)))
+    }
+
+    /// 長さが固定されていない`binding_array`で入力を受け取るかどうか
+    pub(crate) fn has_unsized_input_array(&self) -> bool {
+        self.bindings
+            .iter()
+            .any(|b| b.role == BindingRole::InputArray { size: None })
+    }
+
+    /// 名前を指定した`sampler`のバインディングがあるかどうか
+    pub(crate) fn has_sampler(&self, name: &str) -> bool {
+        self.bindings
+            .iter()
+            .any(|b| b.role == BindingRole::Sampler && b.name == name)
+    }
+
+    /// `params`を受け取るバッファを返します。
+    /// 名前で指定されたバッファ (`is_named`が`true`) を除いて、バッファがちょうど1つである必要があります。
+    pub(crate) fn params_binding(
+        &self,
+        id: &str,
+        is_named: impl Fn(&str) -> bool,
+    ) -> Result<&ReflectedBinding> {
+        let unnamed: Vec<_> = self
+            .bindings
+            .iter()
+            .filter(|b| matches!(b.role, BindingRole::Buffer { .. }) && !is_named(&b.name))
+            .collect();
+        match unnamed.as_slice() {
+            [binding] => Ok(binding),
+            [] => bail!(GpuUtilError::binding(
+                Some(id),
+                "params were given, but the shader has no buffer binding left to receive them"
+                    .to_string(),
+            )),
+            _ => bail!(GpuUtilError::binding(
+                Some(id),
+                format!(
+                    "params are ambiguous between buffers {}. Pass them by name instead",
+                    unnamed
+                        .iter()
+                        .map(|b| b.describe())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )),
+        }
+    }
+
+    /// 指定した名前のバッファ
+    pub(crate) fn buffer_binding(&self, id: &str, name: &str) -> Result<&ReflectedBinding> {
+        match self
+            .bindings
+            .iter()
+            .find(|b| matches!(b.role, BindingRole::Buffer { .. }) && b.name == name)
+        {
+            Some(binding) => Ok(binding),
+            None => bail!(GpuUtilError::binding(
+                Some(id),
+                format!("there is no buffer binding named `{}`", name),
+            )),
+        }
+    }
+
+    /// バッファのバインディングに渡すデータを決めます。
+    ///
+    /// `buffers`に同じ名前のデータがあればそれを、なければ`params`を使います。
+    /// `params`は名前で指定されなかったバッファがちょうど1つの場合にのみ使えます。
+    pub(crate) fn resolve_buffers<'a>(
+        &'a self,
+        id: &str,
+        params: Option<&'a [u8]>,
+        buffers: &'a HashMap<String, Vec<u8>>,
+    ) -> Result<Vec<(&'a ReflectedBinding, &'a [u8])>> {
+        let buffer_bindings: Vec<_> = self
+            .bindings
+            .iter()
+            .filter(|b| matches!(b.role, BindingRole::Buffer { .. }))
+            .collect();
+
+        for name in buffers.keys() {
+            if !buffer_bindings.iter().any(|b| &b.name == name) {
+                bail!(GpuUtilError::binding(
+                    Some(id),
+                    format!(
+                        "buffer `{}` was given, but the shader has no buffer binding with that name",
+                        name
+                    ),
+                ));
+            }
+        }
+
+        if params.is_some() {
+            self.params_binding(id, |name| buffers.contains_key(name))?;
+        }
+
+        let mut resolved = Vec::with_capacity(buffer_bindings.len());
+        for binding in buffer_bindings {
+            let data = match (buffers.get(&binding.name), params) {
+                (Some(data), _) => data.as_slice(),
+                (None, Some(params)) => params,
+                (None, None) => bail!(GpuUtilError::binding(
+                    Some(id),
+                    format!("no data was given for buffer {}", binding.describe()),
+                )),
+            };
+            if let BindingRole::Buffer { min_size, .. } = binding.role {
+                if (data.len() as u64) < min_size.max(1) {
+                    bail!(GpuUtilError::size(
+                        Some(id),
+                        format!(
+                            "buffer {} needs at least {} bytes, but {} bytes were given",
+                            binding.describe(),
+                            min_size.max(1),
+                            data.len()
+                        ),
+                    ));
+                }
+            }
+            resolved.push((binding, data));
+        }
+        Ok(resolved)
+    }
+}
@@ -0,0 +1,94 @@
+// 色空間とアルファの扱いの変換 (color_space.rsのColorConversionと同じ手順)
+// post_process.wgslとcolor_convert.wgslの先頭に連結して使う
+struct ColorConversion {
+    // 線形RGB同士の原色の変換行列
+    matrix: mat3x3<f32>,
+    src_transfer: u32,
+    dst_transfer: u32,
+    // 1ならRGBにアルファを乗算済み
+    src_premultiplied: u32,
+    dst_premultiplied: u32,
+}
+
+// color_space.rsのtransfer_idと対応
+const TRANSFER_LINEAR: u32 = 0u;
+const TRANSFER_SRGB: u32 = 1u;
+const TRANSFER_REC709: u32 = 2u;
+const TRANSFER_PQ: u32 = 3u;
+const TRANSFER_HLG: u32 = 4u;
+
+const PQ_M1: f32 = 0.1593017578125;
+const PQ_M2: f32 = 78.84375;
+const PQ_C1: f32 = 0.8359375;
+const PQ_C2: f32 = 18.8515625;
+const PQ_C3: f32 = 18.6875;
+// 線形の1.0を203cd/m²として扱う
+const PQ_SCALE: f32 = 0.0203;
+
+const HLG_A: f32 = 0.17883277;
+const HLG_B: f32 = 0.28466892;
+const HLG_C: f32 = 0.55991073;
+// 信号値0.75 (基準白) に対応するシーン光
+const HLG_REFERENCE_WHITE: f32 = 0.26496256;
+
+fn decode_transfer(transfer: u32, v: vec3<f32>) -> vec3<f32> {
+    let a = abs(v);
+    switch transfer {
+        case TRANSFER_SRGB: {
+            return sign(v) * select(pow((a + 0.055) / 1.055, vec3(2.4)), a / 12.92, a <= vec3(0.04045));
+        }
+        case TRANSFER_REC709: {
+            return sign(v) * select(pow((a + 0.099) / 1.099, vec3(1.0 / 0.45)), a / 4.5, a < vec3(0.081));
+        }
+        case TRANSFER_PQ: {
+            let p = pow(max(v, vec3(0.0)), vec3(1.0 / PQ_M2));
+            return pow(max(p - PQ_C1, vec3(0.0)) / (PQ_C2 - PQ_C3 * p), vec3(1.0 / PQ_M1)) / PQ_SCALE;
+        }
+        case TRANSFER_HLG: {
+            let c = max(v, vec3(0.0));
+            let e = select((exp((c - HLG_C) / HLG_A) + HLG_B) / 12.0, c * c / 3.0, c <= vec3(0.5));
+            return e / HLG_REFERENCE_WHITE;
+        }
+        default: {
+            return v;
+        }
+    }
+}
+
+fn encode_transfer(transfer: u32, v: vec3<f32>) -> vec3<f32> {
+    let a = abs(v);
+    switch transfer {
+        case TRANSFER_SRGB: {
+            return sign(v) * select(1.055 * pow(a, vec3(1.0 / 2.4)) - 0.055, a * 12.92, a <= vec3(0.0031308));
+        }
+        case TRANSFER_REC709: {
+            return sign(v) * select(1.099 * pow(a, vec3(0.45)) - 0.099, a * 4.5, a < vec3(0.018));
+        }
+        case TRANSFER_PQ: {
+            let y = pow(max(v, vec3(0.0)) * PQ_SCALE, vec3(PQ_M1));
+            return pow((PQ_C1 + PQ_C2 * y) / (1.0 + PQ_C3 * y), vec3(PQ_M2));
+        }
+        case TRANSFER_HLG: {
+            let e = max(v, vec3(0.0)) * HLG_REFERENCE_WHITE;
+            // ln(12e - b)が負にならないよう、selectで使われない側も有効な値にしておく
+            return select(HLG_A * log(max(12.0 * e - HLG_B, vec3(1e-6))) + HLG_C, sqrt(3.0 * e), e <= vec3(1.0 / 12.0));
+        }
+        default: {
+            return v;
+        }
+    }
+}
+
+fn convert_color(conversion: ColorConversion, color: vec4<f32>) -> vec4<f32> {
+    var rgb = color.rgb;
+    // アルファが0の画素は色を持たないため、除算せずにそのまま扱う
+    if (conversion.src_premultiplied != 0u && color.a > 0.0) {
+        rgb /= color.a;
+    }
+    let linear = decode_transfer(conversion.src_transfer, rgb);
+    rgb = encode_transfer(conversion.dst_transfer, conversion.matrix * linear);
+    if (conversion.dst_premultiplied != 0u) {
+        rgb *= color.a;
+    }
+    return vec4<f32>(rgb, color.a);
+}
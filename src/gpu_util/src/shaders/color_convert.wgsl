@@ -0,0 +1,16 @@
+// 入力を別の色空間とアルファの扱いに変換するシェーダー (color.wgslを先頭に連結して使う)
+// 出力のフォーマットは入力に合わせて`OUTPUT_FORMAT`を置き換える
+@group(0) @binding(0) var input_texture: texture_2d<f32>;
+@group(0) @binding(1) var output_texture: texture_storage_2d<OUTPUT_FORMAT, write>;
+@group(0) @binding(2) var<uniform> conversion: ColorConversion;
+
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let dims = textureDimensions(input_texture, 0);
+    if (global_id.x >= dims.x || global_id.y >= dims.y) {
+        return;
+    }
+
+    let color = textureLoad(input_texture, vec2<i32>(global_id.xy), 0);
+    textureStore(output_texture, global_id.xy, convert_color(conversion, color));
+}
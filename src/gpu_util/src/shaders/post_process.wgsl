@@ -1,24 +1,137 @@
-@group(0) @binding(0) var input_texture: texture_2d<f32>; // RGBA float32
-@group(0) @binding(1) var<storage, read_write> output_pixels: array<u32>; // RRGGBBAA u32 packed
+// 最終出力の変換シェーダー (f32 RGBA -> 出力フォーマットのバイト列)
+// 1回の呼び出しで出力バッファのu32を1つ書き込む。変換はoutput_format.rsのCPU実装と同じ結果になるようにしている
+// color.wgslを先頭に連結して使う
+struct Params {
+    format: u32,
+    width: u32,
+    height: u32,
+    // 出力バッファのu32の数
+    word_count: u32,
+    // 最終ステップの色空間とアルファの扱いから出力のものへの変換
+    color: ColorConversion,
+}
 
-@compute @workgroup_size(16, 16, 1)
-fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    let texture_xy = textureDimensions(input_texture, 0);
+@group(0) @binding(0) var input_texture: texture_2d<f32>;
+@group(0) @binding(1) var<storage, read_write> output_words: array<u32>;
+@group(0) @binding(2) var<uniform> params: Params;
 
-    if (global_id.x >= texture_xy.x || global_id.y >= texture_xy.y) {
-        return;
+// OutputFormat::shader_idと対応
+const FORMAT_RGBA8: u32 = 0u;
+const FORMAT_BGRA8: u32 = 1u;
+const FORMAT_RGBA16_UNORM: u32 = 2u;
+const FORMAT_RGBA32_FLOAT: u32 = 3u;
+const FORMAT_I420: u32 = 4u;
+const FORMAT_NV12: u32 = 5u;
+
+const WORKGROUP_SIZE: u32 = 256u;
+
+// BT.709の輝度係数
+const KR: f32 = 0.2126;
+const KB: f32 = 0.0722;
+const LUMA: vec3<f32> = vec3<f32>(KR, 1.0 - KR - KB, KB);
+
+fn load(x: u32, y: u32) -> vec4<f32> {
+    return convert_color(params.color, textureLoad(input_texture, vec2<i32>(i32(x), i32(y)), 0));
+}
+
+// 0..255 にクリップしてから切り捨て
+fn unorm8(v: f32) -> u32 {
+    return u32(clamp(v, 0.0, 1.0) * 255.0);
+}
+
+fn unorm16(v: f32) -> u32 {
+    return u32(clamp(v, 0.0, 1.0) * 65535.0);
+}
+
+// limited rangeの輝度 (16..235)
+fn y_code(rgb: vec3<f32>) -> u32 {
+    return u32(floor(16.0 + 219.0 * dot(clamp(rgb, vec3(0.0), vec3(1.0)), LUMA) + 0.5));
+}
+
+// 2x2ブロックの平均色から計算したlimited rangeの色差 (16..240)。(Cb, Cr)の順
+fn chroma_codes(cx: u32, cy: u32) -> vec2<u32> {
+    var sum = vec3<f32>(0.0);
+    var count = 0.0;
+    for (var y = cy * 2u; y < min(cy * 2u + 2u, params.height); y++) {
+        for (var x = cx * 2u; x < min(cx * 2u + 2u, params.width); x++) {
+            sum += clamp(load(x, y).rgb, vec3(0.0), vec3(1.0));
+            count += 1.0;
+        }
     }
+    let rgb = sum / count;
+    let y = dot(rgb, LUMA);
+    let c = vec2<f32>((rgb.b - y) / (2.0 * (1.0 - KB)), (rgb.r - y) / (2.0 * (1.0 - KR)));
+    return vec2<u32>(floor(128.0 + 224.0 * c + 0.5));
+}
 
-    let pixel_index = global_id.y * texture_xy.x + global_id.x;
+// YUVの出力のoffsetバイト目
+fn yuv_byte(offset: u32) -> u32 {
+    let luma_size = params.width * params.height;
+    let chroma_width = (params.width + 1u) / 2u;
+    let chroma_size = chroma_width * ((params.height + 1u) / 2u);
 
-    // textureLoad は i32 座標を要求する
-    let rgba_f32 = textureLoad(input_texture, vec2<i32>(global_id.xy), 0);
+    if (offset < luma_size) {
+        return y_code(load(offset % params.width, offset / params.width).rgb);
+    }
+    let o = offset - luma_size;
+    if (params.format == FORMAT_I420) {
+        if (o < chroma_size) {
+            return chroma_codes(o % chroma_width, o / chroma_width).x;
+        }
+        if (o < chroma_size * 2u) {
+            let v = o - chroma_size;
+            return chroma_codes(v % chroma_width, v / chroma_width).y;
+        }
+        return 0u;
+    }
+    // NV12: UVが交互に並ぶ
+    if (o < chroma_size * 2u) {
+        let c = chroma_codes((o / 2u) % chroma_width, (o / 2u) / chroma_width);
+        return select(c.x, c.y, o % 2u == 1u);
+    }
+    return 0u;
+}
 
-    let r = u32(clamp(rgba_f32.r, 0.0, 1.0) * 255.0);
-    let g = u32(clamp(rgba_f32.g, 0.0, 1.0) * 255.0);
-    let b = u32(clamp(rgba_f32.b, 0.0, 1.0) * 255.0);
-    let a = u32(clamp(rgba_f32.a, 0.0, 1.0) * 255.0);
+@compute @workgroup_size(256, 1, 1)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+) {
+    // 1次元のインデックスを2次元のディスパッチに分けている
+    let index = global_id.y * num_workgroups.x * WORKGROUP_SIZE + global_id.x;
+    if (index >= params.word_count) {
+        return;
+    }
 
-    // AABBGGRR の u32 にパック
-    output_pixels[pixel_index] = (a << 24) | (b << 16) | (g << 8) | r;
+    var word = 0u;
+    switch params.format {
+        case FORMAT_RGBA8: {
+            let c = load(index % params.width, index / params.width);
+            word = unorm8(c.r) | (unorm8(c.g) << 8u) | (unorm8(c.b) << 16u) | (unorm8(c.a) << 24u);
+        }
+        case FORMAT_BGRA8: {
+            let c = load(index % params.width, index / params.width);
+            word = unorm8(c.b) | (unorm8(c.g) << 8u) | (unorm8(c.r) << 16u) | (unorm8(c.a) << 24u);
+        }
+        case FORMAT_RGBA16_UNORM: {
+            let pixel = index / 2u;
+            let c = load(pixel % params.width, pixel / params.width);
+            if (index % 2u == 0u) {
+                word = unorm16(c.r) | (unorm16(c.g) << 16u);
+            } else {
+                word = unorm16(c.b) | (unorm16(c.a) << 16u);
+            }
+        }
+        case FORMAT_RGBA32_FLOAT: {
+            let pixel = index / 4u;
+            let c = load(pixel % params.width, pixel / params.width);
+            word = bitcast<u32>(c[index % 4u]);
+        }
+        default: {
+            for (var i = 0u; i < 4u; i++) {
+                word |= yuv_byte(index * 4u + i) << (i * 8u);
+            }
+        }
+    }
+    output_words[index] = word;
 }
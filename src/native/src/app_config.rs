@@ -2,7 +2,9 @@ use std::{fs::File, io::Write};
 use anyhow::{ensure, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tracing::{info, warn};
 
+use crate::logging::LoggingConfig;
 use crate::util::get_data_dir;
 use crate::Dirs;
 
@@ -13,6 +15,7 @@ pub struct PythonConfig {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AppConfig {
     pub python: PythonConfig,
+    pub logging: LoggingConfig,
 }
 
 pub fn init_config(dirs: &Dirs) -> Result<()> {
@@ -22,11 +25,11 @@ pub fn init_config(dirs: &Dirs) -> Result<()> {
     if !config_path.exists() {
         let config_bytes = include_bytes!("data/default-config.json");
         let mut file = File::create(&config_path)?;
-        file.write(config_bytes)?;
+        file.write_all(config_bytes)?;
         file.sync_data()?;
-        println!("Default config copied to {:?}", config_path);
+        info!(path = ?config_path, "Default config copied");
     } else {
-        println!("Config file found at {:?}", config_path);
+        info!(path = ?config_path, "Config file found");
     }
 
     Ok(())
@@ -41,10 +44,7 @@ pub fn read_config(dirs: &Dirs) -> Result<AppConfig> {
     let config: AppConfig = match serde_json::from_str(&config) {
         Ok(config) => config,
         Err(err) => {
-            println!(
-                "Failed to parse config.json: {}. Trying to merge with default config.",
-                err
-            );
+            warn!(error = %err, "Failed to parse config.json. Trying to merge with default config.");
             let merged_config = merge_configs(&config)?;
             let config: AppConfig = serde_json::from_value(merged_config)?;
 
@@ -5,8 +5,10 @@ use crate::{
 };
 use napi::bindgen_prelude::Uint8ArraySlice;
 use napi_derive::napi;
-use pyo3::{types::PyAnyMethods, IntoPyObject, Py, PyAny, PyResult, Python};
+use pyo3::{types::PyAnyMethods, Bound, IntoPyObject, Py, PyAny, PyResult, Python};
+use tracing::{debug, error, info, info_span, warn};
 mod app_config;
+pub mod logging;
 mod python;
 mod structs;
 mod util;
@@ -29,9 +31,14 @@ fn ensure_libpython_global(name: &str) -> anyhow::Result<()> {
 }
 
 pub fn _initialize(dirs: &Dirs) -> anyhow::Result<Py<PyAny>> {
+    // configの読み込み中のログも出力されるよう、先にコンソールへの出力を設定する
+    logging::init_console_logging();
     // configの初期化
     app_config::init_config(dirs)?;
     let config = read_config(dirs)?;
+    logging::init_logging(dirs, &config.logging);
+    let _span = info_span!("initialize").entered();
+
     let default_version = config.python.default_version;
     let local_data_dir = get_local_data_dir(dirs)?;
     let python_path = local_data_dir.join("python"); // pythonがある
@@ -39,7 +46,7 @@ pub fn _initialize(dirs: &Dirs) -> anyhow::Result<Py<PyAny>> {
     // pythonがインストールされているか確認
     // python環境変数の設定
     if !python_path.exists() {
-        println!("Found no Python installation at {:?}", python_path);
+        info!(path = ?python_path, "Found no Python installation");
         python::utils::install_python(dirs, &default_version, true)?;
     }
     python::utils::add_python_path_env(dirs)?;
@@ -48,22 +55,24 @@ pub fn _initialize(dirs: &Dirs) -> anyhow::Result<Py<PyAny>> {
     let mut try_count = 0;
     // TODO: try_countが3回を超えたら正しいエラーハンドリングをする
     while !result.installed && try_count < 3 {
-        println!("Python is not installed. Installing...");
+        warn!(try_count, "Python is not installed. Installing...");
         python::utils::install_python(
             dirs,
             result.version.as_ref().unwrap_or(&default_version),
             result.version.is_none(),
         )?;
-        println!("Python installed");
+        info!("Python installed");
         result = python::utils::check_python_installed(dirs)?;
         try_count += 1;
     }
 
-    println!("Installed python version: {:?}", result.version);
+    info!(version = ?result.version, "Installed python version");
 
-    println!("syncing packages...");
-    let sync_result = python::utils::sync_packages(dirs);
-    println!("Package sync result: {:?}", sync_result);
+    info!("Syncing packages...");
+    match python::utils::sync_packages(dirs) {
+        Ok(output) => debug!(output = %output.trim(), "Package sync finished"),
+        Err(e) => warn!(error = %e, "Package sync failed"),
+    }
 
     // Linuxの場合、libpythonをRTLD_GLOBALで読み込む
     #[cfg(target_os = "linux")]
@@ -75,7 +84,7 @@ pub fn _initialize(dirs: &Dirs) -> anyhow::Result<Py<PyAny>> {
             let path = entry.path();
             if let Some(fname) = path.file_name().and_then(|s| s.to_str()) {
                 if fname.starts_with("libpython") && fname.contains(".so") {
-                    println!("Linux: Ensuring libpython global: {}", fname);
+                    debug!(file = fname, "Linux: Ensuring libpython global");
                     ensure_libpython_global(fname)?;
                 }
             }
@@ -87,6 +96,11 @@ pub fn _initialize(dirs: &Dirs) -> anyhow::Result<Py<PyAny>> {
     Ok(pl_manager)
 }
 
+// get_frameで生成するフレームの解像度とピクセルあたりのバイト数 (RGBA8)
+const FRAME_WIDTH: usize = 1920;
+const FRAME_HEIGHT: usize = 1080;
+const FRAME_BYTES_PER_PIXEL: usize = 4;
+
 #[napi(js_name = "PlManager")]
 pub struct JsPlManager {
     plmanager: Option<Py<PyAny>>,
@@ -109,7 +123,7 @@ impl JsPlManager {
     pub fn initialize(&mut self) -> napi::Result<()> {
         let result = _initialize(&self.dirs);
         let pl_manager = result.map_err(|e| {
-            eprintln!("Failed to initialize Python environment: {:?}", e);
+            error!(error = ?e, "Failed to initialize Python environment");
 
             napi::Error::from_reason(format!("Failed to initialize Python environment: {:?}", e))
         })?;
@@ -127,22 +141,48 @@ impl JsPlManager {
         count: i32,
         frame_struct: Vec<FrameLayerStructure>,
     ) -> napi::Result<()> {
-        let pl_manager = self
-            .plmanager
-            .as_ref()
-            .ok_or_else(|| napi::Error::from_reason("PluginManager is not initialized"))?;
+        let pl_manager = self.pl_manager()?;
+
+        // 書き込む前にバッファのサイズを確認する
+        let expected_len = FRAME_WIDTH * FRAME_HEIGHT * FRAME_BYTES_PER_PIXEL;
+        let buffer_slice = unsafe { buffer.as_mut() };
+        if buffer_slice.len() != expected_len {
+            return Err(napi::Error::from_reason(format!(
+                "Frame buffer must be {} bytes ({}x{} RGBA), but got {} bytes",
+                expected_len,
+                FRAME_WIDTH,
+                FRAME_HEIGHT,
+                buffer_slice.len()
+            )));
+        }
 
         Python::attach(|py| -> PyResult<()> {
             let pl_manager = pl_manager.bind(py);
             let frame_struct = frame_struct.into_pyobject(py)?;
 
-            let buffer_ptr = unsafe {
-                let buffer_slice = buffer.as_mut();
-                buffer_slice.as_mut_ptr() as usize
+            // Uint8Arrayのメモリを書き込み可能なmemoryviewとしてPythonに渡す
+            let memory_view = unsafe {
+                Bound::from_owned_ptr_or_err(
+                    py,
+                    pyo3::ffi::PyMemoryView_FromMemory(
+                        buffer_slice.as_mut_ptr() as *mut std::ffi::c_char,
+                        buffer_slice.len() as pyo3::ffi::Py_ssize_t,
+                        pyo3::ffi::PyBUF_WRITE,
+                    ),
+                )?
             };
 
             let func = pl_manager.getattr("make_frame")?;
-            func.call1((count, frame_struct, 1920, 1080, buffer_ptr))?;
+            let result = func.call1((
+                count,
+                frame_struct,
+                FRAME_WIDTH,
+                FRAME_HEIGHT,
+                &memory_view,
+            ));
+            // 呼び出し後にPython側から参照されないよう、memoryviewを解放する
+            memory_view.call_method0("release")?;
+            result?;
 
             Ok(())
         })
@@ -150,4 +190,56 @@ impl JsPlManager {
 
         Ok(())
     }
+
+    /// フレームの生成時に各ステップの処理時間を計測するかどうかを設定します。
+    #[napi]
+    pub fn set_profiling(&self, enabled: bool) -> napi::Result<()> {
+        let pl_manager = self.pl_manager()?;
+        Python::attach(|py| -> PyResult<()> {
+            pl_manager
+                .bind(py)
+                .getattr("generator")?
+                .setattr("profiling", enabled)
+        })
+        .map_err(|e| napi::Error::from_reason(format!("Failed to set profiling: {:?}", e)))
+    }
+
+    /// 最後に生成したフレームの実行レポートを返します。プロファイリングが無効な場合はnullを返します。
+    #[napi]
+    pub fn get_last_report(&self) -> napi::Result<Option<serde_json::Value>> {
+        let Some(json) = self.last_report("to_json")? else {
+            return Ok(None);
+        };
+        serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|e| napi::Error::from_reason(format!("Failed to parse report: {:?}", e)))
+    }
+
+    /// 最後に生成したフレームの実行レポートを、Chromeのトレースイベント形式のJSON文字列で返します。
+    /// プロファイリングが無効な場合はnullを返します。
+    #[napi]
+    pub fn get_last_chrome_trace(&self) -> napi::Result<Option<String>> {
+        self.last_report("to_chrome_trace")
+    }
+}
+
+impl JsPlManager {
+    fn pl_manager(&self) -> napi::Result<&Py<PyAny>> {
+        self.plmanager
+            .as_ref()
+            .ok_or_else(|| napi::Error::from_reason("PluginManager is not initialized"))
+    }
+
+    // 最後の実行レポートを、レポートのメソッドで文字列に変換する
+    fn last_report(&self, method: &str) -> napi::Result<Option<String>> {
+        let pl_manager = self.pl_manager()?;
+        Python::attach(|py| -> PyResult<Option<String>> {
+            let report = pl_manager.bind(py).getattr("last_report")?;
+            if report.is_none() {
+                return Ok(None);
+            }
+            report.call_method0(method)?.extract().map(Some)
+        })
+        .map_err(|e| napi::Error::from_reason(format!("Failed to get report: {:?}", e)))
+    }
 }
@@ -0,0 +1,276 @@
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use napi::threadsafe_function::{ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi::Status;
+use napi_derive::napi;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{warn, Event, Subscriber};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::{MakeWriter, OptionalWriter};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer};
+
+use crate::util::get_data_dir;
+use crate::Dirs;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoggingConfig {
+    // コンソールとJSに出力するイベントのフィルタ (例: "info,gpu_util=debug")
+    // 環境変数RUST_LOGが設定されている場合はそちらを優先する
+    pub level: String,
+    // ログファイルに出力するイベントのフィルタ
+    pub file_level: String,
+    // データディレクトリのlogsにログファイルを書き出すかどうか
+    pub file: bool,
+    // 残しておくログファイルの数 (1日ごとに新しいファイルになる)
+    pub max_log_files: usize,
+}
+
+/// JSに転送するログのイベント
+#[napi(object)]
+pub struct LogEvent {
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    // message以外のフィールド
+    pub fields: Value,
+    // イベントを囲むspanの名前 (外側から順)
+    pub spans: Vec<String>,
+    // UNIX時間 (ミリ秒)
+    pub timestamp: f64,
+}
+
+type LogListener = ThreadsafeFunction<LogEvent, (), LogEvent, Status, false, true>;
+
+// setLogListenerで登録されたJSのコールバック
+static LOG_LISTENER: Mutex<Option<LogListener>> = Mutex::new(None);
+// init_console_loggingで作成された、設定で置き換えるフィルタ
+static FILTERS: OnceLock<ReloadFilters> = OnceLock::new();
+// init_loggingで設定を反映したかどうか
+static CONFIGURED: OnceLock<()> = OnceLock::new();
+// ログファイルへの書き込み。設定が読み込まれるまでは書き込み先がない
+static FILE_WRITER: OnceLock<NonBlocking> = OnceLock::new();
+// ログファイルの書き込みスレッド。破棄すると書き込みが止まるため、プロセスの終了まで保持する
+static FILE_GUARD: OnceLock<WorkerGuard> = OnceLock::new();
+
+type SetFilter = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+// 各レイヤーのフィルタを置き換える関数
+struct ReloadFilters {
+    console: SetFilter,
+    file: SetFilter,
+    js: SetFilter,
+}
+
+/// ログのイベントを受け取るJSのコールバックを登録します。nullを渡すと解除します。
+/// コールバックはJSのスレッドで呼ばれ、プロセスの終了を妨げません。
+#[napi(ts_args_type = "callback: ((event: LogEvent) => void) | null")]
+pub fn set_log_listener(callback: Option<LogListener>) {
+    *LOG_LISTENER.lock().unwrap() = callback;
+}
+
+/// コンソールにのみ出力するtracingのsubscriberを初期化します。2回目以降の呼び出しでは何もしません。
+/// 設定の読み込み中のイベントも出力されるよう、設定を読み込む前に呼び出します。
+/// ログファイルとJSへの出力は、`init_logging`で設定が反映されるまで無効です。
+pub fn init_console_logging() {
+    FILTERS.get_or_init(|| {
+        let (console, console_handle) = reload::Layer::new(parse_filter("info", true).0);
+        let (file, file_handle) = reload::Layer::new(EnvFilter::new("off"));
+        let (js, js_handle) = reload::Layer::new(EnvFilter::new("off"));
+
+        // 他のsubscriberが既に設定されている場合は、そちらに出力を任せる
+        let result = tracing_subscriber::registry()
+            .with(fmt::layer().with_filter(console))
+            .with(
+                fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(FileWriter)
+                    .with_filter(file),
+            )
+            .with(JsLayer.with_filter(js))
+            .try_init();
+        if let Err(e) = result {
+            warn!(error = %e, "A tracing subscriber is already installed");
+        }
+
+        ReloadFilters {
+            console: Box::new(move |filter| console_handle.reload(filter)),
+            file: Box::new(move |filter| file_handle.reload(filter)),
+            js: Box::new(move |filter| js_handle.reload(filter)),
+        }
+    });
+}
+
+/// 設定をsubscriberに反映し、ログファイルとJSへの出力を有効にします。2回目以降の呼び出しでは何もしません。
+/// ログファイルを作成できない場合は警告を出力し、ログファイルなしで続行します。
+pub fn init_logging(dirs: &Dirs, config: &LoggingConfig) {
+    init_console_logging();
+    if CONFIGURED.set(()).is_err() {
+        return;
+    }
+    let filters = FILTERS.get().unwrap();
+
+    let (level, level_error) = parse_filter(&config.level, true);
+    let (file_level, file_level_error) = parse_filter(&config.file_level, false);
+
+    let mut results = vec![
+        (filters.console)(level),
+        (filters.js)(parse_filter(&config.level, true).0),
+    ];
+    if config.file {
+        match create_file_writer(dirs, config.max_log_files) {
+            Ok((writer, guard)) => {
+                FILE_WRITER.get_or_init(|| writer);
+                FILE_GUARD.get_or_init(|| guard);
+                results.push((filters.file)(file_level));
+            }
+            Err(e) => warn!(error = %e, "Failed to open the log file; logging to the console only"),
+        }
+    }
+    for result in results {
+        if let Err(e) = result {
+            warn!(error = %e, "Failed to apply the log filter");
+        }
+    }
+
+    for (filter, error) in [
+        (&config.level, level_error),
+        (&config.file_level, file_level_error),
+    ] {
+        if let Some(error) = error {
+            warn!(
+                filter,
+                error, "Invalid log filter in config.json; falling back to info"
+            );
+        }
+    }
+}
+
+// データディレクトリのlogsに、1日ごとに新しくなるログファイルを作成する
+fn create_file_writer(dirs: &Dirs, max_log_files: usize) -> Result<(NonBlocking, WorkerGuard)> {
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix("aperio")
+        .filename_suffix("log")
+        .max_log_files(max_log_files.max(1))
+        .build(get_data_dir(dirs)?.join("logs"))?;
+    Ok(tracing_appender::non_blocking(appender))
+}
+
+// ログファイルが作成されていればそこに、されていなければどこにも書き込まないwriter
+struct FileWriter;
+
+impl<'a> MakeWriter<'a> for FileWriter {
+    type Writer = OptionalWriter<NonBlocking>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        match FILE_WRITER.get() {
+            Some(writer) => OptionalWriter::some(writer.clone()),
+            None => OptionalWriter::none(),
+        }
+    }
+}
+
+// フィルタを解析する。解析できない場合はinfoにしてエラーを返す
+fn parse_filter(filter: &str, from_env: bool) -> (EnvFilter, Option<String>) {
+    if from_env {
+        if let Ok(filter) = EnvFilter::try_from_default_env() {
+            return (filter, None);
+        }
+    }
+    match EnvFilter::try_new(filter) {
+        Ok(filter) => (filter, None),
+        Err(e) => (EnvFilter::new("info"), Some(e.to_string())),
+    }
+}
+
+// イベントをLogEventに変換してJSのコールバックに渡すレイヤー
+struct JsLayer;
+
+impl<S> Layer<S> for JsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let listener = LOG_LISTENER.lock().unwrap();
+        let Some(listener) = listener.as_ref() else {
+            return;
+        };
+
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let spans = ctx
+            .event_scope(event)
+            .map(|scope| scope.from_root().map(|s| s.name().to_string()).collect())
+            .unwrap_or_default();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64() * 1000.0)
+            .unwrap_or_default();
+
+        let metadata = event.metadata();
+        listener.call(
+            LogEvent {
+                level: metadata.level().to_string(),
+                target: metadata.target().to_string(),
+                message: visitor.message,
+                fields: Value::Object(visitor.fields),
+                spans,
+                timestamp,
+            },
+            ThreadsafeFunctionCallMode::NonBlocking,
+        );
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: Map<String, Value>,
+}
+
+impl FieldVisitor {
+    fn insert(&mut self, field: &Field, value: Value) {
+        if field.name() == "message" {
+            self.message = match value {
+                Value::String(s) => s,
+                v => v.to_string(),
+            };
+        } else {
+            self.fields.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.insert(field, format!("{:?}", value).into());
+    }
+}
@@ -6,6 +6,7 @@ use pyo3::types::{PyDict, PyModule};
 use pyo3::{Py, PyAny, PyErr, Python};
 use std::path::PathBuf;
 use std::str::FromStr;
+use tracing::{debug, info_span};
 
 pub fn initialize_python(dir: &Dirs) -> Result<Py<PyAny>> {
     let appdata_dir = get_data_dir(dir)?;
@@ -15,6 +16,7 @@ pub fn initialize_python(dir: &Dirs) -> Result<Py<PyAny>> {
     let base_plugin_dir = PathBuf::from_str(&dir.default_plugins_dir)?.join("base");
 
     // Pythonのプラグインシステムを初期化
+    let _span = info_span!("initialize_python").entered();
     let pl_manager = Python::attach(|py| {
         // pythonのversionを取得
         let sys = py.import("sys")?;
@@ -31,12 +33,14 @@ pub fn initialize_python(dir: &Dirs) -> Result<Py<PyAny>> {
         sys_path.call_method1("append", (&dir.plugin_manager_dir,))?;
         sys_path.call_method1("append", (&appdata_dir,))?;
 
-        // sys.pathを表示
         let sys_path: Vec<String> = sys.getattr("path")?.extract()?;
-        println!("sys.path: {:?}", sys_path);
+        debug!(?sys_path, "Python sys.path");
 
-        // plmanagerのPluginManagerを初期化
+        // Pythonのloggingをtracingに転送する
         let pl_manager = py.import("aperio_plugin")?;
+        pl_manager.getattr("install_log_bridge")?.call0()?;
+
+        // plmanagerのPluginManagerを初期化
         let init_func = pl_manager.getattr("PluginManager")?;
         let pl_manager = init_func.call1((appdata_dir,))?;
 
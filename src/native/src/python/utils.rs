@@ -10,6 +10,7 @@ use std::process::Command;
 use std::str::FromStr;
 use std::{env, fs};
 use toml_edit::DocumentMut;
+use tracing::{debug, info, instrument, warn};
 
 pub struct PythonStatus {
     pub installed: bool,
@@ -31,15 +32,21 @@ fn file_extension(base_dir: &Path, s: &str) -> PathBuf {
     base_dir.join(s)
 }
 
+#[instrument(skip_all, fields(command = args.first()))]
 fn run_uv(dir: &Dirs, args: Vec<&str>) -> Result<String> {
     let bin_dir = PathBuf::from_str(&dir.resource_dir)?.join("bin");
+    debug!(?args, "Running uv");
     let output = Command::new(file_extension(&bin_dir, "uv"))
         .args(args)
         .output()?;
 
+    let stderr = String::from_utf8(output.stderr.clone())?;
     if !output.status.success() {
-        bail!(String::from_utf8(output.stderr.clone())?);
+        warn!(status = %output.status, stderr = %stderr.trim(), "uv failed");
+        bail!(stderr);
     }
+    // uvは進捗をstderrに出力する
+    debug!(stderr = %stderr.trim(), "uv finished");
 
     Ok(String::from_utf8(output.stdout.clone())?)
 }
@@ -62,7 +69,7 @@ pub fn add_python_path_env(dir: &Dirs) -> Result<()> {
         PyConfig_InitIsolatedConfig(&mut config);
         PyConfig_SetBytesString(&mut config, &mut config.executable, bin_path.as_ptr());
 
-        let err = Py_InitializeFromConfig(&mut config);
+        let err = Py_InitializeFromConfig(&config);
         PyConfig_Clear(&mut config);
         if PyStatus_Exception(err) != 0 {
             bail!(
@@ -79,11 +86,11 @@ pub fn check_python_installed(dir: &Dirs) -> Result<PythonStatus> {
     let appdata_dir = get_local_data_dir(dir)?;
     // python/bin/python(.exe)のpathを取得
     let python_path = file_extension(&appdata_dir.join("python").join("bin"), "python");
-    println!("Checking for Python at path: {:?}", python_path);
+    debug!(path = ?python_path, "Checking for Python");
 
     // pythonが存在するか確認
     if !python_path.exists() {
-        println!("Python executable not found at {:?}", python_path);
+        info!(path = ?python_path, "Python executable not found");
         return Ok(PythonStatus {
             installed: false,
             version: None,
@@ -99,7 +106,7 @@ pub fn check_python_installed(dir: &Dirs) -> Result<PythonStatus> {
         let major: i32 = version.get_item(0)?.extract()?;
         let minor: i32 = version.get_item(1)?.extract()?;
         let micro: i32 = version.get_item(2)?.extract()?;
-        println!("Embed Python version: {}", sys.getattr("version")?);
+        debug!(version = %sys.getattr("version")?, "Embed Python version");
 
         Ok(format!("{}.{}.{}", major, minor, micro))
     })?;
@@ -117,9 +124,10 @@ pub fn check_python_installed(dir: &Dirs) -> Result<PythonStatus> {
     let installed_python_version = installed_python_version.trim(); // 改行を削除
 
     if installed_python_version != python_version {
-        println!(
-            "Python version mismatch: expected(embed libpython) {}, found(installed python) {}. Try reinstalling.",
-            python_version, installed_python_version
+        warn!(
+            expected = %python_version,
+            found = %installed_python_version,
+            "Python version mismatch between embedded libpython and installed python. Try reinstalling."
         );
         // ディレクトリを削除
         fs::remove_dir_all(appdata_dir.join("python")).ok();
@@ -135,6 +143,7 @@ pub fn check_python_installed(dir: &Dirs) -> Result<PythonStatus> {
     })
 }
 
+#[instrument(skip(dir))]
 pub fn install_packages(dir: &Dirs, packages: Vec<&str>) -> Result<()> {
     // appdataのdir pathを取得
     let appdata_path = get_local_data_dir(dir)?;
@@ -158,6 +167,7 @@ pub fn install_packages(dir: &Dirs, packages: Vec<&str>) -> Result<()> {
     Ok(())
 }
 
+#[instrument(skip(dir))]
 pub fn install_python(dir: &Dirs, python_version: &str, is_vague: bool) -> Result<()> {
     // appdataのdir pathを取得
     let appdata_path = get_local_data_dir(dir)?;
@@ -180,10 +190,7 @@ pub fn install_python(dir: &Dirs, python_version: &str, is_vague: bool) -> Resul
         let mut pj_data = pj_data.parse::<DocumentMut>()?;
         pj_data["project"]["requires-python"] = toml_edit::value(&python_version_str);
         fs::write(&appdata_toml, pj_data.to_string())?;
-        println!(
-            "Updated pyproject.toml with requires-python = {}",
-            &python_version_str
-        );
+        info!(requires_python = %python_version_str, "Updated pyproject.toml");
     } else {
         let mut args = vec![
             "init",
@@ -250,7 +257,7 @@ pub fn install_python(dir: &Dirs, python_version: &str, is_vague: bool) -> Resul
             .to_str()
             .context("could not convert wheel path to str")?],
     )?;
-    println!("Successfully installed Python and required packages");
+    info!("Successfully installed Python and required packages");
 
     Ok(())
 }
@@ -264,5 +271,5 @@ pub fn sync_packages(dir: &Dirs) -> Result<String> {
     let mut args = vec!["sync"];
     args.extend(get_base_args(appdata_dir));
 
-    Ok(run_uv(dir, args)?)
+    run_uv(dir, args)
 }
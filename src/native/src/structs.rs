@@ -37,7 +37,7 @@ pub struct FrameLayerStructure {
     pub effects: Vec<GenerateStructure>,
 }
 
-impl<'a, 'py> IntoPyObject<'py> for &'a GenerateStructure {
+impl<'py> IntoPyObject<'py> for &GenerateStructure {
     type Target = PyAny;
     type Output = Bound<'py, Self::Target>;
     type Error = pyo3::PyErr;
@@ -46,7 +46,7 @@ impl<'a, 'py> IntoPyObject<'py> for &'a GenerateStructure {
         let dict = PyDict::new(py);
         dict.set_item("name", &self.name)?;
         dict.set_item("parameters", json_to_pyobject(py, &self.parameters)?)?;
-        Ok(dict.into_bound_py_any(py)?)
+        dict.into_bound_py_any(py)
     }
 }
 
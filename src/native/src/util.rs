@@ -3,12 +3,13 @@ use std::str::FromStr;
 use anyhow::Result;
 use pyo3::{Bound, IntoPyObjectExt, Py, PyAny, PyResult, Python, types::{PyAnyMethods, PyDict, PyList, PyListMethods}};
 use serde_json::Value;
+use tracing::info;
 use crate::Dirs;
 
 pub fn get_data_dir(dirs: &Dirs) -> Result<PathBuf> {
     let appdata_dir = PathBuf::from_str(&dirs.data_dir)?;
     if !appdata_dir.exists() {
-        println!("Creating app data directory at {:?}", &appdata_dir);
+        info!(path = ?appdata_dir, "Creating app data directory");
         std::fs::create_dir_all(&appdata_dir)?;
     }
     Ok(appdata_dir)
@@ -17,7 +18,7 @@ pub fn get_data_dir(dirs: &Dirs) -> Result<PathBuf> {
 pub fn get_local_data_dir(dirs: &Dirs) -> Result<PathBuf> {
     let local_data_dir = PathBuf::from_str(&dirs.local_data_dir)?;
     if !local_data_dir.exists() {
-        println!("Creating local data directory at {:?}", &local_data_dir);
+        info!(path = ?local_data_dir, "Creating local data directory");
         std::fs::create_dir_all(&local_data_dir)?;
     }
     Ok(local_data_dir)
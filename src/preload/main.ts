@@ -1,6 +1,6 @@
 import { contextBridge, ipcRenderer } from "electron";
 import path from "path";
-import { FrameLayerStructure, PlManager } from "native";
+import { FrameLayerStructure, LogEvent, PlManager, setLogListener } from "native";
 
 let plManagerSingleton: PlManager | null = null;
 
@@ -47,6 +47,16 @@ contextBridge.exposeInMainWorld("frame", {
     plManagerSingleton?.getFrame(data, count, frameStruct);
     p1.postMessage(buffer, [buffer]);
   },
+  setProfiling: (enabled: boolean) => {
+    plManagerSingleton?.setProfiling(enabled);
+  },
+  // 最後に生成したフレームの実行レポート。プロファイリングが無効な場合はnull
+  getLastReport: () => plManagerSingleton?.getLastReport() ?? null,
+  getLastChromeTrace: () => plManagerSingleton?.getLastChromeTrace() ?? null,
+  // ネイティブ側とPythonのログのイベントを受け取るコールバックを登録する。nullで解除
+  onLog: (callback: ((event: LogEvent) => void) | null) => {
+    setLogListener(callback);
+  },
 });
 
 contextBridge.exposeInMainWorld("path", {
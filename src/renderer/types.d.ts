@@ -1,10 +1,14 @@
-import { FrameLayerStructure } from "native";
+import { FrameLayerStructure, LogEvent } from "native";
 
 declare global {
   interface Window {
     frame: {
       init: () => void;
       getFrame: (count: number, frameStruct: FrameLayerStructure[]) => Promise<Uint8Array<ArrayBufferLike>>;
+      setProfiling: (enabled: boolean) => void;
+      getLastReport: () => unknown;
+      getLastChromeTrace: () => string | null;
+      onLog: (callback: ((event: LogEvent) => void) | null) => void;
     },
     path: {
       getPath: (name: "userData" | "temp" | "exe") => Promise<string>;